@@ -0,0 +1,90 @@
+//! Compares gates from a `SlotPool` with gates from plain `gate()`.
+//!
+//! This runs without the unstable bench harness. Use
+//! `cargo bench --bench slot_pool` to run it. For each variant, it prints
+//! the time per gate and how many slots had to be allocated.
+//!
+//! The loop behind the notifier is never run, so its notification queue
+//! fills up quickly and most wakeups fail. Both variants pay the same for
+//! that, so the comparison still holds.
+
+extern crate netmachines;
+extern crate rotor;
+
+use std::time::{Duration, Instant};
+use netmachines::sync::{GateReceiver, GateSender, SlotPool, gate};
+use rotor::{Config, EventSet, Loop, Machine, Notifier, Response, Scope,
+            Void};
+
+
+//------------ Idle ----------------------------------------------------------
+
+/// A machine that does nothing but provide a notifier.
+struct Idle;
+
+impl Machine for Idle {
+    type Context = ();
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<()>) -> Response<Self, Void> {
+        match seed { }
+    }
+
+    fn ready(self, _events: EventSet, _scope: &mut Scope<()>)
+             -> Response<Self, Void> {
+        Response::ok(self)
+    }
+
+    fn spawned(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+        Response::ok(self)
+    }
+
+    fn timeout(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+        Response::ok(self)
+    }
+
+    fn wakeup(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+        Response::ok(self)
+    }
+}
+
+
+//------------ Benchmarks ----------------------------------------------------
+
+const ROUNDS: usize = 1_000_000;
+
+/// Sends one value through each of `ROUNDS` gates made by `make`.
+fn run<F>(notifier: &Notifier, make: F) -> Duration
+       where F: Fn(Notifier) -> (GateSender<usize>, GateReceiver<usize>) {
+    let start = Instant::now();
+    for i in 0..ROUNDS {
+        let (tx, rx) = make(notifier.clone());
+        let _ = tx.send(i);
+        assert_eq!(rx.try_get().unwrap(), Some(i));
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration, allocated: usize) {
+    let nanos = elapsed.as_secs() * 1_000_000_000
+              + elapsed.subsec_nanos() as u64;
+    println!("{:<8} {:>6} ns/gate {:>8} slots allocated",
+             name, nanos / ROUNDS as u64, allocated);
+}
+
+fn main() {
+    let mut lc = Loop::new(&Config::new()).unwrap();
+    let mut notifier = None;
+    lc.add_machine_with(|scope| {
+        notifier = Some(scope.notifier());
+        Response::ok(Idle)
+    }).unwrap();
+    let notifier = notifier.unwrap();
+
+    let elapsed = run(&notifier, gate);
+    report("gate()", elapsed, ROUNDS);
+
+    let pool = SlotPool::new(16);
+    let elapsed = run(&notifier, |notifier| pool.gate(notifier));
+    report("pool", elapsed, pool.allocated());
+}
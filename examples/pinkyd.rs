@@ -47,14 +47,18 @@ use std::mem;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::thread;
+use std::time::Duration;
 use bytes::{Buf, ByteBuf};
 use netmachines::error::Error;
-use netmachines::handlers::{AcceptHandler, TransportHandler};
+use netmachines::handlers::{AcceptHandler, AwaitResponse, TransportHandler};
+use netmachines::net::signals::SignalMachine;
 use netmachines::next::Next;
-use netmachines::sockets::{Dgram, Stream};
+use netmachines::sockets::{Dgram, PeerAddr, Stream};
 use netmachines::sync::{DuctReceiver, DuctSender, GateReceiver, GateSender,
-                        Receiver, Sender, channel, duct, gate};
-use rotor::Notifier;
+                        Receiver, Sender, SlotPool, TriggerSender, channel,
+                        duct};
+use netmachines::utils::{ResponseExt, parse_line};
+use rotor::{Compose2, Notifier};
 use rotor::mio::tcp::TcpListener;
 use rotor::mio::udp::UdpSocket;
 use simplelog::{TermLogger, LogLevelFilter};
@@ -88,15 +92,24 @@ fn main() {
     // Create a rotor loop with default config.
     let mut lc = rotor::Loop::new(&rotor::Config::new()).unwrap();
 
+    // The server machines hand out triggers for shutting them down. We
+    // collect them for the signal machine below.
+    let mut triggers = Vec::new();
+
     // When creating a machine, you need the scope. But for that the
     // underyling rotor bits need to be there, hence the closure.
     //
     // The FingerServer has a new function for each of the machine types it
     // supports. First we create the TCP server machine. It wants a value
-    // of the accept handler, so we create one.
+    // of the accept handler, so we create one. Since the loop also runs
+    // the signal machine, the server is wrapped into the first variant of
+    // the LoopMachine.
     lc.add_machine_with(|scope| {
-        // XXX Do something with the trigger.
-        FingerServer::new_tcp(tcp, StreamAccept::new(tx.clone()), scope).0
+        let (m, trigger) = FingerServer::new_tcp(tcp,
+                                                 StreamAccept::new(tx.clone()),
+                                                 scope);
+        triggers.push(trigger);
+        m.map_self(Compose2::A)
     }).unwrap();
 
     // ... and the UDP socket. This one needs a value of the seed for the
@@ -104,19 +117,25 @@ fn main() {
     // via its create() functions). See the StreamAccept type below for
     // a discussion of transport seeds.
     lc.add_machine_with(|scope| {
-        FingerServer::new_udp(udp, tx.clone(), scope)
+        FingerServer::new_udp(udp, tx.clone(), scope).map_self(Compose2::A)
     }).unwrap();
 
     // We only do TLS if netmachines has been built with a TLS implementation.
     // The cfg attributes only work on item level, so we have to have a
     // separate function for it.
-    add_tls_sockets(&config, &tx, &mut lc);
+    add_tls_sockets(&config, &tx, &mut triggers, &mut lc);
+
+    // Finally, the signal machine fires all the triggers and shuts down
+    // the loop upon SIGTERM or SIGINT.
+    lc.add_machine_with(|scope| {
+        SignalMachine::new(triggers, scope).map_self(Compose2::B)
+    }).unwrap();
 
     info!("Setting up done.");
     lc.run(()).unwrap();
 
-    // Cleanup. Since we currently don’t handle signals, we probably never
-    // will arrive here.
+    // Cleanup. The loop only returns once the signal machine shut it down.
+    // Dropping our sender lets the processor thread finish.
     drop(tx);
     join.join().unwrap();
 }
@@ -127,7 +146,8 @@ fn main() {
 /// Creates a self-signed certificate on the fly.
 #[cfg(feature = "openssl")]
 fn add_tls_sockets(__config: &Config, tx: &RequestSender,
-                   lc: &mut rotor::Loop<FingerServer>) {
+                   triggers: &mut Vec<TriggerSender>,
+                   lc: &mut rotor::Loop<LoopMachine>) {
     use openssl::x509::X509Generator;
     use openssl::crypto::hash::Type;
     use openssl::ssl::{SslContext, SslMethod};
@@ -148,8 +168,11 @@ fn add_tls_sockets(__config: &Config, tx: &RequestSender,
     let tls = TlsListener::bind(&addr, ctx).unwrap();
 
     lc.add_machine_with(|scope| {
-        // XXX Do something with the trigger.
-        FingerServer::new_tls(tls, StreamAccept::new(tx.clone()), scope).0
+        let (m, trigger) = FingerServer::new_tls(tls,
+                                                 StreamAccept::new(tx.clone()),
+                                                 scope);
+        triggers.push(trigger);
+        m.map_self(Compose2::A)
     }).unwrap();
 }
 
@@ -158,7 +181,9 @@ fn add_tls_sockets(__config: &Config, tx: &RequestSender,
 ///
 /// Ie., it doesn’t.
 #[cfg(not(feature = "openssl"))]
-fn add_tls_sockets(_config: &Config, _lc: &mut rotor::Loop<FingerServer>) {
+fn add_tls_sockets(_config: &Config, _tx: &RequestSender,
+                   _triggers: &mut Vec<TriggerSender>,
+                   _lc: &mut rotor::Loop<LoopMachine>) {
 }
 
 
@@ -197,22 +222,39 @@ type FingerServer = TlsTcpUdpServer<(), StreamAccept, StreamAccept,
 type FingerServer = TcpUdpServer<(), StreamAccept, DgramHandler>;
 
 
+//------------ LoopMachine ---------------------------------------------------
+
+/// The machine type of the loop.
+///
+/// Next to the server, the loop runs a signal machine that shuts
+/// everything down upon SIGTERM or SIGINT. Rotor’s `Compose2` combines
+/// the two.
+type LoopMachine = Compose2<FingerServer, SignalMachine<()>>;
+
+
 //------------ StreamAccept --------------------------------------------------
 
 /// The accept handler for stream sockets.
 ///
 /// This type stores all information that needs to be passed to each and
 /// every stream transport handler which, in our case, is the sending end
-/// of the request queue.
+/// of the request queue and the pool of gates for returning responses.
+///
+/// The pool is shared between all clones of the value, so all stream
+/// servers recycle the same gates.
 #[derive(Clone)]
 struct StreamAccept {
-    req_tx: RequestSender
+    req_tx: RequestSender,
+    gates: SlotPool<String>,
 }
 
+/// The number of unused gates the accept handler keeps around.
+const GATE_POOL_SIZE: usize = 128;
+
 impl StreamAccept {
     /// Creates a new accept handler value.
     fn new(req_tx: RequestSender) -> Self {
-        StreamAccept { req_tx: req_tx }
+        StreamAccept { req_tx: req_tx, gates: SlotPool::new(GATE_POOL_SIZE) }
     }
 }
 
@@ -241,8 +283,8 @@ impl StreamAccept {
 impl<T: Stream> AcceptHandler<T> for StreamAccept {
     type Output = StreamHandler;
 
-    fn accept(&mut self, _addr: &SocketAddr) -> Option<RequestSender> {
-        Some(self.req_tx.clone())
+    fn accept(&mut self, _addr: &PeerAddr) -> Option<StreamSeed> {
+        Some((self.req_tx.clone(), self.gates.clone()))
     }
 }
 
@@ -307,7 +349,7 @@ impl<T: Stream> TransportHandler<T> for StreamHandler {
     /// construct a new transport handler value. See the discussion of
     /// `StreamHandler` above as to why introducing this seed type may have
     /// been a good idea.
-    type Seed = RequestSender;
+    type Seed = StreamSeed;
 
     /// A new transport has been created.
     ///
@@ -326,7 +368,7 @@ impl<T: Stream> TransportHandler<T> for StreamHandler {
     /// In our case, we simply defer processing to our first state.
     fn create(seed: Self::Seed, _sock: &mut T, notifier: Notifier)
                  -> Next<Self> {
-        StreamRequest::new(seed, notifier)
+        StreamRequest::new(seed.0, seed.1, notifier)
     }
 
     /// The transport socket may have become readable.
@@ -379,10 +421,16 @@ impl<T: Stream> TransportHandler<T> for StreamHandler {
     /// set a timeout by calling `Next`’s `timeout()` function. If no event
     /// happens before that time has passed, `Error::Timeout` happens instead.
     ///
-    /// The implementation below is identical to the default implementation
-    /// and given here merely for posterity.
-    fn error(self, _err: Error) -> Next<Self> {
-        Next::remove()
+    /// We only set a timeout in the await stage, so we don’t hang forever if
+    /// the processor never answers. If that happens, we tell the client.
+    /// Everything else gets the default treatment.
+    fn error(self, err: Error) -> Next<Self> {
+        match self {
+            StreamHandler::Await(_) if err.is_timeout() => {
+                StreamResponse::new(b"Processor took too long.\r\n")
+            }
+            _ => Next::remove()
+        }
     }
 }
 
@@ -394,6 +442,9 @@ struct StreamRequest {
     /// The sending end of the channel for requests.
     sender: RequestSender,
 
+    /// The pool to take the gate for the response from.
+    gates: SlotPool<String>,
+
     /// A notifier to wake ourselves up later.
     notifier: Notifier,
 
@@ -407,10 +458,12 @@ impl StreamRequest {
     /// Most attributes have to be passed in from the outside. The buffer,
     /// however, is created anew. We reserve space for one standard-sized
     /// line which should really be enough.
-    fn new(sender: RequestSender, notifier: Notifier) -> Next<StreamHandler> {
+    fn new(sender: RequestSender, gates: SlotPool<String>,
+           notifier: Notifier) -> Next<StreamHandler> {
         Next::read(
             StreamHandler::Request(
-                StreamRequest { sender: sender, notifier: notifier,
+                StreamRequest { sender: sender, gates: gates,
+                                notifier: notifier,
                                 buf: Vec::with_capacity(80) }
             )
         )
@@ -463,8 +516,12 @@ impl StreamRequest {
     /// The method creates a ‘portal’ for the response and then sends it off
     /// to the processor. See `Return` for a discussion of how responses are
     /// returned.
+    ///
+    /// Rather than allocating a new gate for every request, we take one
+    /// from the pool. It goes back once the await stage has received the
+    /// response and dropped the receiver.
     fn progress(self, request: Request) -> Next<StreamHandler> {
-        let (tx, rx) = gate(self.notifier);
+        let (tx, rx) = self.gates.gate(self.notifier);
 
         if let Err(_) = self.sender.send((request, Return::Stream(tx))) {
             return StreamResponse::new(b"Service temporarily kaputt.\r\n");
@@ -477,32 +534,42 @@ impl StreamRequest {
 
 //--- StreamAwait
 
+/// How long we wait for the processor to answer.
+const AWAIT_TIMEOUT: u64 = 10;
+
 /// The await stage of handling a stream transaction.
 struct StreamAwait {
     /// A response will mysteriously appear here.
-    rx: GateReceiver<String>
+    ///
+    /// Or not, in which case the await response will time out eventually.
+    response: AwaitResponse<String>
 }
 
 impl StreamAwait {
     /// Creates the initial next stream handler for the await stage.
+    ///
+    /// This arms the timeout for waiting.
     fn new(rx: GateReceiver<String>) -> Next<StreamHandler> {
-        Next::wait(
-            StreamHandler::Await(
-                StreamAwait { rx: rx }
-            )
-        )
+        let response = AwaitResponse::new(rx,
+                                          Duration::from_secs(AWAIT_TIMEOUT));
+        response.wait(|response| {
+            StreamHandler::Await(StreamAwait { response: response })
+        })
     }
 
     /// The machine has been woken up through a notifier.
     ///
-    /// This happens when the processor has finished its job. We try to
-    /// get the response by replacing whatever is in `self.rx` with a
-    /// `None`. If that leads to `Some(_)`thing, then we have a response
-    /// and can move on. Otherwise, we just keep waiting.
+    /// This happens when the processor has finished its job. We check if
+    /// the response has arrived. If so, we can move on. Otherwise, we just
+    /// keep waiting.
     fn wakeup(self) -> Next<StreamHandler> {
-        match self.rx.try_get() {
-            Ok(Some(response)) => StreamResponse::new(response.as_bytes()),
-            Ok(None) => Next::wait(StreamHandler::Await(self)),
+        match self.response.poll() {
+            Ok(Ok(response)) => StreamResponse::new(response.as_bytes()),
+            Ok(Err(response)) => {
+                Next::wait(StreamHandler::Await(
+                    StreamAwait { response: response }
+                ))
+            }
             Err(_) => StreamResponse::new(b"Internal server error.\r\n")
         }
     }
@@ -647,7 +714,7 @@ impl<T: Dgram> TransportHandler<T> for DgramHandler {
                 Err(_) => return Next::remove()
             }
         }
-        while let Ok(Some((message, addr))) = self.rx.try_recv() {
+        for (message, addr) in self.rx.try_iter() {
             match sock.send_to(message.as_bytes(), &addr) {
                 Ok(Some(_)) => { }
                 Ok(None) => {
@@ -692,13 +759,16 @@ struct Request {
 }
 
 
+/// The maximum length of a request line we accept.
+const MAX_LINE_LEN: usize = 1024;
+
 impl Request {
     fn parse(data: &[u8]) -> Result<Option<Self>, &'static str> {
         // If there is no "\r\n" in line, we need more data.
-        let mut line = match data.split(|ch| *ch == b'\n')
-                                 .next().map(|line| line.split_last()) {
-            Some(Some((&b'\r', line))) => line,
-            _ => return Ok(None)
+        let mut line = match parse_line(data, MAX_LINE_LEN) {
+            Ok(Some((line, _))) => line,
+            Ok(None) => return Ok(None),
+            Err(_) => return Err("I don't understand your request.\r\n")
         };
 
         // Get on optional starting "/W" followed by spaces.
@@ -763,6 +833,14 @@ impl Return {
 type RequestSender = Sender<(Request, Return)>;
 
 
+//------------ StreamSeed ----------------------------------------------------
+
+/// The seed for stream transport handlers.
+///
+/// The request sender and the pool of response gates.
+type StreamSeed = (RequestSender, SlotPool<String>);
+
+
 //------------ Processor -----------------------------------------------------
 
 struct Processor {
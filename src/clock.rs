@@ -0,0 +1,58 @@
+//! Access to the current time.
+//!
+//! All machines learn about the current time through [now()] rather than
+//! asking their scope directly. Normally, this is exactly the same thing.
+//! With the `test-util` feature enabled, however, the time can be moved
+//! forward manually for the current thread with [advance()]. This allows
+//! testing timeout behaviour without actually having to wait.
+//!
+//! Since only the time seen by the machines and handlers is shifted, the
+//! deadlines handed to rotor are shifted, too. With a clock advanced by
+//! some amount, rotor will fire timers that much later. Tests will want
+//! to call the machines’ `timeout()` methods themselves instead.
+//!
+//! [now()]: fn.now.html
+//! [advance()]: fn.advance.html
+
+use rotor::{GenericScope, Time};
+#[cfg(feature = "test-util")] use std::cell::Cell;
+#[cfg(feature = "test-util")] use std::time::Duration;
+
+
+/// Returns the current time.
+#[cfg(not(feature = "test-util"))]
+#[inline]
+pub fn now<S: GenericScope>(scope: &S) -> Time {
+    scope.now()
+}
+
+/// Returns the current time.
+///
+/// This is the scope’s time moved forward by everything passed to
+/// [advance()](fn.advance.html) on this thread so far.
+#[cfg(feature = "test-util")]
+pub fn now<S: GenericScope>(scope: &S) -> Time {
+    scope.now() + offset()
+}
+
+
+#[cfg(feature = "test-util")]
+thread_local!(static OFFSET: Cell<Duration> = Cell::new(Duration::new(0, 0)));
+
+/// Moves the clock of the current thread forward by `dur`.
+#[cfg(feature = "test-util")]
+pub fn advance(dur: Duration) {
+    OFFSET.with(|offset| offset.set(offset.get() + dur))
+}
+
+/// Returns how far the clock of the current thread has been moved.
+#[cfg(feature = "test-util")]
+pub fn offset() -> Duration {
+    OFFSET.with(|offset| offset.get())
+}
+
+/// Resets the clock of the current thread to the scope’s time.
+#[cfg(feature = "test-util")]
+pub fn reset() {
+    OFFSET.with(|offset| offset.set(Duration::new(0, 0)))
+}
@@ -0,0 +1,205 @@
+//! Coalescing identical requests.
+//!
+//! If many parts of an application ask a client for the same thing at the
+//! same time, there is little point in having the client do it many times
+//! over. The [Coalesce] request handler wraps a [CoalesceHandler] and
+//! only passes on the first of all requests with the same key. Further
+//! requests with that key are attached to the one in flight until it is
+//! done.
+//!
+//! Each request is sent to the client’s request duct together with the
+//! sending end of a [gate]. The inner handler’s
+//! [request()](trait.CoalesceHandler.html#tymethod.request) method
+//! receives a [Completion] in addition to the request. It passes this on
+//! to the transport handler, typically as part of its seed. Once it has
+//! the result, the transport handler calls the completion’s
+//! [complete()](struct.Completion.html#method.complete) method and all the
+//! gates attached receive a copy of the result.
+//!
+//! The gates carry an `Option<_>` of the result. If the completion is
+//! dropped without being completed, for instance because the transport
+//! failed early or a socket couldn’t be created, all gates receive `None`.
+//! The next request with that key will then start afresh.
+//!
+//! [Coalesce]: struct.Coalesce.html
+//! [CoalesceHandler]: trait.CoalesceHandler.html
+//! [Completion]: struct.Completion.html
+//! [gate]: ../sync/fn.gate.html
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use ::error::Error;
+use ::handlers::RequestHandler;
+use ::sync::GateSender;
+
+
+/// The map of requests in flight and the gates waiting for them.
+type InFlight<K, R> = Arc<Mutex<HashMap<K, Vec<GateSender<Option<R>>>>>>;
+
+
+//------------ CoalesceHandler -----------------------------------------------
+
+/// The trait for request handlers wrapped by [Coalesce].
+///
+/// This is almost identical to [RequestHandler] except that requests have
+/// a key and each request comes with a [Completion].
+///
+/// [Coalesce]: struct.Coalesce.html
+/// [RequestHandler]: ../handlers/trait.RequestHandler.html
+/// [Completion]: struct.Completion.html
+pub trait CoalesceHandler {
+    /// The type representing a request.
+    type Request: Send;
+
+    /// The key by which identical requests are recognized.
+    type Key: Clone + Eq + Hash + Send;
+
+    /// The result of a request passed to everyone waiting for it.
+    type Result: Clone + Send;
+
+    /// The output type produced by the handler.
+    type Output;
+
+    /// Returns the key of a request.
+    fn key(&self, request: &Self::Request) -> Self::Key;
+
+    /// Processes the first request for a key.
+    ///
+    /// This is like [RequestHandler::request()] except for the additional
+    /// `done` argument which needs to be completed eventually.
+    ///
+    /// [RequestHandler::request()]:
+    ///     ../handlers/trait.RequestHandler.html#tymethod.request
+    fn request(&mut self, request: Self::Request,
+               done: Completion<Self::Key, Self::Result>)
+               -> Option<Self::Output>;
+
+    /// Handles an error that happened during socket creation.
+    ///
+    /// This works exactly like [RequestHandler::error()]. The completion
+    /// contained in `output` most likely gets dropped and everyone waiting
+    /// receives `None`.
+    ///
+    /// [RequestHandler::error()]:
+    ///     ../handlers/trait.RequestHandler.html#method.error
+    fn error(&mut self, output: Self::Output, err: Error) {
+        let _ = (output, err);
+    }
+
+    /// Handles an error and possibly picks a different output.
+    ///
+    /// This works exactly like [RequestHandler::fallback()].
+    ///
+    /// [RequestHandler::fallback()]:
+    ///     ../handlers/trait.RequestHandler.html#method.fallback
+    fn fallback(&mut self, output: Self::Output, err: Error)
+                -> Option<Self::Output> {
+        self.error(output, err);
+        None
+    }
+}
+
+
+//------------ Coalesce ------------------------------------------------------
+
+/// A request handler coalescing identical requests.
+///
+/// See the [module documentation](index.html) for details.
+pub struct Coalesce<H: CoalesceHandler> {
+    handler: H,
+    in_flight: InFlight<H::Key, H::Result>,
+}
+
+impl<H: CoalesceHandler> Coalesce<H> {
+    /// Creates a new handler wrapping `handler`.
+    pub fn new(handler: H) -> Self {
+        Coalesce { handler: handler,
+                   in_flight: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Returns the number of distinct requests currently in flight.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.lock().map(|map| map.len()).unwrap_or(0)
+    }
+}
+
+impl<H: CoalesceHandler> RequestHandler for Coalesce<H> {
+    type Request = (H::Request, GateSender<Option<H::Result>>);
+    type Output = H::Output;
+
+    fn request(&mut self, request: Self::Request) -> Option<Self::Output> {
+        let (request, gate) = request;
+        let key = self.handler.key(&request);
+        {
+            let mut map = match self.in_flight.lock() {
+                Ok(map) => map,
+                Err(_) => return None
+            };
+            if let Some(waiting) = map.get_mut(&key) {
+                waiting.push(gate);
+                return None
+            }
+            map.insert(key.clone(), vec![gate]);
+        }
+        let done = Completion { key: Some(key),
+                                in_flight: self.in_flight.clone() };
+        self.handler.request(request, done)
+    }
+
+    fn error(&mut self, output: Self::Output, err: Error) {
+        self.handler.error(output, err)
+    }
+
+    fn fallback(&mut self, output: Self::Output, err: Error)
+                -> Option<Self::Output> {
+        self.handler.fallback(output, err)
+    }
+}
+
+
+//------------ Completion ----------------------------------------------------
+
+/// The means to deliver the result of a coalesced request.
+///
+/// If the value is dropped without [complete()](#method.complete) having
+/// been called, everyone waiting for the result receives `None`.
+pub struct Completion<K: Eq + Hash, R: Clone + Send> {
+    key: Option<K>,
+    in_flight: InFlight<K, R>,
+}
+
+impl<K: Eq + Hash, R: Clone + Send> Completion<K, R> {
+    /// Returns the key of the request.
+    pub fn key(&self) -> &K {
+        self.key.as_ref().expect("completion already used")
+    }
+
+    /// Delivers the result to everyone waiting for it.
+    pub fn complete(mut self, result: R) {
+        self.finish(Some(result))
+    }
+
+    /// Removes the request from the map and hands out the result.
+    fn finish(&mut self, result: Option<R>) {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return
+        };
+        let waiting = match self.in_flight.lock() {
+            Ok(mut map) => map.remove(&key),
+            Err(_) => None
+        };
+        for gate in waiting.into_iter().flat_map(|waiting| waiting) {
+            if gate.send(result.clone()).is_err() {
+                debug!("failed to deliver coalesced result");
+            }
+        }
+    }
+}
+
+impl<K: Eq + Hash, R: Clone + Send> Drop for Completion<K, R> {
+    fn drop(&mut self) {
+        self.finish(None)
+    }
+}
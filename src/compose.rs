@@ -1,6 +1,17 @@
-
-use rotor::{EventSet, Machine, Response, Scope, Void};
-use ::utils::ResponseExt;
+//! Combining several machines into one.
+//!
+//! A rotor loop runs machines of a single type, so different machines
+//! have to be combined into an enum with a variant for each of them. For
+//! two machines, rotor provides `Compose2`, re-exported here. For three
+//! and four machines, there are [Compose3] and [Compose4]. Each comes
+//! with a seed enum with a variant for each machine’s seed.
+//!
+//! Both are defined via the [compose_machine!] macro which you can use
+//! for combining any other number of machines.
+//!
+//! [Compose3]: enum.Compose3.html
+//! [Compose4]: enum.Compose4.html
+//! [compose_machine!]: ../macro.compose_machine!.html
 
 //------------ Compose2 -----------------------------------------------------
 
@@ -9,80 +20,23 @@ pub use rotor::Compose2;
 
 //------------ Compose3 -----------------------------------------------------
 
-pub enum Compose3<A: Sized, B: Sized, C: Sized> {
-    A(A),
-    B(B),
-    C(C)
-}
-
-pub enum Compose3Seed<A: Sized, B: Sized, C: Sized> {
-    As(A),
-    Bs(B),
-    Cs(C)
-}
-
-impl<X, AA, BB, CC> Machine for Compose3<AA, BB, CC>
-                    where AA: Machine<Context=X>,
-                          BB: Machine<Context=X>,
-                          CC: Machine<Context=X> {
-    type Context = X;
-    type Seed = Compose3Seed<AA::Seed, BB::Seed, CC::Seed>;
-
-    fn create(seed: Self::Seed, scope: &mut Scope<X>)
-              -> Response<Self, Void> {
-        use self::Compose3::*;
-        use self::Compose3Seed::*;
-
-        match seed {
-            As(s) => AA::create(s, scope).map_self(A),
-            Bs(s) => BB::create(s, scope).map_self(B),
-            Cs(s) => CC::create(s, scope).map_self(C)
-        }
-    }
-
-    fn ready(self, events: EventSet, scope: &mut Scope<X>)
-             -> Response<Self, Self::Seed> {
-        use self::Compose3::*;
-        use self::Compose3Seed::*;
-
-        match self {
-            A(m) => m.ready(events, scope).map(A, As),
-            B(m) => m.ready(events, scope).map(B, Bs),
-            C(m) => m.ready(events, scope).map(C, Cs)
-        }
+compose_machine!(
+    /// A machine combining three machines.
+    Compose3,
+    /// The seed of a `Compose3` machine.
+    Compose3Seed {
+        A(As): AA, B(Bs): BB, C(Cs): CC
     }
+);
 
-    fn spawned(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
-        use self::Compose3::*;
-        use self::Compose3Seed::*;
-
-        match self {
-            A(m) => m.spawned(scope).map(A, As),
-            B(m) => m.spawned(scope).map(B, Bs),
-            C(m) => m.spawned(scope).map(C, Cs)
-        }
-    }
 
-    fn timeout(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
-        use self::Compose3::*;
-        use self::Compose3Seed::*;
+//------------ Compose4 -----------------------------------------------------
 
-        match self {
-            A(m) => m.timeout(scope).map(A, As),
-            B(m) => m.timeout(scope).map(B, Bs),
-            C(m) => m.timeout(scope).map(C, Cs)
-        }
+compose_machine!(
+    /// A machine combining four machines.
+    Compose4,
+    /// The seed of a `Compose4` machine.
+    Compose4Seed {
+        A(As): AA, B(Bs): BB, C(Cs): CC, D(Ds): DD
     }
-
-    fn wakeup(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
-        use self::Compose3::*;
-        use self::Compose3Seed::*;
-
-        match self {
-            A(m) => m.wakeup(scope).map(A, As),
-            B(m) => m.wakeup(scope).map(B, Bs),
-            C(m) => m.wakeup(scope).map(C, Cs)
-        }
-    }
-}
-
+);
@@ -0,0 +1,826 @@
+//! A transport handler that can switch to compressed transfer.
+//!
+//! The types defined here are available via the [handlers] module if the
+//! `flate2` feature is enabled.
+//!
+//! [handlers]: ../handlers/index.html
+
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+use flate2::{Compress, Compression, Decompress, Flush, Status};
+use rotor::{Evented, EventSet, Notifier, PollOpt};
+use rotor::mio::{Selector, Token};
+use ::error::Error;
+use ::handlers::{TransportEvent, TransportHandler, Userdata};
+use ::next::Next;
+use ::sockets::{Blocked, ClearStream, Stream, TcpInfo, Transport};
+use ::utils::{PriorityWriteQueue, QueueWriter};
+
+
+/// The size of the stack buffer for reading compressed data.
+const READ_CHUNK: usize = 4096;
+
+/// The amount of compressed output beyond which writes are refused.
+const WRITE_LIMIT: usize = 64 * 1024;
+
+
+//------------ CompressHandler -----------------------------------------------
+
+/// A transport handler that lets another handler switch on compression.
+///
+/// Some protocols negotiate compression during an initial exchange after
+/// which all further data is compressed. This handler wraps a transport
+/// handler for [CompressSock] and gives it such a socket instead of the
+/// actual socket. This socket starts out passing data through unchanged.
+/// Once the inner handler calls [enable_compression()], everything read
+/// is decompressed and everything written is compressed using zlib’s
+/// deflate format. Calling [disable_compression()] ends the compressed
+/// stream and returns to passing data through. Since [CompressSock]
+/// implements the socket traits of the socket it wraps, a handler generic
+/// over its socket type can be wrapped as is.
+///
+/// Because the switch happens exactly when the method is called, the
+/// handler can pick the frame boundary it needs: it calls the method right
+/// after it has read or written the last frame in the old mode. If it has
+/// already read data beyond that frame, it hands it back via
+/// [unread()] so that it is read again in the new mode. Input that was
+/// read from the socket but not taken by the decompressor when compression
+/// is disabled is handed over to the handler the same way. Nothing read
+/// is ever lost in a switch.
+///
+/// Compressed output is kept in a [PriorityWriteQueue] which the handler
+/// offers to the transport machine, so the machine writes it whenever the
+/// socket becomes writable and it counts as pending output for
+/// `Next::flush_then_remove()`. Each `write()` accepts all of its data
+/// unless the queue already is full, in which case it fails with
+/// `WouldBlock`. Writes in pass-through mode fail the same way while
+/// compressed output from before the switch is still waiting. If the
+/// inner handler has a write queue of its own, its content is compressed
+/// into the wrapper’s queue while compression is enabled.
+///
+/// All other methods are passed on to the inner handler. A socket is only
+/// recycled if compression is disabled and nothing is left in the
+/// wrapper’s buffers.
+///
+/// [CompressSock]: struct.CompressSock.html
+/// [PriorityWriteQueue]: ../utils/struct.PriorityWriteQueue.html
+/// [disable_compression()]:
+///     struct.CompressSock.html#method.disable_compression
+/// [enable_compression()]:
+///     struct.CompressSock.html#method.enable_compression
+/// [unread()]: struct.CompressSock.html#method.unread
+pub struct CompressHandler<H> {
+    handler: H,
+    state: State,
+}
+
+impl<H> CompressHandler<H> {
+    /// Creates a new wrapper for `handler` starting in pass-through mode.
+    pub fn new(handler: H) -> Self {
+        CompressHandler { handler: handler, state: State::new() }
+    }
+
+    /// Returns a reference to the wrapped handler.
+    pub fn get_ref(&self) -> &H {
+        &self.handler
+    }
+
+    /// Returns whether compression is currently enabled.
+    pub fn is_compressed(&self) -> bool {
+        self.state.codec.is_some()
+    }
+
+    /// Trades the wrapper for the wrapped handler.
+    ///
+    /// Any data left in the wrapper’s buffers is dropped.
+    pub fn into_inner(self) -> H {
+        self.handler
+    }
+
+    /// Runs `op` on the inner handler and a socket made from `sock`.
+    fn run<T, F>(self, sock: &mut T, op: F) -> Next<Self>
+           where F: FnOnce(H, &mut CompressSock<T>) -> Next<H> {
+        let CompressHandler { handler, mut state } = self;
+        let next = op(handler, &mut CompressSock::new(sock, &mut state));
+        next.map(move |handler| {
+            CompressHandler { handler: handler, state: state }
+        })
+    }
+}
+
+impl<T, H, S> TransportHandler<T> for CompressHandler<H>
+              where T: Stream,
+                    H: for<'a> TransportHandler<CompressSock<'a, T>,
+                                                Seed=S> {
+    type Seed = S;
+
+    fn create(seed: Self::Seed, sock: &mut T, notifier: Notifier)
+              -> Next<Self> {
+        let mut state = State::new();
+        let next = H::create(seed, &mut CompressSock::new(sock, &mut state),
+                             notifier);
+        next.map(move |handler| {
+            CompressHandler { handler: handler, state: state }
+        })
+    }
+
+    fn on_ready(self, sock: &mut T) -> Next<Self> {
+        self.run(sock, |handler, sock| handler.on_ready(sock))
+    }
+
+    fn readable(self, sock: &mut T) -> Next<Self> {
+        self.run(sock, |handler, sock| handler.readable(sock))
+    }
+
+    fn writable(self, sock: &mut T) -> Next<Self> {
+        self.run(sock, |handler, sock| handler.writable(sock))
+    }
+
+    fn wakeup(self, sock: &mut T) -> Next<Self> {
+        self.run(sock, |handler, sock| handler.wakeup(sock))
+    }
+
+    fn secured(self, sock: &mut T) -> Next<Self> {
+        self.run(sock, |handler, sock| handler.secured(sock))
+    }
+
+    fn wants_urgent(&self) -> bool {
+        self.handler.wants_urgent()
+    }
+
+    fn poll_opt(&self) -> PollOpt {
+        self.handler.poll_opt()
+    }
+
+    fn has_pending_output(&self) -> bool {
+        !self.state.queue.is_empty() || self.handler.has_pending_output()
+    }
+
+    fn write_queue(&mut self) -> Option<QueueWriter<T>> {
+        let CompressHandler { ref mut handler, ref mut state } = *self;
+        if state.codec.is_some() {
+            if let Some(queue) = handler.write_queue() {
+                // Errors only mean that our own queue is full.
+                let _ = queue.into_queue().write_to(&mut Deflater(state));
+            }
+            Some(QueueWriter::new(&mut state.queue))
+        }
+        else if !state.queue.is_empty() {
+            Some(QueueWriter::new(&mut state.queue))
+        }
+        else {
+            handler.write_queue().map(|queue| {
+                QueueWriter::new(queue.into_queue())
+            })
+        }
+    }
+
+    fn urgent(self, sock: &mut T) -> Next<Self> {
+        self.run(sock, |handler, sock| handler.urgent(sock))
+    }
+
+    fn tick(self, sock: &mut T) -> Next<Self> {
+        self.run(sock, |handler, sock| handler.tick(sock))
+    }
+
+    fn error(self, err: Error) -> Next<Self> {
+        let CompressHandler { handler, state } = self;
+        handler.error(err).map(move |handler| {
+            CompressHandler { handler: handler, state: state }
+        })
+    }
+
+    fn recycle(self, mut sock: T) -> Option<(T, Self::Seed)> {
+        let CompressHandler { handler, mut state } = self;
+        if !state.is_clean() {
+            debug!("not recycling socket with compression state left");
+            return None
+        }
+        let seed = {
+            let wrapped = CompressSock::new(&mut sock, &mut state);
+            match handler.recycle(wrapped) {
+                Some((_, seed)) => seed,
+                None => return None
+            }
+        };
+        Some((sock, seed))
+    }
+
+    fn teardown(self, sock: &mut T) {
+        let CompressHandler { handler, mut state } = self;
+        handler.teardown(&mut CompressSock::new(sock, &mut state));
+        // Get out what we can. The machine only flushes the socket.
+        let _ = state.queue.write_to(sock);
+    }
+
+    fn event(self, event: TransportEvent, sock: &mut T,
+             userdata: &mut Userdata) -> Next<Self> {
+        self.run(sock, |handler, sock| handler.event(event, sock, userdata))
+    }
+}
+
+
+//------------ CompressSock --------------------------------------------------
+
+/// The socket given to the handler inside a compress handler.
+///
+/// This wraps the actual socket and, if compression is enabled,
+/// decompresses everything read and compresses everything written. See
+/// [CompressHandler](struct.CompressHandler.html) for details.
+pub struct CompressSock<'a, T: 'a> {
+    sock: &'a mut T,
+    state: &'a mut State,
+}
+
+impl<'a, T: 'a> CompressSock<'a, T> {
+    fn new(sock: &'a mut T, state: &'a mut State) -> Self {
+        CompressSock { sock: sock, state: state }
+    }
+
+    /// Switches on compression from now on.
+    ///
+    /// Calling this again when compression is already enabled does
+    /// nothing. Data already handed back via [unread()](#method.unread)
+    /// will be decompressed.
+    pub fn enable_compression(&mut self) {
+        if self.state.codec.is_none() {
+            self.state.codec = Some(Codec::new())
+        }
+    }
+
+    /// Switches off compression from now on.
+    ///
+    /// This ends the compressed stream, so the peer learns that it is
+    /// complete. Any input read from the socket but not used up by the
+    /// decompressor will be returned by the next reads as is. Calling
+    /// this when compression isn’t enabled does nothing.
+    pub fn disable_compression(&mut self) {
+        if let Some(mut codec) = self.state.codec.take() {
+            let mut chunk = self.state.queue.buffer();
+            codec.finish(&mut chunk);
+            self.state.queue.push(0, chunk);
+        }
+    }
+
+    /// Returns whether compression is enabled.
+    pub fn is_compressed(&self) -> bool {
+        self.state.codec.is_some()
+    }
+
+    /// Hands back data that has been read but not used.
+    ///
+    /// The data will be returned again by the next reads before anything
+    /// else, decompressed first if compression is enabled. This is for
+    /// handlers that have read beyond the frame where compression is
+    /// switched on or off.
+    pub fn unread(&mut self, data: &[u8]) {
+        let rest = ::std::mem::replace(&mut self.state.rbuf, data.to_vec());
+        self.state.rbuf.extend_from_slice(&rest);
+    }
+
+    /// Returns a reference to the underlying socket.
+    pub fn get_ref(&self) -> &T {
+        self.sock
+    }
+
+    /// Returns a mutable reference to the underlying socket.
+    ///
+    /// Reading or writing directly while compression is enabled will
+    /// corrupt the stream.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.sock
+    }
+}
+
+
+//--- Read
+
+impl<'a, T: Read + 'a> Read for CompressSock<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let state = &mut *self.state;
+        let codec = match state.codec {
+            Some(ref mut codec) => codec,
+            None => {
+                if state.rbuf.is_empty() {
+                    return self.sock.read(buf)
+                }
+                let len = ::std::cmp::min(buf.len(), state.rbuf.len());
+                buf[..len].copy_from_slice(&state.rbuf[..len]);
+                state.rbuf.drain(..len);
+                return Ok(len)
+            }
+        };
+        if buf.is_empty() || codec.ended {
+            return Ok(0)
+        }
+        loop {
+            let len = try!(codec.decompress(&mut state.rbuf, buf));
+            if len > 0 || codec.ended {
+                return Ok(len)
+            }
+            let mut chunk = [0u8; READ_CHUNK];
+            match try!(self.sock.read(&mut chunk)) {
+                0 => return Ok(0),
+                len => state.rbuf.extend_from_slice(&chunk[..len])
+            }
+        }
+    }
+}
+
+
+//--- Write
+
+impl<'a, T: Write + 'a> Write for CompressSock<'a, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.state.codec.is_some() {
+            self.state.compress(buf)
+        }
+        else if !self.state.queue.is_empty() {
+            Err(pending())
+        }
+        else {
+            self.sock.write(buf)
+        }
+    }
+
+    /// Flushes the underlying socket.
+    ///
+    /// Compressed output is written by the transport machine and not
+    /// affected by this.
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+
+
+//--- Evented
+
+impl<'a, T: Evented + 'a> Evented for CompressSock<'a, T> {
+    fn register(&self, selector: &mut Selector, token: Token,
+                interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token,
+                  interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.sock.deregister(selector)
+    }
+}
+
+
+//--- Transport, Stream, and ClearStream
+
+impl<'a, T: Transport + 'a> Transport for CompressSock<'a, T> {
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        self.sock.take_socket_error()
+    }
+
+    fn blocked(&self) -> Option<Blocked> {
+        self.sock.blocked()
+    }
+
+    fn is_usable(&self) -> bool {
+        self.sock.is_usable()
+    }
+
+    fn urgent_pending(&self) -> bool {
+        self.sock.urgent_pending()
+    }
+
+    fn flush_output(&mut self) -> io::Result<bool> {
+        self.sock.flush_output()
+    }
+
+    /// Shuts down writing once all compressed output has been written.
+    ///
+    /// Until then, this fails with `WouldBlock`.
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        if !self.state.queue.is_empty() {
+            return Err(pending())
+        }
+        self.sock.shutdown_write()
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        self.sock.set_nodelay(nodelay)
+    }
+
+    fn set_keepalive(&mut self, keepalive: Option<u32>) -> io::Result<()> {
+        self.sock.set_keepalive(keepalive)
+    }
+
+    fn set_linger(&mut self, linger: Option<Duration>) -> io::Result<()> {
+        self.sock.set_linger(linger)
+    }
+
+    fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        self.sock.set_recv_buffer_size(size)
+    }
+
+    fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        self.sock.set_send_buffer_size(size)
+    }
+
+    fn set_user_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.sock.set_user_timeout(timeout)
+    }
+
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        self.sock.set_tos(tos)
+    }
+
+    fn tos(&self) -> io::Result<u8> {
+        self.sock.tos()
+    }
+
+    fn set_congestion_control(&mut self, name: &str) -> io::Result<()> {
+        self.sock.set_congestion_control(name)
+    }
+
+    fn congestion_control(&self) -> io::Result<String> {
+        self.sock.congestion_control()
+    }
+
+    fn tcp_info(&self) -> io::Result<TcpInfo> {
+        self.sock.tcp_info()
+    }
+
+    fn write_ready_hint(&self) -> bool {
+        self.state.queue.is_empty() && self.sock.write_ready_hint()
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        self.sock.is_connected()
+    }
+
+    /// Returns the output waiting in the socket and the wrapper’s queue.
+    fn pending_write_bytes(&self) -> usize {
+        self.sock.pending_write_bytes() + self.state.queue.len()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.peer_addr()
+    }
+
+    fn take_secured(&mut self) -> bool {
+        self.sock.take_secured()
+    }
+}
+
+impl<'a, T: Stream + 'a> Stream for CompressSock<'a, T> { }
+
+impl<'a, T: ClearStream + 'a> ClearStream for CompressSock<'a, T> {
+    fn recv_oob(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        self.sock.recv_oob(buf)
+    }
+
+    /// Receives data without consuming it.
+    ///
+    /// Data handed back via `unread()` is returned first. Peeking isn’t
+    /// possible while compression is enabled and fails as unsupported.
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        if self.state.codec.is_some() {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      "cannot peek compressed data"))
+        }
+        if self.state.rbuf.is_empty() {
+            return self.sock.peek(buf)
+        }
+        let len = ::std::cmp::min(buf.len(), self.state.rbuf.len());
+        buf[..len].copy_from_slice(&self.state.rbuf[..len]);
+        Ok(Some(len))
+    }
+}
+
+
+//------------ Deflater ------------------------------------------------------
+
+/// A writer compressing into the wrapper’s queue.
+///
+/// This is used for moving the inner handler’s write queue over while
+/// compression is enabled.
+struct Deflater<'a>(&'a mut State);
+
+impl<'a> Write for Deflater<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.compress(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+//------------ State ---------------------------------------------------------
+
+/// The buffers of a compress handler.
+struct State {
+    /// The codec if compression is enabled.
+    codec: Option<Codec>,
+
+    /// Data read but not yet used.
+    ///
+    /// This is compressed if `codec` is some and plain otherwise.
+    rbuf: Vec<u8>,
+
+    /// Output not yet written.
+    ///
+    /// This only ever contains compressed data. Since all chunks go into
+    /// the one band, they are written in order.
+    queue: PriorityWriteQueue,
+}
+
+impl State {
+    fn new() -> Self {
+        State {
+            codec: None,
+            rbuf: Vec::new(),
+            queue: PriorityWriteQueue::new(&[WRITE_LIMIT]),
+        }
+    }
+
+    /// Returns whether the buffers are empty and compression is off.
+    fn is_clean(&self) -> bool {
+        self.codec.is_none() && self.rbuf.is_empty() && self.queue.is_empty()
+    }
+
+    /// Compresses all of `buf` into the queue unless it is full.
+    ///
+    /// Must only be called if compression is enabled.
+    fn compress(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.queue.len() >= WRITE_LIMIT {
+            return Err(pending())
+        }
+        let mut chunk = self.queue.buffer();
+        if let Some(ref mut codec) = self.codec {
+            codec.compress(buf, &mut chunk);
+        }
+        self.queue.push(0, chunk);
+        Ok(buf.len())
+    }
+}
+
+/// Returns the error for writes refused while compressed output waits.
+fn pending() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "compressed output pending")
+}
+
+
+//------------ Codec ---------------------------------------------------------
+
+/// The compression state of a stream.
+struct Codec {
+    /// The compressor for outgoing data.
+    comp: Compress,
+
+    /// The decompressor for incoming data.
+    decomp: Decompress,
+
+    /// Whether the peer has ended its compressed stream.
+    ended: bool,
+}
+
+impl Codec {
+    fn new() -> Self {
+        Codec {
+            comp: Compress::new(Compression::Default, true),
+            decomp: Decompress::new(true),
+            ended: false,
+        }
+    }
+
+    /// Decompresses as much as possible from `input` into `buf`.
+    ///
+    /// Whatever is taken from `input` is removed from it. Returns the
+    /// number of bytes placed in buf. Zero means that more input is
+    /// necessary unless the stream has ended.
+    fn decompress(&mut self, input: &mut Vec<u8>, buf: &mut [u8])
+                  -> io::Result<usize> {
+        let total_in = self.decomp.total_in();
+        let total_out = self.decomp.total_out();
+        match self.decomp.decompress(input, buf, Flush::None) {
+            Ok(Status::StreamEnd) => self.ended = true,
+            Ok(_) => { }
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "corrupt compressed data"))
+            }
+        }
+        let consumed = (self.decomp.total_in() - total_in) as usize;
+        input.drain(..consumed);
+        Ok((self.decomp.total_out() - total_out) as usize)
+    }
+
+    /// Compresses all of `buf` into `out`, ending on a flush point.
+    ///
+    /// The flush point makes sure the peer can decompress everything
+    /// written so far without waiting for more data.
+    fn compress(&mut self, buf: &[u8], out: &mut Vec<u8>) {
+        self.run(buf, out, Flush::Sync)
+    }
+
+    /// Ends the compressed stream, placing the remaining output in `out`.
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        self.run(b"", out, Flush::Finish)
+    }
+
+    /// Compresses `buf` into `out` until `flush` is done.
+    fn run(&mut self, mut buf: &[u8], out: &mut Vec<u8>, flush: Flush) {
+        loop {
+            out.reserve(buf.len() / 2 + 64);
+            let total_in = self.comp.total_in();
+            let status = self.comp.compress_vec(buf, out, flush);
+            let consumed = (self.comp.total_in() - total_in) as usize;
+            buf = &buf[consumed..];
+            let finishing = match flush {
+                Flush::Finish => true,
+                _ => false
+            };
+            match status {
+                Status::StreamEnd => break,
+                _ if !finishing && buf.is_empty()
+                                && out.len() < out.capacity() => break,
+                _ => { }
+            }
+        }
+    }
+}
+
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use rotor::Notifier;
+    use ::handlers::{TransportEvent, TransportHandler, Userdata};
+    use ::next::Next;
+    use ::sockets::mock::MockStream;
+    use super::{CompressHandler, CompressSock, State};
+
+    /// A handler counting urgent events and events with user data.
+    ///
+    /// It switches on compression when it becomes readable.
+    struct Probe { urgent: usize }
+
+    impl<'a> TransportHandler<CompressSock<'a, MockStream>> for Probe {
+        type Seed = ();
+
+        fn create(_seed: (), _sock: &mut CompressSock<MockStream>,
+                  _notifier: Notifier) -> Next<Self> {
+            Next::wait(Probe { urgent: 0 })
+        }
+
+        fn readable(self, sock: &mut CompressSock<MockStream>)
+                    -> Next<Self> {
+            sock.enable_compression();
+            Next::read(self)
+        }
+
+        fn writable(self, _sock: &mut CompressSock<MockStream>)
+                    -> Next<Self> {
+            Next::wait(self)
+        }
+
+        fn wakeup(self, _sock: &mut CompressSock<MockStream>)
+                  -> Next<Self> {
+            Next::wait(self)
+        }
+
+        fn wants_urgent(&self) -> bool {
+            true
+        }
+
+        fn urgent(self, _sock: &mut CompressSock<MockStream>)
+                  -> Next<Self> {
+            Next::wait(Probe { urgent: self.urgent + 1 })
+        }
+
+        fn recycle(self, sock: CompressSock<'a, MockStream>)
+                   -> Option<(CompressSock<'a, MockStream>, ())> {
+            Some((sock, ()))
+        }
+
+        fn event(self, event: TransportEvent,
+                 sock: &mut CompressSock<MockStream>,
+                 userdata: &mut Userdata) -> Next<Self> {
+            if let Some(ref mut data) = *userdata {
+                if let Some(count) = data.downcast_mut::<usize>() {
+                    *count += 1;
+                }
+            }
+            event.dispatch(self, sock)
+        }
+    }
+
+    fn probe() -> CompressHandler<Probe> {
+        CompressHandler::new(Probe { urgent: 0 })
+    }
+
+    /// Writes `data` compressed and returns what ended up on the wire.
+    fn compressed(data: &[u8]) -> Vec<u8> {
+        let mut state = State::new();
+        let mut out = Vec::new();
+        {
+            let mut sock = CompressSock::new(&mut out, &mut state);
+            sock.enable_compression();
+            sock.write_all(data).unwrap();
+            sock.disable_compression();
+        }
+        state.queue.write_to(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn passes_through() {
+        let mut state = State::new();
+        let mut out = Vec::new();
+        CompressSock::new(&mut out, &mut state).write_all(b"plain")
+                                               .unwrap();
+        assert_eq!(out, b"plain");
+        assert!(state.queue.is_empty());
+    }
+
+    #[test]
+    fn round_trip_with_handover() {
+        let mut wire = compressed(b"hello, compressed world");
+        wire.extend_from_slice(b"plain again");
+        let mut input = &wire[..];
+        let mut state = State::new();
+        let mut sock = CompressSock::new(&mut input, &mut state);
+        sock.enable_compression();
+        let mut buf = [0u8; 64];
+        let mut got = Vec::new();
+        loop {
+            match sock.read(&mut buf).unwrap() {
+                0 => break,
+                len => got.extend_from_slice(&buf[..len])
+            }
+        }
+        assert_eq!(got, b"hello, compressed world");
+        sock.disable_compression();
+        let mut rest = Vec::new();
+        sock.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"plain again");
+    }
+
+    #[test]
+    fn unread_is_decompressed() {
+        let wire = compressed(b"frame");
+        let mut input = &b""[..];
+        let mut state = State::new();
+        let mut sock = CompressSock::new(&mut input, &mut state);
+        sock.unread(&wire);
+        sock.enable_compression();
+        let mut buf = [0u8; 16];
+        let len = sock.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"frame");
+    }
+
+    #[test]
+    fn plain_write_waits_for_compressed_output() {
+        let mut state = State::new();
+        let mut sock = Vec::new();
+        let mut wrapped = CompressSock::new(&mut sock, &mut state);
+        wrapped.enable_compression();
+        wrapped.write_all(b"compressed").unwrap();
+        wrapped.disable_compression();
+        assert!(wrapped.write(b"plain").is_err());
+    }
+
+    #[test]
+    fn event_passes_userdata_on() {
+        let mut userdata: Userdata = Some(Box::new(0usize));
+        let mut sock = MockStream::new();
+        let handler = probe().event(TransportEvent::Urgent, &mut sock,
+                                    &mut userdata);
+        let handler = handler.into_handler().unwrap();
+        assert_eq!(handler.get_ref().urgent, 1);
+        assert_eq!(*userdata.unwrap().downcast::<usize>().unwrap(), 1);
+    }
+
+    #[test]
+    fn urgent_is_passed_on() {
+        let mut sock = MockStream::new();
+        let handler = probe();
+        assert!(TransportHandler::<MockStream>::wants_urgent(&handler));
+        let handler = handler.urgent(&mut sock).into_handler().unwrap();
+        assert_eq!(handler.get_ref().urgent, 1);
+    }
+
+    #[test]
+    fn recycle_only_without_compression() {
+        assert!(probe().recycle(MockStream::new()).is_some());
+        let mut sock = MockStream::new();
+        let handler = probe().readable(&mut sock).into_handler().unwrap();
+        assert!(handler.is_compressed());
+        assert!(handler.recycle(sock).is_none());
+    }
+}
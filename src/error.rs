@@ -11,6 +11,8 @@ use std::result;
 
 #[cfg(feature = "openssl")]
 use openssl::ssl::error::SslError as OpensslError;
+#[cfg(feature = "security-framework")]
+use security_framework::base::Error as SecurityFrameworkError;
 
 
 //------------ Error --------------------------------------------------------
@@ -22,7 +24,62 @@ pub enum Error {
     Io(io::Error),
     NoSlabSpace,
     Timeout,
+    ReadTimeout,
+    WriteTimeout,
     Tls, // XXX Make this proper.
+
+    /// A datagram was larger than the socket can send.
+    ///
+    /// Datagram sockets return this wrapped into an IO error of kind
+    /// `InvalidInput`. Converting such an IO error into a value of this
+    /// type unwraps it again.
+    MessageTooLarge,
+
+    /// A handler’s internal state has grown beyond its limit.
+    ///
+    /// This is reported by the [BoundedState] wrapper right before it
+    /// removes the connection.
+    ///
+    /// [BoundedState]: ../handlers/struct.BoundedState.html
+    StateTooLarge,
+}
+
+impl Error {
+    /// Creates an error signalling that an operation would block.
+    ///
+    /// Unlike creating an IO error with a message, this doesn’t allocate.
+    pub fn would_block() -> Self {
+        Error::Io(io::Error::from(io::ErrorKind::WouldBlock))
+    }
+
+    /// Returns whether the error signals that an operation would block.
+    ///
+    /// Such errors aren’t really errors but merely mean that one needs to
+    /// try again later.
+    pub fn is_would_block(&self) -> bool {
+        match *self {
+            Error::Io(ref err) => err.kind() == io::ErrorKind::WouldBlock,
+            _ => false
+        }
+    }
+
+    /// Returns whether the error signals that a timeout has passed.
+    ///
+    /// This is true for general, read, and write timeouts.
+    pub fn is_timeout(&self) -> bool {
+        match *self {
+            Error::Timeout | Error::ReadTimeout | Error::WriteTimeout => true,
+            _ => false
+        }
+    }
+
+    /// Returns whether an IO error wraps `Error::MessageTooLarge`.
+    pub fn is_message_too_large_io(err: &io::Error) -> bool {
+        match err.get_ref().and_then(|err| err.downcast_ref::<Error>()) {
+            Some(&Error::MessageTooLarge) => true,
+            _ => false
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -40,7 +97,11 @@ impl error::Error for Error {
             Error::Io(ref err) => err.description(),
             Error::NoSlabSpace => "slab space limit reached",
             Error::Timeout => "Timeout",
+            Error::ReadTimeout => "Read timeout",
+            Error::WriteTimeout => "Write timeout",
             Error::Tls => "TLS error",
+            Error::MessageTooLarge => "message too large",
+            Error::StateTooLarge => "handler state limit exceeded",
         }
     }
 
@@ -54,7 +115,12 @@ impl error::Error for Error {
 
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
-        Error::Io(err)
+        if Error::is_message_too_large_io(&err) {
+            Error::MessageTooLarge
+        }
+        else {
+            Error::Io(err)
+        }
     }
 }
 
@@ -68,6 +134,13 @@ impl From<OpensslError> for Error {
     }
 }
 
+#[cfg(feature = "security-framework")]
+impl From<SecurityFrameworkError> for Error {
+    fn from(_: SecurityFrameworkError) -> Error {
+        Error::Tls
+    }
+}
+
 
 //------------ Result -------------------------------------------------------
 
@@ -9,16 +9,30 @@
 //! [transports sockets][Transport], and a [TransportHandler] implements the
 //! behaviour of such transport sockets. 
 //!
+//! With the `flate2` feature, the module also provides the
+//! [CompressHandler] wrapper for protocols that switch to compressed
+//! transfer midway.
+//!
 //! [AcceptHandler]: trait.AcceptHandler.html
+//! [CompressHandler]: struct.CompressHandler.html
 //! [RequestHandler]: trait.RequestHandler.html
 //! [TransportHandler]: trait.TransportHandler.html
 //! [Stream]: ../sockets/trait.Stream.html
 //! [Transport]: ../sockets/trait.Transport.html
 
-use std::net::SocketAddr;
-use rotor::Notifier;
+use std::{error, fmt, str};
+use std::any::Any;
+use std::time::Duration;
+use rotor::{Notifier, PollOpt};
 use ::error::Error;
 use ::next::Next;
+use ::request::TranslateError;
+use ::sockets::PeerAddr;
+use ::sync::{GateReceiver, GateRecvError};
+use ::utils::{LineError, QueueWriter, WriteBudget, parse_line};
+
+#[cfg(feature = "flate2")]
+pub use ::compress::{CompressHandler, CompressSock};
 
 
 //------------ AcceptHandler -------------------------------------------------
@@ -38,19 +52,70 @@ pub trait AcceptHandler<T> {
     /// Accepts an incoming connection request.
     ///
     /// The `addr` argument contains the peer address of the incoming request.
+    /// For network sockets, this is the peer’s socket address, for Unix
+    /// domain sockets the path the peer is bound to, if any.
     ///
     /// The method can decide whether to accept the request or not. If it
-    /// returns `None`, the connection is closed cleanly immediately.
+    /// returns `None`, the connection is closed immediately, either
+    /// cleanly or by resetting it as determined by the server’s
+    /// [RejectMode], and counted in the server’s [ServerStats], if any.
     /// Otherwise, the method returns the seed for the transport handler to
     /// be created for processing the connection. See the discussion of how
     /// transport handlers are created at the [TransportHandler] trait.
     ///
+    /// Note that the method is called only after the accept socket has
+    /// done all its work. For encrypted sockets, this means that the TLS
+    /// handshake has already been completed, so rejecting a connection
+    /// here wastes the effort spent on it. If you only need to limit
+    /// connections per peer address, use the server’s per IP address
+    /// limit instead: it is checked before the accept handler is asked
+    /// and rejected peers are reported via [rejected()].
+    ///
+    /// [RejectMode]: ../net/machines/enum.RejectMode.html
+    /// [ServerStats]: ../net/machines/struct.ServerStats.html
     /// [TransportHandler]: trait.TransportHandler.html
-    fn accept(&mut self, addr: &SocketAddr)
+    /// [rejected()]: #method.rejected
+    fn accept(&mut self, addr: &PeerAddr)
               -> Option<<Self::Output as TransportHandler<T>>::Seed>;
 
+    /// Called when a connection request was rejected by the server.
+    ///
+    /// This happens if the server limits the number of connections per
+    /// peer address and the peer with address `addr` has reached its
+    /// limit. The connection has already been closed when the method is
+    /// called.
+    ///
+    /// The default implementation logs the rejection.
+    fn rejected(&mut self, addr: &PeerAddr) {
+        debug!("rejected connection from {}: too many connections", addr);
+    }
+
+    /// Receives the write budget shared by the server’s connections.
+    ///
+    /// If the server’s [ServerStats] have a write budget, the server
+    /// calls this method once when it is created. The handler should keep
+    /// the budget and give a clone to the seed of each transport handler
+    /// for use with its [WriteBuffer]. This way, the statistics know how
+    /// much output is pending across all connections.
+    ///
+    /// The default implementation drops the budget.
+    ///
+    /// [ServerStats]: ../net/machines/struct.ServerStats.html
+    /// [WriteBuffer]: ../utils/struct.WriteBuffer.html
+    fn write_budget(&mut self, budget: WriteBudget) {
+        let _ = budget;
+    }
+
     /// Handles an error that happened during accepting.
     ///
+    /// The server machine calls this for every failed accept except for
+    /// `WouldBlock`, as well as when applying socket options to an
+    /// accepted socket fails. No error is dropped silently. After a
+    /// failed accept, the machine stops accepting until the listener
+    /// becomes readable again. Errors such as running out of file
+    /// descriptors tend to persist, though, in which case this method is
+    /// called on every turn of the loop until the situation clears.
+    ///
     /// Returns whether to continue (`Ok(())`) or shut down (`Err(())`).
     /// The somewhat odd return type was chosen over a simple `bool` to
     /// make clear what is what.
@@ -107,13 +172,56 @@ pub trait RequestHandler {
     ///
     /// The `output` argument will contain the output generated by the
     /// [request()](#tymethod.request) method. The `err` argument is the
-    /// error that happened while creating a socket for the output.
+    /// error that happened while creating a socket for the output. For
+    /// combined client machines the output is the variant originally
+    /// chosen, so you can tell which kind of socket failed.
+    ///
+    /// If the socket was created but the loop has no room for another
+    /// machine, `err` is `Error::NoSlabSpace`. For the combinations where
+    /// the client machine can’t recover the output from the socket, the
+    /// method isn’t called at all and the request is dropped.
     ///
     /// The default implementation does nothing.
     fn error(&mut self, output: Self::Output, err: Error) {
         // Underscores in argument names look bad in documentation ...
         let _ = (output, err);
     }
+
+    /// Handles an error and possibly picks a different output.
+    ///
+    /// This is called instead of [error()](#method.error) with the same
+    /// arguments. If the method returns `Some(_)` output, the client
+    /// machine tries again with it. This can be used to fall back to a
+    /// different transport, for instance, trying UDP if connecting via
+    /// TCP failed. Beware that the method will be called again if that
+    /// fails, too.
+    ///
+    /// If `err` is `Error::NoSlabSpace`, any output returned is ignored
+    /// since trying again right away would fail just the same. Keep the
+    /// request and retry later or shed load instead.
+    ///
+    /// The default implementation calls `error()` and returns `None`.
+    fn fallback(&mut self, output: Self::Output, err: Error)
+                -> Option<Self::Output> {
+        self.error(output, err);
+        None
+    }
+
+    /// Handles an error that happened while creating the socket.
+    ///
+    /// This is called instead of [fallback()](#method.fallback) if
+    /// creating the socket for an output failed. In addition to the
+    /// output and error, `err` contains the context of the failure, ie.,
+    /// the kind of socket the client machine tried to create and the
+    /// address it used. The return value is treated the same as that of
+    /// `fallback()`.
+    ///
+    /// The default implementation passes output and error on to
+    /// `fallback()`.
+    fn translate_error(&mut self, err: TranslateError<Self::Output>)
+                       -> Option<Self::Output> {
+        self.fallback(err.output, err.error)
+    }
 }
 
 
@@ -202,6 +310,23 @@ pub trait TransportHandler<T>: Sized {
     fn create(seed: Self::Seed, sock: &mut T, notifier: Notifier)
               -> Next<Self>;
 
+    /// Called once when the machine has become live.
+    ///
+    /// Unlike [create()](#tymethod.create), which runs while the machine
+    /// is still being constructed, this method is called only after the
+    /// socket has been successfully registered with the loop. It is
+    /// called exactly once, before any other method is called for an
+    /// event on the socket. This makes it the right place for things
+    /// such as sending a first message proactively.
+    ///
+    /// Like with [tick()](#method.tick), the returned value is merged
+    /// with what `create()` asked for, so the default implementation of
+    /// returning `Next::wait(self)` changes nothing.
+    fn on_ready(self, sock: &mut T) -> Next<Self> {
+        let _ = sock;
+        Next::wait(self)
+    }
+
     /// Called when the socket may have become readable.
     ///
     /// This does not necessarily mean that reading from the socket will
@@ -230,6 +355,117 @@ pub trait TransportHandler<T>: Sized {
     /// order to being woken up.
     fn wakeup(self, sock: &mut T) -> Next<Self>;
 
+    /// Called when a start-TLS handshake has completed successfully.
+    ///
+    /// Only sockets implementing [HybridStream] ever become secure
+    /// later. After the handshake started by `connect_secure()` or
+    /// `accept_secure()` has finished, this method is called once right
+    /// after the event during which it happened. From then on, anything
+    /// written to the socket is encrypted. A failed handshake is signalled
+    /// through [error()](#method.error) instead.
+    ///
+    /// The default implementation forwards to [wakeup()](#tymethod.wakeup).
+    ///
+    /// [HybridStream]: ../sockets/trait.HybridStream.html
+    fn secured(self, sock: &mut T) -> Next<Self> {
+        self.wakeup(sock)
+    }
+
+    /// Returns whether the handler wants to learn about urgent data.
+    ///
+    /// Checking for urgent data costs an extra system call every time the
+    /// socket becomes readable, so it only happens if this method returns
+    /// `true`. The default implementation returns `false`.
+    fn wants_urgent(&self) -> bool {
+        false
+    }
+
+    /// Returns how the socket should be registered with the loop.
+    ///
+    /// The default implementation returns `PollOpt::level()`, which means
+    /// the handler is called again and again for as long as the socket
+    /// stays readable or writable. Returning `PollOpt::edge()`, possibly
+    /// together with `PollOpt::oneshot()`, avoids these repeated calls for
+    /// busy sockets. But with edge-triggered registration, the handler is
+    /// only called again once something new happens. It therefore has to
+    /// read or write until the socket fails with `WouldBlock` every time
+    /// it is called or it may never hear from the socket again. Since the
+    /// machine registers anew after every event, oneshot registration
+    /// works without further ado.
+    ///
+    /// The method is called every time the socket is registered, but
+    /// there is little point in changing the answer during the lifetime
+    /// of a handler.
+    fn poll_opt(&self) -> PollOpt {
+        PollOpt::level()
+    }
+
+    /// Returns whether the handler still has output to write.
+    ///
+    /// This is only asked after the handler has returned
+    /// [Next::flush_then_remove()]. As long as it returns `true`, the
+    /// machine keeps calling [writable()](#tymethod.writable). Once it
+    /// returns `false`, the socket is closed. The default implementation
+    /// returns `false`.
+    ///
+    /// [Next::flush_then_remove()]:
+    ///     ../next/struct.Next.html#method.flush_then_remove
+    fn has_pending_output(&self) -> bool {
+        false
+    }
+
+    /// Returns the handler’s write queue if the machine should write it.
+    ///
+    /// A handler with prioritized output can keep it in a
+    /// [PriorityWriteQueue] and have the machine take care of writing it
+    /// by returning a [QueueWriter] for it here. Whenever the queue has
+    /// output, the machine waits for the socket to become writable, even
+    /// if the handler only asked for reading, and writes as much of the
+    /// queue as it can. Only once the queue is empty is
+    /// [writable()](#tymethod.writable) called, and only if the handler
+    /// asked for writing. A queue with output also counts as pending
+    /// output for `Next::flush_then_remove()`.
+    ///
+    /// The method is called at least once for every event, so it should
+    /// be cheap. The default implementation returns `None`, meaning the
+    /// handler does all writing itself.
+    ///
+    /// [PriorityWriteQueue]: ../utils/struct.PriorityWriteQueue.html
+    /// [QueueWriter]: ../utils/struct.QueueWriter.html
+    fn write_queue(&mut self) -> Option<QueueWriter<T>> {
+        None
+    }
+
+    /// Called when urgent data is waiting on the socket.
+    ///
+    /// This only happens if [wants_urgent()](#method.wants_urgent) returns
+    /// `true`. The method is called before [readable()](#tymethod.readable)
+    /// and should retrieve the data, for instance by calling `recv_oob()`
+    /// on a [ClearStream]. Like with [tick()](#method.tick), the returned
+    /// value is merged with what the handler asked for last.
+    ///
+    /// The default implementation does nothing.
+    ///
+    /// [ClearStream]: ../sockets/trait.ClearStream.html
+    fn urgent(self, sock: &mut T) -> Next<Self> {
+        let _ = sock;
+        Next::wait(self)
+    }
+
+    /// Called when a recurring tick started via `Next::tick()` is due.
+    ///
+    /// The tick is rearmed automatically before the method is called, so
+    /// you don’t need to request it again. The returned value is merged
+    /// with what the handler asked for last, so the default implementation
+    /// of returning `Next::wait(self)` leaves everything as it was.
+    ///
+    /// A timeout set by `Next::timeout()` is unaffected by ticks and will
+    /// still be signalled through [error()](#method.error).
+    fn tick(self, sock: &mut T) -> Next<Self> {
+        let _ = sock;
+        Next::wait(self)
+    }
+
     /// Called when an error has occured on the socket.
     ///
     /// You are free to signal any next value here, though most likely
@@ -238,10 +474,912 @@ pub trait TransportHandler<T>: Sized {
     ///
     /// Note that if a timeout is installed using `Next::timeout()` and this
     /// timeout passes, this is signalled as an `Error::Timeout` error and
-    /// thus will result in this method being called.
+    /// thus will result in this method being called. Timeouts installed
+    /// via `Next::read_timeout()` and `Next::write_timeout()` are signalled
+    /// as `Error::ReadTimeout` and `Error::WriteTimeout`, respectively.
     fn error(self, err: Error) -> Next<Self> {
         let _ = err;
         Next::remove()
     }
+
+    /// Called when the handler has returned `Next::recycle()`.
+    ///
+    /// The method receives the socket by value. If the socket is still
+    /// good for reuse, it should return it together with the seed for
+    /// the transport handler that will take over the socket later. The
+    /// pair is sent to the pool of the machine. If the method returns
+    /// `None` or the machine doesn’t have a pool, the socket is closed.
+    ///
+    /// The default implementation returns `None`.
+    fn recycle(self, sock: T) -> Option<(T, Self::Seed)> {
+        let _ = sock;
+        None
+    }
+
+    /// Called when the machine goes away while the handler is still alive.
+    ///
+    /// Normally, the handler is consumed when it returns `Next::remove()`
+    /// or when its [recycle()](#method.recycle) method is called and this
+    /// method is never called. But the machine can also disappear
+    /// without asking the handler, most notably when the event loop is
+    /// shut down and drops all its machines or when the socket can’t be
+    /// registered with the loop. In this case, this method is called
+    /// exactly once, giving the handler a last chance to clean up. The
+    /// socket is still open at this point and will be flushed and closed
+    /// only after the method returns.
+    ///
+    /// Since the method may be called while a machine is being dropped,
+    /// it should not panic.
+    ///
+    /// The default implementation does nothing.
+    fn teardown(self, sock: &mut T) {
+        let _ = sock;
+    }
+
+    /// Called for every event together with the connection’s user data.
+    ///
+    /// The transport machine doesn’t call the methods for the various
+    /// events directly but rather calls this method with the kind of
+    /// `event` that happened, the socket, and the user data attached to
+    /// the machine. User data is a slot for things that belong to the
+    /// connection rather than the handler, such as a session object
+    /// shared by wrappers and observers around a protocol handler. Since
+    /// the machine doesn’t know what is being stored, you have to
+    /// downcast the data to the type you expect. Once done, let the
+    /// event be processed as usual:
+    ///
+    /// ```ignore
+    /// fn event(self, event: TransportEvent, sock: &mut T,
+    ///          userdata: &mut Userdata) -> Next<Self> {
+    ///     if let Some(ref mut data) = *userdata {
+    ///         if let Some(session) = data.downcast_mut::<Session>() {
+    ///             session.events += 1;
+    ///         }
+    ///     }
+    ///     event.dispatch(self, sock)
+    /// }
+    /// ```
+    ///
+    /// The user data can also be replaced or taken out entirely. Wrapping
+    /// handlers should pass the user data on to the handler they wrap.
+    ///
+    /// Errors are not events in this sense, they always go to
+    /// [error()](#method.error) directly.
+    ///
+    /// The default implementation ignores the user data and calls the
+    /// method for the event.
+    fn event(self, event: TransportEvent, sock: &mut T,
+             userdata: &mut Userdata) -> Next<Self> {
+        let _ = userdata;
+        event.dispatch(self, sock)
+    }
+}
+
+
+//------------ TransportEvent ------------------------------------------------
+
+/// The kind of event passed to a transport handler’s `event()` method.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransportEvent {
+    /// The machine has become live, see `on_ready()`.
+    Ready,
+
+    /// The socket is readable, see `readable()`.
+    Readable,
+
+    /// The socket is writable, see `writable()`.
+    Writable,
+
+    /// The handler was woken up, see `wakeup()`.
+    Wakeup,
+
+    /// The socket has become secure, see `secured()`.
+    Secured,
+
+    /// Urgent data is waiting, see `urgent()`.
+    Urgent,
+
+    /// A tick is due, see `tick()`.
+    Tick,
+}
+
+impl TransportEvent {
+    /// Calls the method of `handler` for this event.
+    pub fn dispatch<T, H>(self, handler: H, sock: &mut T) -> Next<H>
+                    where H: TransportHandler<T> {
+        match self {
+            TransportEvent::Ready => handler.on_ready(sock),
+            TransportEvent::Readable => handler.readable(sock),
+            TransportEvent::Writable => handler.writable(sock),
+            TransportEvent::Wakeup => handler.wakeup(sock),
+            TransportEvent::Secured => handler.secured(sock),
+            TransportEvent::Urgent => handler.urgent(sock),
+            TransportEvent::Tick => handler.tick(sock),
+        }
+    }
+
+    /// Returns a short name of the event for logging.
+    pub fn name(self) -> &'static str {
+        match self {
+            TransportEvent::Ready => "ready",
+            TransportEvent::Readable => "readable",
+            TransportEvent::Writable => "writable",
+            TransportEvent::Wakeup => "wakeup",
+            TransportEvent::Secured => "secured",
+            TransportEvent::Urgent => "urgent",
+            TransportEvent::Tick => "tick",
+        }
+    }
+}
+
+
+//------------ Userdata ------------------------------------------------------
+
+/// The user data attached to a transport machine.
+///
+/// See the [event()](trait.TransportHandler.html#method.event) method of
+/// transport handlers for how to use it.
+pub type Userdata = Option<Box<Any + Send>>;
+
+
+//------------ AwaitResponse -------------------------------------------------
+
+/// A building block for transport handlers waiting for a response.
+///
+/// A common pattern for transport handlers is to send off a request to
+/// some other part of the program together with a [gate] for the answer
+/// and then wait to be woken up when the answer has arrived. If for some
+/// reason the answer never arrives, the handler would wait forever. This
+/// type helps with adding a timeout to such waiting.
+///
+/// The value wraps the receiving end of the gate together with the time
+/// the handler is willing to wait. It is typically used as the state of
+/// the handler while waiting for the answer. The [wait()](#method.wait)
+/// method turns it into the handler’s next value with the timeout armed.
+/// When the handler is woken up, [poll()](#method.poll) checks whether
+/// the answer has arrived. If the timeout passes first, the handler’s
+/// `error()` method is called with `Error::Timeout`, for which
+/// `Error::is_timeout()` returns `true`. The handler can then answer
+/// with an error of its own, for instance.
+///
+/// Since the timeout is only armed once, spurious wakeups or returning
+/// `Next::wait()` again while in this state don’t extend it.
+///
+/// [gate]: ../sync/fn.gate.html
+pub struct AwaitResponse<T> {
+    rx: GateReceiver<T>,
+    timeout: Duration,
+}
+
+impl<T: Send> AwaitResponse<T> {
+    /// Creates a new value waiting on `rx` for no longer than `timeout`.
+    pub fn new(rx: GateReceiver<T>, timeout: Duration) -> Self {
+        AwaitResponse { rx: rx, timeout: timeout }
+    }
+
+    /// Returns the timeout.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Starts waiting.
+    ///
+    /// The closure `op` turns the value into the transport handler. The
+    /// method returns that handler waiting with the timeout armed.
+    pub fn wait<H, F>(self, op: F) -> Next<H>
+                where F: FnOnce(Self) -> H {
+        let timeout = self.timeout;
+        Next::wait(op(self)).timeout(timeout)
+    }
+
+    /// Checks whether the response has arrived.
+    ///
+    /// Returns `Ok(Ok(_))` with the response if it has, `Ok(Err(self))`
+    /// if it hasn’t yet and the handler should keep waiting, and `Err(_)`
+    /// if the sending side has failed and no response will arrive.
+    pub fn poll(self) -> Result<Result<T, Self>, GateRecvError> {
+        match try!(self.rx.try_get()) {
+            Some(response) => Ok(Ok(response)),
+            None => Ok(Err(self))
+        }
+    }
+}
+
+
+//------------ BodyStream ----------------------------------------------------
+
+/// The longest chunk size line a body stream accepts.
+const MAX_CHUNK_LINE: usize = 1024;
+
+/// The largest trailer a body stream accepts.
+const MAX_TRAILER: usize = 64 * 1024;
+
+/// A building block for transport handlers receiving large bodies.
+///
+/// Some protocols, most prominently HTTP, send message bodies that can be
+/// much larger than what one would like to keep in memory. Instead of
+/// collecting such a body, a transport handler can use a value of this
+/// type to hand the body to a [BodySink] piece by piece as it arrives.
+///
+/// The stream is created either for a body of a known length via
+/// [with_length()](#method.with_length) or for a body using the chunked
+/// framing of HTTP/1.1 via [chunked()](#method.chunked). The handler then
+/// [feeds](#method.feed) it everything it reads from its socket until the
+/// stream [is done](#method.is_done).
+///
+/// If the sink can’t keep up, it can ask for the stream to be paused. The
+/// stream then stops consuming data and [wants_read()] returns `false`,
+/// so the handler stops reading from the socket and data piles up in the
+/// kernel’s buffers, eventually slowing down the peer. Once the sink has
+/// caught up, whoever is feeding it should wake up the handler which
+/// then calls [resume()] and continues feeding the stream.
+///
+/// [BodySink]: trait.BodySink.html
+/// [wants_read()]: #method.wants_read
+/// [resume()]: #method.resume
+pub struct BodyStream<K: BodySink> {
+    state: BodyState,
+    sink: K,
+    paused: bool,
+}
+
+/// Where in a body a body stream currently is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum BodyState {
+    /// This many bytes of the unchunked body are left.
+    Length(u64),
+
+    /// Waiting for a chunk size line.
+    ChunkSize,
+
+    /// This many bytes of the current chunk are left.
+    ChunkData(u64),
+
+    /// Waiting for the line end following a chunk’s data.
+    ChunkEnd,
+
+    /// Reading the trailer which so far is this many bytes long.
+    Trailer(usize),
+
+    /// The body is complete.
+    Done,
+}
+
+/// # Creation and Access
+///
+impl<K: BodySink> BodyStream<K> {
+    /// Creates a stream for a body of `len` bytes.
+    ///
+    /// If `len` is zero, the body is complete right away and the sink’s
+    /// `end()` method is called immediately.
+    pub fn with_length(len: u64, sink: K) -> Self {
+        let mut res = BodyStream { state: BodyState::Length(len), sink: sink,
+                                   paused: false };
+        if len == 0 {
+            res.finish()
+        }
+        res
+    }
+
+    /// Creates a stream for a body in chunked framing.
+    ///
+    /// Chunk extensions and trailer fields are dropped. Only the data of
+    /// the chunks is handed to the sink.
+    pub fn chunked(sink: K) -> Self {
+        BodyStream { state: BodyState::ChunkSize, sink: sink, paused: false }
+    }
+
+    /// Returns a reference to the sink.
+    pub fn sink(&self) -> &K {
+        &self.sink
+    }
+
+    /// Returns a mutable reference to the sink.
+    pub fn sink_mut(&mut self) -> &mut K {
+        &mut self.sink
+    }
+
+    /// Trades the stream for its sink.
+    pub fn into_sink(self) -> K {
+        self.sink
+    }
+}
+
+/// # Processing
+///
+impl<K: BodySink> BodyStream<K> {
+    /// Returns whether the complete body has been received.
+    pub fn is_done(&self) -> bool {
+        self.state == BodyState::Done
+    }
+
+    /// Returns whether the sink has asked for the stream to be paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Returns whether the stream wants more data from the socket.
+    ///
+    /// This is the case if the body isn’t complete yet and the stream
+    /// isn’t paused.
+    pub fn wants_read(&self) -> bool {
+        !self.paused && !self.is_done()
+    }
+
+    /// Returns the next value for `handler` based on the stream’s state.
+    ///
+    /// This is `Next::read()` if the stream [wants to read] or
+    /// `Next::wait()` otherwise. Handlers that also need to write can
+    /// use [wants_read()] directly instead.
+    ///
+    /// [wants to read]: #method.wants_read
+    /// [wants_read()]: #method.wants_read
+    pub fn next<H>(&self, handler: H) -> Next<H> {
+        if self.wants_read() {
+            Next::read(handler)
+        }
+        else {
+            Next::wait(handler)
+        }
+    }
+
+    /// Tries to resume a paused stream.
+    ///
+    /// If the stream is paused, asks the sink whether it is ready for
+    /// more data. Returns whether the stream is running now. Handlers
+    /// should call this method when woken up while paused.
+    pub fn resume(&mut self) -> bool {
+        if self.paused && self.sink.is_ready() {
+            self.paused = false
+        }
+        !self.paused
+    }
+
+    /// Feeds data to the stream.
+    ///
+    /// Hands all body data contained in `data` to the sink until either
+    /// `data` is exhausted, the body is complete, or the sink asks for a
+    /// pause. Returns the number of bytes consumed. Whatever is left has
+    /// to be kept by the caller: it may be the start of a chunk size line
+    /// that is only complete with the next read or data following the
+    /// body.
+    ///
+    /// Once the end of the body has been reached, the sink’s `end()`
+    /// method is called. This happens even if the sink asked for a pause
+    /// with the last piece of data.
+    pub fn feed(&mut self, mut data: &[u8]) -> Result<usize, BodyError> {
+        let total = data.len();
+        while !self.paused {
+            let state = self.state;
+            match state {
+                BodyState::Length(left) | BodyState::ChunkData(left) => {
+                    if data.is_empty() {
+                        break
+                    }
+                    let len = if left < data.len() as u64 { left as usize }
+                              else { data.len() };
+                    let (now, rest) = data.split_at(len);
+                    data = rest;
+                    let left = left - len as u64;
+                    self.state = match state {
+                        BodyState::Length(_) if left == 0 => BodyState::Done,
+                        BodyState::Length(_) => BodyState::Length(left),
+                        _ if left == 0 => BodyState::ChunkEnd,
+                        _ => BodyState::ChunkData(left),
+                    };
+                    if let BodyFlow::Pause = self.sink.data(now) {
+                        self.paused = true
+                    }
+                    if self.is_done() {
+                        self.finish()
+                    }
+                }
+                BodyState::ChunkSize => {
+                    let (line, rest) = match try!(body_line(data,
+                                                            MAX_CHUNK_LINE)) {
+                        Some(res) => res,
+                        None => break
+                    };
+                    let size = try!(parse_chunk_size(line));
+                    data = rest;
+                    self.state = if size == 0 { BodyState::Trailer(0) }
+                                 else { BodyState::ChunkData(size) };
+                }
+                BodyState::ChunkEnd => {
+                    if data.len() < 2 {
+                        break
+                    }
+                    if &data[..2] != b"\r\n" {
+                        return Err(BodyError::Invalid)
+                    }
+                    data = &data[2..];
+                    self.state = BodyState::ChunkSize;
+                }
+                BodyState::Trailer(used) => {
+                    let (line, rest) = match try!(body_line(data,
+                                                            MAX_TRAILER)) {
+                        Some(res) => res,
+                        None => break
+                    };
+                    let used = used + (data.len() - rest.len());
+                    if used > MAX_TRAILER {
+                        return Err(BodyError::TooLong)
+                    }
+                    data = rest;
+                    if line.is_empty() {
+                        self.finish()
+                    }
+                    else {
+                        self.state = BodyState::Trailer(used)
+                    }
+                }
+                BodyState::Done => break
+            }
+        }
+        Ok(total - data.len())
+    }
+
+    /// Marks the body as complete.
+    fn finish(&mut self) {
+        self.state = BodyState::Done;
+        self.sink.end()
+    }
+}
+
+/// Splits a line off the beginning of `data` for a body stream.
+fn body_line(data: &[u8], max_len: usize)
+             -> Result<Option<(&[u8], &[u8])>, BodyError> {
+    parse_line(data, max_len).map_err(|err| match err {
+        LineError::TooLong => BodyError::TooLong,
+        LineError::BareLf => BodyError::Invalid,
+    })
+}
+
+/// Parses the size from a chunk size line, dropping any extensions.
+fn parse_chunk_size(line: &[u8]) -> Result<u64, BodyError> {
+    let size = match line.iter().position(|&ch| ch == b';') {
+        Some(semicolon) => &line[..semicolon],
+        None => line
+    };
+    let size = try!(str::from_utf8(size).map_err(|_| BodyError::Invalid));
+    let size = size.trim();
+    if size.is_empty() {
+        return Err(BodyError::Invalid)
+    }
+    u64::from_str_radix(size, 16).map_err(|_| BodyError::Invalid)
+}
+
+
+//------------ BodySink ------------------------------------------------------
+
+/// The receiving end of a [BodyStream].
+///
+/// [BodyStream]: struct.BodyStream.html
+pub trait BodySink {
+    /// Receives the next piece of the body.
+    ///
+    /// The sink has to take all of `data`. If it would rather not receive
+    /// any more for now, it returns `BodyFlow::Pause`.
+    fn data(&mut self, data: &[u8]) -> BodyFlow;
+
+    /// Called once the body is complete.
+    ///
+    /// If the connection goes away before that, the sink is simply
+    /// dropped without this method ever being called.
+    fn end(&mut self);
+
+    /// Returns whether the sink is ready to receive data again.
+    ///
+    /// This is called when a paused stream is asked to resume. The
+    /// default implementation always returns `true`.
+    fn is_ready(&mut self) -> bool {
+        true
+    }
+}
+
+impl<K: BodySink + ?Sized> BodySink for Box<K> {
+    fn data(&mut self, data: &[u8]) -> BodyFlow {
+        (**self).data(data)
+    }
+
+    fn end(&mut self) {
+        (**self).end()
+    }
+
+    fn is_ready(&mut self) -> bool {
+        (**self).is_ready()
+    }
+}
+
+
+//------------ BodyFlow ------------------------------------------------------
+
+/// Whether a body sink wants to receive more data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BodyFlow {
+    /// Keep the data coming.
+    Continue,
+
+    /// Pause the stream until the sink is ready again.
+    Pause,
+}
+
+
+//------------ BodyError -----------------------------------------------------
+
+/// A body stream received data that violates the framing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BodyError {
+    /// The framing is broken.
+    Invalid,
+
+    /// A chunk size line or the trailer is too long.
+    TooLong,
+}
+
+impl error::Error for BodyError {
+    fn description(&self) -> &str {
+        match *self {
+            BodyError::Invalid => "invalid body framing",
+            BodyError::TooLong => "body framing line too long",
+        }
+    }
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(error::Error::description(self))
+    }
+}
+
+
+//------------ Tracer --------------------------------------------------------
+
+/// A transport handler logging all events for protocol debugging.
+///
+/// The tracer wraps another transport handler and uses it for all the
+/// actual work. Before passing on events, it logs them via the `log`
+/// crate at trace level together with what the wrapped handler asked
+/// for next in return. Because its seed is the wrapped handler’s seed,
+/// it can be used in place of the handler without any other changes.
+///
+/// While the tracer can show what happens when, a handler never sees the
+/// socket calls a machine makes. To also log the data read and written,
+/// use a [TracingSocket] as the machine’s socket type. The two are meant
+/// to be used together but each works well enough on its own.
+///
+/// [TracingSocket]: ../sockets/dump/struct.TracingSocket.html
+pub struct Tracer<H>(H);
+
+impl<H> Tracer<H> {
+    /// Creates a new tracer wrapping `handler`.
+    pub fn new(handler: H) -> Self {
+        Tracer(handler)
+    }
+
+    /// Returns a reference to the wrapped handler.
+    pub fn get_ref(&self) -> &H {
+        &self.0
+    }
+
+    /// Trades the tracer for the wrapped handler.
+    pub fn into_inner(self) -> H {
+        self.0
+    }
+
+    /// Logs the outcome of the event `what` and wraps it up.
+    fn trace(what: &str, next: Next<H>) -> Next<Self> {
+        trace!("{}: {:?}", what, next);
+        next.map(Tracer)
+    }
+}
+
+impl<T, H: TransportHandler<T>> TransportHandler<T> for Tracer<H> {
+    type Seed = H::Seed;
+
+    fn create(seed: Self::Seed, sock: &mut T, notifier: Notifier)
+              -> Next<Self> {
+        Tracer::trace("create", H::create(seed, sock, notifier))
+    }
+
+    fn on_ready(self, sock: &mut T) -> Next<Self> {
+        Tracer::trace("ready", self.0.on_ready(sock))
+    }
+
+    fn readable(self, sock: &mut T) -> Next<Self> {
+        Tracer::trace("readable", self.0.readable(sock))
+    }
+
+    fn writable(self, sock: &mut T) -> Next<Self> {
+        Tracer::trace("writable", self.0.writable(sock))
+    }
+
+    fn wakeup(self, sock: &mut T) -> Next<Self> {
+        Tracer::trace("wakeup", self.0.wakeup(sock))
+    }
+
+    fn secured(self, sock: &mut T) -> Next<Self> {
+        Tracer::trace("secured", self.0.secured(sock))
+    }
+
+    fn wants_urgent(&self) -> bool {
+        self.0.wants_urgent()
+    }
+
+    fn poll_opt(&self) -> PollOpt {
+        self.0.poll_opt()
+    }
+
+    fn has_pending_output(&self) -> bool {
+        self.0.has_pending_output()
+    }
+
+    fn write_queue(&mut self) -> Option<QueueWriter<T>> {
+        self.0.write_queue()
+    }
+
+    fn urgent(self, sock: &mut T) -> Next<Self> {
+        Tracer::trace("urgent", self.0.urgent(sock))
+    }
+
+    fn tick(self, sock: &mut T) -> Next<Self> {
+        Tracer::trace("tick", self.0.tick(sock))
+    }
+
+    fn error(self, err: Error) -> Next<Self> {
+        trace!("error: {}", err);
+        Tracer::trace("error", self.0.error(err))
+    }
+
+    fn recycle(self, sock: T) -> Option<(T, Self::Seed)> {
+        let res = self.0.recycle(sock);
+        trace!("recycle: {}", if res.is_some() { "reused" }
+                              else { "closed" });
+        res
+    }
+
+    fn teardown(self, sock: &mut T) {
+        trace!("teardown");
+        self.0.teardown(sock)
+    }
+
+    fn event(self, event: TransportEvent, sock: &mut T,
+             userdata: &mut Userdata) -> Next<Self> {
+        Tracer::trace(event.name(), self.0.event(event, sock, userdata))
+    }
+}
+
+
+//------------ ApproxSize ----------------------------------------------------
+
+/// A type that can estimate how much memory it is using.
+///
+/// Transport handlers implement this trait in order to be used with the
+/// [BoundedState] wrapper. The estimate doesn’t need to be exact but it
+/// should include whatever the handler may accumulate while processing
+/// input, such as buffered but not yet parsed data.
+///
+/// [BoundedState]: struct.BoundedState.html
+pub trait ApproxSize {
+    /// Returns the approximate amount of memory used in bytes.
+    fn approx_size(&self) -> usize;
+}
+
+
+//------------ BoundedState --------------------------------------------------
+
+/// A transport handler limiting the size of another handler’s state.
+///
+/// A byte quota limits how much a peer can send but not what a handler
+/// makes of it. A parser buffering a huge header block, for instance,
+/// may still accumulate unbounded state. This type wraps such a handler
+/// and, after every callback, asks it for the [approximate size][ApproxSize]
+/// of its state. If this exceeds the limit, the wrapped handler’s
+/// [error()](trait.TransportHandler.html#method.error) method is called
+/// with `Error::StateTooLarge` so it can clean up, and the connection is
+/// removed regardless of what the handler asked for in return.
+///
+/// The seed of the wrapper is a pair of the wrapped handler’s seed and
+/// the limit in bytes.
+///
+/// [ApproxSize]: trait.ApproxSize.html
+pub struct BoundedState<H> {
+    handler: H,
+    limit: usize,
+}
+
+impl<H> BoundedState<H> {
+    /// Creates a new wrapper for `handler` limiting its state to `limit`.
+    pub fn new(handler: H, limit: usize) -> Self {
+        BoundedState { handler: handler, limit: limit }
+    }
+
+    /// Returns a reference to the wrapped handler.
+    pub fn get_ref(&self) -> &H {
+        &self.handler
+    }
+
+    /// Returns the limit for the wrapped handler’s state.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Trades the wrapper for the wrapped handler.
+    pub fn into_inner(self) -> H {
+        self.handler
+    }
+}
+
+impl<H: ApproxSize> BoundedState<H> {
+    /// Checks the state size of the handler in `next` and wraps it up.
+    fn check<T>(next: Next<H>, limit: usize) -> Next<Self>
+             where H: TransportHandler<T> {
+        let size = match next.handler() {
+            Some(handler) => handler.approx_size(),
+            None => return Next::remove()
+        };
+        if size <= limit {
+            return next.map(|handler| BoundedState::new(handler, limit))
+        }
+        debug!("handler state of {} bytes exceeds limit of {} bytes",
+               size, limit);
+        if let Some(handler) = next.into_handler() {
+            let _ = handler.error(Error::StateTooLarge);
+        }
+        Next::remove()
+    }
+}
+
+impl<T, H> TransportHandler<T> for BoundedState<H>
+     where H: TransportHandler<T> + ApproxSize {
+    type Seed = (H::Seed, usize);
+
+    fn create(seed: Self::Seed, sock: &mut T, notifier: Notifier)
+              -> Next<Self> {
+        let (seed, limit) = seed;
+        BoundedState::check(H::create(seed, sock, notifier), limit)
+    }
+
+    fn on_ready(self, sock: &mut T) -> Next<Self> {
+        BoundedState::check(self.handler.on_ready(sock), self.limit)
+    }
+
+    fn readable(self, sock: &mut T) -> Next<Self> {
+        BoundedState::check(self.handler.readable(sock), self.limit)
+    }
+
+    fn writable(self, sock: &mut T) -> Next<Self> {
+        BoundedState::check(self.handler.writable(sock), self.limit)
+    }
+
+    fn wakeup(self, sock: &mut T) -> Next<Self> {
+        BoundedState::check(self.handler.wakeup(sock), self.limit)
+    }
+
+    fn secured(self, sock: &mut T) -> Next<Self> {
+        BoundedState::check(self.handler.secured(sock), self.limit)
+    }
+
+    fn wants_urgent(&self) -> bool {
+        self.handler.wants_urgent()
+    }
+
+    fn poll_opt(&self) -> PollOpt {
+        self.handler.poll_opt()
+    }
+
+    fn has_pending_output(&self) -> bool {
+        self.handler.has_pending_output()
+    }
+
+    fn write_queue(&mut self) -> Option<QueueWriter<T>> {
+        self.handler.write_queue()
+    }
+
+    fn urgent(self, sock: &mut T) -> Next<Self> {
+        BoundedState::check(self.handler.urgent(sock), self.limit)
+    }
+
+    fn tick(self, sock: &mut T) -> Next<Self> {
+        BoundedState::check(self.handler.tick(sock), self.limit)
+    }
+
+    fn error(self, err: Error) -> Next<Self> {
+        BoundedState::check(self.handler.error(err), self.limit)
+    }
+
+    fn recycle(self, sock: T) -> Option<(T, Self::Seed)> {
+        let limit = self.limit;
+        self.handler.recycle(sock).map(|(sock, seed)| (sock, (seed, limit)))
+    }
+
+    fn teardown(self, sock: &mut T) {
+        self.handler.teardown(sock)
+    }
+
+    fn event(self, event: TransportEvent, sock: &mut T,
+             userdata: &mut Userdata) -> Next<Self> {
+        BoundedState::check(self.handler.event(event, sock, userdata),
+                            self.limit)
+    }
 }
 
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use rotor::Notifier;
+    use ::next::Next;
+    use super::{TransportEvent, TransportHandler, Tracer, Userdata};
+
+    /// A handler counting its events in the user data.
+    ///
+    /// It also remembers how many readable events it saw itself.
+    struct Counter(usize);
+
+    impl TransportHandler<()> for Counter {
+        type Seed = ();
+
+        fn create(_seed: (), _sock: &mut (), _notifier: Notifier)
+                  -> Next<Self> {
+            Next::wait(Counter(0))
+        }
+
+        fn readable(self, _sock: &mut ()) -> Next<Self> {
+            Next::wait(Counter(self.0 + 1))
+        }
+
+        fn writable(self, _sock: &mut ()) -> Next<Self> {
+            Next::wait(self)
+        }
+
+        fn wakeup(self, _sock: &mut ()) -> Next<Self> {
+            Next::wait(self)
+        }
+
+        fn event(self, event: TransportEvent, sock: &mut (),
+                 userdata: &mut Userdata) -> Next<Self> {
+            if let Some(ref mut data) = *userdata {
+                if let Some(count) = data.downcast_mut::<usize>() {
+                    *count += 1;
+                }
+            }
+            event.dispatch(self, sock)
+        }
+    }
+
+    fn count(userdata: &Userdata) -> usize {
+        *userdata.as_ref().unwrap().downcast_ref::<usize>().unwrap()
+    }
+
+    #[test]
+    fn event_sees_userdata() {
+        let mut userdata: Userdata = Some(Box::new(0usize));
+        let handler = Counter(0);
+        let handler = handler.event(TransportEvent::Readable, &mut (),
+                                    &mut userdata).into_handler().unwrap();
+        let handler = handler.event(TransportEvent::Wakeup, &mut (),
+                                    &mut userdata).into_handler().unwrap();
+        assert_eq!(handler.0, 1);
+        assert_eq!(count(&userdata), 2);
+    }
+
+    #[test]
+    fn event_without_userdata() {
+        let mut userdata = None;
+        let handler = Counter(0).event(TransportEvent::Readable, &mut (),
+                                       &mut userdata);
+        assert_eq!(handler.into_handler().unwrap().0, 1);
+    }
+
+    #[test]
+    fn tracer_passes_userdata_on() {
+        let mut userdata: Userdata = Some(Box::new(0usize));
+        let handler = Tracer::new(Counter(0));
+        let handler = handler.event(TransportEvent::Tick, &mut (),
+                                    &mut userdata).into_handler().unwrap();
+        assert_eq!(handler.get_ref().0, 0);
+        assert_eq!(count(&userdata), 1);
+    }
+}
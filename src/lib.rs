@@ -9,21 +9,42 @@
 //! [intro]: intro/index.html
 //! [rotor]: ../rotor/index.html
 
+extern crate libc;
 #[macro_use] extern crate log;
 extern crate rotor;
 
+#[cfg(feature = "base64")]
+extern crate base64;
+
+#[cfg(feature = "flate2")]
+extern crate flate2;
+
 #[cfg(feature = "openssl")]
 extern crate openssl;
 
+#[cfg(feature = "rustls")]
+extern crate rustls;
+
 #[cfg(feature = "security-framework")]
 extern crate security_framework;
 
+#[cfg(feature = "sha1")]
+extern crate sha1;
+
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
 pub use error::{Error, Result};
 pub use handlers::{AcceptHandler, RequestHandler, TransportHandler};
 pub use next::Next;
 
 #[macro_use] mod macros;
 
+#[cfg(feature = "test-util")] pub mod clock;
+#[cfg(not(feature = "test-util"))] mod clock;
+
+pub mod coalesce;
+pub mod compose;
 pub mod error;
 pub mod handlers;
 pub mod intro;
@@ -34,5 +55,6 @@ pub mod sockets;
 pub mod sync;
 pub mod utils;
 
-mod compose;
+#[cfg(feature = "flate2")] mod compress;
+mod trace;
 
@@ -1,4 +1,4 @@
-//! Internal macros.
+//! Macros.
 
 /// Creates the machine method implementation for a wrapped machine.
 ///
@@ -39,5 +39,146 @@ macro_rules! wrapped_machine {
                   -> Response<Self, Self::Seed> {
             self.0.wakeup(scope).map_self($map)
         }
+
+        fn spawn_error(self, scope: &mut Scope<Self::Context>,
+                       error: ::rotor::SpawnError<Self::Seed>)
+                       -> Response<Self, Self::Seed> {
+            self.0.spawn_error(scope, error).map_self($map)
+        }
     };
 }
+
+
+/// Defines a machine combining several other machines.
+///
+/// The macro expands to a public enum with a variant for each of the
+/// combined machines, a public seed enum with a variant for each of their
+/// seeds, and the `Machine` implementation dispatching everything to the
+/// machine in the current variant. This is how [Compose3] and
+/// [Compose4] are defined and you can use it for any number of machines
+/// that the crate doesn’t provide.
+///
+/// The macro takes the name of the machine enum and the seed enum
+/// followed, in braces, by one entry per machine of the form
+/// `Variant(SeedVariant): Param` where `Param` is the name of the type
+/// parameter for that machine. Attributes, such as doc comments, can be
+/// given before the machine name and the seed name. For instance,
+/// `Compose3` is defined like this:
+///
+/// ```ignore
+/// compose_machine!(Compose3, Compose3Seed {
+///     A(As): AA, B(Bs): BB, C(Cs): CC
+/// });
+/// ```
+///
+/// Since the expansion refers to rotor by its absolute path, the crate
+/// using the macro needs to depend on rotor directly.
+///
+/// [Compose3]: compose/enum.Compose3.html
+/// [Compose4]: compose/enum.Compose4.html
+#[macro_export]
+macro_rules! compose_machine {
+    (
+        $(#[$attr:meta])* $name:ident,
+        $(#[$seed_attr:meta])* $seed:ident {
+            $( $var:ident($svar:ident): $ty:ident ),+
+        }
+    ) => {
+        $(#[$attr])*
+        pub enum $name<$($ty),+> {
+            $( $var($ty) ),+
+        }
+
+        $(#[$seed_attr])*
+        pub enum $seed<$($ty),+> {
+            $( $svar($ty) ),+
+        }
+
+        impl<X, $($ty),+> ::rotor::Machine for $name<$($ty),+>
+                          where $( $ty: ::rotor::Machine<Context=X> ),+ {
+            type Context = X;
+            type Seed = $seed<$( <$ty as ::rotor::Machine>::Seed ),+>;
+
+            fn create(seed: Self::Seed, scope: &mut ::rotor::Scope<X>)
+                      -> ::rotor::Response<Self, ::rotor::Void> {
+                match seed {
+                    $(
+                        $seed::$svar(s) => {
+                            <$ty as ::rotor::Machine>::create(s, scope)
+                                .map($name::$var, |seed| seed)
+                        }
+                    )+
+                }
+            }
+
+            fn ready(self, events: ::rotor::EventSet,
+                     scope: &mut ::rotor::Scope<X>)
+                     -> ::rotor::Response<Self, Self::Seed> {
+                match self {
+                    $(
+                        $name::$var(m) => {
+                            m.ready(events, scope).map($name::$var,
+                                                       $seed::$svar)
+                        }
+                    )+
+                }
+            }
+
+            fn spawned(self, scope: &mut ::rotor::Scope<X>)
+                       -> ::rotor::Response<Self, Self::Seed> {
+                match self {
+                    $(
+                        $name::$var(m) => {
+                            m.spawned(scope).map($name::$var, $seed::$svar)
+                        }
+                    )+
+                }
+            }
+
+            fn timeout(self, scope: &mut ::rotor::Scope<X>)
+                       -> ::rotor::Response<Self, Self::Seed> {
+                match self {
+                    $(
+                        $name::$var(m) => {
+                            m.timeout(scope).map($name::$var, $seed::$svar)
+                        }
+                    )+
+                }
+            }
+
+            fn wakeup(self, scope: &mut ::rotor::Scope<X>)
+                      -> ::rotor::Response<Self, Self::Seed> {
+                match self {
+                    $(
+                        $name::$var(m) => {
+                            m.wakeup(scope).map($name::$var, $seed::$svar)
+                        }
+                    )+
+                }
+            }
+
+            fn spawn_error(self, scope: &mut ::rotor::Scope<X>,
+                           error: ::rotor::SpawnError<Self::Seed>)
+                           -> ::rotor::Response<Self, Self::Seed> {
+                use ::rotor::SpawnError::{NoSlabSpace, UserError};
+
+                // A machine only ever learns about seeds it spawned itself.
+                match (self, error) {
+                    $(
+                        ($name::$var(m), NoSlabSpace($seed::$svar(s))) => {
+                            m.spawn_error(scope, NoSlabSpace(s))
+                             .map($name::$var, $seed::$svar)
+                        }
+                    )+
+                    $(
+                        ($name::$var(m), UserError(err)) => {
+                            m.spawn_error(scope, UserError(err))
+                             .map($name::$var, $seed::$svar)
+                        }
+                    )+
+                    _ => unreachable!("spawn error for a foreign seed")
+                }
+            }
+        }
+    }
+}
@@ -1,15 +1,23 @@
 //! Machines for unencrypted network sockets.
 
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::marker::PhantomData;
 use std::net::SocketAddr;
-use rotor::{Compose2, EventSet, GenericScope, Machine, Response, Scope, Void};
+use std::time::Duration;
+use rotor::{Compose2, EventSet, GenericScope, Machine, Notifier, Response,
+            Scope, Void};
 use rotor::mio::tcp::{TcpListener, TcpStream};
 use rotor::mio::udp::UdpSocket;
-use super::machines::{ServerMachine, TransportMachine};
+#[cfg(unix)] use rotor::mio::unix::{UnixListener, UnixStream};
+use super::machines::{ServerConfig, ServerMachine, TransportMachine};
 use ::handlers::{AcceptHandler, RequestHandler, TransportHandler};
-use ::request::{RequestMachine, SeedFactory, TranslateError};
+use ::next::Next;
+use ::request::{ConnectMachine, RequestMachine, RequestMachineConfig,
+                RequestSeed, SeedFactory, TranslateError, Transport};
+use ::sockets::{Dgram, FilteredDgram, SocketOptions, SourceFilter};
 use ::utils::ResponseExt;
-use ::sync::{DuctSender, TriggerSender};
+use ::sync::{DuctSender, TriggerReceiver, TriggerSender, trigger};
 
 
 //============ Transport Machines ============================================
@@ -51,6 +59,64 @@ impl<X, H: TransportHandler<TcpStream>> Machine for TcpTransport<X, H> {
     wrapped_machine!(TransportMachine, TcpTransport);
 }
 
+impl<X, H> ConnectMachine for TcpTransport<X, H>
+           where H: TransportHandler<TcpStream> {
+    fn create_connecting(seed: Self::Seed, timeout: Duration,
+                         scope: &mut Scope<X>) -> Response<Self, Void> {
+        TransportMachine::create_connecting(seed, timeout, scope)
+                         .map_self(TcpTransport)
+    }
+}
+
+
+//------------ UnixTransport -------------------------------------------------
+
+/// The transport machine for Unix domain stream sockets.
+///
+/// This is the same as [TcpTransport] except that it uses a [UnixStream]
+/// instead. Since both are [ClearStream]s, a transport handler generic
+/// over that trait can be used with either.
+///
+/// [TcpTransport]: struct.TcpTransport.html
+/// [UnixStream]: ../../../rotor/mio/unix/struct.UnixStream.html
+/// [ClearStream]: ../../sockets/trait.ClearStream.html
+#[cfg(unix)]
+pub struct UnixTransport<X, H>(TransportMachine<X, UnixStream, H>)
+           where H: TransportHandler<UnixStream>;
+
+#[cfg(unix)]
+impl<X, H: TransportHandler<UnixStream>> UnixTransport<X, H> {
+    /// Creates a new machine.
+    ///
+    /// The function takes a transport socket and a transport handler seed,
+    /// as well as the scope for the new machine. It creates a new machine
+    /// using this scope by calling the handler’s [create()] method.
+    ///
+    /// [create()]: ../../handlers/trait.TransportHandler.html#tymethod.create
+    pub fn new<S: GenericScope>(sock: UnixStream, seed: H::Seed,
+                                scope: &mut S) -> Response<Self, Void> {
+        TransportMachine::new(sock, seed, scope).map_self(UnixTransport)
+    }
+}
+
+#[cfg(unix)]
+impl<X, H: TransportHandler<UnixStream>> Machine for UnixTransport<X, H> {
+    type Context = X;
+    type Seed = (UnixStream, H::Seed);
+
+    wrapped_machine!(TransportMachine, UnixTransport);
+}
+
+#[cfg(unix)]
+impl<X, H> ConnectMachine for UnixTransport<X, H>
+           where H: TransportHandler<UnixStream> {
+    fn create_connecting(seed: Self::Seed, timeout: Duration,
+                         scope: &mut Scope<X>) -> Response<Self, Void> {
+        TransportMachine::create_connecting(seed, timeout, scope)
+                         .map_self(UnixTransport)
+    }
+}
+
 
 //------------ UdpTransport -------------------------------------------------
 
@@ -64,9 +130,18 @@ impl<X, H: TransportHandler<TcpStream>> Machine for TcpTransport<X, H> {
 /// You can add a machine to a loop before its start by using the
 /// [new()](#method.new) function.
 ///
+/// If only datagrams from certain addresses should reach the handler, the
+/// machine can instead operate on a [FilteredDgram] by being created via
+/// [new_with_source_filter()](#method.new_with_source_filter). This is
+/// what the third type argument `D` is for. The same mechanism is used
+/// for dropping zero-length datagrams if the machine is created via
+/// [new_dropping_empty()](#method.new_dropping_empty). By default, they
+/// are delivered to the handler just like any other datagram.
+///
 /// [UdpSocket]: ../../../rotor/mio/udp/struct.UdpSocket.html
-pub struct UdpTransport<X, H>(TransportMachine<X, UdpSocket, H>)
-           where H: TransportHandler<UdpSocket>;
+/// [FilteredDgram]: ../../sockets/struct.FilteredDgram.html
+pub struct UdpTransport<X, H, D=UdpSocket>(TransportMachine<X, D, H>)
+           where D: Dgram, H: TransportHandler<D>;
 
 impl<X, H: TransportHandler<UdpSocket>> UdpTransport<X, H> {
     /// Creates a new machine.
@@ -82,13 +157,76 @@ impl<X, H: TransportHandler<UdpSocket>> UdpTransport<X, H> {
     }
 }
 
-impl<X, H: TransportHandler<UdpSocket>> Machine for UdpTransport<X, H> {
+impl<X, H> UdpTransport<X, H, FilteredDgram<UdpSocket>>
+           where H: TransportHandler<FilteredDgram<UdpSocket>> {
+    /// Creates a new machine that only accepts certain source addresses.
+    ///
+    /// This is like [new()](#method.new) except that datagrams received
+    /// from addresses not allowed by `filter` are dropped before the
+    /// handler gets to see them. This is useful for connected clients
+    /// that should only ever hear from their peer.
+    pub fn new_with_source_filter<S>(sock: UdpSocket, filter: SourceFilter,
+                                     seed: H::Seed, scope: &mut S)
+                                     -> Response<Self, Void>
+                                  where S: GenericScope {
+        UdpTransport::new_filtered(FilteredDgram::new(sock, filter), seed,
+                                   scope)
+    }
+
+    /// Creates a new machine that drops zero-length datagrams.
+    ///
+    /// This is like [new()](#method.new) except that datagrams without
+    /// any content are dropped before the handler gets to see them.
+    pub fn new_dropping_empty<S>(sock: UdpSocket, seed: H::Seed,
+                                 scope: &mut S) -> Response<Self, Void>
+                              where S: GenericScope {
+        let sock = FilteredDgram::new(sock, SourceFilter::all())
+                                 .drop_empty(true);
+        UdpTransport::new_filtered(sock, seed, scope)
+    }
+
+    /// Creates a new machine for an already configured filtered socket.
+    ///
+    /// Use this if you need both a source filter and dropping of
+    /// zero-length datagrams.
+    pub fn new_filtered<S>(sock: FilteredDgram<UdpSocket>, seed: H::Seed,
+                           scope: &mut S) -> Response<Self, Void>
+                        where S: GenericScope {
+        TransportMachine::new(sock, seed, scope).map_self(UdpTransport)
+    }
+}
+
+impl<X, D: Dgram, H: TransportHandler<D>> Machine for UdpTransport<X, H, D> {
     type Context = X;
-    type Seed = (UdpSocket, H::Seed);
+    type Seed = (D, H::Seed);
 
     wrapped_machine!(TransportMachine, UdpTransport);
 }
 
+impl<X, D: Dgram, H: TransportHandler<D>> ConnectMachine
+                                          for UdpTransport<X, H, D> { }
+
+
+//------------ UdpDemux ------------------------------------------------------
+
+/// A transport machine sharing a UDP socket between several services.
+///
+/// This is a [UdpTransport] with a [Demux] as its transport handler. The
+/// demux takes care of routing incoming datagrams to the right service
+/// and of sending their outgoing datagrams. Since a `Demux` is its own
+/// seed, create the machine via `UdpDemux::new(sock, demux, scope)`.
+///
+/// [UdpTransport]: struct.UdpTransport.html
+/// [Demux]: struct.Demux.html
+pub type UdpDemux<X, C> = UdpTransport<X, Demux<C>>;
+
+
+// XXX Connection migration for QUIC-like protocols, ie., keying sessions
+//     by a connection ID extracted from the payload with the source
+//     address only as a hint, belongs into a per-peer UDP session server.
+//     There is no such server yet -- a UdpTransport has a single handler
+//     seeing all datagrams -- so it has to be added first.
+
 
 //------------ TcpUdpTransport -----------------------------------------------
 
@@ -234,6 +372,24 @@ impl<X, TH, UH> Machine for TcpUdpTransport<X, TH, UH>
     }
 }
 
+impl<X, TH, UH> ConnectMachine for TcpUdpTransport<X, TH, UH>
+                where TH: TransportHandler<TcpStream>,
+                      UH: TransportHandler<UdpSocket> {
+    fn create_connecting(seed: Self::Seed, timeout: Duration,
+                         scope: &mut Scope<X>) -> Response<Self, Void> {
+        match seed {
+            TcpUdp::Tcp(seed) => {
+                TcpTransport::create_connecting(seed, timeout, scope)
+                             .map_self(TcpUdpTransport::from)
+            }
+            TcpUdp::Udp(seed) => {
+                UdpTransport::create(seed, scope)
+                             .map_self(TcpUdpTransport::from)
+            }
+        }
+    }
+}
+
 
 //============ Server Machines ===============================================
 
@@ -268,6 +424,66 @@ impl<X, H: AcceptHandler<TcpStream>> TcpServer<X, H> {
         let (m, t) = ServerMachine::new(sock, handler, scope);
         (m.map_self(TcpServer), t)
     }
+
+    /// Creates a new accept machine that configures accepted sockets.
+    ///
+    /// This is like [new()](#method.new) but all the socket options given
+    /// in `options` are applied to every accepted socket.
+    pub fn new_with_options<S>(sock: TcpListener, handler: H,
+                               options: SocketOptions, scope: &mut S)
+                               -> (Response<Self, Void>, TriggerSender)
+                            where S: GenericScope {
+        let (m, t) = ServerMachine::new_with_options(sock, handler, options,
+                                                     scope);
+        (m.map_self(TcpServer), t)
+    }
+
+    /// Creates a new accept machine limiting connections per peer.
+    ///
+    /// This is like [new()](#method.new) but at most `limit` connections
+    /// from the same IP address are allowed at the same time.
+    pub fn new_with_per_ip_limit<S>(sock: TcpListener, handler: H,
+                                    limit: usize, scope: &mut S)
+                                    -> (Response<Self, Void>, TriggerSender)
+                                 where S: GenericScope {
+        let (m, t) = ServerMachine::new_with_per_ip_limit(sock, handler,
+                                                          limit, scope);
+        (m.map_self(TcpServer), t)
+    }
+
+    /// Creates a new accept machine with the given configuration.
+    ///
+    /// This is like [new()](#method.new) but the machine is configured
+    /// via `config`. See [ServerConfig] for the available options.
+    ///
+    /// [ServerConfig]: ../machines/struct.ServerConfig.html
+    pub fn new_with_config<S>(sock: TcpListener, handler: H,
+                              config: ServerConfig, scope: &mut S)
+                              -> (Response<Self, Void>, TriggerSender)
+                           where S: GenericScope {
+        let (m, t) = ServerMachine::new_with_config(sock, handler, config,
+                                                    scope);
+        (m.map_self(TcpServer), t)
+    }
+
+    /// Creates a new accept machine that waits before accepting.
+    ///
+    /// This is like [new_with_config()](#method.new_with_config) but the
+    /// machine only starts accepting connections once the trigger returned
+    /// last is fired. The trigger returned second shuts down the machine
+    /// as usual. See [ServerMachine::new_paused()] for details.
+    ///
+    /// [ServerMachine::new_paused()]:
+    ///     ../machines/struct.ServerMachine.html#method.new_paused
+    pub fn new_paused<S>(sock: TcpListener, handler: H, config: ServerConfig,
+                         scope: &mut S)
+                         -> (Response<Self, Void>, TriggerSender,
+                             TriggerSender)
+                      where S: GenericScope {
+        let (m, t, r) = ServerMachine::new_paused(sock, handler, config,
+                                                  scope);
+        (m.map_self(TcpServer), t, r)
+    }
 }
 
 impl<X, H: AcceptHandler<TcpStream>> Machine for TcpServer<X, H> {
@@ -278,6 +494,104 @@ impl<X, H: AcceptHandler<TcpStream>> Machine for TcpServer<X, H> {
 }
 
 
+//------------ UnixServer ----------------------------------------------------
+
+/// A server machine for Unix domain stream sockets.
+///
+/// This is the same as [TcpServer] except that it listens on a
+/// [UnixListener] and creates [UnixStream]s. The peer address passed to
+/// the accept handler is a [PeerAddr::Unix].
+///
+/// Since Unix domain socket peers don’t have an IP address, there is no
+/// per IP address limit for this machine.
+///
+/// [TcpServer]: struct.TcpServer.html
+/// [UnixListener]: ../../../rotor/mio/unix/struct.UnixListener.html
+/// [UnixStream]: ../../../rotor/mio/unix/struct.UnixStream.html
+/// [PeerAddr::Unix]: ../../sockets/enum.PeerAddr.html#variant.Unix
+#[cfg(unix)]
+pub struct UnixServer<X, H>(ServerMachine<X, UnixListener, H>)
+           where H: AcceptHandler<UnixStream>;
+
+/// # Machine Creation
+///
+#[cfg(unix)]
+impl<X, H: AcceptHandler<UnixStream>> UnixServer<X, H> {
+    /// Creates a new accept machine with the given socket and handler.
+    ///
+    /// Returns the rotor response for the new machine and a the sending
+    /// side of a [trigger] that can be used to terminate the machine.
+    ///
+    /// [trigger]: ../../sync/fn.trigger.html
+    pub fn new<S: GenericScope>(sock: UnixListener, handler: H,
+                                scope: &mut S)
+                                -> (Response<Self, Void>, TriggerSender) {
+        let (m, t) = ServerMachine::new(sock, handler, scope);
+        (m.map_self(UnixServer), t)
+    }
+
+    /// Creates a new accept machine that configures accepted sockets.
+    ///
+    /// This is like [new()](#method.new) but all the socket options given
+    /// in `options` are applied to every accepted socket. Most options
+    /// are specific to TCP and fail for Unix domain sockets, so only the
+    /// buffer sizes should be set.
+    pub fn new_with_options<S>(sock: UnixListener, handler: H,
+                               options: SocketOptions, scope: &mut S)
+                               -> (Response<Self, Void>, TriggerSender)
+                            where S: GenericScope {
+        let (m, t) = ServerMachine::new_with_options(sock, handler, options,
+                                                     scope);
+        (m.map_self(UnixServer), t)
+    }
+
+    /// Creates a new accept machine with the given configuration.
+    ///
+    /// This is like [new()](#method.new) but the machine is configured
+    /// via `config`. See [ServerConfig] for the available options.
+    ///
+    /// [ServerConfig]: ../machines/struct.ServerConfig.html
+    pub fn new_with_config<S>(sock: UnixListener, handler: H,
+                              config: ServerConfig, scope: &mut S)
+                              -> (Response<Self, Void>, TriggerSender)
+                           where S: GenericScope {
+        let (m, t) = ServerMachine::new_with_config(sock, handler, config,
+                                                    scope);
+        (m.map_self(UnixServer), t)
+    }
+
+    /// Creates a new accept machine that waits before accepting.
+    ///
+    /// This is like [TcpServer::new_paused()] but for a Unix domain
+    /// socket.
+    ///
+    /// [TcpServer::new_paused()]: struct.TcpServer.html#method.new_paused
+    pub fn new_paused<S>(sock: UnixListener, handler: H,
+                         config: ServerConfig, scope: &mut S)
+                         -> (Response<Self, Void>, TriggerSender,
+                             TriggerSender)
+                      where S: GenericScope {
+        let (m, t, r) = ServerMachine::new_paused(sock, handler, config,
+                                                  scope);
+        (m.map_self(UnixServer), t, r)
+    }
+}
+
+#[cfg(unix)]
+impl<X, H: AcceptHandler<UnixStream>> Machine for UnixServer<X, H> {
+    type Context = X;
+    type Seed = <ServerMachine<X, UnixListener, H> as Machine>::Seed;
+
+    wrapped_machine!(ServerMachine, UnixServer);
+}
+
+
+// XXX A TcpUnixServer combining a TCP server and a Unix domain socket
+//     server for the same accept handler should go here. Since the two
+//     produce different transport sockets, it needs a stream type
+//     covering both first.
+
+
 //------------ TcpUdpServer -------------------------------------------------
 
 /// A machine that combines a TCP server and a UDP transport.
@@ -318,6 +632,21 @@ impl<X, AH, UH> TcpUdpServer<X, AH, UH>
         (m.map_self(|m| TcpUdpServer(Compose2::A(m))), t)
     }
 
+    /// Creates a new machine for a configured TCP accept socket.
+    ///
+    /// This is like [new_tcp()](#method.new_tcp) but the accept machine
+    /// is configured via `config`. See [ServerConfig] for the options.
+    ///
+    /// [ServerConfig]: ../machines/struct.ServerConfig.html
+    pub fn new_tcp_with_config<S>(sock: TcpListener, handler: AH,
+                                  config: ServerConfig, scope: &mut S)
+                                  -> (Response<Self, Void>, TriggerSender)
+                               where S: GenericScope {
+        let (m, t) = TcpServer::new_with_config(sock, handler, config,
+                                                scope);
+        (m.map_self(|m| TcpUdpServer(Compose2::A(m))), t)
+    }
+
     /// Creates a new machine for a UDP transport socket.
     ///
     /// The machine will use the given socket and create a transport handler
@@ -343,6 +672,86 @@ impl<X, AH, UH> Machine for TcpUdpServer<X, AH, UH>
 }
 
 
+//------------ UdpServer ----------------------------------------------------
+
+/// A server machine for unencrypted datagram sockets.
+///
+/// The type is generic over the rotor context `X` and the transport
+/// handler `H` which must accept [UdpSocket] as its type argument.
+///
+/// Since datagram sockets don’t have connections, the machine is really
+/// just a [UdpTransport] with a single transport handler seeing all
+/// datagrams. Unlike a bare transport, though, it can be shut down from
+/// outside through the [trigger] returned by [new()](#method.new), just
+/// like a [TcpServer]. When that happens, the handler is torn down and
+/// the socket closed.
+///
+/// [UdpSocket]: ../../../rotor/mio/udp/struct.UdpSocket.html
+/// [UdpTransport]: struct.UdpTransport.html
+/// [TcpServer]: struct.TcpServer.html
+/// [trigger]: ../../sync/fn.trigger.html
+pub struct UdpServer<X, H: TransportHandler<UdpSocket>> {
+    transport: UdpTransport<X, H>,
+    rx: TriggerReceiver,
+}
+
+/// # Machine Creation
+///
+impl<X, H: TransportHandler<UdpSocket>> UdpServer<X, H> {
+    /// Creates a new machine with the given socket and handler seed.
+    ///
+    /// Returns the rotor response for the new machine and the sending
+    /// side of a [trigger] that can be used to terminate the machine.
+    ///
+    /// [trigger]: ../../sync/fn.trigger.html
+    pub fn new<S: GenericScope>(sock: UdpSocket, seed: H::Seed,
+                                scope: &mut S)
+                                -> (Response<Self, Void>, TriggerSender) {
+        let (tx, rx) = trigger(scope.notifier());
+        (UdpServer::wrap(UdpTransport::new(sock, seed, scope), rx), tx)
+    }
+}
+
+impl<X, H: TransportHandler<UdpSocket>> UdpServer<X, H> {
+    /// Wraps the response of the transport machine.
+    fn wrap<Q, R>(res: Response<UdpTransport<X, H>, Q>, rx: TriggerReceiver)
+                  -> Response<Self, R> {
+        res.map(|transport| UdpServer { transport: transport, rx: rx },
+                |_| unreachable!("transport machines don’t spawn"))
+    }
+}
+
+impl<X, H: TransportHandler<UdpSocket>> Machine for UdpServer<X, H> {
+    type Context = X;
+    type Seed = Void;
+
+    fn create(seed: Self::Seed, _scope: &mut Scope<X>)
+              -> Response<Self, Void> {
+        match seed { }
+    }
+
+    fn ready(self, events: EventSet, scope: &mut Scope<X>)
+             -> Response<Self, Self::Seed> {
+        UdpServer::wrap(self.transport.ready(events, scope), self.rx)
+    }
+
+    fn spawned(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        UdpServer::wrap(self.transport.spawned(scope), self.rx)
+    }
+
+    fn timeout(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        UdpServer::wrap(self.transport.timeout(scope), self.rx)
+    }
+
+    fn wakeup(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        if self.rx.triggered() {
+            return Response::done()
+        }
+        UdpServer::wrap(self.transport.wakeup(scope), self.rx)
+    }
+}
+
+
 //============ Client Machines ===============================================
 
 //------------ TcpClient ----------------------------------------------------
@@ -395,6 +804,21 @@ impl<X, RH, TH> TcpClient<X, RH, TH>
         let (m, tx) = RequestMachine::new(handler, TcpFactory::new(), scope);
         (m.map_self(TcpClient), tx)
     }
+
+    /// Creates a new request machine using the given configuration.
+    ///
+    /// This is like [new()](#method.new) but allows setting a connect
+    /// timeout and the other options of [RequestMachineConfig].
+    ///
+    /// [RequestMachineConfig]: ../../request/struct.RequestMachineConfig.html
+    pub fn with_config<S>(handler: RH, config: RequestMachineConfig,
+                          scope: &mut S)
+                          -> (Response<Self, Void>, DuctSender<RH::Request>)
+                       where S: GenericScope {
+        let (m, tx) = RequestMachine::with_config(handler, TcpFactory::new(),
+                                                  config, scope);
+        (m.map_self(TcpClient), tx)
+    }
 }
 
 //--- Machine
@@ -403,7 +827,7 @@ impl<X, RH, TH> Machine for TcpClient<X, RH, TH>
                 where RH: RequestHandler<Output=(SocketAddr, TH::Seed)>,
                       TH: TransportHandler<TcpStream> {
     type Context = X;
-    type Seed = (TcpStream, TH::Seed);
+    type Seed = RequestSeed<(TcpStream, TH::Seed)>;
 
     wrapped_machine!(RequestMachine, TcpClient);
 }
@@ -425,13 +849,22 @@ impl<X, RH, TH> UdpClient<X, RH, TH>
         let (m, tx) = RequestMachine::new(handler, UdpFactory::new(), scope);
         (m.map_self(UdpClient), tx)
     }
+
+    pub fn with_config<S>(handler: RH, config: RequestMachineConfig,
+                          scope: &mut S)
+                          -> (Response<Self, Void>, DuctSender<RH::Request>)
+                       where S: GenericScope {
+        let (m, tx) = RequestMachine::with_config(handler, UdpFactory::new(),
+                                                  config, scope);
+        (m.map_self(UdpClient), tx)
+    }
 }
 
 impl<X, RH, TH> Machine for UdpClient<X, RH, TH>
                 where RH: RequestHandler<Output=(SocketAddr, TH::Seed)>,
                       TH: TransportHandler<UdpSocket> {
     type Context = X;
-    type Seed = (UdpSocket, TH::Seed);
+    type Seed = RequestSeed<(UdpSocket, TH::Seed)>;
 
     wrapped_machine!(RequestMachine, UdpClient);
 }
@@ -460,6 +893,19 @@ impl<X, RH, TH, UH> TcpUdpClient<X, RH, TH, UH>
                                           scope);
         (m.map_self(TcpUdpClient), tx)
     }
+
+    /// Creates a new request machine using the given configuration.
+    ///
+    /// A connect timeout in `config` only applies to TCP sockets.
+    pub fn with_config<S>(handler: RH, config: RequestMachineConfig,
+                          scope: &mut S)
+                          -> (Response<Self, Void>, DuctSender<RH::Request>)
+                       where S: GenericScope {
+        let (m, tx) = RequestMachine::with_config(handler,
+                                                  TcpUdpFactory::new(),
+                                                  config, scope);
+        (m.map_self(TcpUdpClient), tx)
+    }
 }
 
 impl<X, RH, TH, UH> Machine for TcpUdpClient<X, RH, TH, UH>
@@ -468,7 +914,8 @@ impl<X, RH, TH, UH> Machine for TcpUdpClient<X, RH, TH, UH>
                   TH: TransportHandler<TcpStream>,
                   UH: TransportHandler<UdpSocket> {
     type Context = X;
-    type Seed = TcpUdp<(TcpStream, TH::Seed), (UdpSocket, UH::Seed)>;
+    type Seed = RequestSeed<TcpUdp<(TcpStream, TH::Seed),
+                                   (UdpSocket, UH::Seed)>>;
 
     wrapped_machine!(RequestMachine, TcpUdpClient);
 }
@@ -478,10 +925,20 @@ impl<X, RH, TH, UH> Machine for TcpUdpClient<X, RH, TH, UH>
 
 //------------ TcpFactory ----------------------------------------------------
 
-pub struct TcpFactory<S>(PhantomData<S>);
+pub struct TcpFactory<S> {
+    /// The address the most recent socket is connecting to.
+    ///
+    /// The request machine spawns every seed right after translating it,
+    /// so a failed spawn is always for the last address.
+    target: Cell<Option<SocketAddr>>,
+    marker: PhantomData<S>
+}
 
 impl<S> TcpFactory<S> {
-    fn new() -> Self { TcpFactory(PhantomData) }
+    /// Creates a new factory.
+    pub fn new() -> Self {
+        TcpFactory { target: Cell::new(None), marker: PhantomData }
+    }
 }
 
 impl<S> SeedFactory<(SocketAddr, S), (TcpStream, S)> for TcpFactory<S> {
@@ -489,10 +946,26 @@ impl<S> SeedFactory<(SocketAddr, S), (TcpStream, S)> for TcpFactory<S> {
                  -> Result<(TcpStream, S), TranslateError<(SocketAddr, S)>> {
         let (addr, seed) = output;
         match TcpStream::connect(&addr) {
-            Ok(sock) => Ok((sock, seed)),
-            Err(err) => Err(TranslateError((addr, seed), err.into()))
+            Ok(sock) => {
+                self.target.set(Some(addr));
+                Ok((sock, seed))
+            }
+            Err(err) => {
+                Err(TranslateError::new((addr, seed), err.into(),
+                                        Transport::Tcp, addr))
+            }
         }
     }
+
+    /// Recovers the output from the address the socket was connecting to.
+    ///
+    /// The socket itself only knows its peer once connecting has
+    /// succeeded which, for anything but the local host, hardly ever
+    /// happens this early, so the factory keeps the address around.
+    fn recover(&self, seed: (TcpStream, S)) -> Option<(SocketAddr, S)> {
+        let (_, seed) = seed;
+        self.target.get().map(|addr| (addr, seed))
+    }
 }
 
 
@@ -510,18 +983,36 @@ impl<S> SeedFactory<(SocketAddr, S), (UdpSocket, S)> for UdpFactory<S> {
         let (addr, seed) = output;
         match UdpSocket::bound(&addr) {
             Ok(sock) => Ok((sock, seed)),
-            Err(err) => Err(TranslateError((addr, seed), err.into()))
+            Err(err) => {
+                Err(TranslateError::new((addr, seed), err.into(),
+                                        Transport::Udp, addr))
+            }
         }
     }
+
+    /// Recovers the output from the address the socket is bound to.
+    ///
+    /// If the output asked for port zero, the recovered output contains
+    /// the port actually chosen.
+    fn recover(&self, seed: (UdpSocket, S)) -> Option<(SocketAddr, S)> {
+        let (sock, seed) = seed;
+        sock.local_addr().ok().map(|addr| (addr, seed))
+    }
 }
 
 
 //------------ TcpUdpFactory ------------------------------------------------
 
-struct TcpUdpFactory<TS, US>(PhantomData<(TS, US)>);
+struct TcpUdpFactory<TS, US> {
+    /// The address the most recent TCP socket is connecting to.
+    target: Cell<Option<SocketAddr>>,
+    marker: PhantomData<(TS, US)>
+}
 
 impl<TS, US> TcpUdpFactory<TS, US> {
-    fn new() -> Self { TcpUdpFactory(PhantomData) }
+    fn new() -> Self {
+        TcpUdpFactory { target: Cell::new(None), marker: PhantomData }
+    }
 }
 
 impl<TS, US> SeedFactory<TcpUdp<(SocketAddr, TS), (SocketAddr, US)>,
@@ -536,19 +1027,342 @@ impl<TS, US> SeedFactory<TcpUdp<(SocketAddr, TS), (SocketAddr, US)>,
         match output {
             Tcp((addr, seed)) => {
                 match TcpStream::connect(&addr) {
-                    Ok(sock) => Ok(Tcp((sock, seed))),
-                    Err(err) => Err(TranslateError(Tcp((addr, seed)),
-                                                   err.into()))
+                    Ok(sock) => {
+                        self.target.set(Some(addr));
+                        Ok(Tcp((sock, seed)))
+                    }
+                    Err(err) => {
+                        Err(TranslateError::new(Tcp((addr, seed)),
+                                                err.into(), Transport::Tcp,
+                                                addr))
+                    }
                 }
             }
             Udp((addr, seed)) => {
                 match UdpSocket::bound(&addr) {
                     Ok(sock) => Ok(Udp((sock, seed))),
-                    Err(err) => Err(TranslateError(Udp((addr, seed)),
-                                                   err.into()))
+                    Err(err) => {
+                        Err(TranslateError::new(Udp((addr, seed)),
+                                                err.into(), Transport::Udp,
+                                                addr))
+                    }
+                }
+            }
+        }
+    }
+
+    fn recover(&self, seed: TcpUdp<(TcpStream, TS), (UdpSocket, US)>)
+               -> Option<TcpUdp<(SocketAddr, TS), (SocketAddr, US)>> {
+        use self::TcpUdp::*;
+
+        match seed {
+            Tcp((_, seed)) => {
+                self.target.get().map(|addr| Tcp((addr, seed)))
+            }
+            Udp((sock, seed)) => {
+                sock.local_addr().ok().map(|addr| Udp((addr, seed)))
+            }
+        }
+    }
+}
+
+
+//============ Datagram Demultiplexing =======================================
+
+//------------ Demux ---------------------------------------------------------
+
+/// A transport handler dispatching datagrams to several services.
+///
+/// Sometimes a single UDP socket needs to serve more than one protocol,
+/// for instance when the protocols are told apart by a message type in
+/// the payload. Since only one machine can own the socket, the demux
+/// does it for all of them.
+///
+/// Each service is added via [service()](#method.service) and is
+/// identified by the order in which it was added, starting at zero.
+/// For every datagram received, the [Classifier] `C` decides which
+/// service gets it. Datagrams it doesn’t want to give to any service
+/// are dropped.
+///
+/// Services don’t send datagrams themselves but rather place them into
+/// their own [Outbox]. When the socket becomes writable, the demux takes
+/// turns between all outboxes, sending one datagram from each in every
+/// round, so that a busy service can’t hold up the other ones. If an
+/// outbox is full, new datagrams placed into it are dropped. Since
+/// datagrams are unreliable anyway, a failure to send a datagram is
+/// logged and the datagram dropped, too.
+///
+/// An error receiving datagrams, however, ends the transport.
+///
+/// [Classifier]: trait.Classifier.html
+/// [Outbox]: struct.Outbox.html
+pub struct Demux<C: Classifier> {
+    /// The classifier for incoming datagrams.
+    classifier: C,
+
+    /// The services with their outboxes.
+    services: Vec<(Box<DemuxService>, Outbox)>,
+
+    /// The index of the service whose outbox is up next for sending.
+    turn: usize,
+
+    /// The maximum number of datagrams in a new outbox.
+    outbox_limit: usize,
+
+    /// The buffer for receiving datagrams.
+    buf: Vec<u8>,
+}
+
+/// The size of the receive buffer of a demux.
+///
+/// This is large enough for any UDP datagram.
+const DEMUX_BUF_SIZE: usize = 65_536;
+
+/// The maximum number of datagrams received in one go.
+const DEMUX_RECV_BATCH: usize = 64;
+
+/// The maximum number of datagrams in an outbox by default.
+const DEFAULT_OUTBOX_LIMIT: usize = 1024;
+
+/// # Creation and Configuration
+///
+impl<C: Classifier> Demux<C> {
+    /// Creates a new demux using `classifier` and no services yet.
+    pub fn new(classifier: C) -> Self {
+        Demux {
+            classifier: classifier,
+            services: Vec::new(),
+            turn: 0,
+            outbox_limit: DEFAULT_OUTBOX_LIMIT,
+            buf: vec![0; DEMUX_BUF_SIZE],
+        }
+    }
+
+    /// Sets the maximum number of datagrams per outbox.
+    ///
+    /// This only applies to services added after the call.
+    pub fn outbox_limit(mut self, limit: usize) -> Self {
+        self.outbox_limit = limit;
+        self
+    }
+
+    /// Adds a service.
+    ///
+    /// The service’s index is the number of services added before.
+    pub fn service(mut self, service: Box<DemuxService>) -> Self {
+        let outbox = Outbox::new(self.outbox_limit);
+        self.services.push((service, outbox));
+        self
+    }
+
+    /// Returns the number of services.
+    pub fn len(&self) -> usize {
+        self.services.len()
+    }
+
+    /// Returns whether there are no services.
+    pub fn is_empty(&self) -> bool {
+        self.services.is_empty()
+    }
+}
+
+/// # Internal Helpers
+///
+impl<C: Classifier> Demux<C> {
+    /// Sends datagrams from the outboxes until the socket is full.
+    fn send_all<D: Dgram>(&mut self, sock: &mut D) {
+        let count = self.services.len();
+        let mut idle = 0;
+        while idle < count {
+            let turn = self.turn;
+            self.turn = (turn + 1) % count;
+            let outbox = &mut self.services[turn].1;
+            let (data, addr) = match outbox.queue.pop_front() {
+                Some(item) => item,
+                None => {
+                    idle += 1;
+                    continue
+                }
+            };
+            idle = 0;
+            match sock.send_to(&data, &addr) {
+                Ok(Some(_)) => { }
+                Ok(None) => {
+                    // Put it back and make sure it goes first next time.
+                    outbox.queue.push_front((data, addr));
+                    self.turn = turn;
+                    return
+                }
+                Err(err) => {
+                    debug!("dropping datagram to {}: {}", addr, err);
+                }
+            }
+        }
+    }
+
+    /// Returns the next value based on whether there is anything to send.
+    fn next(self) -> Next<Self> {
+        if self.services.iter().any(|item| !item.1.is_empty()) {
+            Next::read_and_write(self)
+        }
+        else {
+            Next::read(self)
+        }
+    }
+}
+
+
+//--- TransportHandler
+
+impl<D: Dgram, C: Classifier> TransportHandler<D> for Demux<C> {
+    type Seed = Self;
+
+    fn create(mut seed: Self, _sock: &mut D, notifier: Notifier)
+              -> Next<Self> {
+        for item in &mut seed.services {
+            item.0.start(notifier.clone(), &mut item.1)
+        }
+        seed.next()
+    }
+
+    fn readable(mut self, sock: &mut D) -> Next<Self> {
+        for _ in 0..DEMUX_RECV_BATCH {
+            let (len, addr) = match sock.recv_from(&mut self.buf) {
+                Ok(Some(res)) => res,
+                Ok(None) => break,
+                Err(err) => {
+                    debug!("receiving datagram failed: {}", err);
+                    return Next::remove()
+                }
+            };
+            let data = &self.buf[..len];
+            match self.classifier.classify(data, &addr) {
+                Some(index) if index < self.services.len() => {
+                    let item = &mut self.services[index];
+                    item.0.received(data, addr, &mut item.1)
                 }
+                _ => debug!("dropping unclaimed datagram from {}", addr)
             }
         }
+        self.send_all(sock);
+        self.next()
+    }
+
+    fn writable(mut self, sock: &mut D) -> Next<Self> {
+        self.send_all(sock);
+        self.next()
+    }
+
+    fn wakeup(mut self, sock: &mut D) -> Next<Self> {
+        for item in &mut self.services {
+            item.0.wakeup(&mut item.1)
+        }
+        self.send_all(sock);
+        self.next()
+    }
+}
+
+
+//------------ Classifier ----------------------------------------------------
+
+/// A type deciding which service of a demux receives a datagram.
+///
+/// The trait is implemented for closures taking the datagram’s content
+/// and source address, so that in most cases you can simply give a
+/// closure to [Demux::new()].
+///
+/// [Demux::new()]: struct.Demux.html#method.new
+pub trait Classifier {
+    /// Returns the index of the service that should receive a datagram.
+    ///
+    /// The datagram’s content is given in `data`, the address it was sent
+    /// from in `addr`. If the method returns `None` or an index that
+    /// doesn’t belong to a service, the datagram is dropped.
+    fn classify(&mut self, data: &[u8], addr: &SocketAddr) -> Option<usize>;
+}
+
+impl<F> Classifier for F
+     where F: FnMut(&[u8], &SocketAddr) -> Option<usize> {
+    fn classify(&mut self, data: &[u8], addr: &SocketAddr) -> Option<usize> {
+        (*self)(data, addr)
+    }
+}
+
+
+//------------ DemuxService --------------------------------------------------
+
+/// A service sharing a datagram socket through a demux.
+///
+/// Each service keeps whatever state it needs. It receives the datagrams
+/// the [Demux] hands to it via [received()](#tymethod.received) and
+/// places the datagrams it wants to send into the [Outbox] given to each
+/// method.
+///
+/// [Demux]: struct.Demux.html
+/// [Outbox]: struct.Outbox.html
+pub trait DemuxService {
+    /// Called once when the demux is started.
+    ///
+    /// The `notifier` wakes up the demux, which then calls
+    /// [wakeup()](#method.wakeup) for all services. A service that has
+    /// other parts of the program do work for it can use it to learn
+    /// about the results. The default implementation does nothing.
+    fn start(&mut self, notifier: Notifier, outbox: &mut Outbox) {
+        let _ = (notifier, outbox);
+    }
+
+    /// Called for every datagram claimed for this service.
+    fn received(&mut self, data: &[u8], addr: SocketAddr,
+                outbox: &mut Outbox);
+
+    /// Called when the demux has been woken up.
+    ///
+    /// The default implementation does nothing.
+    fn wakeup(&mut self, outbox: &mut Outbox) {
+        let _ = outbox;
+    }
+}
+
+
+//------------ Outbox --------------------------------------------------------
+
+/// The queue of outgoing datagrams of a demux service.
+pub struct Outbox {
+    /// The queued datagrams with their destination.
+    queue: VecDeque<(Vec<u8>, SocketAddr)>,
+
+    /// The maximum length of the queue.
+    limit: usize,
+}
+
+impl Outbox {
+    /// Creates a new outbox holding up to `limit` datagrams.
+    fn new(limit: usize) -> Self {
+        Outbox { queue: VecDeque::new(), limit: limit }
+    }
+
+    /// Queues a datagram with content `data` to be sent to `addr`.
+    ///
+    /// Returns whether there was room. If not, the datagram is dropped.
+    pub fn send(&mut self, data: Vec<u8>, addr: SocketAddr) -> bool {
+        if self.queue.len() >= self.limit {
+            debug!("outbox full, dropping datagram to {}", addr);
+            false
+        }
+        else {
+            self.queue.push_back((data, addr));
+            true
+        }
+    }
+
+    /// Returns the number of datagrams waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Returns whether there are no datagrams waiting.
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
     }
 }
 
@@ -562,3 +1376,87 @@ pub enum TcpUdp<T, U> {
     Udp(U)
 }
 
+
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::net::{self, SocketAddr};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use rotor::{Config, Loop, Notifier};
+    use rotor::mio::tcp::TcpStream;
+    use ::error::Error;
+    use ::handlers::{RequestHandler, TransportHandler};
+    use ::next::Next;
+    use super::TcpClient;
+
+    struct Connect(mpsc::Sender<(SocketAddr, bool)>);
+
+    impl RequestHandler for Connect {
+        type Request = SocketAddr;
+        type Output = (SocketAddr, ());
+
+        fn request(&mut self, addr: SocketAddr) -> Option<Self::Output> {
+            Some((addr, ()))
+        }
+
+        fn error(&mut self, output: Self::Output, err: Error) {
+            let no_space = match err { Error::NoSlabSpace => true,
+                                       _ => false };
+            self.0.send((output.0, no_space)).unwrap();
+        }
+    }
+
+    struct Idle;
+
+    impl TransportHandler<TcpStream> for Idle {
+        type Seed = ();
+
+        fn create(_seed: (), _sock: &mut TcpStream, _notifier: Notifier)
+                  -> Next<Self> {
+            Next::wait(Idle)
+        }
+
+        fn readable(self, _sock: &mut TcpStream) -> Next<Self> {
+            Next::wait(self)
+        }
+
+        fn writable(self, _sock: &mut TcpStream) -> Next<Self> {
+            Next::wait(self)
+        }
+
+        fn wakeup(self, _sock: &mut TcpStream) -> Next<Self> {
+            Next::wait(self)
+        }
+    }
+
+    /// With only room for the request machine, spawning the transport
+    /// machine fails and the handler gets the target address back.
+    #[test]
+    fn no_slab_space_recovers_target() {
+        let lsnr = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = lsnr.local_addr().unwrap();
+        let (err_tx, err_rx) = mpsc::channel();
+        let (req_tx, req_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut config = Config::new();
+            config.slab_capacity(1);
+            let mut lc = Loop::new(&config).unwrap();
+            lc.add_machine_with(|scope| {
+                let (m, tx) = TcpClient::<(), _, Idle>::new(Connect(err_tx),
+                                                            scope);
+                req_tx.send(tx).unwrap();
+                m
+            }).unwrap();
+            lc.run(()).unwrap();
+        });
+        let requests = req_rx.recv().unwrap();
+        requests.send(target).unwrap();
+        assert_eq!(err_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+                   (target, true));
+        drop(lsnr);
+    }
+}
@@ -0,0 +1,988 @@
+//! A transport handler for HTTP/1.1 servers.
+//!
+//! The [Http1Handler] reads requests from a stream socket, hands each
+//! complete request to a [Service], and writes the response the service
+//! returns. It takes care of the message framing defined in [RFC 7230]:
+//! request bodies are read according to their `Content-Length` header or
+//! the chunked transfer coding, responses get a `Content-Length` header,
+//! and the connection is kept alive or closed depending on the protocol
+//! version and the `Connection` headers of both request and response.
+//! Pipelined requests are answered in order.
+//!
+//! Requests the handler can’t make sense of are answered with a suitable
+//! error response after which the connection is closed. This includes
+//! heads or bodies that exceed the limits given by [DEFAULT_HEAD_LIMIT]
+//! and [DEFAULT_BODY_LIMIT].
+//!
+//! Responses are kept in a [WriteBuffer]. If a client keeps sending
+//! requests without reading the responses, the handler stops reading once
+//! there is too much output pending and only continues once most of it
+//! has been written.
+//!
+//! Since a service is called right in the middle of event processing, it
+//! must not block. Normally, it only sees complete requests. For large
+//! bodies, such as file uploads, a service can provide a [BodySink] via
+//! its [body_sink()] method instead. The body is then handed to the sink
+//! as it arrives without being limited by [DEFAULT_BODY_LIMIT], and the
+//! request without the body is passed to the service once the body is
+//! complete. Responses can’t be streamed. Services that need that will
+//! have to do with a transport handler of their own.
+//!
+//! [RFC 7230]: https://tools.ietf.org/html/rfc7230
+//! [Http1Handler]: struct.Http1Handler.html
+//! [Service]: trait.Service.html
+//! [BodySink]: ../../handlers/trait.BodySink.html
+//! [body_sink()]: trait.Service.html#method.body_sink
+//! [DEFAULT_HEAD_LIMIT]: constant.DEFAULT_HEAD_LIMIT.html
+//! [DEFAULT_BODY_LIMIT]: constant.DEFAULT_BODY_LIMIT.html
+//! [WriteBuffer]: ../../utils/struct.WriteBuffer.html
+
+use std::{io, str};
+use rotor::Notifier;
+use ::error::Error;
+use ::handlers::{BodyFlow, BodySink, BodyStream, TransportHandler};
+use ::next::Next;
+use ::sockets::Stream;
+use ::utils::{WriteBuffer, parse_line};
+use super::{READ_CHUNK, has_token};
+
+
+/// The limit for the size of a request’s head.
+///
+/// This is the request line and all header lines together.
+pub const DEFAULT_HEAD_LIMIT: usize = 64 * 1024;
+
+/// The limit for the size of a request’s body.
+pub const DEFAULT_BODY_LIMIT: usize = 16 * 1024 * 1024;
+
+/// The maximum number of header fields in a request.
+const MAX_HEADERS: usize = 100;
+
+/// The amount of pending output at which the handler stops reading.
+const WRITE_HIGH: usize = 64 * 1024;
+
+/// The amount of pending output at which the handler reads again.
+const WRITE_LOW: usize = 16 * 1024;
+
+
+//------------ Request -------------------------------------------------------
+
+/// A complete HTTP request.
+#[derive(Clone, Debug)]
+pub struct Request {
+    method: String,
+    target: String,
+    minor: u8,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// Returns the request method.
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// Returns the request target, normally the path and query.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Returns the minor version of the protocol, ie., 0 or 1.
+    pub fn minor_version(&self) -> u8 {
+        self.minor
+    }
+
+    /// Returns all header fields in the order they were received.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// Returns the value of the first header field called `name`.
+    ///
+    /// Header names are compared ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+                           .map(|&(_, ref value)| value.as_str())
+    }
+
+    /// Returns the body of the request.
+    ///
+    /// If the body was handed to a body sink, this is empty.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Trades the request for its body.
+    pub fn into_body(self) -> Vec<u8> {
+        self.body
+    }
+
+    /// Returns whether the client wants the connection closed.
+    fn wants_close(&self) -> bool {
+        let mut close = false;
+        let mut keep_alive = false;
+        for &(ref name, ref value) in &self.headers {
+            if name.eq_ignore_ascii_case("connection") {
+                close = close || has_token(value, "close");
+                keep_alive = keep_alive || has_token(value, "keep-alive");
+            }
+        }
+        close || (self.minor == 0 && !keep_alive)
+    }
+}
+
+
+//------------ Response ------------------------------------------------------
+
+/// An HTTP response to be sent.
+///
+/// The handler adds the `Content-Length` header and a `Connection` header
+/// as needed, so any such headers set here are ignored.
+#[derive(Clone, Debug)]
+pub struct Response {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    close: bool,
+}
+
+impl Response {
+    /// Creates a new response with the given status code.
+    ///
+    /// The response starts out with the standard reason phrase for the
+    /// status, no headers, and an empty body.
+    pub fn new(status: u16) -> Self {
+        Response {
+            status: status,
+            reason: reason_phrase(status).into(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            close: false,
+        }
+    }
+
+    /// Replaces the reason phrase.
+    pub fn reason(mut self, reason: &str) -> Self {
+        self.reason = reason.into();
+        self
+    }
+
+    /// Adds a header field.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sets the body.
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Closes the connection after this response.
+    pub fn close(mut self) -> Self {
+        self.close = true;
+        self
+    }
+
+    /// Returns the status code.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Appends the encoded response to `buf`.
+    ///
+    /// If `head_only` is `true`, the body is left out as required for
+    /// responses to HEAD requests.
+    fn encode(&self, buf: &mut Vec<u8>, head_only: bool, keep_alive: bool,
+              minor: u8) {
+        buf.extend_from_slice(format!("HTTP/1.1 {} {}\r\n", self.status,
+                                      self.reason).as_bytes());
+        for &(ref name, ref value) in &self.headers {
+            if name.eq_ignore_ascii_case("content-length")
+                    || name.eq_ignore_ascii_case("connection")
+                    || name.eq_ignore_ascii_case("transfer-encoding") {
+                continue
+            }
+            buf.extend_from_slice(format!("{}: {}\r\n", name, value)
+                                  .as_bytes());
+        }
+        let has_body = !(self.status < 200 || self.status == 204
+                         || self.status == 304);
+        if has_body {
+            buf.extend_from_slice(format!("Content-Length: {}\r\n",
+                                          self.body.len()).as_bytes());
+        }
+        if !keep_alive {
+            buf.extend_from_slice(b"Connection: close\r\n");
+        }
+        else if minor == 0 {
+            buf.extend_from_slice(b"Connection: keep-alive\r\n");
+        }
+        buf.extend_from_slice(b"\r\n");
+        if has_body && !head_only {
+            buf.extend_from_slice(&self.body);
+        }
+    }
+}
+
+
+//------------ Service -------------------------------------------------------
+
+/// The trait for answering HTTP requests.
+///
+/// The trait is implemented for all closures taking a request and
+/// returning a response, so simple services don’t need a type of their
+/// own.
+pub trait Service {
+    /// Produces the response for a request.
+    fn handle(&mut self, req: Request) -> Response;
+
+    /// Returns a sink for streaming the body of a request.
+    ///
+    /// The method is called with the request’s head once it has been
+    /// received if the request has a body. If it returns a sink, the body
+    /// is handed to it as it arrives and [handle()] is called with an
+    /// empty body once the body is complete.
+    ///
+    /// The sink can pause the body if it can’t keep up. The connection
+    /// then stops reading until it is woken up via `notifier` and the
+    /// sink’s `is_ready()` method returns `true`.
+    ///
+    /// The default implementation returns `None`, causing the body to be
+    /// collected into the request.
+    ///
+    /// [handle()]: #tymethod.handle
+    fn body_sink(&mut self, req: &Request, notifier: &Notifier)
+                 -> Option<Box<BodySink>> {
+        let _ = (req, notifier);
+        None
+    }
+}
+
+impl<F: FnMut(Request) -> Response> Service for F {
+    fn handle(&mut self, req: Request) -> Response {
+        (*self)(req)
+    }
+}
+
+
+//------------ Http1Handler --------------------------------------------------
+
+/// The transport handler for an HTTP/1.1 server connection.
+///
+/// The handler’s seed is the [Service] answering the requests.
+///
+/// [Service]: trait.Service.html
+pub struct Http1Handler<S: Service> {
+    service: S,
+    rbuf: Vec<u8>,
+    wbuf: WriteBuffer,
+
+    /// The head of a request whose body hasn’t been read completely yet.
+    head: Option<Head>,
+
+    /// The stream for that body.
+    stream: Option<BodyStream<Sink>>,
+
+    /// The notifier for waking up paused streams.
+    notifier: Notifier,
+
+    /// Whether we are done reading and only write out what’s left.
+    closing: bool,
+}
+
+impl<S: Service> Http1Handler<S> {
+    /// Creates a new handler for `service`.
+    fn new(service: S, notifier: Notifier) -> Self {
+        Http1Handler {
+            service: service,
+            rbuf: Vec::new(),
+            wbuf: WriteBuffer::new(WRITE_HIGH, WRITE_LOW),
+            head: None,
+            stream: None,
+            notifier: notifier,
+            closing: false,
+        }
+    }
+
+    /// Returns what should happen next.
+    fn next(self) -> Next<Self> {
+        let reading = self.reading();
+        if !self.wbuf.is_empty() {
+            if self.closing || !reading { Next::write(self) }
+            else { Next::read_and_write(self) }
+        }
+        else if self.closing {
+            Next::remove()
+        }
+        else if !reading {
+            Next::wait(self)
+        }
+        else {
+            Next::read(self)
+        }
+    }
+
+    /// Returns whether we want to read from the socket.
+    ///
+    /// We don’t while a body stream is paused or too much output is
+    /// waiting to be written.
+    fn reading(&self) -> bool {
+        !self.wbuf.is_paused()
+            && self.stream.as_ref().map_or(true, BodyStream::wants_read)
+    }
+
+    /// Processes all complete requests in the read buffer.
+    ///
+    /// Stops early if too much output is waiting to be written. The
+    /// remaining requests are processed once it has been.
+    fn process(&mut self) {
+        while !self.closing && !self.wbuf.is_paused() {
+            if self.head.is_none() {
+                match parse_head(&self.rbuf) {
+                    Ok(Some((head, used))) => {
+                        self.rbuf.drain(..used);
+                        if let Err(status) = self.start_body(&head) {
+                            return self.fail(status)
+                        }
+                        self.head = Some(head);
+                    }
+                    Ok(None) => return,
+                    Err(status) => return self.fail(status)
+                }
+            }
+            let body = match self.head.as_ref().map(|head| head.body) {
+                Some(Body::Empty) => Vec::new(),
+                Some(_) => {
+                    match self.stream_body() {
+                        Ok(Some(body)) => body,
+                        Ok(None) => return,
+                        Err(status) => return self.fail(status)
+                    }
+                }
+                None => return
+            };
+            let mut req = match self.head.take() {
+                Some(head) => head.req,
+                None => return
+            };
+            req.body = body;
+            self.respond(req)
+        }
+    }
+
+    /// Prepares for reading the body of the request with `head`.
+    ///
+    /// Asks the service for a body sink and, if it doesn’t provide one,
+    /// checks that the body isn’t too large to be collected.
+    fn start_body(&mut self, head: &Head) -> Result<(), u16> {
+        let sink = match head.body {
+            Body::Empty => return Ok(()),
+            _ => self.service.body_sink(&head.req, &self.notifier)
+        };
+        let sink = match sink {
+            Some(sink) => Sink::Service(sink),
+            None => {
+                if let Body::Length(len) = head.body {
+                    if len > DEFAULT_BODY_LIMIT {
+                        return Err(413)
+                    }
+                }
+                Sink::Collect { body: Vec::new(), too_large: false }
+            }
+        };
+        self.stream = Some(match head.body {
+            Body::Length(len) => BodyStream::with_length(len as u64, sink),
+            _ => BodyStream::chunked(sink)
+        });
+        if head.expect_continue {
+            self.queue(b"HTTP/1.1 100 Continue\r\n\r\n");
+        }
+        Ok(())
+    }
+
+    /// Feeds the read buffer to the body stream.
+    ///
+    /// Returns the body once it is complete or `None` if more data is
+    /// needed. The body is empty if it went to the service’s sink. Once
+    /// the body is complete, the stream is dropped.
+    fn stream_body(&mut self) -> Result<Option<Vec<u8>>, u16> {
+        let done = match self.stream {
+            Some(ref mut stream) => {
+                let used = try!(stream.feed(&self.rbuf)
+                                      .map_err(|_| 400u16));
+                self.rbuf.drain(..used);
+                if stream.sink().is_too_large() {
+                    return Err(413)
+                }
+                stream.is_done()
+            }
+            None => return Ok(Some(Vec::new()))
+        };
+        if !done {
+            return Ok(None)
+        }
+        Ok(Some(self.stream.take().map_or_else(Vec::new, |stream| {
+            stream.into_sink().into_body()
+        })))
+    }
+
+    /// Hands a complete request to the service and queues the response.
+    fn respond(&mut self, req: Request) {
+        let head_only = req.method == "HEAD";
+        let minor = req.minor;
+        let mut keep_alive = !req.wants_close();
+        let resp = self.service.handle(req);
+        keep_alive = keep_alive && !resp.close;
+        let mut buf = Vec::new();
+        resp.encode(&mut buf, head_only, keep_alive, minor);
+        self.queue(&buf);
+        if !keep_alive {
+            self.closing = true
+        }
+    }
+
+    /// Answers a broken request and closes the connection.
+    fn fail(&mut self, status: u16) {
+        debug!("bad HTTP request, responding with {}", status);
+        let mut buf = Vec::new();
+        Response::new(status).encode(&mut buf, false, false, 1);
+        self.queue(&buf);
+        self.closing = true;
+    }
+
+    /// Adds `data` to the output.
+    fn queue(&mut self, data: &[u8]) {
+        // Without a write budget, pushing never fails. It merely pauses
+        // the buffer.
+        let _ = self.wbuf.push(data);
+    }
+}
+
+
+//--- TransportHandler
+
+impl<T: Stream, S: Service> TransportHandler<T> for Http1Handler<S> {
+    type Seed = S;
+
+    fn create(seed: Self::Seed, sock: &mut T, notifier: Notifier)
+              -> Next<Self> {
+        let _ = sock;
+        Http1Handler::new(seed, notifier).next()
+    }
+
+    fn readable(mut self, sock: &mut T) -> Next<Self> {
+        while !self.closing && self.reading() {
+            let mut buf = [0u8; READ_CHUNK];
+            match sock.read(&mut buf) {
+                Ok(0) => {
+                    // The client is done sending. Answer what we have
+                    // and close.
+                    self.closing = true;
+                    break
+                }
+                Ok(len) => self.rbuf.extend_from_slice(&buf[..len]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    break
+                }
+                Err(_) => return Next::remove()
+            }
+            self.process();
+        }
+        self.next()
+    }
+
+    fn writable(mut self, sock: &mut T) -> Next<Self> {
+        if self.wbuf.write_to(sock).is_err() {
+            return Next::remove()
+        }
+        // If we stopped processing for too much output, there may be
+        // requests waiting in the read buffer.
+        self.process();
+        self.next()
+    }
+
+    fn wakeup(mut self, sock: &mut T) -> Next<Self> {
+        let _ = sock;
+        let resumed = match self.stream {
+            Some(ref mut stream) => stream.is_paused() && stream.resume(),
+            None => false
+        };
+        if resumed {
+            // Whatever piled up in the read buffer while we were paused
+            // needs to go first.
+            self.process()
+        }
+        self.next()
+    }
+
+    fn error(self, err: Error) -> Next<Self> {
+        let _ = err;
+        Next::remove()
+    }
+}
+
+
+//------------ Sink ----------------------------------------------------------
+
+/// Where the body of a request goes.
+enum Sink {
+    /// The body is handed to the sink provided by the service.
+    Service(Box<BodySink>),
+
+    /// The body is collected for the request.
+    ///
+    /// If it grows beyond `DEFAULT_BODY_LIMIT`, the rest is dropped and
+    /// `too_large` is set.
+    Collect { body: Vec<u8>, too_large: bool },
+}
+
+impl Sink {
+    /// Returns whether a collected body has grown too large.
+    fn is_too_large(&self) -> bool {
+        match *self {
+            Sink::Collect { too_large, .. } => too_large,
+            Sink::Service(_) => false
+        }
+    }
+
+    /// Trades the sink for the collected body.
+    fn into_body(self) -> Vec<u8> {
+        match self {
+            Sink::Collect { body, .. } => body,
+            Sink::Service(_) => Vec::new()
+        }
+    }
+}
+
+impl BodySink for Sink {
+    fn data(&mut self, data: &[u8]) -> BodyFlow {
+        match *self {
+            Sink::Service(ref mut sink) => sink.data(data),
+            Sink::Collect { ref mut body, ref mut too_large } => {
+                if body.len() + data.len() > DEFAULT_BODY_LIMIT {
+                    // Pausing stops the stream right here.
+                    *too_large = true;
+                    return BodyFlow::Pause
+                }
+                body.extend_from_slice(data);
+                BodyFlow::Continue
+            }
+        }
+    }
+
+    fn end(&mut self) {
+        if let Sink::Service(ref mut sink) = *self {
+            sink.end()
+        }
+    }
+
+    fn is_ready(&mut self) -> bool {
+        match *self {
+            Sink::Service(ref mut sink) => sink.is_ready(),
+            Sink::Collect { too_large, .. } => !too_large
+        }
+    }
+}
+
+
+//------------ Parsing -------------------------------------------------------
+
+/// The head of a request and how to read its body.
+struct Head {
+    req: Request,
+    body: Body,
+    expect_continue: bool,
+}
+
+/// How the body of a request is framed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Body {
+    Empty,
+    Length(usize),
+    Chunked,
+}
+
+/// Parses a request head from the beginning of `buf`.
+///
+/// Returns the head and the number of bytes it occupied or `None` if the
+/// buffer doesn’t contain a complete head yet. If the head is invalid,
+/// returns the status code for the error response.
+fn parse_head(buf: &[u8]) -> Result<Option<(Head, usize)>, u16> {
+    let mut rest = buf;
+
+    // Clients may send empty lines before a request. Ignore them.
+    let mut line;
+    loop {
+        match parse_line(rest, DEFAULT_HEAD_LIMIT) {
+            Ok(Some((res, tail))) => {
+                line = res;
+                rest = tail;
+                if !line.is_empty() {
+                    break
+                }
+            }
+            Ok(None) => return head_pending(buf),
+            Err(_) => return Err(400)
+        }
+    }
+    let line = try!(str::from_utf8(line).map_err(|_| 400u16));
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(),
+                                           parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v), None) if !m.is_empty()
+                                             && !t.is_empty() => (m, t, v),
+        _ => return Err(400)
+    };
+    if !method.bytes().all(is_token) {
+        return Err(400)
+    }
+    let minor = match version {
+        "HTTP/1.1" => 1,
+        "HTTP/1.0" => 0,
+        _ if version.starts_with("HTTP/") => return Err(505),
+        _ => return Err(400)
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let line = match parse_line(rest, DEFAULT_HEAD_LIMIT) {
+            Ok(Some((line, tail))) => {
+                rest = tail;
+                line
+            }
+            Ok(None) => return head_pending(buf),
+            Err(_) => return Err(400)
+        };
+        if buf.len() - rest.len() > DEFAULT_HEAD_LIMIT {
+            return Err(431)
+        }
+        if line.is_empty() {
+            break
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(431)
+        }
+        let colon = match line.iter().position(|&ch| ch == b':') {
+            Some(colon) => colon,
+            None => return Err(400)
+        };
+        let (name, value) = (&line[..colon], &line[colon + 1..]);
+        // This also rejects obsolete line folding since whitespace isn’t
+        // a token character.
+        if name.is_empty() || !name.iter().all(|&ch| is_token(ch)) {
+            return Err(400)
+        }
+        let name = String::from_utf8_lossy(name).into_owned();
+        let value = String::from_utf8_lossy(value).trim().to_string();
+        headers.push((name, value));
+    }
+
+    let req = Request {
+        method: method.into(),
+        target: target.into(),
+        minor: minor,
+        headers: headers,
+        body: Vec::new(),
+    };
+    let body = try!(body_framing(&req));
+    let expect_continue = minor == 1 && req.header("expect").map_or(false,
+        |value| value.eq_ignore_ascii_case("100-continue")
+    );
+    Ok(Some((Head { req: req, body: body, expect_continue: expect_continue },
+             buf.len() - rest.len())))
+}
+
+/// Returns the result for a head that isn’t complete yet.
+///
+/// This fails if the head already is too long.
+fn head_pending(buf: &[u8]) -> Result<Option<(Head, usize)>, u16> {
+    if buf.len() > DEFAULT_HEAD_LIMIT {
+        Err(431)
+    }
+    else {
+        Ok(None)
+    }
+}
+
+/// Determines how the body of a request is framed.
+///
+/// Requests with both a `Transfer-Encoding` and a `Content-Length` header
+/// as well as those with conflicting lengths are rejected since they can
+/// be used to smuggle requests past proxies.
+fn body_framing(req: &Request) -> Result<Body, u16> {
+    let mut chunked = false;
+    let mut length = None;
+    for &(ref name, ref value) in &req.headers {
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            if chunked || !value.eq_ignore_ascii_case("chunked") {
+                return Err(501)
+            }
+            chunked = true;
+        }
+        else if name.eq_ignore_ascii_case("content-length") {
+            if value.is_empty() || !value.bytes().all(is_digit) {
+                return Err(400)
+            }
+            let len = match value.parse::<usize>() {
+                Ok(len) => len,
+                Err(_) => return Err(413)
+            };
+            if length.map_or(false, |old| old != len) {
+                return Err(400)
+            }
+            length = Some(len);
+        }
+    }
+    match (chunked, length) {
+        (true, Some(_)) => Err(400),
+        (true, None) => Ok(Body::Chunked),
+        (false, Some(0)) | (false, None) => Ok(Body::Empty),
+        (false, Some(len)) => Ok(Body::Length(len)),
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// Returns whether `ch` is allowed in a token.
+fn is_token(ch: u8) -> bool {
+    match ch {
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-'
+            | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => true,
+        _ => ch.is_ascii_alphanumeric()
+    }
+}
+
+/// Returns whether `ch` is a decimal digit.
+fn is_digit(ch: u8) -> bool {
+    ch.is_ascii_digit()
+}
+
+/// Returns the standard reason phrase for a status code.
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use rotor::{Config, EventSet, Loop, Machine, Notifier, Response, Scope,
+                Void};
+    use ::handlers::TransportHandler;
+    use ::sockets::mock::MockStream;
+    use super::{DEFAULT_BODY_LIMIT, MAX_HEADERS, WRITE_HIGH, Http1Handler,
+                Request};
+
+    /// A machine that does nothing.
+    struct Idle;
+
+    impl Machine for Idle {
+        type Context = ();
+        type Seed = Void;
+
+        fn create(seed: Void, _scope: &mut Scope<()>)
+                  -> Response<Self, Void> {
+            match seed { }
+        }
+
+        fn ready(self, _events: EventSet, _scope: &mut Scope<()>)
+                 -> Response<Self, Void> {
+            Response::ok(self)
+        }
+
+        fn spawned(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            Response::ok(self)
+        }
+
+        fn timeout(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            Response::ok(self)
+        }
+
+        fn wakeup(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            Response::ok(self)
+        }
+    }
+
+    /// Returns a notifier for a loop that is never run.
+    ///
+    /// The loop needs to be kept around for as long as the notifier is
+    /// used.
+    fn notifier() -> (Loop<Idle>, Notifier) {
+        let mut lc = Loop::new(&Config::new()).unwrap();
+        let mut notifier = None;
+        lc.add_machine_with(|scope| {
+            notifier = Some(scope.notifier());
+            Response::ok(Idle)
+        }).unwrap();
+        (lc, notifier.unwrap())
+    }
+
+    /// Answers with the request target followed by the body.
+    fn echo(req: Request) -> super::Response {
+        let mut body = req.target().as_bytes().to_vec();
+        body.extend_from_slice(req.body());
+        super::Response::new(200).body(body)
+    }
+
+    type Echo = Http1Handler<fn(Request) -> super::Response>;
+
+    /// Feeds `input` to a new handler and returns it with the output.
+    ///
+    /// Returns `None` for the handler if it asked to be removed.
+    fn serve(input: &[u8]) -> (Option<Echo>, MockStream, Loop<Idle>) {
+        let (lc, notifier) = notifier();
+        let mut sock = MockStream::new();
+        sock.push_inbound(input);
+        let handler = Http1Handler::new(echo as fn(Request) -> _, notifier);
+        let handler = handler.readable(&mut sock).into_handler();
+        let handler = handler.and_then(|handler| {
+            handler.writable(&mut sock).into_handler()
+        });
+        (handler, sock, lc)
+    }
+
+    fn response(body: &str) -> String {
+        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(), body)
+    }
+
+    #[test]
+    fn keep_alive() {
+        let (handler, mut sock, _lc) = serve(b"GET /a HTTP/1.1\r\n\r\n");
+        assert_eq!(sock.take_outbound(), response("/a").into_bytes());
+        assert!(!handler.unwrap().closing);
+    }
+
+    #[test]
+    fn connection_close() {
+        let (handler, mut sock, _lc)
+            = serve(b"GET /a HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert_eq!(sock.take_outbound(),
+                   b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\
+                     Connection: close\r\n\r\n/a".to_vec());
+        assert!(handler.is_none());
+    }
+
+    #[test]
+    fn pipelined() {
+        let (handler, mut sock, _lc)
+            = serve(b"GET /a HTTP/1.1\r\n\r\n\
+                      POST /b HTTP/1.1\r\nContent-Length: 2\r\n\r\nxy\
+                      POST /c HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+                      \r\n1\r\nz\r\n0\r\n\r\n");
+        let mut expected = response("/a");
+        expected.push_str(&response("/bxy"));
+        expected.push_str(&response("/cz"));
+        assert_eq!(sock.take_outbound(), expected.into_bytes());
+        assert!(handler.is_some());
+    }
+
+    #[test]
+    fn expect_continue() {
+        let (handler, mut sock, _lc)
+            = serve(b"POST /a HTTP/1.1\r\nExpect: 100-continue\r\n\
+                      Content-Length: 3\r\n\r\n");
+        assert_eq!(sock.take_outbound(),
+                   b"HTTP/1.1 100 Continue\r\n\r\n".to_vec());
+        sock.push_inbound(b"abc");
+        let handler = handler.unwrap().readable(&mut sock).into_handler()
+                             .unwrap();
+        handler.writable(&mut sock).into_handler().unwrap();
+        assert_eq!(sock.take_outbound(), response("/aabc").into_bytes());
+    }
+
+    #[test]
+    fn bad_head() {
+        let (handler, mut sock, _lc)
+            = serve(b"GET /a HTTP/1.1\r\nno colon\r\n\r\n");
+        assert!(sock.take_outbound().starts_with(b"HTTP/1.1 400 "));
+        assert!(handler.is_none());
+    }
+
+    #[test]
+    fn too_many_headers() {
+        let mut input = b"GET /a HTTP/1.1\r\n".to_vec();
+        for _ in 0..MAX_HEADERS + 1 {
+            input.extend_from_slice(b"X: y\r\n");
+        }
+        input.extend_from_slice(b"\r\n");
+        let (handler, mut sock, _lc) = serve(&input);
+        assert!(sock.take_outbound().starts_with(b"HTTP/1.1 431 "));
+        assert!(handler.is_none());
+    }
+
+    #[test]
+    fn chunked_too_large() {
+        let input = format!("POST /a HTTP/1.1\r\n\
+                             Transfer-Encoding: chunked\r\n\r\n\
+                             {:x}\r\n", DEFAULT_BODY_LIMIT + 1);
+        let mut input = input.into_bytes();
+        input.extend_from_slice(&vec![b'x'; DEFAULT_BODY_LIMIT + 1]);
+        let (_, mut sock, _lc) = serve(&input);
+        assert!(sock.take_outbound().starts_with(b"HTTP/1.1 413 "));
+    }
+
+    #[test]
+    fn stops_reading_while_output_piles_up() {
+        let (_lc, notifier) = notifier();
+        let mut sock = MockStream::new();
+        let target: String = ::std::iter::once('/')
+                                    .chain(::std::iter::repeat('a').take(1000))
+                                    .collect();
+        let request = format!("GET {} HTTP/1.1\r\n\r\n", target);
+        for _ in 0..200 {
+            sock.push_inbound(request.as_bytes());
+        }
+        sock.set_writable(false);
+        let handler = Http1Handler::new(echo as fn(Request) -> _, notifier);
+        let next = handler.readable(&mut sock);
+        assert_eq!(format!("{:?}", next), "Next::Write");
+        let handler = next.into_handler().unwrap();
+        assert!(handler.wbuf.len() >= WRITE_HIGH);
+        assert!(sock.inbound_len() > 0);
+
+        // Once the client reads, we answer everything.
+        sock.set_writable(true);
+        let mut handler = handler;
+        let mut written = 0;
+        while sock.inbound_len() > 0 || !handler.wbuf.is_empty() {
+            handler = handler.writable(&mut sock).into_handler().unwrap();
+            handler = handler.readable(&mut sock).into_handler().unwrap();
+            written += sock.take_outbound().len();
+        }
+        assert_eq!(written, 200 * response(&target).len());
+    }
+}
@@ -10,18 +10,36 @@
 //! [net]: ../index.html
 //! [RequestMachine]: ../../request/struct.RequestMachine.html
 
+use std::any::Any;
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::io;
 use std::marker::PhantomData;
-use rotor::{EventSet, GenericScope, Machine, PollOpt, Response, Scope, Void};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use rotor::{EventSet, GenericScope, Machine, PollOpt, Response, Scope, Time,
+            Void};
+use ::clock;
 use ::error::Error;
-use ::handlers::{AcceptHandler, TransportHandler};
+use ::handlers::{AcceptHandler, TransportEvent, TransportHandler, Userdata};
 use ::next::Intent;
-use ::sockets::{Accept, Blocked, Transport};
-use ::sync::{TriggerReceiver, TriggerSender, trigger};
-use ::utils::ResponseExt;
+use ::request::ConnectMachine;
+use ::sockets::{Accept, PeerAddr, SocketOptions, Transport};
+use ::sync::{Sender, TriggerReceiver, TriggerSender, trigger};
+use ::trace::{self, ConnSpan};
+use ::utils::{ResponseExt, WriteBudget};
 
 
 //------------ TransportMachine ----------------------------------------------
 
+/// How often to try flushing a socket’s output before closing it.
+const FLUSH_ATTEMPTS: usize = 4;
+
+/// How many connections a server machine accepts in one go.
+const ACCEPT_BATCH: usize = 32;
+
 /// A machine combining a transport socket and a transport handler.
 ///
 /// The type is generic over the rotor context `X`, the transport socket
@@ -32,8 +50,37 @@ use ::utils::ResponseExt;
 /// machines, during the [Machine::create()] method. The seed for this case
 /// is a pair of the new transport socket and the transport handler’s seed.
 ///
+/// # Teardown
+///
+/// The machine normally ends when the handler returns `Next::remove()`
+/// or hands over the socket via `Next::recycle()`. In both cases, the
+/// handler has been consumed by then and nothing else happens.
+///
+/// Sometimes, though, the machine goes away while the handler is still
+/// alive. Most importantly, this happens when the event loop is shut
+/// down and drops all its machines but also when the socket can’t be
+/// registered with the loop. In these cases, the handler’s [teardown()]
+/// method is called exactly once while the socket is still open. Only
+/// after it has returned is the socket flushed and closed.
+///
 /// [Machine::create()]: ../../../rotor/trait.Machine.html#tymethod.create
-pub struct TransportMachine<X, T: Transport, H: TransportHandler<T>> {
+/// [teardown()]: ../../handlers/trait.TransportHandler.html#method.teardown
+pub struct TransportMachine<X, T: Transport, H: TransportHandler<T>>(
+    /// The actual machine.
+    ///
+    /// This is only ever `None` while the value is being dropped or
+    /// after the connection has been taken out for processing an event.
+    Option<TransportConn<X, T, H>>
+);
+
+// XXX A configurable read chunk size owned by the machine, settable via
+//     something like TcpTransport::new_with_read_size(), needs the
+//     machine to lend a read buffer to the handler first. Currently,
+//     handlers read from the socket themselves using buffers of their
+//     own choosing, so there is nothing for the machine to size.
+
+/// The state of a transport machine.
+struct TransportConn<X, T: Transport, H: TransportHandler<T>> {
     /// The transport socket.
     sock: T,
 
@@ -43,6 +90,39 @@ pub struct TransportMachine<X, T: Transport, H: TransportHandler<T>> {
     /// The handler’s last intent. 
     intent: Intent,
 
+    /// What the socket is currently registered with the loop for.
+    ///
+    /// These are the events actually registered, i.e., after the socket’s
+    /// blocked direction has been taken into account.
+    registered: (EventSet, PollOpt),
+
+    /// Where to send the socket if the handler wants it recycled.
+    pool: Option<Sender<(T, H::Seed)>>,
+
+    /// The tracing span of the connection.
+    ///
+    /// This is empty unless the `tracing` feature is enabled.
+    span: ConnSpan,
+
+    /// Arbitrary data attached to the connection.
+    userdata: Userdata,
+
+    /// Whether the handler’s `on_ready()` has been called already.
+    live: bool,
+
+    /// When to give up on a socket that is still connecting.
+    ///
+    /// This is cleared by the first event on the socket.
+    connecting: Option<Time>,
+
+    /// Where to record statistics if anywhere.
+    stats: Option<MachineStats>,
+
+    /// The number of consecutive events that changed nothing.
+    ///
+    /// This is only kept up to date if there are statistics.
+    idle_run: usize,
+
     /// Binding the context.
     marker: PhantomData<X>
 }
@@ -62,35 +142,243 @@ impl<X, T: Transport, H: TransportHandler<T>> TransportMachine<X, T, H> {
     /// [create()]: ../../handlers/trait.TransportHandler.html#tymethod.create
     /// [LoopCreator]: ../../../rotor/struct.LoopCreator.html
     /// [LoopInstance]: ../../../rotor/struct.LoopInstance.html
-    pub fn new<S: GenericScope>(mut sock: T, seed: H::Seed, scope: &mut S)
+    pub fn new<S: GenericScope>(sock: T, seed: H::Seed, scope: &mut S)
                                 -> Response<Self, Void> {
-        let next = H::create(seed, &mut sock, scope.notifier());
+        TransportConn::create_with(sock, seed, None, None, None, None,
+                                   ConnSpan::new(), scope)
+                      .map_self(TransportMachine::wrap)
+    }
+
+    /// Creates a new machine recording statistics.
+    ///
+    /// This is like [new()](#method.new) except that the machine updates
+    /// `stats` as it processes events. See [MachineStats] for what is
+    /// being recorded.
+    ///
+    /// [MachineStats]: struct.MachineStats.html
+    pub fn new_with_stats<S>(sock: T, seed: H::Seed, stats: MachineStats,
+                             scope: &mut S) -> Response<Self, Void>
+                          where S: GenericScope {
+        TransportConn::create_with(sock, seed, None, None, Some(stats),
+                                   None, ConnSpan::new(), scope)
+                      .map_self(TransportMachine::wrap)
+    }
+
+    /// Creates a new machine with user data attached.
+    ///
+    /// This is like [new()](#method.new) but also attaches `userdata` to
+    /// the machine. See [userdata()](#method.userdata) for what this is
+    /// good for.
+    pub fn new_with_userdata<S>(sock: T, seed: H::Seed,
+                                userdata: Box<Any + Send>, scope: &mut S)
+                                -> Response<Self, Void>
+                             where S: GenericScope {
+        TransportConn::create_with(sock, seed, None, Some(userdata), None,
+                                   None, ConnSpan::new(), scope)
+                      .map_self(TransportMachine::wrap)
+    }
+
+    /// Creates a new machine that can recycle its socket.
+    ///
+    /// This is like [new()](#method.new) except that if the handler
+    /// returns `Next::recycle()` and its [recycle()] method returns the
+    /// socket and a new seed, the pair is sent to `pool`. Since that pair
+    /// is exactly the seed of a transport machine, whoever maintains the
+    /// pool can later use it to create a new machine for the socket.
+    ///
+    /// [recycle()]: ../../handlers/trait.TransportHandler.html#method.recycle
+    pub fn new_pooled<S>(sock: T, seed: H::Seed, pool: Sender<(T, H::Seed)>,
+                         scope: &mut S) -> Response<Self, Void>
+                      where S: GenericScope {
+        TransportConn::create_with(sock, seed, Some(pool), None, None,
+                                   None, ConnSpan::new(), scope)
+                      .map_self(TransportMachine::wrap)
+    }
+
+    /// Creates a new machine for a socket that is still connecting.
+    ///
+    /// This is like [new()](#method.new) except that the machine waits
+    /// for the socket to become writable in addition to whatever the
+    /// handler asks for. If there hasn’t been any event on the socket
+    /// after `timeout`, the handler’s [error()] method is called with
+    /// `Error::Timeout`.
+    ///
+    /// [error()]: ../../handlers/trait.TransportHandler.html#method.error
+    pub fn new_connecting<S>(sock: T, seed: H::Seed, timeout: Duration,
+                             scope: &mut S) -> Response<Self, Void>
+                          where S: GenericScope {
+        TransportConn::create_with(sock, seed, None, None, None,
+                                   Some(timeout), ConnSpan::new(), scope)
+                      .map_self(TransportMachine::wrap)
+    }
+}
+
+/// # Access to User Data
+///
+impl<X, T: Transport, H: TransportHandler<T>> TransportMachine<X, T, H> {
+    /// Returns a reference to the user data attached to the machine.
+    ///
+    /// User data is a slot for keeping things with a connection that
+    /// aren’t part of the transport handler, such as a session object
+    /// used by wrappers and observers around the machine. The handler
+    /// receives the data with every event through its [event()] method.
+    /// Since the machine doesn’t know what is being stored, you have to
+    /// downcast the data to the type you expect:
+    ///
+    /// ```ignore
+    /// let session = machine.userdata()
+    ///                      .and_then(|data| data.downcast_ref::<Session>());
+    /// if let Some(session) = session {
+    ///     ...
+    /// }
+    /// ```
+    ///
+    /// [event()]: ../../handlers/trait.TransportHandler.html#method.event
+    pub fn userdata(&self) -> Option<&(Any + Send)> {
+        self.conn().userdata.as_ref().map(|data| &**data)
+    }
+
+    /// Returns a mutable reference to the user data.
+    ///
+    /// Use `downcast_mut()` to get to the actual data.
+    pub fn userdata_mut(&mut self) -> Option<&mut (Any + Send)> {
+        self.conn_mut().userdata.as_mut().map(|data| &mut **data)
+    }
+
+    /// Replaces the user data, returning the previous data.
+    pub fn set_userdata(&mut self, userdata: Userdata) -> Userdata {
+        ::std::mem::replace(&mut self.conn_mut().userdata, userdata)
+    }
+}
+
+/// # Introspection
+///
+impl<X, T: Transport, H: TransportHandler<T>> TransportMachine<X, T, H> {
+    /// Returns the events the machine is currently waiting for.
+    pub fn events(&self) -> EventSet {
+        self.conn().intent.events()
+    }
+
+    /// Returns the earliest deadline of the machine if there is one.
+    pub fn deadline(&self) -> Option<Time> {
+        self.conn().intent.deadline()
+    }
+
+    /// Returns a description of what the machine is waiting for.
+    ///
+    /// See [Intent::describe()] for details.
+    ///
+    /// [Intent::describe()]: ../../next/struct.Intent.html#method.describe
+    pub fn describe(&self) -> String {
+        self.conn().intent.describe()
+    }
+
+    /// Returns the statistics the machine records if there are any.
+    pub fn stats(&self) -> Option<&MachineStats> {
+        self.conn().stats.as_ref()
+    }
+
+    /// Starts or stops recording statistics.
+    ///
+    /// This is useful for machines that have been created from a seed
+    /// and thus couldn’t be given statistics right away.
+    pub fn set_stats(&mut self, stats: Option<MachineStats>) {
+        self.conn_mut().stats = stats
+    }
+}
+
+/// # Internal Helpers
+///
+impl<X, T: Transport, H: TransportHandler<T>> TransportMachine<X, T, H> {
+    /// Creates a new machine for a connection accepted from `addr`.
+    ///
+    /// The connection’s span records the peer address.
+    fn accepted<S>(sock: T, seed: H::Seed, addr: &PeerAddr, scope: &mut S)
+                   -> Response<Self, Void>
+                where S: GenericScope {
+        TransportConn::create_with(sock, seed, None, None, None, None,
+                                   ConnSpan::accepted(addr), scope)
+                      .map_self(TransportMachine::wrap)
+    }
+
+    /// Wraps a connection into a machine.
+    fn wrap(conn: TransportConn<X, T, H>) -> Self {
+        TransportMachine(Some(conn))
+    }
+
+    /// Takes the connection out of the machine.
+    ///
+    /// The now empty machine is dropped without tearing anything down.
+    fn take(mut self) -> TransportConn<X, T, H> {
+        self.0.take().expect("transport machine without connection")
+    }
+
+    fn conn(&self) -> &TransportConn<X, T, H> {
+        self.0.as_ref().expect("transport machine without connection")
+    }
+
+    fn conn_mut(&mut self) -> &mut TransportConn<X, T, H> {
+        self.0.as_mut().expect("transport machine without connection")
+    }
+}
+
+impl<X, T: Transport, H: TransportHandler<T>> TransportConn<X, T, H> {
+    /// Creates a new machine with all options.
+    fn create_with<S: GenericScope>(mut sock: T, seed: H::Seed,
+                                    pool: Option<Sender<(T, H::Seed)>>,
+                                    userdata: Userdata,
+                                    stats: Option<MachineStats>,
+                                    connect: Option<Duration>,
+                                    span: ConnSpan,
+                                    scope: &mut S) -> Response<Self, Void> {
+        let next = {
+            let _enter = span.enter();
+            H::create(seed, &mut sock, scope.notifier())
+        };
         if let Some((intent, handler)) = Intent::new(next, scope) {
-            let conn = TransportMachine::make(sock, handler, intent);
-            match scope.register(&conn.sock, conn.intent.events(),
-                                 PollOpt::level()) {
+            let mut conn = TransportConn::make(sock, handler, intent, pool,
+                                               userdata, stats, span);
+            if let Some(timeout) = connect {
+                conn.connecting = Some(clock::now(scope) + timeout);
+            }
+            let registered = (conn.events(), conn.handler.poll_opt());
+            match scope.register(&conn.sock, registered.0, registered.1) {
                 Ok(_) => { }
-                Err(err) => return Response::error(err.into())
+                Err(err) => return conn.fail(err)
             }
-            conn.response()
+            conn.registered = registered;
+            if conn.intent.is_recycle() {
+                return conn.recycle(scope)
+            }
+            // Come back right away so the handler’s on_ready() gets
+            // called once the machine is live.
+            let now = clock::now(scope);
+            Response::ok(conn).deadline(now)
         }
         else {
-            Response::done()
+            TransportConn::remove(sock)
         }
     }
-}
 
-/// # Internal Helpers
-///
-impl<X, T: Transport, H: TransportHandler<T>> TransportMachine<X, T, H> {
     /// Creates a new object from its parts.
     ///
     /// Sadly, `new()` is already taken …
-    fn make(sock: T, handler: H, intent: Intent) -> Self {
-        TransportMachine {
+    fn make(sock: T, handler: H, intent: Intent,
+            pool: Option<Sender<(T, H::Seed)>>,
+            userdata: Userdata,
+            stats: Option<MachineStats>, span: ConnSpan) -> Self {
+        TransportConn {
             sock: sock,
             handler: handler,
             intent: intent,
+            registered: (EventSet::none(), PollOpt::level()),
+            pool: pool,
+            span: span,
+            userdata: userdata,
+            live: false,
+            connecting: None,
+            stats: stats,
+            idle_run: 0,
             marker: PhantomData
         }
     }
@@ -100,118 +388,601 @@ impl<X, T: Transport, H: TransportHandler<T>> TransportMachine<X, T, H> {
     /// Reregisters for the correct events depending on the socket’s
     /// blocked state and the handler’s interests and generates the
     /// correct response.
-    fn next<S>(self, scope: &mut Scope<X>) -> Response<Self, S> {
-        let events = match self.sock.blocked() {
-            Some(Blocked::Read) => EventSet::readable(),
-            Some(Blocked::Write) => EventSet::writable(),
-            None => self.intent.events()
-        };
-        match scope.reregister(&self.sock, events, PollOpt::level()) {
+    fn next<S>(mut self, scope: &mut Scope<X>) -> Response<Self, S> {
+        if !self.sock.is_usable() {
+            return self.unusable()
+        }
+        if self.intent.is_recycle() {
+            return self.recycle(scope)
+        }
+        if self.intent.is_flush() && !self.handler.has_pending_output()
+                                  && !self.queue_pending() {
+            self.intent = self.intent.into_close();
+        }
+        if self.intent.is_close() {
+            return self.close_after_write(scope)
+        }
+        let mut events = self.intent.to_events(self.sock.blocked());
+        if self.sock.blocked().is_none() && self.queue_pending() {
+            events = events | EventSet::writable();
+        }
+        if self.connecting.is_some() {
+            events = events | EventSet::writable();
+        }
+        match self.reregister(events, scope) {
             Ok(_) => { }
-            Err(err) => return Response::error(err.into())
+            Err(err) => return self.fail(err)
         }
         self.response()
     }
 
+    /// Returns the events to register the socket for initially.
+    ///
+    /// A socket that is still connecting needs to wait for writable to
+    /// learn that connecting has finished.
+    fn events(&self) -> EventSet {
+        if self.connecting.is_some() {
+            self.intent.events() | EventSet::writable()
+        }
+        else {
+            self.intent.events()
+        }
+    }
+
+    /// Returns whether the handler’s write queue has output.
+    fn queue_pending(&mut self) -> bool {
+        match self.handler.write_queue() {
+            Some(queue) => !queue.is_empty(),
+            None => false
+        }
+    }
+
+    /// Writes as much of the handler’s write queue as possible.
+    ///
+    /// Returns `Ok(true)` if the queue is empty afterwards or the handler
+    /// doesn’t have one.
+    fn write_queue(&mut self) -> io::Result<bool> {
+        match self.handler.write_queue() {
+            Some(queue) => queue.write_to(&mut self.sock),
+            None => Ok(true)
+        }
+    }
+
+    /// Reregisters the socket for `events` unless it already is.
+    ///
+    /// Since a oneshot registration is disarmed by every event, the socket
+    /// is always reregistered if the handler asks for that.
+    fn reregister(&mut self, events: EventSet, scope: &mut Scope<X>)
+                  -> io::Result<()> {
+        let opts = self.handler.poll_opt();
+        if !opts.is_oneshot() && self.registered == (events, opts) {
+            return Ok(())
+        }
+        try!(scope.reregister(&self.sock, events, opts));
+        self.registered = (events, opts);
+        Ok(())
+    }
+
+    /// Calls the handler’s `on_ready()` if that hasn’t happened yet.
+    ///
+    /// Returns the machine if processing should continue or the
+    /// response to return if the machine has ended.
+    fn make_live<S>(mut self, scope: &mut Scope<X>)
+                    -> Result<Self, Response<Self, S>> {
+        if self.live {
+            return Ok(self)
+        }
+        self.live = true;
+        trace::event("on_ready");
+        let next = self.handler.event(TransportEvent::Ready, &mut self.sock,
+                                      &mut self.userdata);
+        if let Some((intent, handler)) = self.intent.merge(next, scope) {
+            let conn = TransportConn { handler: handler, intent: intent,
+                                       .. self };
+            if conn.intent.is_recycle() {
+                Err(conn.recycle(scope))
+            }
+            else {
+                Ok(conn)
+            }
+        }
+        else {
+            Err(TransportConn::remove(self.sock))
+        }
+    }
+
     /// Generates the correct response for this machine.
     ///
     /// This is a `Response::ok()` in any case, but may have a deadline
-    /// attached.
+    /// attached if the handler asked for a timeout or a tick.
     fn response<S>(self) -> Response<Self, S> {
-        if let Some(deadline) = self.intent.deadline() {
+        let timer = match (self.intent.timer(), self.connecting) {
+            (Some(timer), Some(connect)) => Some(min(timer, connect)),
+            (timer, None) => timer,
+            (None, connect) => connect
+        };
+        if let Some(deadline) = timer {
             Response::ok(self).deadline(deadline)
         }
         else {
             Response::ok(self)
         }
     }
-}
 
+    /// Returns what the handler’s intent looks like to the loop.
+    ///
+    /// If this is the same before and after calling the handler, the
+    /// call didn’t change anything.
+    fn snapshot(&self) -> (EventSet, Option<Time>) {
+        (self.intent.events(), self.intent.timer())
+    }
 
-//--- Machine
+    /// Records a read or write event given the snapshot from before.
+    fn record_event(&mut self, before: (EventSet, Option<Time>),
+                    read: bool) {
+        let idle = self.snapshot() == before;
+        if let Some(ref stats) = self.stats {
+            if idle {
+                self.idle_run += 1;
+                if read { stats.record_idle_read() }
+                else { stats.record_idle_write() }
+                stats.record_idle_run(self.idle_run);
+            }
+            else {
+                self.idle_run = 0;
+            }
+        }
+    }
 
-impl<X, T, H> Machine for TransportMachine<X, T, H>
-              where T: Transport, H: TransportHandler<T> {
-    type Context = X;
+    /// Ends the machine after the handler asked for removal.
+    ///
+    /// Any output the handler hasn’t written to the socket yet is lost.
+    /// Output buffered by the socket itself, however, is flushed first.
+    fn remove<S>(sock: T) -> Response<Self, S> {
+        Self::close(sock);
+        Response::done()
+    }
 
-    /// Our seed is a pair of the new socket and the handler’s seed.
-    type Seed = (T, H::Seed);
+    /// Ends the machine while the handler is still alive.
+    ///
+    /// The handler gets its [teardown()] call first, then the socket is
+    /// closed the same way as for a normal removal.
+    ///
+    /// [teardown()]: ../../handlers/trait.TransportHandler.html#method.teardown
+    fn teardown(self) {
+        let TransportConn { mut sock, handler, span, .. } = self;
+        let _enter = span.enter();
+        trace::event("teardown");
+        handler.teardown(&mut sock);
+        Self::close(sock);
+    }
 
-    fn create(seed: Self::Seed, scope: &mut Scope<X>)
-              -> Response<Self, Void> {
-        TransportMachine::new(seed.0, seed.1, scope)
+    /// Ends the machine after (re)registering the socket failed.
+    fn fail<S>(self, err: io::Error) -> Response<Self, S> {
+        self.teardown();
+        Response::error(err.into())
     }
 
-    fn ready(mut self, events: EventSet, scope: &mut Scope<X>)
-                -> Response<Self, Self::Seed> {
+    /// Flushes and closes the socket.
+    ///
+    /// Since the machine is going away, we can’t wait for the socket to
+    /// become writable again, so there only are a few attempts before we
+    /// give up and close the socket anyway.
+    fn close(mut sock: T) {
+        trace::closed();
+        for _ in 0..FLUSH_ATTEMPTS {
+            match sock.flush_output() {
+                // No point in trying again if the send buffer is full.
+                Ok(false) => if !sock.write_ready_hint() { break },
+                Ok(true) => break,
+                Err(err) => {
+                    debug!("flushing before close failed: {}", err);
+                    break
+                }
+            }
+        }
+    }
+
+    /// Flushes the socket and closes it gracefully once that succeeded.
+    ///
+    /// If output remains, the socket is reregistered so we come back
+    /// when it becomes writable. Once everything is written, writing is
+    /// shut down and the machine ends. Since the handler is done, errors
+    /// are only logged.
+    fn close_after_write<S>(mut self, scope: &mut Scope<X>)
+                            -> Response<Self, S> {
+        match self.sock.flush_output() {
+            Ok(true) => {
+                if let Err(err) = self.sock.shutdown_write() {
+                    debug!("shutdown before close failed: {}", err);
+                }
+                TransportConn::remove(self.sock)
+            }
+            Ok(false) => {
+                let events = self.intent.to_events(self.sock.blocked());
+                match self.reregister(events, scope) {
+                    Ok(_) => self.response(),
+                    Err(err) => self.fail(err)
+                }
+            }
+            Err(err) => {
+                debug!("flushing before close failed: {}", err);
+                TransportConn::remove(self.sock)
+            }
+        }
+    }
+
+    /// Ends the machine after the socket has become unusable.
+    ///
+    /// The handler learns about this through a single TLS error. Since
+    /// the socket is beyond saving, the machine ends either way. If the
+    /// handler wants to carry on regardless, it still gets its
+    /// [teardown()] call so it can release its state.
+    ///
+    /// [teardown()]: ../../handlers/trait.TransportHandler.html#method.teardown
+    fn unusable<S>(self) -> Response<Self, S> {
+        debug!("socket unusable, closing");
+        match self.handler.error(Error::Tls).into_handler() {
+            Some(handler) => {
+                TransportConn { handler: handler, .. self }.teardown();
+                Response::done()
+            }
+            None => TransportConn::remove(self.sock)
+        }
+    }
+
+    /// Ends the machine, handing the socket to the pool if possible.
+    ///
+    /// If there is no pool, the handler isn’t even asked and the socket
+    /// is simply closed.
+    fn recycle<G: GenericScope, S>(self, scope: &mut G) -> Response<Self, S> {
+        if let Some(pool) = self.pool {
+            if let Some((sock, seed)) = self.handler.recycle(self.sock) {
+                if let Err(err) = scope.deregister(&sock) {
+                    return Response::error(err.into())
+                }
+                if pool.send((sock, seed)).is_err() {
+                    debug!("pool gone, dropping recycled socket");
+                }
+            }
+        }
+        Response::done()
+    }
+}
+
+
+/// # Event Handling
+///
+impl<X, T: Transport, H: TransportHandler<T>> TransportConn<X, T, H> {
+    fn ready<S>(mut self, events: EventSet, scope: &mut Scope<X>)
+                -> Response<Self, S> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+
+        self = match self.make_live(scope) {
+            Ok(conn) => conn,
+            Err(res) => return res
+        };
+
+        if !self.sock.is_usable() {
+            return self.unusable()
+        }
+
+        // The first event means connecting has finished one way or
+        // another. The writable we waited for only goes to the handler
+        // if it asked for it.
+        let events = if self.connecting.take().is_some() {
+            let wanted = self.intent.events() | EventSet::hup()
+                                              | EventSet::error();
+            let events = events & wanted;
+            if events == EventSet::none() {
+                return self.next(scope)
+            }
+            events
+        }
+        else {
+            events
+        };
+
         if events.is_error() {
             if let Err(err) = self.sock.take_socket_error() {
                 let next = self.handler.error(err.into());
                 if let Some((intent, handler)) = self.intent.merge(next,
                                                                    scope) {
-                    return TransportMachine::make(self.sock, handler, intent)
-                                            .next(scope);
+                    return TransportConn { handler: handler,
+                                           intent: intent, .. self }
+                                         .next(scope);
                 }
                 else {
-                    return Response::done()
+                    return TransportConn::remove(self.sock)
                 }
             }
         }
 
+        // A handler waiting for its output to be written before closing
+        // doesn’t get to see socket events anymore.
+        if self.intent.is_close() {
+            return self.close_after_write(scope)
+        }
+
         // If the socket is blocked, we pretent the events are actually those
         // the handler has requested so the socket is read from or written to
         // and can become unblocked. (If the handler’s request was for wait,
-        // then what are we doing here in the first place?)
-        let events = if let Some(_) = self.sock.blocked() {
+        // then what are we doing here in the first place?) Output in the
+        // handler’s write queue counts as a request for writing.
+        //
+        // If the handler has paused reading or is only finishing its
+        // output before removal, a readable event may still have been
+        // pending from before, so we drop it. Hangup and error are kept,
+        // though. A hangup while reading is paused is passed on as
+        // readable so the handler gets to see the end of stream rather
+        // than the loop reporting the hangup over and over.
+        let queued = self.queue_pending();
+        let wanted = if queued {
+            self.intent.events() | EventSet::writable()
+        }
+        else {
             self.intent.events()
+        };
+        let events = if let Some(_) = self.sock.blocked() {
+            wanted
+        } else if self.intent.is_read_paused() || self.intent.is_flush() {
+            let res = events & (wanted | EventSet::hup()
+                                       | EventSet::error());
+            if res.is_hup() && !self.intent.is_flush() {
+                res | EventSet::readable()
+            }
+            else {
+                res
+            }
         } else {
             events
         };
 
-        self.intent = Intent::default();
+        // With a write queue, the handler only gets to write once the
+        // queue is empty and only if it asked for it.
+        let wants_write = self.intent.events().is_writable();
+        self.intent = self.intent.carry_over();
+        if events.is_readable() && self.handler.wants_urgent()
+                                && self.sock.urgent_pending() {
+            trace::event("urgent");
+            let next = self.handler.event(TransportEvent::Urgent,
+                                          &mut self.sock,
+                                          &mut self.userdata);
+            if let Some((intent, handler)) = self.intent.merge(next, scope) {
+                self = TransportConn { handler: handler, intent: intent,
+                                       .. self };
+            }
+            else {
+                return TransportConn::remove(self.sock)
+            }
+        }
+
         if events.is_readable() {
-            let next = self.handler.readable(&mut self.sock);
+            trace::event("readable");
+            let before = self.snapshot();
+            let next = self.handler.event(TransportEvent::Readable,
+                                          &mut self.sock,
+                                          &mut self.userdata);
             if let Some((intent, handler)) = self.intent.merge(next, scope) {
-                self = TransportMachine::make(self.sock, handler, intent)
+                self = TransportConn { handler: handler, intent: intent,
+                                       .. self };
+                self.record_event(before, true);
+                if self.intent.is_recycle() {
+                    return self.recycle(scope)
+                }
+                if self.intent.is_close() {
+                    return self.next(scope)
+                }
+                if !self.sock.is_usable() {
+                    return self.unusable()
+                }
             }
             else {
-                return Response::done()
+                return TransportConn::remove(self.sock)
+            }
+        }
+
+        let flushed = if events.is_writable() && queued {
+            match self.write_queue() {
+                Ok(done) => done,
+                Err(err) => {
+                    let next = self.handler.error(err.into());
+                    return match self.intent.merge(next, scope) {
+                        Some((intent, handler)) => {
+                            TransportConn { handler: handler,
+                                            intent: intent, .. self }
+                                          .next(scope)
+                        }
+                        None => TransportConn::remove(self.sock)
+                    }
+                }
             }
         }
+        else {
+            true
+        };
 
-        if events.is_writable() {
-            let next = self.handler.writable(&mut self.sock);
+        if events.is_writable() && flushed && (!queued || wants_write) {
+            trace::event("writable");
+            let before = self.snapshot();
+            let next = self.handler.event(TransportEvent::Writable,
+                                          &mut self.sock,
+                                          &mut self.userdata);
             if let Some((intent, handler)) = self.intent.merge(next, scope) {
-                self = TransportMachine::make(self.sock, handler, intent)
+                self = TransportConn { handler: handler, intent: intent,
+                                       .. self };
+                self.record_event(before, false);
             }
             else {
-                return Response::done()
+                return TransportConn::remove(self.sock)
             }
         }
-        self.next(scope)
+        match self.secured(scope) {
+            Ok(conn) => conn.next(scope),
+            Err(res) => res
+        }
     }
 
-    fn spawned(self, _scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
-        Response::ok(self)
+    fn timeout<S>(mut self, scope: &mut Scope<X>) -> Response<Self, S> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+
+        // The first timeout is the one scheduled by create_with().
+        if !self.live {
+            return match self.make_live(scope) {
+                Ok(conn) => conn.next(scope),
+                Err(res) => res
+            }
+        }
+
+        if let Some(ref stats) = self.stats {
+            stats.record_timer()
+        }
+        let now = clock::now(scope);
+        let (intent, next) = if self.connect_expired(now) {
+            debug!("connecting timed out");
+            self.connecting = None;
+            (self.intent, self.handler.error(Error::Timeout))
+        }
+        else if self.intent.tick_due(now) {
+            let next = self.handler.event(TransportEvent::Tick,
+                                          &mut self.sock,
+                                          &mut self.userdata);
+            (self.intent.rearm_tick(now), next)
+        }
+        else {
+            let (intent, err) = self.intent.expire(now);
+            (intent, self.handler.error(err))
+        };
+        if let Some((intent, handler)) = intent.merge(next, scope) {
+            TransportConn { handler: handler, intent: intent, .. self }
+                          .next(scope)
+        }
+        else {
+            TransportConn::remove(self.sock)
+        }
     }
 
-    fn timeout(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
-        let next = self.handler.error(Error::Timeout);
+    fn wakeup<S>(mut self, scope: &mut Scope<X>) -> Response<Self, S> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+
+        self = match self.make_live(scope) {
+            Ok(conn) => conn,
+            Err(res) => return res
+        };
+
+        if self.intent.is_close() {
+            return self.response()
+        }
+
+        trace::event("wakeup");
+        let before = self.snapshot();
+        let next = self.handler.event(TransportEvent::Wakeup, &mut self.sock,
+                                      &mut self.userdata);
         if let Some((intent, handler)) = self.intent.merge(next, scope) {
-            TransportMachine::make(self.sock, handler, intent).next(scope)
+            let conn = TransportConn { handler: handler, intent: intent,
+                                       .. self };
+            if let Some(ref stats) = conn.stats {
+                stats.record_wakeup(conn.snapshot() == before)
+            }
+            match conn.secured(scope) {
+                Ok(conn) => conn.next(scope),
+                Err(res) => res
+            }
         }
         else {
-            Response::done()
+            TransportConn::remove(self.sock)
+        }
+    }
+
+    /// Returns whether connecting has taken too long at `now`.
+    fn connect_expired(&self, now: Time) -> bool {
+        match self.connecting {
+            Some(deadline) => deadline <= now,
+            None => false
         }
     }
 
-    fn wakeup(mut self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
-        let next = self.handler.wakeup(&mut self.sock);
+    /// Tells the handler if the socket has just become secure.
+    ///
+    /// Since the handshake progresses while the handler reads or writes,
+    /// this is checked after the handler has seen an event.
+    fn secured<S>(mut self, scope: &mut Scope<X>)
+                  -> Result<Self, Response<Self, S>> {
+        if self.intent.is_close() || !self.sock.take_secured() {
+            return Ok(self)
+        }
+        trace::event("secured");
+        let next = self.handler.event(TransportEvent::Secured, &mut self.sock,
+                                      &mut self.userdata);
         if let Some((intent, handler)) = self.intent.merge(next, scope) {
-            TransportMachine::make(self.sock, handler, intent).next(scope)
+            Ok(TransportConn { handler: handler, intent: intent, .. self })
         }
         else {
-            Response::done()
+            Err(TransportConn::remove(self.sock))
+        }
+    }
+}
+
+
+//--- Machine
+
+impl<X, T, H> Machine for TransportMachine<X, T, H>
+              where T: Transport, H: TransportHandler<T> {
+    type Context = X;
+
+    /// Our seed is a pair of the new socket and the handler’s seed.
+    type Seed = (T, H::Seed);
+
+    fn create(seed: Self::Seed, scope: &mut Scope<X>)
+              -> Response<Self, Void> {
+        TransportMachine::new(seed.0, seed.1, scope)
+    }
+
+    fn ready(self, events: EventSet, scope: &mut Scope<X>)
+                -> Response<Self, Self::Seed> {
+        self.take().ready(events, scope).map_self(TransportMachine::wrap)
+    }
+
+    fn spawned(self, _scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        Response::ok(self)
+    }
+
+    fn timeout(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        self.take().timeout(scope).map_self(TransportMachine::wrap)
+    }
+
+    fn wakeup(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        self.take().wakeup(scope).map_self(TransportMachine::wrap)
+    }
+}
+
+
+//--- ConnectMachine
+
+impl<X, T, H> ConnectMachine for TransportMachine<X, T, H>
+              where T: Transport, H: TransportHandler<T> {
+    fn create_connecting(seed: Self::Seed, timeout: Duration,
+                         scope: &mut Scope<X>) -> Response<Self, Void> {
+        TransportMachine::new_connecting(seed.0, seed.1, timeout, scope)
+    }
+}
+
+
+//--- Drop
+
+impl<X, T, H> Drop for TransportMachine<X, T, H>
+              where T: Transport, H: TransportHandler<T> {
+    /// Tears down the connection if it is still there.
+    ///
+    /// During normal event processing, the connection is taken out of
+    /// the machine before the machine is dropped, so this only does
+    /// something if rotor drops a live machine.
+    fn drop(&mut self) {
+        if let Some(conn) = self.0.take() {
+            conn.teardown()
         }
     }
 }
@@ -245,26 +1016,73 @@ pub struct ServerMachine<X, A, H>(
 
 
 /// The two flavors of a server machine.
-enum ServerInner<A, H, M> {
+enum ServerInner<A: Accept, H: AcceptHandler<A::Output>, M> {
     /// Accept socket and handler.
     ///
     /// Never mind the use of term ‘listener’ here …
     Lsnr(ServerListener<A, H>),
 
     /// A wrapped transport machine.
-    Conn(M)
+    ///
+    /// If the server limits connections per peer, the guard counting this
+    /// connection is kept alongside.
+    Conn(M, Option<PeerGuard>)
 }
 
+/// The seed of a connection accepted by a listener.
+type AcceptSeed<A, H> = (<A as Accept>::Output,
+                         <<H as AcceptHandler<<A as Accept>::Output>>::Output
+                            as TransportHandler<<A as Accept>::Output>>::Seed,
+                         Option<PeerGuard>, PeerAddr);
+
 /// All we need for a listenig flavor machine.
-struct ServerListener<A, H> {
+struct ServerListener<A: Accept, H: AcceptHandler<A::Output>> {
     /// The accept socket.
     sock: A,
 
     /// The accept handler.
     handler: H,
 
+    /// Accepted connections waiting to be spawned.
+    queue: VecDeque<AcceptSeed<A, H>>,
+
+    /// Socket options to apply to each accepted socket.
+    options: Option<SocketOptions>,
+
+    /// The limit of connections per peer address.
+    limit: Option<PeerLimit>,
+
+    /// How to close connections we don’t want.
+    reject: RejectMode,
+
+    /// Where to count what happened to connection requests.
+    stats: Option<ServerStats>,
+
     /// The receiving end of a trigger for shutting down the machine.
-    rx: TriggerReceiver
+    rx: TriggerReceiver,
+
+    /// The receiving end of a trigger for resuming a paused machine.
+    ///
+    /// This is `None` once the machine accepts connections.
+    paused: Option<TriggerReceiver>,
+}
+
+impl<A: Accept, H: AcceptHandler<A::Output>> ServerListener<A, H> {
+    /// Closes an accepted connection we don’t want to serve.
+    ///
+    /// Dropping the socket would close it, too, but this way we make sure
+    /// the peer learns about it in the way the configuration asked for.
+    /// Errors are ignored since the socket goes away either way.
+    fn reject(&self, mut sock: A::Output) {
+        if let Some(ref stats) = self.stats {
+            stats.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        let _ = match self.reject {
+            RejectMode::Graceful => sock.shutdown_write(),
+            RejectMode::Reset => sock.set_linger(Some(Duration::from_secs(0)))
+        };
+        trace::event("rejected");
+    }
 }
 
 
@@ -285,55 +1103,251 @@ impl<X, A: Accept, H: AcceptHandler<A::Output>> ServerMachine<X, A, H> {
     /// unlikely, it may happen.
     pub fn new<S: GenericScope>(sock: A, handler: H, scope: &mut S)
                                 -> (Response<Self, Void>, TriggerSender) {
+        ServerMachine::new_with_config(sock, handler, ServerConfig::new(),
+                                       scope)
+    }
+
+    /// Creates a new machine applying socket options to accepted sockets.
+    ///
+    /// This is like [new()](#method.new) except that `options` will be
+    /// applied to every accepted socket before the accept handler gets to
+    /// see it. If applying fails, the accept handler’s `error()` method is
+    /// called and the socket is dropped.
+    pub fn new_with_options<S>(sock: A, handler: H, options: SocketOptions,
+                               scope: &mut S)
+                               -> (Response<Self, Void>, TriggerSender)
+                            where S: GenericScope {
+        ServerMachine::new_with_config(sock, handler,
+                                       ServerConfig::new().options(options),
+                                       scope)
+    }
+
+    /// Creates a new machine limiting the connections per peer address.
+    ///
+    /// This is like [new()](#method.new) except that only `limit`
+    /// connections from the same IP address may be active at any time.
+    /// Further connection requests from that address are closed right
+    /// away and the accept handler’s [rejected()] method is called.
+    ///
+    /// [rejected()]: ../../handlers/trait.AcceptHandler.html#method.rejected
+    pub fn new_with_per_ip_limit<S>(sock: A, handler: H, limit: usize,
+                                    scope: &mut S)
+                                    -> (Response<Self, Void>, TriggerSender)
+                                 where S: GenericScope {
+        ServerMachine::new_with_config(sock, handler,
+                                       ServerConfig::new().per_ip_limit(limit),
+                                       scope)
+    }
+
+    /// Creates a new machine with the given configuration.
+    ///
+    /// This is the most general form of [new()](#method.new). All the
+    /// other constructors are merely shortcuts for setting one option of
+    /// `config`. See [ServerConfig] for the available options.
+    ///
+    /// [ServerConfig]: struct.ServerConfig.html
+    pub fn new_with_config<S>(sock: A, handler: H, config: ServerConfig,
+                              scope: &mut S)
+                              -> (Response<Self, Void>, TriggerSender)
+                           where S: GenericScope {
+        ServerMachine::create_with(sock, handler, config, None, scope)
+    }
+
+    /// Creates a new machine that doesn’t accept connections just yet.
+    ///
+    /// This is like [new_with_config()](#method.new_with_config) except
+    /// that the machine starts out paused: the accept socket is registered
+    /// with the loop but the machine doesn’t wait for it to become
+    /// readable. Connection requests queue up in the socket’s backlog
+    /// until accepting starts or, if the backlog is full, are refused by
+    /// the operating system. This allows binding the accept socket early
+    /// while delaying service until, for instance, caches have been warmed
+    /// or a database connection has been established.
+    ///
+    /// In addition to the shutdown trigger, returns the sending end of a
+    /// second [trigger] for resuming the machine. Once it is fired, the
+    /// machine starts accepting. Shutting down the machine works while
+    /// it is paused, too.
+    ///
+    /// [trigger]: ../../sync/fn.trigger.html
+    pub fn new_paused<S>(sock: A, handler: H, config: ServerConfig,
+                         scope: &mut S)
+                         -> (Response<Self, Void>, TriggerSender,
+                             TriggerSender)
+                      where S: GenericScope {
         let (tx, rx) = trigger(scope.notifier());
-        match scope.register(&sock, EventSet::readable(), PollOpt::level()) {
-            Ok(()) => {
-                let lsnr = ServerListener { sock: sock, handler: handler,
-                                            rx: rx };
-                (Response::ok(ServerMachine::lsnr(lsnr)), tx)
+        let (m, t) = ServerMachine::create_with(sock, handler, config,
+                                                Some(rx), scope);
+        (m, t, tx)
+    }
+}
+
+
+/// # Introspection
+///
+impl<X, A: Accept, H: AcceptHandler<A::Output>> ServerMachine<X, A, H> {
+    /// Returns the transport machine if this is a connection.
+    pub fn connection(&self) -> Option<&TransportMachine<X, A::Output,
+                                                         H::Output>> {
+        match self.0 {
+            ServerInner::Lsnr(_) => None,
+            ServerInner::Conn(ref conn, _) => Some(conn)
+        }
+    }
+
+    /// Returns a description of what the machine is waiting for.
+    ///
+    /// For a connection, this is the description of its transport
+    /// machine, see [Intent::describe()] for details.
+    ///
+    /// [Intent::describe()]: ../../next/struct.Intent.html#method.describe
+    pub fn describe(&self) -> String {
+        match self.0 {
+            ServerInner::Lsnr(ref lsnr) if lsnr.paused.is_some() => {
+                "paused".into()
             }
-            Err(err) => (Response::error(err.into()), tx),
+            ServerInner::Lsnr(ref lsnr) => {
+                format!("listening, {} connections queued", lsnr.queue.len())
+            }
+            ServerInner::Conn(ref conn, _) => conn.describe()
         }
     }
 }
 
-
 /// # Internal Helpers
 /// 
 impl<X, A: Accept, H: AcceptHandler<A::Output>> ServerMachine<X, A, H> {
+    /// Creates a new machine with all options.
+    ///
+    /// If `paused` is given, the machine starts out paused until that
+    /// trigger fires.
+    fn create_with<S: GenericScope>(sock: A, handler: H,
+                                    config: ServerConfig,
+                                    paused: Option<TriggerReceiver>,
+                                    scope: &mut S)
+                                    -> (Response<Self, Void>, TriggerSender) {
+        let mut handler = handler;
+        if let Some(budget) = config.stats.as_ref()
+                                    .and_then(ServerStats::write_budget) {
+            handler.write_budget(budget.clone())
+        }
+        let (tx, rx) = trigger(scope.notifier());
+        let events = if paused.is_some() { EventSet::none() }
+                     else { EventSet::readable() };
+        match scope.register(&sock, events, PollOpt::level()) {
+            Ok(()) => {
+                let lsnr = ServerListener {
+                    sock: sock, handler: handler, queue: VecDeque::new(),
+                    options: config.options,
+                    limit: config.per_ip_limit.map(PeerLimit::new),
+                    reject: config.reject, stats: config.stats, rx: rx,
+                    paused: paused
+                };
+                (Response::ok(ServerMachine::lsnr(lsnr)), tx)
+            }
+            Err(err) => (Response::error(err.into()), tx),
+        }
+    }
+
     /// Creates an accept flavor value.
     fn lsnr(lsnr: ServerListener<A, H>) -> Self {
         ServerMachine(ServerInner::Lsnr(lsnr), PhantomData)
     }
 
     /// Creates a connection flavor value.
-    fn conn(conn: TransportMachine<X, A::Output, H::Output>)
-            -> Self {
-        ServerMachine(ServerInner::Conn(conn), PhantomData)
+    fn conn(conn: TransportMachine<X, A::Output, H::Output>,
+            guard: Option<PeerGuard>) -> Self {
+        ServerMachine(ServerInner::Conn(conn, guard), PhantomData)
     }
 
-    /// Accepts a new connection request.
+    /// Accepts pending connection requests and spawns the next one.
+    ///
+    /// Rotor only lets us spawn one new machine per event. So instead of
+    /// accepting only one connection, we accept up to `ACCEPT_BATCH` of
+    /// them and keep their seeds in a queue. After each spawn, rotor calls
+    /// `spawned()`, giving us the chance to spawn the next one until the
+    /// queue is empty. This way, we pull the full backlog even if the
+    /// accept socket will only tell us about it once.
+    ///
+    /// Since the accept handler has already decided on each queued
+    /// connection when it was accepted, any limit it imposes applies at
+    /// that point rather than when the connection is finally spawned.
     fn accept(mut lsnr: ServerListener<A, H>)
               -> Response<Self, <Self as Machine>::Seed> {
-        match lsnr.sock.accept() {
-            Ok(Some((sock, addr))) => {
-                if let Some(seed) = lsnr.handler.accept(&addr) {
-                    Response::spawn(ServerMachine::lsnr(lsnr), (sock, seed))
+        while lsnr.queue.len() < ACCEPT_BATCH {
+            match lsnr.sock.accept() {
+                Ok(Some((mut sock, addr))) => {
+                    let guard = match (lsnr.limit.as_ref(), addr.ip()) {
+                        (Some(limit), Some(ip)) => match limit.acquire(ip) {
+                            Some(guard) => Some(guard),
+                            None => {
+                                lsnr.reject(sock);
+                                lsnr.handler.rejected(&addr);
+                                continue
+                            }
+                        },
+                        _ => None
+                    };
+                    let res = match lsnr.options {
+                        Some(ref options) => options.apply(&mut sock),
+                        None => Ok(())
+                    };
+                    if let Err(err) = res {
+                        if let Err(()) = lsnr.handler.error(err.into()) {
+                            return Response::done()
+                        }
+                        continue
+                    }
+                    match lsnr.handler.accept(&addr) {
+                        Some(seed) => {
+                            lsnr.queue.push_back((sock, seed, guard, addr))
+                        }
+                        None => lsnr.reject(sock)
+                    }
                 }
-                else {
-                    Response::ok(ServerMachine::lsnr(lsnr))
+                Ok(None) => break,
+                Err(ref err) if err.is_would_block() => {
+                    // Some accept sockets do more than just accepting (TLS,
+                    // for instance) and may run into blocking. This isn’t
+                    // worth bothering the handler with.
+                    break
                 }
-            }
-            Ok(None) => {
-                Response::ok(ServerMachine::lsnr(lsnr))
-            }
-            Err(err) => {
-                match lsnr.handler.error(err.into()) {
-                    Ok(()) => Response::ok(ServerMachine::lsnr(lsnr)),
-                    Err(()) => Response::done()
+                Err(err) => {
+                    match lsnr.handler.error(err.into()) {
+                        Ok(()) => break,
+                        Err(()) => return Response::done()
+                    }
                 }
             }
         }
+        ServerMachine::spawn_next(lsnr)
+    }
+
+    /// Starts accepting if the machine is paused and has been resumed.
+    fn resume(mut lsnr: ServerListener<A, H>, scope: &mut Scope<X>)
+              -> Response<Self, <Self as Machine>::Seed> {
+        let resumed = match lsnr.paused {
+            Some(ref paused) => paused.triggered(),
+            None => false
+        };
+        if resumed {
+            lsnr.paused = None;
+            if let Err(err) = scope.reregister(&lsnr.sock,
+                                               EventSet::readable(),
+                                               PollOpt::level()) {
+                return Response::error(err.into())
+            }
+        }
+        Response::ok(ServerMachine::lsnr(lsnr))
+    }
+
+    /// Spawns the next queued connection or waits for more.
+    fn spawn_next(mut lsnr: ServerListener<A, H>)
+                  -> Response<Self, <Self as Machine>::Seed> {
+        match lsnr.queue.pop_front() {
+            Some(seed) => Response::spawn(ServerMachine::lsnr(lsnr), seed),
+            None => Response::ok(ServerMachine::lsnr(lsnr))
+        }
     }
 }
 
@@ -343,21 +1357,29 @@ impl<X, A: Accept, H: AcceptHandler<A::Output>> ServerMachine<X, A, H> {
 impl<X, A, H> Machine for ServerMachine<X, A, H>
               where A: Accept, H: AcceptHandler<A::Output> {
     type Context = X;
-    type Seed = (A::Output, <H::Output as TransportHandler<A::Output>>::Seed);
+    type Seed = AcceptSeed<A, H>;
 
     fn create(seed: Self::Seed, scope: &mut Scope<X>)
               -> Response<Self, Void> {
-        TransportMachine::create(seed, scope).map_self(ServerMachine::conn)
+        let (sock, seed, guard, addr) = seed;
+        TransportMachine::accepted(sock, seed, &addr, scope)
+                         .map_self(|conn| ServerMachine::conn(conn, guard))
     }
 
     fn ready(self, events: EventSet, scope: &mut Scope<X>)
              -> Response<Self, Self::Seed> {
         match self.0 {
             ServerInner::Lsnr(lsnr) => {
-                ServerMachine::accept(lsnr)
+                if lsnr.paused.is_some() {
+                    Response::ok(ServerMachine::lsnr(lsnr))
+                }
+                else {
+                    ServerMachine::accept(lsnr)
+                }
             }
-            ServerInner::Conn(conn) => {
-                conn.ready(events, scope).map_self(ServerMachine::conn)
+            ServerInner::Conn(conn, guard) => {
+                conn.ready(events, scope)
+                    .map_self(|conn| ServerMachine::conn(conn, guard))
             }
         }
     }
@@ -365,10 +1387,16 @@ impl<X, A, H> Machine for ServerMachine<X, A, H>
     fn spawned(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
         match self.0 {
             ServerInner::Lsnr(lsnr) => {
-                ServerMachine::accept(lsnr)
+                if lsnr.queue.is_empty() {
+                    ServerMachine::accept(lsnr)
+                }
+                else {
+                    ServerMachine::spawn_next(lsnr)
+                }
             }
-            ServerInner::Conn(conn) => {
-                conn.spawned(scope).map_self(ServerMachine::conn)
+            ServerInner::Conn(conn, guard) => {
+                conn.spawned(scope)
+                    .map_self(|conn| ServerMachine::conn(conn, guard))
             }
         }
     }
@@ -376,8 +1404,9 @@ impl<X, A, H> Machine for ServerMachine<X, A, H>
     fn timeout(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
         match self.0 {
             ServerInner::Lsnr(_) => unreachable!("listener can’t timeout"),
-            ServerInner::Conn(conn) => {
-                conn.timeout(scope).map_self(ServerMachine::conn)
+            ServerInner::Conn(conn, guard) => {
+                conn.timeout(scope)
+                    .map_self(|conn| ServerMachine::conn(conn, guard))
             }
         }
     }
@@ -389,13 +1418,514 @@ impl<X, A, H> Machine for ServerMachine<X, A, H>
                     Response::done()
                 }
                 else {
-                    Response::ok(ServerMachine::lsnr(lsnr))
+                    ServerMachine::resume(lsnr, scope)
                 }
             }
-            ServerInner::Conn(conn) => {
-                conn.wakeup(scope).map_self(ServerMachine::conn)
+            ServerInner::Conn(conn, guard) => {
+                conn.wakeup(scope)
+                    .map_self(|conn| ServerMachine::conn(conn, guard))
+            }
+        }
+    }
+}
+
+
+
+//------------ ServerConfig --------------------------------------------------
+
+/// The configuration of a server machine.
+///
+/// A new value is created with nothing configured at all. You can then
+/// change individual options via the builder-style methods.
+#[derive(Clone, Debug, Default)]
+pub struct ServerConfig {
+    options: Option<SocketOptions>,
+    per_ip_limit: Option<usize>,
+    reject: RejectMode,
+    stats: Option<ServerStats>,
+}
+
+impl ServerConfig {
+    /// Creates a new, empty configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the socket options to apply to each accepted socket.
+    ///
+    /// If applying fails, the accept handler’s `error()` method is called
+    /// and the socket is dropped.
+    pub fn options(mut self, options: SocketOptions) -> Self {
+        self.options = Some(options);
+        self
+    }
+
+    /// Limits the number of active connections per peer IP address.
+    ///
+    /// Further connection requests from an address that has reached its
+    /// limit are rejected before the accept handler is asked about them.
+    /// Instead, its `rejected()` method is called. Peers of Unix domain
+    /// sockets don’t have an IP address and aren’t limited.
+    pub fn per_ip_limit(mut self, limit: usize) -> Self {
+        self.per_ip_limit = Some(limit);
+        self
+    }
+
+    /// Sets how rejected connections are closed.
+    ///
+    /// The default is [RejectMode::Graceful].
+    ///
+    /// [RejectMode::Graceful]: enum.RejectMode.html#variant.Graceful
+    pub fn reject(mut self, mode: RejectMode) -> Self {
+        self.reject = mode;
+        self
+    }
+
+    /// Sets the statistics to update.
+    ///
+    /// Keep a clone of `stats` to learn what the server has been up to.
+    pub fn stats(mut self, stats: ServerStats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+}
+
+
+//------------ RejectMode ----------------------------------------------------
+
+/// How a server closes connections it has accepted but doesn’t want.
+///
+/// This happens if the accept handler’s `accept()` method returns `None`
+/// or if the peer has reached its per IP address limit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RejectMode {
+    /// Shut down the sending side and then close the socket.
+    ///
+    /// The peer sees an orderly end of the connection.
+    Graceful,
+
+    /// Reset the connection.
+    ///
+    /// This sets the socket’s linger time to zero before closing it,
+    /// causing a RST to be sent and the socket’s resources to be released
+    /// right away. This is the cheaper choice when under a flood of
+    /// unwanted connections.
+    Reset,
+}
+
+impl Default for RejectMode {
+    fn default() -> Self {
+        RejectMode::Graceful
+    }
+}
+
+
+//------------ ServerStats ---------------------------------------------------
+
+/// Statistics of a server machine.
+///
+/// Values of this type are cheap to clone and all clones share the same
+/// counters. Create one, hand a clone to the server via
+/// [ServerConfig::stats()], and read the counters whenever you like.
+///
+/// [ServerConfig::stats()]: struct.ServerConfig.html#method.stats
+#[derive(Clone, Debug, Default)]
+pub struct ServerStats {
+    rejected: Arc<AtomicUsize>,
+    budget: Option<WriteBudget>,
+}
+
+impl ServerStats {
+    /// Creates a new value with all counters at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new value that also keeps track of pending output.
+    ///
+    /// The server passes `budget` on to its accept handler through the
+    /// handler’s [write_budget()] method so its connections can share it.
+    ///
+    /// [write_budget()]:
+    ///     ../../handlers/trait.AcceptHandler.html#method.write_budget
+    pub fn with_write_budget(budget: WriteBudget) -> Self {
+        ServerStats { budget: Some(budget), .. Self::default() }
+    }
+
+    /// Returns the write budget if there is one.
+    pub fn write_budget(&self) -> Option<&WriteBudget> {
+        self.budget.as_ref()
+    }
+
+    /// Returns the output pending across all connections.
+    ///
+    /// This is the total of the write budget or zero if there is none.
+    pub fn pending_output(&self) -> usize {
+        self.budget.as_ref().map_or(0, WriteBudget::total)
+    }
+
+    /// Returns the number of rejected connections.
+    ///
+    /// This counts connections rejected by the accept handler as well as
+    /// those rejected because of the per IP address limit.
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+
+//------------ MachineStats --------------------------------------------------
+
+/// Statistics about the events processed by machines.
+///
+/// These counters help with diagnosing wakeup storms and busy loops. Like
+/// with [ServerStats], values are cheap to clone and all clones share the
+/// same counters, so a single value can be shared by many machines. Give
+/// a clone to a transport machine via [TransportMachine::new_with_stats()]
+/// or [TransportMachine::set_stats()] or to a request machine via its
+/// configuration. Machines without statistics don’t pay for them.
+///
+/// An event is considered idle if the handler, in its reply, asked for
+/// exactly the same events and deadline as before. For wakeups, this
+/// makes the wakeup spurious: the handler was woken up for nothing, most
+/// likely because a notifier was used more often than necessary. For a
+/// request machine, a wakeup is spurious if there were no requests.
+///
+/// For reading and writing, idle events are less clear-cut since a
+/// handler happily processing a steady stream of data will also keep
+/// asking for the same thing. A handler that doesn’t actually read or
+/// write, however, is woken up over and over again since the socket stays
+/// readable or writable. This shows as a long run of idle events, so the
+/// longest run of consecutive idle reads and writes of any single machine
+/// is recorded, too.
+///
+/// [ServerStats]: struct.ServerStats.html
+/// [TransportMachine::new_with_stats()]:
+///     struct.TransportMachine.html#method.new_with_stats
+/// [TransportMachine::set_stats()]:
+///     struct.TransportMachine.html#method.set_stats
+#[derive(Clone, Debug, Default)]
+pub struct MachineStats(Arc<StatsCounters>);
+
+/// The actual counters of machine statistics.
+#[derive(Debug, Default)]
+struct StatsCounters {
+    wakeups: AtomicUsize,
+    spurious_wakeups: AtomicUsize,
+    idle_reads: AtomicUsize,
+    idle_writes: AtomicUsize,
+    longest_idle_run: AtomicUsize,
+    timers: AtomicUsize,
+}
+
+/// # Creation and Access
+///
+impl MachineStats {
+    /// Creates a new value with all counters at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of wakeups.
+    pub fn wakeups(&self) -> usize {
+        self.0.wakeups.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of wakeups that didn’t change anything.
+    pub fn spurious_wakeups(&self) -> usize {
+        self.0.spurious_wakeups.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of read events that didn’t change anything.
+    pub fn idle_reads(&self) -> usize {
+        self.0.idle_reads.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of write events that didn’t change anything.
+    pub fn idle_writes(&self) -> usize {
+        self.0.idle_writes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the longest run of consecutive idle reads and writes.
+    pub fn longest_idle_run(&self) -> usize {
+        self.0.longest_idle_run.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of timers that have fired.
+    ///
+    /// This includes timeouts as well as ticks.
+    pub fn timers(&self) -> usize {
+        self.0.timers.load(Ordering::Relaxed)
+    }
+}
+
+/// # Recording
+///
+/// These methods are used by the machines to update the counters. They
+/// are public so that machines defined elsewhere can use them, too.
+///
+impl MachineStats {
+    /// Records a wakeup and whether it was spurious.
+    pub fn record_wakeup(&self, spurious: bool) {
+        self.0.wakeups.fetch_add(1, Ordering::Relaxed);
+        if spurious {
+            self.0.spurious_wakeups.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records an idle read event.
+    pub fn record_idle_read(&self) {
+        self.0.idle_reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an idle write event.
+    pub fn record_idle_write(&self) {
+        self.0.idle_writes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that a machine has seen `run` idle events in a row.
+    pub fn record_idle_run(&self, run: usize) {
+        let mut current = self.0.longest_idle_run.load(Ordering::Relaxed);
+        while run > current {
+            match self.0.longest_idle_run.compare_exchange(
+                current, run, Ordering::Relaxed, Ordering::Relaxed
+            ) {
+                Ok(_) => break,
+                Err(prev) => current = prev
+            }
+        }
+    }
+
+    /// Records a timer firing.
+    pub fn record_timer(&self) {
+        self.0.timers.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+
+//------------ PeerLimit -----------------------------------------------------
+
+/// The number of active connections per peer address.
+type PeerCounts = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// Limits the number of active connections per peer address.
+struct PeerLimit {
+    /// The maximum number of connections per address.
+    limit: usize,
+
+    /// The current number of connections per address.
+    ///
+    /// This is shared with the guards of all connections.
+    counts: PeerCounts,
+}
+
+impl PeerLimit {
+    fn new(limit: usize) -> Self {
+        PeerLimit { limit: limit,
+                    counts: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Counts a new connection from `addr` if it is within the limit.
+    fn acquire(&self, addr: IpAddr) -> Option<PeerGuard> {
+        let mut counts = match self.counts.lock() {
+            Ok(counts) => counts,
+            // Don’t punish peers for our own bugs.
+            Err(_) => return Some(PeerGuard { addr: addr, counts: None })
+        };
+        let count = counts.entry(addr).or_insert(0);
+        if *count >= self.limit {
+            return None
+        }
+        *count += 1;
+        Some(PeerGuard { addr: addr, counts: Some(self.counts.clone()) })
+    }
+}
+
+
+//------------ PeerGuard -----------------------------------------------------
+
+/// Represents one connection counted towards a peer’s connection limit.
+///
+/// The connection stops being counted when the value is dropped which
+/// happens when its machine goes away.
+pub struct PeerGuard {
+    addr: IpAddr,
+    counts: Option<PeerCounts>,
+}
+
+impl Drop for PeerGuard {
+    fn drop(&mut self) {
+        let counts = match self.counts {
+            Some(ref counts) => counts,
+            None => return
+        };
+        if let Ok(mut counts) = counts.lock() {
+            let remove = match counts.get_mut(&self.addr) {
+                Some(count) => {
+                    *count = count.saturating_sub(1);
+                    *count == 0
+                }
+                None => false
+            };
+            if remove {
+                counts.remove(&self.addr);
             }
         }
     }
 }
 
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::io;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use rotor::{Evented, EventSet, Notifier, PollOpt, Time};
+    use rotor::mio::{Selector, Token};
+    use ::handlers::TransportHandler;
+    use ::next::{Intent, Next};
+    use ::sockets::Transport;
+    use ::trace::ConnSpan;
+    use ::utils::{WriteBudget, WriteBuffer};
+    use super::{FLUSH_ATTEMPTS, MachineStats, ServerStats, TransportConn};
+
+    /// A socket with `pending` rounds of buffered output.
+    struct Buffered {
+        pending: usize,
+        hint: bool,
+        flushes: Rc<Cell<usize>>,
+    }
+
+    impl Buffered {
+        fn close(pending: usize, hint: bool) -> usize {
+            let flushes = Rc::new(Cell::new(0));
+            let sock = Buffered { pending: pending, hint: hint,
+                                  flushes: flushes.clone() };
+            TransportConn::<(), Buffered, Idle>::close(sock);
+            flushes.get()
+        }
+
+        /// Creates a connection whose handler waits for nothing.
+        fn conn() -> TransportConn<(), Buffered, Idle> {
+            let sock = Buffered { pending: 0, hint: true,
+                                  flushes: Rc::new(Cell::new(0)) };
+            TransportConn::make(sock, Idle, Intent::default(), None, None,
+                                None, ConnSpan::new())
+        }
+    }
+
+    impl Evented for Buffered {
+        fn register(&self, _selector: &mut Selector, _token: Token,
+                    _interest: EventSet, _opts: PollOpt) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn reregister(&self, _selector: &mut Selector, _token: Token,
+                      _interest: EventSet, _opts: PollOpt)
+                      -> io::Result<()> {
+            Ok(())
+        }
+
+        fn deregister(&self, _selector: &mut Selector) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Buffered {
+        fn take_socket_error(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn flush_output(&mut self) -> io::Result<bool> {
+            self.flushes.set(self.flushes.get() + 1);
+            self.pending = self.pending.saturating_sub(1);
+            Ok(self.pending == 0)
+        }
+
+        fn write_ready_hint(&self) -> bool {
+            self.hint
+        }
+    }
+
+    struct Idle;
+
+    impl TransportHandler<Buffered> for Idle {
+        type Seed = ();
+
+        fn create(_seed: (), _sock: &mut Buffered, _notifier: Notifier)
+                  -> Next<Self> {
+            Next::wait(Idle)
+        }
+
+        fn readable(self, _sock: &mut Buffered) -> Next<Self> {
+            Next::wait(self)
+        }
+
+        fn writable(self, _sock: &mut Buffered) -> Next<Self> {
+            Next::wait(self)
+        }
+
+        fn wakeup(self, _sock: &mut Buffered) -> Next<Self> {
+            Next::wait(self)
+        }
+    }
+
+    #[test]
+    fn close_flushes_until_done() {
+        assert_eq!(Buffered::close(0, true), 1);
+        assert_eq!(Buffered::close(2, true), 2);
+    }
+
+    #[test]
+    fn close_gives_up_eventually() {
+        assert_eq!(Buffered::close(100, true), FLUSH_ATTEMPTS);
+    }
+
+    #[test]
+    fn close_stops_on_full_send_buffer() {
+        assert_eq!(Buffered::close(100, false), 1);
+    }
+
+    #[test]
+    fn connecting_waits_for_writable() {
+        let mut conn = Buffered::conn();
+        assert_eq!(conn.events(), EventSet::none());
+        conn.connecting = Some(Time::zero());
+        assert_eq!(conn.events(), EventSet::writable());
+    }
+
+    #[test]
+    fn connect_expires() {
+        let start = Time::zero() + Duration::from_secs(10);
+        let mut conn = Buffered::conn();
+        assert!(!conn.connect_expired(start));
+        conn.connecting = Some(start + Duration::from_secs(5));
+        assert!(!conn.connect_expired(start));
+        assert!(conn.connect_expired(start + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn longest_idle_run_only_grows() {
+        let stats = MachineStats::new();
+        stats.record_idle_run(3);
+        stats.record_idle_run(2);
+        assert_eq!(stats.longest_idle_run(), 3);
+        stats.record_idle_run(5);
+        assert_eq!(stats.longest_idle_run(), 5);
+    }
+
+    #[test]
+    fn server_stats_pending_output() {
+        assert_eq!(ServerStats::new().pending_output(), 0);
+        let budget = WriteBudget::new(10);
+        let stats = ServerStats::with_write_budget(budget.clone());
+        let mut buf = WriteBuffer::with_budget(100, 0, budget);
+        buf.push(b"1234").unwrap();
+        assert_eq!(stats.pending_output(), 4);
+        drop(buf);
+        assert_eq!(stats.pending_output(), 0);
+    }
+}
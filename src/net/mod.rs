@@ -11,14 +11,16 @@
 //! *Servers* react to request coming in from the network. For stream sockets,
 //! they combine a listening socket and accept handler with the transports
 //! created from accepting incoming streams. Since datagram sockets don’t
-//! have connections, there are no servers for them as such. However, there
-//! are combined server machines for stream and datagram sockets. With these,
-//! the datagram part is really just a transport.
+//! have connections, their servers, such as the [UdpServer], are really
+//! just a transport that can be shut down like a stream server. There
+//! also are combined server machines for stream and datagram sockets. With
+//! these, the datagram part is a plain transport.
 //!
 //! *Clients* react to request from within the application itself, typcially
 //! by communicating through the network. Clients typically consist of a
 //! request machine wrapping a request handler which can add transports to
-//! the client on the fly.
+//! the client on the fly. The [PersistentClient] is the exception: it
+//! keeps a single connection alive and feeds it messages.
 //!
 //! For network machines, we designate the transports provided by these
 //! machines using the name of the transport protocols in question: `Tcp`
@@ -40,18 +42,88 @@
 //! didn’t do that for encrypted machines to make it explicit which TLS
 //! dependency you are using.
 //!
+//! The machines involving encrypted sockets are actually defined in the
+//! [tls] module, generic over the TLS backend. The backend modules only
+//! provide type aliases for them. If you want to write code that works
+//! with any backend, use the [tls] module directly.
+//!
 //! The set of combined machines is not yet complete. If you are missing a
 //! particular combination, feel free to open a Github issues or, better yet,
 //! provide a pull request.
 //!
 //! [clear]: clear/index.html
+//! [UdpServer]: clear/struct.UdpServer.html
+//! [PersistentClient]: persistent/struct.PersistentClient.html
+//! [tls]: tls/index.html
 //! [openssl]: https://crates.io/crates/openssl
 //! [security-framework]: https://crates.io/crates/security-framework
 //! [rustls]: https://github.com/ctz/rustls
 
 pub use self::clear::*;
+pub use self::machines::{MachineStats, RejectMode, ServerConfig,
+                         ServerStats};
+#[cfg(unix)] pub use self::multi::{MultiLoop, MultiLoopHandle};
+pub use self::persistent::{MessageSender, Messages, PersistentClient,
+                           PersistentConfig};
+pub use self::pool::{ConnPool, PoolSweepMachine};
+pub use self::ticker::{ShutdownMachine, TickerMachine, run_for};
 
 pub mod clear;
 pub mod machines;
+#[cfg(unix)] pub mod multi;
+pub mod persistent;
+pub mod pool;
+#[cfg(unix)] pub mod restart;
+#[cfg(unix)] pub mod signals;
+pub mod ticker;
+#[cfg(unix)] pub mod systemd;
+pub mod tls;
 
+#[cfg(feature = "http1")] pub mod http1;
 #[cfg(feature = "openssl")] pub mod openssl;
+#[cfg(feature = "rustls")] pub mod rustls;
+#[cfg(feature = "security-framework")] pub mod security_framework;
+#[cfg(feature = "websocket")] pub mod websocket;
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// The size of the buffer the HTTP-based handlers read into.
+#[cfg(any(feature = "http1", feature = "websocket"))]
+const READ_CHUNK: usize = 4096;
+
+/// Returns whether the comma separated list `value` contains `token`.
+///
+/// Tokens are compared ignoring case as is the rule for HTTP header
+/// values such as those of the `Connection` header.
+#[cfg(any(feature = "http1", feature = "websocket"))]
+fn has_token(value: &str, token: &str) -> bool {
+    value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token))
+}
+
+/// Converts all of `fds` into sockets using `op`.
+///
+/// If converting one of them fails, the file descriptors that haven’t
+/// been converted yet are closed before returning the error. Those that
+/// have been converted are closed when their sockets are dropped and
+/// `op` has to make sure the failing one is closed, too.
+#[cfg(unix)]
+fn adopt_fds<T, F>(fds: Vec<::std::os::unix::io::RawFd>, op: F)
+                   -> ::std::io::Result<Vec<T>>
+             where F: Fn(::std::os::unix::io::RawFd)
+                        -> ::std::io::Result<T> {
+    let mut res = Vec::new();
+    let mut fds = fds.into_iter();
+    while let Some(fd) = fds.next() {
+        match op(fd) {
+            Ok(sock) => res.push(sock),
+            Err(err) => {
+                for fd in fds {
+                    unsafe { ::libc::close(fd); }
+                }
+                return Err(err)
+            }
+        }
+    }
+    Ok(res)
+}
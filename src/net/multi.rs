@@ -0,0 +1,337 @@
+//! Running a server on several threads.
+//!
+//! A rotor loop runs on a single thread. To make use of more than one
+//! CPU core, a server can simply run several loops, each on its own
+//! thread and each with its own listening sockets. If all these sockets
+//! are bound to the same addresses with the `SO_REUSEPORT` option set,
+//! the kernel distributes incoming connections between them and thus
+//! between the threads. The threads share nothing, so there is no
+//! locking involved in processing connections.
+//!
+//! The [MultiLoop] type sets all this up. You tell it the number of
+//! threads and the addresses to listen on, and give it a context and an
+//! accept handler. Each thread receives a clone of the context and, for
+//! each of its listeners, a clone of the accept handler. Anything that
+//! should be shared between threads, such as statistics or a
+//! configuration, needs to live behind something like an `Arc` in the
+//! context or handler.
+//!
+//! Starting returns a [MultiLoopHandle] which allows shutting down all
+//! loops together and waiting for the threads to finish.
+//!
+//! There are some caveats:
+//!
+//! * On Linux, the kernel picks the listener for a new connection by
+//!   hashing its addresses. This spreads the number of connections
+//!   evenly but not necessarily the load they cause. A connection stays
+//!   with its thread for its entire lifetime, so a few busy long-lived
+//!   connections may keep one thread busy while the others are idle.
+//!
+//! * Other systems accept `SO_REUSEPORT` but may not balance at all.
+//!   Some BSDs hand all connections to the last socket bound.
+//!
+//! * If the number of listeners changes, for instance because a thread
+//!   shuts down, connection requests already queued for the removed
+//!   listener are lost.
+//!
+//! [MultiLoop]: struct.MultiLoop.html
+//! [MultiLoopHandle]: struct.MultiLoopHandle.html
+
+use std::{io, thread};
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use rotor::{self, Compose2, EventSet, GenericScope, Machine, Response, Scope,
+            Void};
+use rotor::mio::tcp::TcpStream;
+use ::handlers::AcceptHandler;
+use ::sockets::tcp_listener_reuse_port;
+use ::sync::{TriggerReceiver, TriggerSender, trigger};
+use ::utils::ResponseExt;
+use super::clear::TcpServer;
+use super::machines::ServerConfig;
+
+
+//------------ MultiLoop -----------------------------------------------------
+
+/// A server running on several threads.
+///
+/// See the [module documentation](index.html) for how this works.
+#[derive(Clone, Debug)]
+pub struct MultiLoop {
+    threads: usize,
+    addrs: Vec<SocketAddr>,
+    config: ServerConfig,
+}
+
+impl MultiLoop {
+    /// Creates a new value for the given number of threads.
+    ///
+    /// # Panics
+    ///
+    /// The function panics if `threads` is zero.
+    pub fn new(threads: usize) -> Self {
+        assert!(threads > 0, "multi loop without threads");
+        MultiLoop { threads: threads, addrs: Vec::new(),
+                    config: ServerConfig::new() }
+    }
+
+    /// Adds an address to listen on.
+    ///
+    /// Each thread will get its own listener for each address.
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.addrs.push(addr);
+        self
+    }
+
+    /// Sets the configuration for all server machines.
+    pub fn server_config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Returns the number of threads.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Returns the addresses to listen on.
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Starts all threads.
+    ///
+    /// Each thread creates a loop with a TCP server machine for each of
+    /// the addresses, using a clone of `handler`, and runs it with a
+    /// clone of `context`.
+    ///
+    /// The function waits until all threads have set up their loop. If
+    /// any of them fails, for instance because binding a listener fails,
+    /// the threads already running are shut down again and the first
+    /// error is returned.
+    pub fn run<X, H>(self, context: X, handler: H)
+                     -> io::Result<MultiLoopHandle>
+               where X: Clone + Send + 'static,
+                     H: AcceptHandler<TcpStream> + Clone + Send + 'static {
+        let (tx, rx) = mpsc::channel();
+        let mut res = MultiLoopHandle { triggers: Vec::new(),
+                                        threads: Vec::new() };
+        let mut err = None;
+        for i in 0..self.threads {
+            let addrs = self.addrs.clone();
+            let config = self.config.clone();
+            let context = context.clone();
+            let handler = handler.clone();
+            let tx = tx.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("netmachines-loop-{}", i))
+                .spawn(move || run_loop(addrs, config, context, handler, tx));
+            match spawned {
+                Ok(thread) => res.threads.push(thread),
+                Err(thread_err) => {
+                    err = Some(thread_err);
+                    break
+                }
+            }
+        }
+        drop(tx);
+        for _ in 0..res.threads.len() {
+            match rx.recv() {
+                Ok(Ok(trigger)) => res.triggers.push(trigger),
+                Ok(Err(loop_err)) => {
+                    if err.is_none() {
+                        err = Some(loop_err)
+                    }
+                }
+                Err(_) => {
+                    if err.is_none() {
+                        err = Some(io::Error::new(io::ErrorKind::Other,
+                                                  "loop thread failed"))
+                    }
+                }
+            }
+        }
+        match err {
+            Some(err) => {
+                res.shutdown();
+                let _ = res.join();
+                Err(err)
+            }
+            None => Ok(res)
+        }
+    }
+}
+
+/// Sets up and runs the loop of a single thread.
+///
+/// The outcome of the setup is reported via `tx`.
+fn run_loop<X, H>(addrs: Vec<SocketAddr>, config: ServerConfig, context: X,
+                  handler: H, tx: mpsc::Sender<io::Result<TriggerSender>>)
+                  -> io::Result<()>
+            where H: AcceptHandler<TcpStream> + Clone {
+    let mut lc = match rotor::Loop::new(&rotor::Config::new()) {
+        Ok(lc) => lc,
+        Err(err) => {
+            let _ = tx.send(Err(err));
+            return Ok(())
+        }
+    };
+    let mut triggers = Vec::new();
+    for addr in &addrs {
+        let sock = match tcp_listener_reuse_port(addr) {
+            Ok(sock) => sock,
+            Err(err) => {
+                let _ = tx.send(Err(err));
+                return Ok(())
+            }
+        };
+        let handler = handler.clone();
+        let config = config.clone();
+        let res = lc.add_machine_with(|scope| {
+            let (m, t) = TcpServer::new_with_config(sock, handler, config,
+                                                    scope);
+            triggers.push(t);
+            m.map_self(Compose2::A)
+        });
+        if res.is_err() {
+            let _ = tx.send(Err(no_slab_space()));
+            return Ok(())
+        }
+    }
+    let mut stop = None;
+    let res = lc.add_machine_with(|scope| {
+        let (m, t) = StopMachine::new(triggers, scope);
+        stop = Some(t);
+        m.map_self(Compose2::B)
+    });
+    match (res, stop) {
+        (Ok(()), Some(stop)) => {
+            let _ = tx.send(Ok(stop));
+        }
+        _ => {
+            let _ = tx.send(Err(no_slab_space()));
+            return Ok(())
+        }
+    }
+    lc.run(context)
+}
+
+/// Returns the error for failing to add a machine to a new loop.
+fn no_slab_space() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "no slab space for machine")
+}
+
+
+//------------ MultiLoopHandle -----------------------------------------------
+
+/// The threads started by a multi loop.
+///
+/// Dropping the handle leaves the threads running.
+pub struct MultiLoopHandle {
+    triggers: Vec<TriggerSender>,
+    threads: Vec<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl MultiLoopHandle {
+    /// Returns the triggers for shutting down each of the loops.
+    ///
+    /// Firing a trigger closes the listeners of its loop and then shuts
+    /// the loop down. You can, for instance, hand these to a
+    /// [ShutdownMachine] of another loop.
+    ///
+    /// [ShutdownMachine]: ../ticker/struct.ShutdownMachine.html
+    pub fn triggers(&self) -> &[TriggerSender] {
+        &self.triggers
+    }
+
+    /// Shuts down all loops.
+    ///
+    /// This only fires the triggers and returns right away. Call
+    /// [join()](#method.join) to wait for the threads to actually finish.
+    /// Connections still open when a loop shuts down are dropped.
+    pub fn shutdown(&self) {
+        for trigger in &self.triggers {
+            if let Err(err) = trigger.trigger() {
+                debug!("loop shutdown trigger failed: {:?}", err);
+            }
+        }
+    }
+
+    /// Waits for all threads to finish.
+    ///
+    /// Returns the first error a loop has failed with, if any.
+    pub fn join(self) -> io::Result<()> {
+        let mut res = Ok(());
+        for thread in self.threads {
+            let thread_res = match thread.join() {
+                Ok(thread_res) => thread_res,
+                Err(_) => Err(io::Error::new(io::ErrorKind::Other,
+                                             "loop thread panicked"))
+            };
+            if res.is_ok() {
+                res = thread_res
+            }
+        }
+        res
+    }
+}
+
+
+//------------ StopMachine ---------------------------------------------------
+
+/// A machine shutting down the loop when triggered.
+///
+/// Before doing so, it fires the triggers of the loop’s server machines so
+/// their listeners are closed.
+struct StopMachine<X> {
+    rx: TriggerReceiver,
+    triggers: Vec<TriggerSender>,
+    marker: PhantomData<X>
+}
+
+impl<X> StopMachine<X> {
+    fn new<S: GenericScope>(triggers: Vec<TriggerSender>, scope: &mut S)
+                            -> (Response<Self, Void>, TriggerSender) {
+        let (tx, rx) = trigger(scope.notifier());
+        (Response::ok(StopMachine { rx: rx, triggers: triggers,
+                                    marker: PhantomData }),
+         tx)
+    }
+}
+
+impl<X> Machine for StopMachine<X> {
+    type Context = X;
+    type Seed = Void;
+
+    fn create(seed: Self::Seed, _scope: &mut Scope<X>)
+              -> Response<Self, Void> {
+        match seed { }
+    }
+
+    fn ready(self, _events: EventSet, _scope: &mut Scope<X>)
+             -> Response<Self, Self::Seed> {
+        Response::ok(self)
+    }
+
+    fn spawned(self, _scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        Response::ok(self)
+    }
+
+    fn timeout(self, _scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        Response::ok(self)
+    }
+
+    fn wakeup(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        if !self.rx.triggered() {
+            return Response::ok(self)
+        }
+        for trigger in &self.triggers {
+            if let Err(err) = trigger.trigger() {
+                debug!("server shutdown trigger failed: {:?}", err);
+            }
+        }
+        scope.shutdown_loop();
+        Response::done()
+    }
+}
@@ -0,0 +1,685 @@
+//! A client keeping a single connection alive.
+//!
+//! The client machines in [clear] and [tls] are request-oriented: each
+//! request sent to the machine creates a new transport machine. Some
+//! clients, such as one talking to a message queue, would rather have a
+//! single long-lived connection that they feed messages to over time.
+//! This is what the [PersistentClient] is for.
+//!
+//! When the connection fails, the client backs off before reconnecting as
+//! described by a [PersistentConfig]. The configuration can also limit
+//! the number of attempts after which the client gives up. The
+//! [MessageSender] returned when creating the client tells whether it
+//! currently is connected and can reject messages while it is backing
+//! off. Its [ConnectionStatus] can be handed to a supervising thread that
+//! wants to keep an eye on the client.
+//!
+//! [clear]: ../clear/index.html
+//! [tls]: ../tls/index.html
+//! [PersistentClient]: struct.PersistentClient.html
+//! [PersistentConfig]: struct.PersistentConfig.html
+//! [MessageSender]: struct.MessageSender.html
+//! [ConnectionStatus]: struct.ConnectionStatus.html
+
+use std::{error, fmt};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use rotor::{EventSet, GenericScope, Machine, Response, Scope, Time, Void};
+use rotor::mio::tcp::TcpStream;
+use super::clear::TcpFactory;
+use super::machines::TransportMachine;
+use ::clock;
+use ::handlers::TransportHandler;
+use ::request::SeedFactory;
+use ::sync::{DuctReceiver, DuctSendError, DuctSender, RecvError,
+             TriggerReceiver, TriggerSender, duct, trigger};
+use ::utils::Backoff;
+
+
+/// The delay before the first reconnect in seconds by default.
+const DEFAULT_RECONNECT_DELAY: u64 = 1;
+
+/// The maximum delay between reconnects in seconds by default.
+const DEFAULT_MAX_RECONNECT_DELAY: u64 = 60;
+
+/// The number of messages queued while backing off by default.
+const DEFAULT_OFFLINE_QUEUE: usize = 1024;
+
+
+//------------ PersistentClient ----------------------------------------------
+
+/// A client machine with a single connection that is kept alive.
+///
+/// The type is generic over the rotor context `X`, the type of messages
+/// `T` that are sent to the connection, the part `S` of the transport
+/// handler’s seed given by the user, and the transport handler `H`.
+///
+/// The machine is created via [new()](#method.new) which returns a
+/// [MessageSender] for sending messages in addition to the rotor
+/// response. The machine connects to the given address right away and
+/// creates a transport handler from a seed consisting of the receiving
+/// end of the message queue, wrapped into a [Messages] value, and a clone
+/// of the seed `S`. Whenever a message is sent, the handler’s `wakeup()`
+/// method is called and it should take all queued messages via
+/// [Messages::try_recv()].
+///
+/// If connecting fails or the connection ends for whatever reason, the
+/// machine backs off for a while and then connects again, creating a new
+/// transport handler. The delay starts out at one second and doubles
+/// with every failed attempt up to a minute, with some jitter applied.
+/// It starts over once a connection has been established. All this can
+/// be changed through a [PersistentConfig] when creating the machine via
+/// [new_with_config()](#method.new_with_config).
+///
+/// Messages sent in the meantime remain queued for the new handler
+/// unless the configuration says to reject them. Since the handler is
+/// woken up right after it has been created, it will learn about them
+/// without further ado.
+///
+/// The machine keeps reconnecting until the [trigger] also returned by
+/// [new()](#method.new) is fired. The transport handler is then torn
+/// down and the socket closed. If the configuration limits the number of
+/// attempts, the machine also ends once that many attempts in a row have
+/// failed without establishing a connection. Its state then is
+/// `ConnectionState::Failed` and sending messages fails.
+///
+/// [trigger]: ../../sync/fn.trigger.html
+/// [MessageSender]: struct.MessageSender.html
+/// [Messages]: struct.Messages.html
+/// [Messages::try_recv()]: struct.Messages.html#method.try_recv
+/// [PersistentConfig]: struct.PersistentConfig.html
+pub struct PersistentClient<X, T, S, H>
+           where T: Send, S: Clone,
+                 H: TransportHandler<TcpStream, Seed=(Messages<T>, S)> {
+    /// Everything needed for connecting.
+    client: Client<T, S>,
+
+    /// Whether we have a connection or are backing off.
+    state: State<TransportMachine<X, TcpStream, H>>,
+}
+
+/// The static part of a persistent client.
+struct Client<T: Send, S: Clone> {
+    /// The address to connect to.
+    addr: SocketAddr,
+
+    /// The user’s part of the transport handler seed.
+    seed: S,
+
+    /// The receiving end of the message duct.
+    messages: Messages<T>,
+
+    /// How long to wait before reconnecting.
+    backoff: Backoff,
+
+    /// Where we let the world know what we are doing.
+    status: ConnectionStatus,
+
+    /// The number of failed attempts after which we give up.
+    max_retries: Option<usize>,
+
+    /// The number of attempts that failed since the last connection.
+    failures: usize,
+
+    /// The factory for creating sockets.
+    factory: TcpFactory<(Messages<T>, S)>,
+
+    /// The receiving end of a trigger for shutting down the machine.
+    rx: TriggerReceiver,
+}
+
+/// The state of a persistent client.
+enum State<M> {
+    /// There is a transport machine for the connection.
+    ///
+    /// The connection may still be in the process of being established.
+    Active(M),
+
+    /// We are backing off until the given time before reconnecting.
+    Backoff(Time),
+}
+
+
+/// # Machine Creation
+///
+impl<X, T, S, H> PersistentClient<X, T, S, H>
+                 where T: Send, S: Clone,
+                       H: TransportHandler<TcpStream,
+                                           Seed=(Messages<T>, S)> {
+    /// Creates a new machine connecting to `addr`.
+    ///
+    /// Returns the rotor response, the sender for messages, and the
+    /// sending end of a trigger for shutting down the machine. The
+    /// machine uses the default configuration.
+    pub fn new<G: GenericScope>(addr: SocketAddr, seed: S, scope: &mut G)
+                                -> (Response<Self, Void>, MessageSender<T>,
+                                    TriggerSender) {
+        PersistentClient::new_with_config(addr, seed,
+                                          PersistentConfig::default(),
+                                          scope)
+    }
+
+    /// Creates a new machine that always waits `delay` before reconnecting.
+    ///
+    /// This is like [new()](#method.new) except that there is neither
+    /// exponential backoff nor jitter.
+    pub fn new_with_delay<G>(addr: SocketAddr, seed: S, delay: Duration,
+                             scope: &mut G)
+                             -> (Response<Self, Void>, MessageSender<T>,
+                                 TriggerSender)
+                          where G: GenericScope {
+        let config = PersistentConfig::new()
+                                      .backoff(Backoff::constant(delay));
+        PersistentClient::new_with_config(addr, seed, config, scope)
+    }
+
+    /// Creates a new machine using the given configuration.
+    ///
+    /// This is like [new()](#method.new) except for the configuration.
+    pub fn new_with_config<G>(addr: SocketAddr, seed: S,
+                              config: PersistentConfig, scope: &mut G)
+                              -> (Response<Self, Void>, MessageSender<T>,
+                                  TriggerSender)
+                           where G: GenericScope {
+        let (duct_tx, duct_rx) = duct(scope.notifier());
+        let (tx, rx) = trigger(scope.notifier());
+        let status = ConnectionStatus::new();
+        let sender = MessageSender {
+            tx: duct_tx, status: status.clone(), offline: config.offline
+        };
+        let client = Client {
+            addr: addr,
+            seed: seed,
+            messages: Messages(Arc::new(duct_rx)),
+            backoff: config.backoff,
+            status: status,
+            max_retries: config.max_retries,
+            failures: 0,
+            factory: TcpFactory::new(),
+            rx: rx
+        };
+        (PersistentClient::connect(client, scope), sender, tx)
+    }
+}
+
+/// # Introspection
+///
+impl<X, T, S, H> PersistentClient<X, T, S, H>
+                 where T: Send, S: Clone,
+                       H: TransportHandler<TcpStream,
+                                           Seed=(Messages<T>, S)> {
+    /// Returns the address the client connects to.
+    pub fn addr(&self) -> SocketAddr {
+        self.client.addr
+    }
+
+    /// Returns what the client is currently doing.
+    pub fn state(&self) -> ConnectionState {
+        self.client.status.get()
+    }
+
+    /// Returns the transport machine if the client has one.
+    ///
+    /// This is the case while connecting and while connected.
+    pub fn connection(&self) -> Option<&TransportMachine<X, TcpStream, H>> {
+        match self.state {
+            State::Active(ref conn) => Some(conn),
+            State::Backoff(_) => None
+        }
+    }
+}
+
+/// # Internal Helpers
+///
+impl<X, T, S, H> PersistentClient<X, T, S, H>
+                 where T: Send, S: Clone,
+                       H: TransportHandler<TcpStream,
+                                           Seed=(Messages<T>, S)> {
+    /// Connects and creates a transport machine.
+    ///
+    /// Right after creating the machine, we wake it up so that the new
+    /// handler gets to see messages that arrived while we were waiting.
+    fn connect<G, R>(client: Client<T, S>, scope: &mut G) -> Response<Self, R>
+               where G: GenericScope {
+        client.status.set(ConnectionState::Connecting);
+        let seed = (client.messages.clone(), client.seed.clone());
+        match client.factory.translate((client.addr, seed)) {
+            Ok((sock, seed)) => {
+                let res = TransportMachine::new(sock, seed, scope);
+                let _ = scope.notifier().wakeup();
+                PersistentClient::connected(client, res, scope)
+            }
+            Err(err) => {
+                debug!("connecting to {} failed: {}", client.addr, err.1);
+                PersistentClient::back_off(client, scope)
+            }
+        }
+    }
+
+    /// Processes the response of the transport machine.
+    ///
+    /// If the transport machine has stopped, we back off.
+    fn connected<G, Q, R>(client: Client<T, S>,
+                          res: Response<TransportMachine<X, TcpStream, H>, Q>,
+                          scope: &mut G) -> Response<Self, R>
+                       where G: GenericScope {
+        if res.is_stopped() {
+            debug!("connection to {} ended", client.addr);
+            PersistentClient::back_off(client, scope)
+        }
+        else {
+            res.map(|conn| PersistentClient {
+                        client: client, state: State::Active(conn)
+                    },
+                    |_| unreachable!("transport machines don’t spawn"))
+        }
+    }
+
+    /// Processes socket events for the transport machine.
+    ///
+    /// The first time the socket becomes readable or writable without
+    /// an error, the connection has been established.
+    fn conn_ready(mut client: Client<T, S>,
+                  conn: TransportMachine<X, TcpStream, H>,
+                  events: EventSet,
+                  scope: &mut Scope<X>) -> Response<Self, Void> {
+        if client.status.get() == ConnectionState::Connecting
+                && (events.is_readable() || events.is_writable())
+                && !events.is_error() && !events.is_hup() {
+            debug!("connected to {}", client.addr);
+            client.status.set(ConnectionState::Connected);
+            client.backoff.reset();
+            client.failures = 0;
+        }
+        let res = conn.ready(events, scope);
+        PersistentClient::connected(client, res, scope)
+    }
+
+    /// Backs off before reconnecting.
+    ///
+    /// If we have run out of retries, the machine ends instead.
+    fn back_off<G, R>(mut client: Client<T, S>, scope: &mut G)
+                      -> Response<Self, R>
+                   where G: GenericScope {
+        client.failures += 1;
+        if let Some(max) = client.max_retries {
+            if client.failures > max {
+                debug!("giving up on {} after {} attempts", client.addr,
+                       client.failures);
+                client.status.set(ConnectionState::Failed);
+                return Response::done()
+            }
+        }
+        let delay = client.backoff.next_delay();
+        debug!("reconnecting to {} in {:?}", client.addr, delay);
+        client.status.set(ConnectionState::Backoff);
+        let deadline = clock::now(scope) + delay;
+        PersistentClient { client: client, state: State::Backoff(deadline) }
+                         .response()
+    }
+
+    /// Returns the response for the current state.
+    fn response<R>(self) -> Response<Self, R> {
+        match self.state {
+            State::Backoff(deadline) => Response::ok(self).deadline(deadline),
+            State::Active(_) => Response::ok(self)
+        }
+    }
+}
+
+
+//--- Machine
+
+impl<X, T, S, H> Machine for PersistentClient<X, T, S, H>
+                 where T: Send, S: Clone,
+                       H: TransportHandler<TcpStream,
+                                           Seed=(Messages<T>, S)> {
+    type Context = X;
+    type Seed = Void;
+
+    fn create(seed: Self::Seed, _scope: &mut Scope<X>)
+              -> Response<Self, Void> {
+        match seed { }
+    }
+
+    fn ready(self, events: EventSet, scope: &mut Scope<X>)
+             -> Response<Self, Self::Seed> {
+        match self.state {
+            State::Active(conn) => {
+                PersistentClient::conn_ready(self.client, conn, events,
+                                             scope)
+            }
+            State::Backoff(_) => self.response()
+        }
+    }
+
+    fn spawned(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        match self.state {
+            State::Active(conn) => {
+                let res = conn.spawned(scope);
+                PersistentClient::connected(self.client, res, scope)
+            }
+            State::Backoff(_) => self.response()
+        }
+    }
+
+    fn timeout(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        match self.state {
+            State::Active(conn) => {
+                let res = conn.timeout(scope);
+                PersistentClient::connected(self.client, res, scope)
+            }
+            State::Backoff(deadline) => {
+                if deadline <= clock::now(scope) {
+                    PersistentClient::connect(self.client, scope)
+                }
+                else {
+                    self.response()
+                }
+            }
+        }
+    }
+
+    fn wakeup(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        if self.client.rx.triggered() {
+            return Response::done()
+        }
+        match self.state {
+            State::Active(conn) => {
+                let res = conn.wakeup(scope);
+                PersistentClient::connected(self.client, res, scope)
+            }
+            State::Backoff(_) => self.response()
+        }
+    }
+}
+
+
+//------------ PersistentConfig ----------------------------------------------
+
+/// The configuration of a persistent client.
+///
+/// By default, the client backs off starting at one second, doubling up
+/// to a minute, with jitter. It never gives up. Up to 1024 messages are
+/// queued while backing off.
+#[derive(Clone, Debug)]
+pub struct PersistentConfig {
+    backoff: Backoff,
+    offline: OfflinePolicy,
+    max_retries: Option<usize>,
+}
+
+impl PersistentConfig {
+    /// Creates a new default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the backoff to use before reconnecting.
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sets what to do with messages while backing off.
+    pub fn offline(mut self, offline: OfflinePolicy) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Limits how often the client reconnects without success.
+    ///
+    /// After the initial attempt, the client tries `max_retries` more
+    /// times to connect. If none of them succeeds, it gives up and the
+    /// machine ends. Once a connection has been established, the count
+    /// starts over. By default, the client keeps trying forever.
+    pub fn max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+}
+
+impl Default for PersistentConfig {
+    fn default() -> Self {
+        PersistentConfig {
+            backoff: Backoff::new(
+                Duration::from_secs(DEFAULT_RECONNECT_DELAY),
+                Duration::from_secs(DEFAULT_MAX_RECONNECT_DELAY)
+            ),
+            offline: OfflinePolicy::default(),
+            max_retries: None
+        }
+    }
+}
+
+
+//------------ OfflinePolicy -------------------------------------------------
+
+/// What to do with messages sent while a persistent client backs off.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OfflinePolicy {
+    /// Queue messages as long as there are less than the given number.
+    ///
+    /// Once the limit has been reached, further messages are rejected.
+    /// The default is `OfflinePolicy::Queue(1024)`. Since messages pile
+    /// up in memory for as long as the client is offline, the limit
+    /// should only be raised if the peer is known to come back soon.
+    Queue(usize),
+
+    /// Reject all messages.
+    Reject,
+}
+
+impl Default for OfflinePolicy {
+    fn default() -> Self {
+        OfflinePolicy::Queue(DEFAULT_OFFLINE_QUEUE)
+    }
+}
+
+
+//------------ ConnectionState -----------------------------------------------
+
+/// What a persistent client is currently doing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionState {
+    /// The client is establishing a connection.
+    Connecting,
+
+    /// The client is connected.
+    Connected,
+
+    /// The client is waiting before reconnecting.
+    Backoff,
+
+    /// The client has given up after too many failed attempts.
+    Failed,
+}
+
+
+//------------ ConnectionStatus ----------------------------------------------
+
+/// A handle to the connection state of a persistent client.
+///
+/// The handle can be cloned and sent to other threads freely. It always
+/// returns the latest state of the client, so the state may have changed
+/// already by the time you act on it.
+#[derive(Clone, Debug)]
+pub struct ConnectionStatus(Arc<AtomicUsize>);
+
+impl ConnectionStatus {
+    /// Creates a new status for a client that is connecting.
+    fn new() -> Self {
+        ConnectionStatus(Arc::new(AtomicUsize::new(0)))
+    }
+
+    /// Returns the current state of the client.
+    pub fn get(&self) -> ConnectionState {
+        match self.0.load(Ordering::Relaxed) {
+            0 => ConnectionState::Connecting,
+            1 => ConnectionState::Connected,
+            2 => ConnectionState::Backoff,
+            _ => ConnectionState::Failed
+        }
+    }
+
+    /// Returns whether the client currently is connected.
+    pub fn is_connected(&self) -> bool {
+        self.get() == ConnectionState::Connected
+    }
+
+    /// Returns whether the client has given up.
+    pub fn is_failed(&self) -> bool {
+        self.get() == ConnectionState::Failed
+    }
+
+    /// Updates the state of the client.
+    fn set(&self, state: ConnectionState) {
+        let value = match state {
+            ConnectionState::Connecting => 0,
+            ConnectionState::Connected => 1,
+            ConnectionState::Backoff => 2,
+            ConnectionState::Failed => 3
+        };
+        self.0.store(value, Ordering::Relaxed)
+    }
+}
+
+
+//------------ MessageSender -------------------------------------------------
+
+/// The sending end for messages to a persistent client.
+///
+/// Messages are sent into a [duct] and the sender can be cloned as
+/// often as necessary. In addition, the sender applies the client’s
+/// [OfflinePolicy] while the client is backing off.
+///
+/// [duct]: ../../sync/fn.duct.html
+/// [OfflinePolicy]: enum.OfflinePolicy.html
+pub struct MessageSender<T> {
+    tx: DuctSender<T>,
+    status: ConnectionStatus,
+    offline: OfflinePolicy,
+}
+
+impl<T: Send> MessageSender<T> {
+    /// Sends a message to the client.
+    ///
+    /// If the client is backing off and its policy says so, the message
+    /// is rejected and returned in the error.
+    pub fn send(&self, value: T) -> Result<(), MessageSendError<T>> {
+        if self.status.get() == ConnectionState::Backoff {
+            let reject = match self.offline {
+                OfflinePolicy::Queue(limit) => self.tx.len() >= limit,
+                OfflinePolicy::Reject => true
+            };
+            if reject {
+                return Err(MessageSendError::Rejected(value))
+            }
+        }
+        self.tx.send(value).map_err(MessageSendError::Duct)
+    }
+
+    /// Returns the number of messages waiting.
+    ///
+    /// The same caveats as for `DuctSender::len()` apply.
+    pub fn len(&self) -> usize {
+        self.tx.len()
+    }
+
+    /// Returns whether there are no messages waiting.
+    pub fn is_empty(&self) -> bool {
+        self.tx.is_empty()
+    }
+
+    /// Returns a handle to the connection state of the client.
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.clone()
+    }
+}
+
+impl<T> Clone for MessageSender<T> {
+    fn clone(&self) -> Self {
+        MessageSender {
+            tx: self.tx.clone(),
+            status: self.status.clone(),
+            offline: self.offline
+        }
+    }
+}
+
+
+//------------ MessageSendError ----------------------------------------------
+
+/// Sending a message to a persistent client failed.
+#[derive(Debug)]
+pub enum MessageSendError<T> {
+    /// The client is backing off and rejected the message.
+    Rejected(T),
+
+    /// Sending into the duct failed.
+    Duct(DuctSendError<T>),
+}
+
+impl<T: fmt::Debug> error::Error for MessageSendError<T> {
+    fn description(&self) -> &str {
+        match *self {
+            MessageSendError::Rejected(_) => "message rejected",
+            MessageSendError::Duct(DuctSendError::SendError(_)) => {
+                "client is gone"
+            }
+            MessageSendError::Duct(DuctSendError::WakeupError) => {
+                "failed to wake up client"
+            }
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Display for MessageSendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(error::Error::description(self))
+    }
+}
+
+
+//------------ Messages ------------------------------------------------------
+
+/// The receiving end of the message duct of a persistent client.
+///
+/// Each transport handler created by a [PersistentClient] receives a
+/// clone of this value as part of its seed. All clones share the same
+/// duct.
+///
+/// [PersistentClient]: struct.PersistentClient.html
+pub struct Messages<T>(Arc<DuctReceiver<T>>);
+
+impl<T: Send> Messages<T> {
+    /// Takes the next message from the duct.
+    ///
+    /// Returns `Ok(None)` if there currently are no messages. Returns an
+    /// error if there are none and all sending ends of the duct are gone
+    /// so that no more messages will arrive.
+    ///
+    /// As with any duct, the handler is only woken up again once it has
+    /// tried to receive, so it should always take all messages.
+    pub fn try_recv(&self) -> Result<Option<T>, RecvError> {
+        self.0.try_recv()
+    }
+
+    /// Returns the number of messages waiting.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns whether there are no messages waiting.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<T> Clone for Messages<T> {
+    fn clone(&self) -> Self {
+        Messages(self.0.clone())
+    }
+}
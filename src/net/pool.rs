@@ -0,0 +1,298 @@
+//! Keeping recycled sockets for later reuse.
+
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use rotor::{EventSet, GenericScope, Machine, Response, Scope, Time, Void};
+use ::clock;
+use ::sync::{Receiver, Sender, TriggerReceiver, TriggerSender, channel,
+             trigger};
+
+
+//------------ ConnPool ------------------------------------------------------
+
+/// A pool of recycled sockets.
+///
+/// Transport machines created via [TransportMachine::new_pooled()] send
+/// their socket and a new seed to a pool when their handler is done with
+/// the socket but it is still good for more. This type is a pool ready
+/// to be used for that purpose.
+///
+/// You get the sender for a new machine via [sender()](#method.sender).
+/// Later, [checkout()](#method.checkout) hands out a recycled socket, its
+/// seed, and a sender for the machine to be created for it.
+///
+/// Sockets that have silently died may linger in a pool for a long time.
+/// The pool can therefore be given a maximum age for connections and a
+/// maximum time a socket may sit idle in the pool. Sockets past either
+/// limit are closed rather than reused. This happens during checkout and
+/// whenever [sweep()](#method.sweep) is called, typically by a
+/// [PoolSweepMachine].
+///
+/// The age is measured from when the sender for a new connection was
+/// requested. Since the pool only learns about returned sockets when it
+/// is used, the idle time starts whenever the pool next looked. The
+/// sweep interval therefore limits the accuracy of the idle limit.
+///
+/// The pool is cheap to clone and all clones share the same sockets.
+///
+/// [TransportMachine::new_pooled()]:
+///     ../machines/struct.TransportMachine.html#method.new_pooled
+/// [PoolSweepMachine]: struct.PoolSweepMachine.html
+pub struct ConnPool<T, S> {
+    inner: Arc<Mutex<PoolInner<T, S>>>,
+}
+
+struct PoolInner<T, S> {
+    /// The sockets ready for reuse, least recently returned first.
+    idle: VecDeque<Entry<T, S>>,
+
+    /// Receivers for sockets currently in use and their creation time.
+    busy: Vec<(Time, Receiver<(T, S)>)>,
+
+    /// The maximum age of a connection.
+    max_age: Option<Duration>,
+
+    /// The maximum time a socket may sit in the pool.
+    max_idle: Option<Duration>,
+}
+
+struct Entry<T, S> {
+    sock: T,
+    seed: S,
+    created: Time,
+    returned: Time,
+}
+
+impl<T, S> ConnPool<T, S> {
+    /// Creates a new pool without any limits.
+    pub fn new() -> Self {
+        ConnPool {
+            inner: Arc::new(Mutex::new(PoolInner {
+                idle: VecDeque::new(),
+                busy: Vec::new(),
+                max_age: None,
+                max_idle: None,
+            }))
+        }
+    }
+
+    /// Sets the maximum age of connections in the pool.
+    pub fn max_age(self, max_age: Duration) -> Self {
+        self.lock().max_age = Some(max_age);
+        self
+    }
+
+    /// Sets the maximum time sockets may sit idle in the pool.
+    pub fn max_idle(self, max_idle: Duration) -> Self {
+        self.lock().max_idle = Some(max_idle);
+        self
+    }
+
+    /// Returns a sender for the machine of a new connection.
+    pub fn sender<G: GenericScope>(&self, scope: &G) -> Sender<(T, S)> {
+        let (tx, rx) = channel();
+        self.lock().busy.push((clock::now(scope), rx));
+        tx
+    }
+
+    /// Takes a socket out of the pool.
+    ///
+    /// Returns the socket, its seed, as well as the sender for the new
+    /// machine for it or `None` if there are no usable sockets in the
+    /// pool. The most recently returned socket is used first.
+    pub fn checkout<G>(&self, scope: &G) -> Option<(T, S, Sender<(T, S)>)>
+                    where G: GenericScope {
+        let now = clock::now(scope);
+        let mut inner = self.lock();
+        inner.collect(now);
+        inner.evict(now);
+        match inner.idle.pop_back() {
+            Some(entry) => {
+                let (tx, rx) = channel();
+                inner.busy.push((entry.created, rx));
+                Some((entry.sock, entry.seed, tx))
+            }
+            None => None
+        }
+    }
+
+    /// Collects returned sockets and closes those past their limits.
+    pub fn sweep<G: GenericScope>(&self, scope: &G) {
+        let now = clock::now(scope);
+        let mut inner = self.lock();
+        inner.collect(now);
+        inner.evict(now);
+    }
+
+    /// Returns the number of sockets currently ready for reuse.
+    pub fn len(&self) -> usize {
+        self.lock().idle.len()
+    }
+
+    /// Returns whether there are no sockets ready for reuse.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> MutexGuard<PoolInner<T, S>> {
+        match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+}
+
+impl<T, S> PoolInner<T, S> {
+    /// Moves all sockets that have been returned into the idle list.
+    ///
+    /// Receivers whose machine has gone away without recycling are
+    /// dropped.
+    fn collect(&mut self, now: Time) {
+        let mut i = 0;
+        while i < self.busy.len() {
+            match self.busy[i].1.try_recv() {
+                Ok(None) => {
+                    i += 1;
+                    continue
+                }
+                Ok(Some((sock, seed))) => {
+                    let created = self.busy[i].0;
+                    self.idle.push_back(Entry { sock: sock, seed: seed,
+                                                created: created,
+                                                returned: now });
+                }
+                Err(_) => { }
+            }
+            self.busy.swap_remove(i);
+        }
+    }
+
+    /// Drops all idle sockets that are past one of the limits.
+    fn evict(&mut self, now: Time) {
+        let max_age = self.max_age;
+        let max_idle = self.max_idle;
+        let before = self.idle.len();
+        self.idle.retain(|entry| {
+            if let Some(max_age) = max_age {
+                if entry.created + max_age <= now {
+                    return false
+                }
+            }
+            if let Some(max_idle) = max_idle {
+                if entry.returned + max_idle <= now {
+                    return false
+                }
+            }
+            true
+        });
+        if self.idle.len() < before {
+            debug!("closed {} stale pooled sockets",
+                   before - self.idle.len());
+        }
+    }
+}
+
+impl<T, S> Clone for ConnPool<T, S> {
+    fn clone(&self) -> Self {
+        ConnPool { inner: self.inner.clone() }
+    }
+}
+
+
+//------------ PoolSweepMachine ----------------------------------------------
+
+/// A machine regularly sweeping a connection pool.
+///
+/// Every `interval`, the machine calls the pool’s
+/// [sweep()](struct.ConnPool.html#method.sweep) method, thereby closing
+/// sockets that have gotten too old or have been idle for too long even
+/// if nobody checks out sockets for a while.
+///
+/// Like with the [TickerMachine], [new()](#method.new) also returns a
+/// trigger sender for stopping the machine again.
+///
+/// [TickerMachine]: ../ticker/struct.TickerMachine.html
+pub struct PoolSweepMachine<X, T, S> {
+    /// The pool to sweep.
+    pool: ConnPool<T, S>,
+
+    /// The interval between two sweeps.
+    interval: Duration,
+
+    /// The time of the next sweep.
+    deadline: Time,
+
+    /// The receiving end of a trigger for shutting down the machine.
+    rx: TriggerReceiver,
+
+    /// Binding the context.
+    marker: PhantomData<X>
+}
+
+/// # Machine Creation
+///
+impl<X, T, S> PoolSweepMachine<X, T, S> {
+    /// Creates a new machine.
+    pub fn new<G: GenericScope>(pool: ConnPool<T, S>, interval: Duration,
+                                scope: &mut G)
+                                -> (Response<Self, Void>, TriggerSender) {
+        let (tx, rx) = trigger(scope.notifier());
+        let deadline = clock::now(scope) + interval;
+        let machine = PoolSweepMachine { pool: pool, interval: interval,
+                                         deadline: deadline, rx: rx,
+                                         marker: PhantomData };
+        (machine.response(), tx)
+    }
+}
+
+/// # Internal Helpers
+///
+impl<X, T, S> PoolSweepMachine<X, T, S> {
+    fn response<R>(self) -> Response<Self, R> {
+        let deadline = self.deadline;
+        Response::ok(self).deadline(deadline)
+    }
+}
+
+
+//--- Machine
+
+impl<X, T, S> Machine for PoolSweepMachine<X, T, S> {
+    type Context = X;
+    type Seed = Void;
+
+    fn create(seed: Self::Seed, _scope: &mut Scope<X>)
+              -> Response<Self, Void> {
+        match seed { }
+    }
+
+    fn ready(self, _events: EventSet, _scope: &mut Scope<X>)
+             -> Response<Self, Self::Seed> {
+        self.response()
+    }
+
+    fn spawned(self, _scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        self.response()
+    }
+
+    fn timeout(mut self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        self.pool.sweep(scope);
+        let now = clock::now(scope);
+        self.deadline = self.deadline + self.interval;
+        if self.deadline <= now {
+            self.deadline = now + self.interval
+        }
+        self.response()
+    }
+
+    fn wakeup(self, _scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        if self.rx.triggered() {
+            Response::done()
+        }
+        else {
+            self.response()
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Handing listening sockets over to a successor process.
+//!
+//! For a restart without downtime, a new instance of a program takes over
+//! the listening sockets of the old one. Since both processes share the
+//! very same sockets, no connection request is lost in the process. This
+//! module helps with doing so by letting the successor inherit the sockets
+//! when it is started via `exec()` or `std::process::Command`.
+//!
+//! The old process collects the raw file descriptors of its listeners and
+//! passes them to [export()]. This clears their close-on-exec flag and
+//! returns the value for the environment variable named by [ENV_VAR]
+//! which tells the successor about them. You need to get hold of the file
+//! descriptors before giving the listeners to their server machines. Since
+//! the machines never close a listener unless shut down, the descriptors
+//! stay valid.
+//!
+//! The successor calls [import()] or [tcp_listeners()] to adopt the
+//! sockets. The order of the descriptors is kept, so the processes can
+//! agree on which socket is which.
+//!
+//! The safe order of things is this:
+//!
+//! 1. The old process exports its listeners and starts the successor.
+//! 2. The successor adopts the listeners, creates its server machines,
+//!    and tells the old process that it is ready, for instance by
+//!    signalling it.
+//! 3. Only then does the old process shut down its server machines via
+//!    their triggers. Connections already accepted are not affected and
+//!    can be finished before the process exits.
+//!
+//! If the successor fails to start, the old process just carries on.
+//! During the second step, both processes accept connections which is
+//! fine since any request goes to exactly one of them.
+//!
+//! [export()]: fn.export.html
+//! [import()]: fn.import.html
+//! [tcp_listeners()]: fn.tcp_listeners.html
+//! [ENV_VAR]: constant.ENV_VAR.html
+
+use std::env;
+use std::io;
+use std::os::unix::io::RawFd;
+use libc;
+use rotor::mio::tcp::TcpListener;
+use ::sockets::tcp_listener_from_raw_fd;
+use super::adopt_fds;
+
+
+/// The environment variable carrying the inherited file descriptors.
+pub const ENV_VAR: &'static str = "NETMACHINES_LISTEN_FDS";
+
+
+/// Prepares file descriptors for being inherited by a successor.
+///
+/// Returns the value of the environment variable [ENV_VAR] to be set for
+/// the successor process.
+///
+/// [ENV_VAR]: constant.ENV_VAR.html
+pub fn export<I: IntoIterator<Item=RawFd>>(fds: I) -> io::Result<String> {
+    let mut res = String::new();
+    for fd in fds {
+        try!(set_cloexec(fd, false));
+        if !res.is_empty() {
+            res.push(',')
+        }
+        res.push_str(&fd.to_string());
+    }
+    Ok(res)
+}
+
+/// Returns the file descriptors inherited from a predecessor.
+///
+/// If the environment variable isn’t set, returns an empty vector. The
+/// variable is removed, so a second call returns nothing and children of
+/// this process won’t see it. The returned file descriptors have their
+/// close-on-exec flag set again.
+///
+/// The ownership of the file descriptors passes to the caller.
+pub fn import() -> io::Result<Vec<RawFd>> {
+    let value = match env::var(ENV_VAR) {
+        Ok(value) => value,
+        Err(_) => return Ok(Vec::new())
+    };
+    env::remove_var(ENV_VAR);
+    let mut res = Vec::new();
+    for item in value.split(',').filter(|item| !item.is_empty()) {
+        let fd = try!(item.parse().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           "invalid inherited file descriptor")
+        }));
+        try!(set_cloexec(fd, true));
+        res.push(fd)
+    }
+    Ok(res)
+}
+
+/// Returns TCP listeners for all inherited file descriptors.
+///
+/// Fails if any of the file descriptors isn’t a listening stream socket.
+/// In this case, all inherited file descriptors are closed.
+pub fn tcp_listeners() -> io::Result<Vec<TcpListener>> {
+    adopt_fds(try!(import()), |fd| unsafe { tcp_listener_from_raw_fd(fd) })
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    let flags = if cloexec { flags | libc::FD_CLOEXEC }
+                else { flags & !libc::FD_CLOEXEC };
+    let res = unsafe { libc::fcntl(fd, libc::F_SETFD, flags) };
+    if res == -1 { Err(io::Error::last_os_error()) }
+    else { Ok(()) }
+}
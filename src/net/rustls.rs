@@ -0,0 +1,53 @@
+//! Encrypted and combined machines using rustls.
+//!
+//! The types in here are aliases for the machines of the [tls] module
+//! with [Rustls] as the backend.
+//!
+//! [tls]: ../tls/index.html
+//! [Rustls]: ../../sockets/rustls/struct.Rustls.html
+
+use ::sockets::rustls::Rustls;
+use super::tls;
+
+pub use super::tls::{TlsTcp, TlsTcpOrUdp, TlsUdp};
+
+
+//============ Transport Machines ============================================
+
+pub type TlsTransport<X, H> = tls::TlsTransport<X, Rustls, H>;
+
+pub type StartTlsTransport<X, H> = tls::StartTlsTransport<X, Rustls, H>;
+
+pub type TlsTcpTransport<X, SH, CH> = tls::TlsTcpTransport<X, Rustls, SH, CH>;
+
+pub type TlsUdpTransport<X, TH, UH> = tls::TlsUdpTransport<X, Rustls, TH, UH>;
+
+
+//============ Server Machines ===============================================
+
+pub type TlsServer<X, H> = tls::TlsServer<X, Rustls, H>;
+
+pub type StartTlsServer<X, H> = tls::StartTlsServer<X, Rustls, H>;
+
+pub type TlsTcpServer<X, SH, CH> = tls::TlsTcpServer<X, Rustls, SH, CH>;
+
+pub type TlsUdpServer<X, AH, UH> = tls::TlsUdpServer<X, Rustls, AH, UH>;
+
+pub type StartTlsUdpServer<X, AH, UH> = tls::StartTlsUdpServer<X, Rustls,
+                                                               AH, UH>;
+
+pub type TlsTcpUdpServer<X, SH, CH, UH> = tls::TlsTcpUdpServer<X, Rustls,
+                                                               SH, CH, UH>;
+
+
+//============ Client Machines ===============================================
+
+pub type TlsClient<X, RH, TH> = tls::TlsClient<X, Rustls, RH, TH>;
+
+pub type StartTlsClient<X, RH, TH> = tls::StartTlsClient<X, Rustls, RH, TH>;
+
+pub type TlsTcpClient<X, RH, SH, CH> = tls::TlsTcpClient<X, Rustls, RH,
+                                                         SH, CH>;
+
+pub type TlsUdpClient<X, RH, TH, UH> = tls::TlsUdpClient<X, Rustls, RH,
+                                                         TH, UH>;
@@ -0,0 +1,51 @@
+//! Encrypted and combined machines using Secure Transport.
+//!
+//! The types in here are aliases for the machines of the [tls] module
+//! with [SecurityFramework] as the backend.
+//!
+//! [tls]: ../tls/index.html
+//! [SecurityFramework]:
+//!     ../../sockets/security_framework/struct.SecurityFramework.html
+
+use ::sockets::security_framework::SecurityFramework as Sf;
+use super::tls;
+
+pub use super::tls::{TlsTcp, TlsTcpOrUdp, TlsUdp};
+
+
+//============ Transport Machines ============================================
+
+pub type TlsTransport<X, H> = tls::TlsTransport<X, Sf, H>;
+
+pub type StartTlsTransport<X, H> = tls::StartTlsTransport<X, Sf, H>;
+
+pub type TlsTcpTransport<X, SH, CH> = tls::TlsTcpTransport<X, Sf, SH, CH>;
+
+pub type TlsUdpTransport<X, TH, UH> = tls::TlsUdpTransport<X, Sf, TH, UH>;
+
+
+//============ Server Machines ===============================================
+
+pub type TlsServer<X, H> = tls::TlsServer<X, Sf, H>;
+
+pub type StartTlsServer<X, H> = tls::StartTlsServer<X, Sf, H>;
+
+pub type TlsTcpServer<X, SH, CH> = tls::TlsTcpServer<X, Sf, SH, CH>;
+
+pub type TlsUdpServer<X, AH, UH> = tls::TlsUdpServer<X, Sf, AH, UH>;
+
+pub type StartTlsUdpServer<X, AH, UH> = tls::StartTlsUdpServer<X, Sf, AH, UH>;
+
+pub type TlsTcpUdpServer<X, SH, CH, UH> = tls::TlsTcpUdpServer<X, Sf, SH, CH,
+                                                               UH>;
+
+
+//============ Client Machines ===============================================
+
+pub type TlsClient<X, RH, TH> = tls::TlsClient<X, Sf, RH, TH>;
+
+pub type StartTlsClient<X, RH, TH> = tls::StartTlsClient<X, Sf, RH, TH>;
+
+pub type TlsTcpClient<X, RH, SH, CH> = tls::TlsTcpClient<X, Sf, RH, SH, CH>;
+
+pub type TlsUdpClient<X, RH, TH, UH> = tls::TlsUdpClient<X, Sf, RH, TH, UH>;
@@ -0,0 +1,317 @@
+//! Shutting down the loop upon a signal.
+//!
+//! A daemon is typically asked to terminate by sending it `SIGTERM` or,
+//! when running in a terminal, `SIGINT`. The [SignalMachine] catches
+//! these signals and, upon receiving one, fires all the triggers it has
+//! been given, typically those of the server machines, and then shuts
+//! down the loop so that its `run()` method returns and the program can
+//! clean up.
+//!
+//! Very little may safely be done inside a signal handler, so the machine
+//! uses the self-pipe trick: it creates a pipe and registers its reading
+//! end with the loop. The signal handler only writes a single byte, the
+//! signal number, to the writing end which is a system call that is safe
+//! to use in a signal handler. All the actual work happens in the loop
+//! when the pipe becomes readable. Both ends of the pipe are
+//! non-blocking, so if the pipe is full, the handler simply loses its
+//! byte which is fine since there is one waiting already.
+//!
+//! The signal handler can only write to one pipe, so there can only be
+//! one machine per process at any time. Creating a second machine fails
+//! while the first one is still around. When the machine goes away, the
+//! default disposition of its signals is restored.
+//!
+//! [SignalMachine]: struct.SignalMachine.html
+
+use std::{io, mem, ptr};
+use std::marker::PhantomData;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use libc;
+use rotor::{Evented, EventSet, GenericScope, Machine, PollOpt, Response,
+            Scope, Void};
+use rotor::mio::{Selector, Token};
+use rotor::mio::unix::EventedFd;
+use ::sync::TriggerSender;
+
+
+/// The writing end of the signal pipe plus one or zero if there is none.
+static SIGNAL_FD: AtomicUsize = AtomicUsize::new(0);
+
+/// The signals caught by default.
+const DEFAULT_SIGNALS: &'static [libc::c_int] = &[libc::SIGTERM,
+                                                  libc::SIGINT];
+
+
+//------------ SignalMachine -------------------------------------------------
+
+/// A machine shutting down the loop when a signal arrives.
+///
+/// See the [module documentation](index.html) for details.
+pub struct SignalMachine<X> {
+    /// The pipe the signal handler writes to.
+    pipe: SignalPipe,
+
+    /// The triggers to fire before shutting down.
+    triggers: Vec<TriggerSender>,
+
+    /// Binding the context.
+    marker: PhantomData<X>
+}
+
+/// # Machine Creation
+///
+impl<X> SignalMachine<X> {
+    /// Creates a new machine catching `SIGTERM` and `SIGINT`.
+    ///
+    /// Upon receiving either signal, the machine fires all of `triggers`
+    /// and shuts down the loop.
+    ///
+    /// The response is an error if the signal handlers can’t be installed
+    /// or if there already is a signal machine.
+    pub fn new<S: GenericScope>(triggers: Vec<TriggerSender>,
+                                scope: &mut S) -> Response<Self, Void> {
+        SignalMachine::new_with_signals(DEFAULT_SIGNALS, triggers, scope)
+    }
+
+    /// Creates a new machine catching the given signals.
+    ///
+    /// This is like [new()](#method.new) but catches `signals` instead of
+    /// `SIGTERM` and `SIGINT`.
+    pub fn new_with_signals<S>(signals: &[libc::c_int],
+                               triggers: Vec<TriggerSender>, scope: &mut S)
+                               -> Response<Self, Void>
+                            where S: GenericScope {
+        let pipe = match SignalPipe::new(signals) {
+            Ok(pipe) => pipe,
+            Err(err) => return Response::error(err.into())
+        };
+        if let Err(err) = scope.register(&pipe, EventSet::readable(),
+                                         PollOpt::level()) {
+            return Response::error(err.into())
+        }
+        Response::ok(SignalMachine { pipe: pipe, triggers: triggers,
+                                     marker: PhantomData })
+    }
+}
+
+
+//--- Machine
+
+impl<X> Machine for SignalMachine<X> {
+    type Context = X;
+    type Seed = Void;
+
+    fn create(seed: Self::Seed, _scope: &mut Scope<X>)
+              -> Response<Self, Void> {
+        match seed { }
+    }
+
+    fn ready(self, _events: EventSet, scope: &mut Scope<X>)
+             -> Response<Self, Self::Seed> {
+        match self.pipe.drain() {
+            Ok(Some(signal)) => {
+                debug!("received signal {}, shutting down", signal);
+            }
+            Ok(None) => return Response::ok(self),
+            Err(err) => {
+                debug!("reading signal pipe failed: {}", err);
+                return Response::ok(self)
+            }
+        }
+        for trigger in &self.triggers {
+            if let Err(err) = trigger.trigger() {
+                debug!("shutdown trigger failed: {:?}", err);
+            }
+        }
+        scope.shutdown_loop();
+        Response::done()
+    }
+
+    fn spawned(self, _scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        Response::ok(self)
+    }
+
+    fn timeout(self, _scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        Response::ok(self)
+    }
+
+    fn wakeup(self, _scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        Response::ok(self)
+    }
+}
+
+
+//------------ SignalPipe ----------------------------------------------------
+
+/// The self-pipe for signals.
+///
+/// Creating a value installs the signal handlers, dropping it restores
+/// the default disposition of the signals and closes the pipe.
+struct SignalPipe {
+    read: RawFd,
+    write: RawFd,
+    signals: Vec<libc::c_int>,
+}
+
+impl SignalPipe {
+    fn new(signals: &[libc::c_int]) -> io::Result<Self> {
+        let mut fds = [0 as libc::c_int; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+            return Err(io::Error::last_os_error())
+        }
+        // From here on, drop cleans up. Since no signals are installed
+        // yet, only the pipe is closed.
+        let mut res = SignalPipe { read: fds[0], write: fds[1],
+                                   signals: Vec::new() };
+        try!(prepare_fd(res.read));
+        try!(prepare_fd(res.write));
+        let swapped = SIGNAL_FD.compare_exchange(0, res.write as usize + 1,
+                                                 Ordering::SeqCst,
+                                                 Ordering::SeqCst);
+        if swapped.is_err() {
+            // Someone else owns the signal handler. Make sure we don’t
+            // clear the static on drop.
+            unsafe { libc::close(res.write); }
+            res.write = -1;
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      "there already is a signal machine"))
+        }
+        for &signal in signals {
+            try!(set_handler(signal, on_signal as libc::sighandler_t));
+            res.signals.push(signal);
+        }
+        Ok(res)
+    }
+
+    /// Reads everything from the pipe.
+    ///
+    /// Returns the last signal received or `None` if there was nothing.
+    fn drain(&self) -> io::Result<Option<libc::c_int>> {
+        let mut buf = [0u8; 16];
+        let mut res = None;
+        loop {
+            let len = unsafe {
+                libc::read(self.read, buf.as_mut_ptr() as *mut libc::c_void,
+                           buf.len())
+            };
+            if len > 0 {
+                res = Some(buf[len as usize - 1] as libc::c_int);
+                continue
+            }
+            if len == 0 {
+                return Ok(res)
+            }
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::WouldBlock => return Ok(res),
+                io::ErrorKind::Interrupted => continue,
+                _ => return Err(err)
+            }
+        }
+    }
+}
+
+impl Evented for SignalPipe {
+    fn register(&self, selector: &mut Selector, token: Token,
+                interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.read).register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token,
+                  interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.read).reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        EventedFd(&self.read).deregister(selector)
+    }
+}
+
+impl Drop for SignalPipe {
+    fn drop(&mut self) {
+        for &signal in &self.signals {
+            let _ = set_handler(signal, libc::SIG_DFL);
+        }
+        if self.write >= 0 {
+            let _ = SIGNAL_FD.compare_exchange(self.write as usize + 1, 0,
+                                               Ordering::SeqCst,
+                                               Ordering::SeqCst);
+        }
+        unsafe {
+            libc::close(self.read);
+            if self.write >= 0 {
+                libc::close(self.write);
+            }
+        }
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// The signal handler.
+///
+/// This must only do what is safe in a signal handler. Loading an atomic
+/// and calling `write()` is. Since the handler may interrupt code that is
+/// about to look at `errno`, it is restored before returning.
+extern "C" fn on_signal(signal: libc::c_int) {
+    let fd = SIGNAL_FD.load(Ordering::SeqCst);
+    if fd != 0 {
+        let byte = signal as u8;
+        unsafe {
+            let errno = *errno_location();
+            libc::write((fd - 1) as RawFd,
+                        &byte as *const u8 as *const libc::c_void, 1);
+            *errno_location() = errno;
+        }
+    }
+}
+
+/// Returns a pointer to the calling thread’s `errno`.
+#[cfg(any(target_os = "linux", target_os = "emscripten"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__errno_location()
+}
+
+#[cfg(any(target_os = "android", target_os = "netbsd",
+          target_os = "openbsd"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__errno()
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+          target_os = "dragonfly"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__error()
+}
+
+/// Installs `handler` for `signal`.
+fn set_handler(signal: libc::c_int, handler: libc::sighandler_t)
+               -> io::Result<()> {
+    let res = unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, ptr::null_mut())
+    };
+    if res == -1 { Err(io::Error::last_os_error()) }
+    else { Ok(()) }
+}
+
+/// Makes a pipe file descriptor non-blocking and close-on-exec.
+fn prepare_fd(fd: RawFd) -> io::Result<()> {
+    let res = unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    if res == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    let res = unsafe { libc::fcntl(fd, libc::F_SETFL,
+                                   flags | libc::O_NONBLOCK) };
+    if res == -1 { Err(io::Error::last_os_error()) }
+    else { Ok(()) }
+}
@@ -0,0 +1,101 @@
+//! Support for systemd socket activation.
+//!
+//! With socket activation, systemd (or a compatible service manager) binds
+//! and listens on sockets on behalf of a service and passes them to the
+//! service process when starting it. The sockets are given as consecutive
+//! file descriptors starting at 3. Their number is given in the
+//! `LISTEN_FDS` environment variable and the process they are meant for in
+//! `LISTEN_PID`.
+//!
+//! The [listen_fds()] function returns the file descriptors passed to
+//! the current process while [tcp_listeners()] goes one step further and
+//! turns them into TCP listeners ready to be given to a server machine.
+//! [unix_listeners()] does the same for Unix domain sockets. For TLS
+//! servers, pass the file descriptors to `TlsListener::from_raw_fd()`
+//! instead.
+//!
+//! Both functions remove the environment variables so that they aren’t
+//! inherited by child processes and a second call returns nothing. This
+//! makes sure that each file descriptor is only adopted once.
+//!
+//! [listen_fds()]: fn.listen_fds.html
+//! [tcp_listeners()]: fn.tcp_listeners.html
+//! [unix_listeners()]: fn.unix_listeners.html
+
+use std::env;
+use std::io;
+use std::os::unix::io::RawFd;
+use libc;
+use rotor::mio::tcp::TcpListener;
+use rotor::mio::unix::UnixListener;
+use ::sockets::{tcp_listener_from_raw_fd, unix_listener_from_raw_fd};
+use super::adopt_fds;
+
+
+/// The first file descriptor passed by the service manager.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+
+/// Returns the file descriptors passed to this process.
+///
+/// If the environment variables aren’t set or are meant for a different
+/// process, returns an empty vector. If they are set but malformed, fails
+/// with an `InvalidData` error. All returned file descriptors have their
+/// close-on-exec flag set.
+///
+/// The ownership of the file descriptors passes to the caller.
+pub fn listen_fds() -> io::Result<Vec<RawFd>> {
+    let pid = env::var("LISTEN_PID");
+    let fds = env::var("LISTEN_FDS");
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    let (pid, fds) = match (pid, fds) {
+        (Ok(pid), Ok(fds)) => (pid, fds),
+        _ => return Ok(Vec::new())
+    };
+    let pid = try!(parse_var(&pid));
+    if pid != unsafe { libc::getpid() } {
+        return Ok(Vec::new())
+    }
+    let count = try!(parse_var(&fds));
+    let mut res = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        try!(set_cloexec(fd));
+        res.push(fd)
+    }
+    Ok(res)
+}
+
+/// Returns TCP listeners for all file descriptors passed to this process.
+///
+/// Fails if any of the file descriptors isn’t a listening stream socket.
+/// In this case, all file descriptors passed to the process are closed.
+pub fn tcp_listeners() -> io::Result<Vec<TcpListener>> {
+    adopt_fds(try!(listen_fds()),
+              |fd| unsafe { tcp_listener_from_raw_fd(fd) })
+}
+
+/// Returns Unix listeners for all file descriptors passed to this process.
+///
+/// Fails if any of the file descriptors isn’t a listening stream socket.
+/// In this case, all file descriptors passed to the process are closed.
+pub fn unix_listeners() -> io::Result<Vec<UnixListener>> {
+    adopt_fds(try!(listen_fds()),
+              |fd| unsafe { unix_listener_from_raw_fd(fd) })
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+fn parse_var(value: &str) -> io::Result<libc::c_int> {
+    value.parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData,
+                       "invalid socket activation variable")
+    })
+}
+
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    let res = unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    if res == -1 { Err(io::Error::last_os_error()) }
+    else { Ok(()) }
+}
@@ -0,0 +1,228 @@
+//! Machines for timed work.
+
+use std::marker::PhantomData;
+use std::time::Duration;
+use rotor::{EventSet, GenericScope, LoopCreator, Machine, Response, Scope,
+            Time, Void};
+use ::clock;
+use ::error::{Error, Result};
+use ::sync::{TriggerReceiver, TriggerSender, trigger};
+use ::utils::ResponseExt;
+
+
+//------------ TickerMachine -------------------------------------------------
+
+/// A machine calling a closure at a fixed interval.
+///
+/// The machine has no socket at all. It only keeps a timer and calls the
+/// closure `F` with the loop’s context every time the timer expires. This
+/// is useful for work that isn’t tied to any connection, such as flushing
+/// statistics or rotating logs.
+///
+/// The machine is added to a loop via [new()](#method.new) which also
+/// returns the sending end of a [trigger] for stopping it again. Since
+/// closure types can’t be named, you may want to use a plain function and
+/// `fn(&mut X)` for `F` when you need to spell out the machine type.
+///
+/// Deadlines are spaced by the interval from the previous deadline rather
+/// than from when the closure returned, so the ticks don’t drift. If the
+/// loop falls behind by more than one interval, ticks are skipped.
+///
+/// [trigger]: ../../sync/fn.trigger.html
+pub struct TickerMachine<X, F: FnMut(&mut X)> {
+    /// The closure to call.
+    op: F,
+
+    /// The interval between two calls.
+    interval: Duration,
+
+    /// The time of the next call.
+    deadline: Time,
+
+    /// The receiving end of a trigger for shutting down the machine.
+    rx: TriggerReceiver,
+
+    /// Binding the context.
+    marker: PhantomData<X>
+}
+
+/// # Machine Creation
+///
+impl<X, F: FnMut(&mut X)> TickerMachine<X, F> {
+    /// Creates a new machine.
+    ///
+    /// The closure `op` will be called for the first time one `interval`
+    /// after the machine was created.
+    pub fn new<S: GenericScope>(interval: Duration, op: F, scope: &mut S)
+                                -> (Response<Self, Void>, TriggerSender) {
+        let (tx, rx) = trigger(scope.notifier());
+        let deadline = clock::now(scope) + interval;
+        let machine = TickerMachine { op: op, interval: interval,
+                                      deadline: deadline, rx: rx,
+                                      marker: PhantomData };
+        (machine.response(), tx)
+    }
+}
+
+/// # Internal Helpers
+///
+impl<X, F: FnMut(&mut X)> TickerMachine<X, F> {
+    fn response<S>(self) -> Response<Self, S> {
+        let deadline = self.deadline;
+        Response::ok(self).deadline(deadline)
+    }
+}
+
+
+//--- Machine
+
+impl<X, F: FnMut(&mut X)> Machine for TickerMachine<X, F> {
+    type Context = X;
+    type Seed = Void;
+
+    fn create(seed: Self::Seed, _scope: &mut Scope<X>)
+              -> Response<Self, Void> {
+        match seed { }
+    }
+
+    fn ready(self, _events: EventSet, _scope: &mut Scope<X>)
+             -> Response<Self, Self::Seed> {
+        self.response()
+    }
+
+    fn spawned(self, _scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        self.response()
+    }
+
+    fn timeout(mut self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        (self.op)(&mut **scope);
+        let now = clock::now(scope);
+        self.deadline = self.deadline + self.interval;
+        if self.deadline <= now {
+            self.deadline = now + self.interval
+        }
+        self.response()
+    }
+
+    fn wakeup(self, _scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        if self.rx.triggered() {
+            Response::done()
+        }
+        else {
+            self.response()
+        }
+    }
+}
+
+
+//------------ ShutdownMachine -----------------------------------------------
+
+/// A machine shutting down the loop after a fixed time.
+///
+/// The machine has no socket either. When its deadline passes, it fires
+/// all the triggers it has been given, typically those returned when
+/// creating server machines, and then shuts down the loop so that its
+/// `run()` method returns.
+///
+/// This is useful for time-boxed services and, in particular, for
+/// integration tests that need a server running for a while. In most
+/// cases, [run_for()] is the easiest way to use the machine.
+///
+/// [run_for()]: fn.run_for.html
+pub struct ShutdownMachine<X> {
+    /// The time to shut down.
+    deadline: Time,
+
+    /// The triggers to fire before shutting down.
+    triggers: Vec<TriggerSender>,
+
+    /// Binding the context.
+    marker: PhantomData<X>
+}
+
+/// # Machine Creation
+///
+impl<X> ShutdownMachine<X> {
+    /// Creates a new machine.
+    ///
+    /// The machine will fire all of `triggers` and shut down the loop
+    /// `duration` after it was created.
+    pub fn new<S: GenericScope>(duration: Duration,
+                                triggers: Vec<TriggerSender>,
+                                scope: &mut S) -> Response<Self, Void> {
+        let deadline = clock::now(scope) + duration;
+        ShutdownMachine { deadline: deadline, triggers: triggers,
+                          marker: PhantomData }.response()
+    }
+}
+
+/// # Internal Helpers
+///
+impl<X> ShutdownMachine<X> {
+    fn response<S>(self) -> Response<Self, S> {
+        let deadline = self.deadline;
+        Response::ok(self).deadline(deadline)
+    }
+}
+
+
+//--- Machine
+
+impl<X> Machine for ShutdownMachine<X> {
+    type Context = X;
+    type Seed = Void;
+
+    fn create(seed: Self::Seed, _scope: &mut Scope<X>)
+              -> Response<Self, Void> {
+        match seed { }
+    }
+
+    fn ready(self, _events: EventSet, _scope: &mut Scope<X>)
+             -> Response<Self, Self::Seed> {
+        self.response()
+    }
+
+    fn spawned(self, _scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        self.response()
+    }
+
+    fn timeout(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        for trigger in &self.triggers {
+            if let Err(err) = trigger.trigger() {
+                debug!("shutdown trigger failed: {:?}", err);
+            }
+        }
+        scope.shutdown_loop();
+        Response::done()
+    }
+
+    fn wakeup(self, _scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        self.response()
+    }
+}
+
+
+//------------ run_for -------------------------------------------------------
+
+/// Runs a loop for a fixed time.
+///
+/// Adds a [ShutdownMachine] for `duration` and `triggers` to the loop
+/// creator `lc` and then runs the loop with `context`. Since the loop’s
+/// machine type is fixed, `wrap` has to turn the shutdown machine into
+/// it, for instance `Compose2::B` if the loop uses `Compose2`.
+///
+/// The function returns once the loop has been shut down.
+///
+/// [ShutdownMachine]: struct.ShutdownMachine.html
+pub fn run_for<M, F>(mut lc: LoopCreator<M>, context: M::Context,
+                     duration: Duration, triggers: Vec<TriggerSender>,
+                     wrap: F) -> Result<()>
+               where M: Machine, F: FnOnce(ShutdownMachine<M::Context>) -> M {
+    let res = lc.add_machine_with(|scope| {
+        ShutdownMachine::new(duration, triggers, scope).map_self(wrap)
+    });
+    if res.is_err() {
+        return Err(Error::NoSlabSpace)
+    }
+    Ok(try!(lc.run(context)))
+}
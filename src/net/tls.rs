@@ -0,0 +1,1010 @@
+//! Encrypted and combined machines generic over the TLS backend.
+//!
+//! All machines in this module take the TLS backend as their type
+//! argument `B`. It picks the socket types and the client configuration
+//! through the [TlsBackend] trait. This way, code can be written once for
+//! all backends. If you only ever use one backend, the modules for the
+//! specific backends, such as [openssl], provide type aliases with the
+//! backend filled in.
+//!
+//! The composition types for machines using both encrypted and other
+//! sockets are defined here, too, since they don’t depend on the backend.
+//!
+//! [TlsBackend]: ../../sockets/tls/trait.TlsBackend.html
+//! [openssl]: ../openssl/index.html
+
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::time::Duration;
+use rotor::{EventSet, GenericScope, Machine, Response, Scope, Void};
+use rotor::mio::tcp::{TcpListener, TcpStream};
+use rotor::mio::udp::UdpSocket;
+use ::sockets::tls::TlsBackend;
+use super::machines::{ServerConfig, ServerMachine, TransportMachine};
+use super::clear::{TcpServer, TcpTransport, UdpTransport};
+use ::compose::{Compose2, Compose3};
+use ::handlers::{AcceptHandler, RequestHandler, TransportHandler};
+use ::request::{ConnectMachine, RequestMachine, RequestMachineConfig,
+                RequestSeed, SeedFactory, TranslateError, Transport};
+use ::utils::ResponseExt;
+use ::sync::{DuctSender, TriggerSender};
+
+//============ Transport Machines ============================================
+
+//------------ TlsTransport --------------------------------------------------
+
+pub struct TlsTransport<X, B, H>(TransportMachine<X, B::Stream, H>)
+           where B: TlsBackend,
+                 H: TransportHandler<B::Stream>;
+
+impl<X, B, H> TlsTransport<X, B, H>
+           where B: TlsBackend, H: TransportHandler<B::Stream> {
+    pub fn new<S: GenericScope>(sock: B::Stream, seed: H::Seed,
+                                scope: &mut S) -> Response<Self, Void> {
+        TransportMachine::new(sock, seed, scope).map_self(TlsTransport)
+    }
+}
+
+impl<X, B, H> Machine for TlsTransport<X, B, H>
+           where B: TlsBackend, H: TransportHandler<B::Stream> {
+    type Context = X;
+    type Seed = (B::Stream, H::Seed);
+
+    wrapped_machine!(TransportMachine, TlsTransport);
+}
+
+impl<X, B, H> ConnectMachine for TlsTransport<X, B, H>
+           where B: TlsBackend, H: TransportHandler<B::Stream> {
+    fn create_connecting(seed: Self::Seed, timeout: Duration,
+                         scope: &mut Scope<X>) -> Response<Self, Void> {
+        TransportMachine::create_connecting(seed, timeout, scope)
+                         .map_self(TlsTransport)
+    }
+}
+
+
+//------------ StartTlsTransport ---------------------------------------------
+
+pub struct StartTlsTransport<X, B, H>(TransportMachine<X, B::StartTlsStream,
+                                                       H>)
+           where B: TlsBackend,
+                 H: TransportHandler<B::StartTlsStream>;
+
+impl<X, B, H> StartTlsTransport<X, B, H>
+           where B: TlsBackend, H: TransportHandler<B::StartTlsStream> {
+    pub fn new<S: GenericScope>(sock: B::StartTlsStream, seed: H::Seed,
+                                scope: &mut S) -> Response<Self, Void> {
+        TransportMachine::new(sock, seed, scope).map_self(StartTlsTransport)
+    }
+}
+
+impl<X, B, H> Machine for StartTlsTransport<X, B, H>
+           where B: TlsBackend, H: TransportHandler<B::StartTlsStream> {
+    type Context = X;
+    type Seed = (B::StartTlsStream, H::Seed);
+
+    wrapped_machine!(TransportMachine, StartTlsTransport);
+}
+
+impl<X, B, H> ConnectMachine for StartTlsTransport<X, B, H>
+           where B: TlsBackend, H: TransportHandler<B::StartTlsStream> {
+    fn create_connecting(seed: Self::Seed, timeout: Duration,
+                         scope: &mut Scope<X>) -> Response<Self, Void> {
+        TransportMachine::create_connecting(seed, timeout, scope)
+                         .map_self(StartTlsTransport)
+    }
+}
+
+
+//------------ TlsTcpTransport -----------------------------------------------
+
+pub struct TlsTcpTransport<X, B, SH, CH>(TlsTcp<TlsTransport<X, B, SH>,
+                                               TcpTransport<X, CH>>)
+           where B: TlsBackend,
+                 SH: TransportHandler<B::Stream>,
+                 CH: TransportHandler<TcpStream>;
+
+impl<X, B: TlsBackend, SH, CH> TlsTcpTransport<X, B, SH, CH>
+                where SH: TransportHandler<B::Stream>,
+                      CH: TransportHandler<TcpStream> {
+    pub fn new_tls<S: GenericScope>(sock: B::Stream, seed: SH::Seed,
+                                    scope: &mut S) -> Response<Self, Void> {
+        TlsTransport::<X, B, SH>::new(sock, seed, scope)
+                                .map_self(TlsTcpTransport::from)
+    }
+
+    pub fn new_tcp<S: GenericScope>(sock: TcpStream, seed: CH::Seed,
+                                    scope: &mut S) -> Response<Self, Void> {
+        TcpTransport::new(sock, seed, scope).map_self(TlsTcpTransport::from)
+    }
+}
+
+
+//--- From
+
+impl<X, B, SH, CH> From<TlsTransport<X, B, SH>>
+                   for TlsTcpTransport<X, B, SH, CH>
+                where B: TlsBackend,
+                      SH: TransportHandler<B::Stream>,
+                      CH: TransportHandler<TcpStream> {
+    fn from(tls: TlsTransport<X, B, SH>) -> Self {
+        TlsTcpTransport(TlsTcp::Tls(tls))
+    }
+}
+
+impl<X, B, SH, CH> From<TcpTransport<X, CH>>
+                   for TlsTcpTransport<X, B, SH, CH>
+                where B: TlsBackend,
+                      SH: TransportHandler<B::Stream>,
+                      CH: TransportHandler<TcpStream> {
+    fn from(tcp: TcpTransport<X, CH>) -> Self {
+        TlsTcpTransport(TlsTcp::Tcp(tcp))
+    }
+}
+
+
+//--- Machine
+
+impl<X, B: TlsBackend, SH, CH> Machine for TlsTcpTransport<X, B, SH, CH>
+                where SH: TransportHandler<B::Stream>,
+                      CH: TransportHandler<TcpStream> {
+    type Context = X;
+    type Seed = TlsTcp<<TlsTransport<X, B, SH> as Machine>::Seed,
+                        <TcpTransport<X, CH> as Machine>::Seed>;
+
+    fn create(seed: Self::Seed, scope: &mut Scope<X>)
+              -> Response<Self, Void> {
+        match seed {
+            TlsTcp::Tls(seed) => {
+                TlsTransport::<X, B, SH>::create(seed, scope)
+                                        .map_self(TlsTcpTransport::from)
+            }
+            TlsTcp::Tcp(seed) => {
+                TcpTransport::create(seed, scope)
+                             .map_self(TlsTcpTransport::from)
+            }
+        }
+    }
+
+    fn ready(self, events: EventSet, scope: &mut Scope<X>)
+             -> Response<Self, Self::Seed> {
+        match self.0 {
+            TlsTcp::Tls(tls) => {
+                tls.ready(events, scope)
+                   .map(TlsTcpTransport::from, TlsTcp::Tls)
+            }
+            TlsTcp::Tcp(tcp) => {
+                tcp.ready(events, scope)
+                   .map(TlsTcpTransport::from, TlsTcp::Tcp)
+            }
+        }
+    }
+
+    fn spawned(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        match self.0 {
+            TlsTcp::Tls(tls) => {
+                tls.spawned(scope).map(TlsTcpTransport::from, TlsTcp::Tls)
+            }
+            TlsTcp::Tcp(tcp) => {
+                tcp.spawned(scope).map(TlsTcpTransport::from, TlsTcp::Tcp)
+            }
+        }
+    }
+
+    fn timeout(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        match self.0 {
+            TlsTcp::Tls(tls) => {
+                tls.timeout(scope).map(TlsTcpTransport::from, TlsTcp::Tls)
+            }
+            TlsTcp::Tcp(tcp) => {
+                tcp.timeout(scope).map(TlsTcpTransport::from, TlsTcp::Tcp)
+            }
+        }
+    }
+    
+    fn wakeup(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        match self.0 {
+            TlsTcp::Tls(tls) => {
+                tls.wakeup(scope).map(TlsTcpTransport::from, TlsTcp::Tls)
+            }
+            TlsTcp::Tcp(tcp) => {
+                tcp.wakeup(scope).map(TlsTcpTransport::from, TlsTcp::Tcp)
+            }
+        }
+    }
+}
+
+impl<X, B, SH, CH> ConnectMachine for TlsTcpTransport<X, B, SH, CH>
+                where B: TlsBackend,
+                      SH: TransportHandler<B::Stream>,
+                      CH: TransportHandler<TcpStream> {
+    fn create_connecting(seed: Self::Seed, timeout: Duration,
+                         scope: &mut Scope<X>) -> Response<Self, Void> {
+        match seed {
+            TlsTcp::Tls(seed) => {
+                TlsTransport::<X, B, SH>::create_connecting(seed, timeout,
+                                                            scope)
+                                        .map_self(TlsTcpTransport::from)
+            }
+            TlsTcp::Tcp(seed) => {
+                TcpTransport::create_connecting(seed, timeout, scope)
+                             .map_self(TlsTcpTransport::from)
+            }
+        }
+    }
+}
+
+
+//------------ TlsUdpTransport -----------------------------------------------
+
+pub struct TlsUdpTransport<X, B, TH, UH>(TlsUdp<TlsTransport<X, B, TH>,
+                                             UdpTransport<X, UH>>)
+           where B: TlsBackend,
+                 TH: TransportHandler<B::Stream>,
+                 UH: TransportHandler<UdpSocket>;
+
+impl<X, B: TlsBackend, TH, UH> TlsUdpTransport<X, B, TH, UH>
+                where TH: TransportHandler<B::Stream>,
+                      UH: TransportHandler<UdpSocket> {
+    pub fn new_tls<S: GenericScope>(sock: B::Stream, seed: TH::Seed,
+                                    scope: &mut S) -> Response<Self, Void> {
+        TlsTransport::<X, B, TH>::new(sock, seed, scope)
+                                .map_self(TlsUdpTransport::from)
+    }
+
+    pub fn new_udp<S: GenericScope>(sock: UdpSocket, seed: UH::Seed,
+                                    scope: &mut S) -> Response<Self, Void> {
+        UdpTransport::new(sock, seed, scope).map_self(TlsUdpTransport::from)
+    }
+}
+
+
+//--- From
+
+impl<X, B, TH, UH> From<TlsTransport<X, B, TH>>
+                   for TlsUdpTransport<X, B, TH, UH>
+                where B: TlsBackend,
+                      TH: TransportHandler<B::Stream>,
+                      UH: TransportHandler<UdpSocket> {
+    fn from(tls: TlsTransport<X, B, TH>) -> Self {
+        TlsUdpTransport(TlsUdp::Tls(tls))
+    }
+}
+                
+impl<X, B, TH, UH> From<UdpTransport<X, UH>>
+                   for TlsUdpTransport<X, B, TH, UH>
+                where B: TlsBackend,
+                      TH: TransportHandler<B::Stream>,
+                      UH: TransportHandler<UdpSocket> {
+    fn from(udp: UdpTransport<X, UH>) -> Self {
+        TlsUdpTransport(TlsUdp::Udp(udp))
+    }
+}
+
+
+//--- Machine
+
+impl<X, B: TlsBackend, TH, UH> Machine for TlsUdpTransport<X, B, TH, UH>
+                where TH: TransportHandler<B::Stream>,
+                      UH: TransportHandler<UdpSocket> {
+    type Context = X;
+    type Seed = TlsUdp<(B::Stream, TH::Seed), (UdpSocket, UH::Seed)>;
+
+    fn create(seed: Self::Seed, scope: &mut Scope<X>)
+              -> Response<Self, Void> {
+        match seed {
+            TlsUdp::Tls(seed) => {
+                TlsTransport::<X, B, TH>::create(seed, scope)
+                                        .map_self(TlsUdpTransport::from)
+            }
+            TlsUdp::Udp(seed) => {
+                UdpTransport::create(seed, scope)
+                             .map_self(TlsUdpTransport::from)
+            }
+        }
+    }
+
+    fn ready(self, events: EventSet, scope: &mut Scope<X>)
+             -> Response<Self, Self::Seed> {
+        match self.0 {
+            TlsUdp::Tls(tls) => {
+                tls.ready(events, scope)
+                    .map(TlsUdpTransport::from, TlsUdp::Tls)
+            }
+            TlsUdp::Udp(udp) => {
+                udp.ready(events, scope)
+                    .map(TlsUdpTransport::from, TlsUdp::Udp)
+            }
+        }
+    }
+
+    fn spawned(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        match self.0 {
+            TlsUdp::Tls(tls) => {
+                tls.spawned(scope).map(TlsUdpTransport::from, TlsUdp::Tls)
+            }
+            TlsUdp::Udp(udp) => {
+                udp.spawned(scope).map(TlsUdpTransport::from, TlsUdp::Udp)
+            }
+        }
+    }
+
+    fn timeout(self, scope: &mut Scope<Self::Context>)
+               -> Response<Self, Self::Seed> {
+        match self.0 {
+            TlsUdp::Tls(tls) => {
+                tls.timeout(scope).map(TlsUdpTransport::from, TlsUdp::Tls)
+            }
+            TlsUdp::Udp(udp) => {
+                udp.timeout(scope).map(TlsUdpTransport::from, TlsUdp::Udp)
+            }
+        }
+    }
+
+    fn wakeup(self, scope: &mut Scope<Self::Context>)
+              -> Response<Self, Self::Seed> {
+        match self.0 {
+            TlsUdp::Tls(tls) => {
+                tls.wakeup(scope).map(TlsUdpTransport::from, TlsUdp::Tls)
+            }
+            TlsUdp::Udp(udp) => {
+                udp.wakeup(scope).map(TlsUdpTransport::from, TlsUdp::Udp)
+            }
+        }
+    }
+}
+
+impl<X, B, TH, UH> ConnectMachine for TlsUdpTransport<X, B, TH, UH>
+                where B: TlsBackend,
+                      TH: TransportHandler<B::Stream>,
+                      UH: TransportHandler<UdpSocket> {
+    fn create_connecting(seed: Self::Seed, timeout: Duration,
+                         scope: &mut Scope<X>) -> Response<Self, Void> {
+        match seed {
+            TlsUdp::Tls(seed) => {
+                TlsTransport::<X, B, TH>::create_connecting(seed, timeout,
+                                                            scope)
+                                        .map_self(TlsUdpTransport::from)
+            }
+            TlsUdp::Udp(seed) => {
+                UdpTransport::create(seed, scope)
+                             .map_self(TlsUdpTransport::from)
+            }
+        }
+    }
+}
+
+
+//============ Server Machines ===============================================
+
+//------------ TlsServer -----------------------------------------------------
+
+pub struct TlsServer<X, B, H>(ServerMachine<X, B::Listener, H>)
+           where B: TlsBackend,
+                 H: AcceptHandler<B::Stream>;
+
+impl<X, B, H> TlsServer<X, B, H>
+           where B: TlsBackend, H: AcceptHandler<B::Stream> {
+    pub fn new<S: GenericScope>(sock: B::Listener, handler: H, scope: &mut S)
+                                -> (Response<Self, Void>, TriggerSender) {
+        let (m, t) = ServerMachine::new(sock, handler, scope);
+        (m.map_self(TlsServer), t)
+    }
+
+    pub fn new_with_config<S>(sock: B::Listener, handler: H,
+                              config: ServerConfig, scope: &mut S)
+                              -> (Response<Self, Void>, TriggerSender)
+                           where S: GenericScope {
+        let (m, t) = ServerMachine::new_with_config(sock, handler, config,
+                                                    scope);
+        (m.map_self(TlsServer), t)
+    }
+}
+
+impl<X, B, H> Machine for TlsServer<X, B, H>
+           where B: TlsBackend, H: AcceptHandler<B::Stream> {
+    type Context = X;
+    type Seed = <ServerMachine<X, B::Listener, H> as Machine>::Seed;
+
+    wrapped_machine!(ServerMachine, TlsServer);
+}
+
+
+//------------ StartTlsServer -----------------------------------------------
+
+pub struct StartTlsServer<X, B, H>(ServerMachine<X, B::StartTlsListener, H>)
+           where B: TlsBackend,
+                 H: AcceptHandler<B::StartTlsStream>;
+
+impl<X, B, H> StartTlsServer<X, B, H>
+           where B: TlsBackend, H: AcceptHandler<B::StartTlsStream> {
+    pub fn new<S>(sock: B::StartTlsListener, handler: H, scope: &mut S)
+                  -> (Response<Self, Void>, TriggerSender)
+               where S: GenericScope {
+        let (m, t) = ServerMachine::new(sock, handler, scope);
+        (m.map_self(StartTlsServer), t)
+    }
+
+    pub fn new_with_config<S>(sock: B::StartTlsListener, handler: H,
+                              config: ServerConfig, scope: &mut S)
+                              -> (Response<Self, Void>, TriggerSender)
+                           where S: GenericScope {
+        let (m, t) = ServerMachine::new_with_config(sock, handler, config,
+                                                    scope);
+        (m.map_self(StartTlsServer), t)
+    }
+}
+
+impl<X, B, H> Machine for StartTlsServer<X, B, H>
+           where B: TlsBackend, H: AcceptHandler<B::StartTlsStream> {
+    type Context = X;
+    type Seed = <ServerMachine<X, B::StartTlsListener, H> as Machine>::Seed;
+
+    wrapped_machine!(ServerMachine, StartTlsServer);
+}
+
+
+//------------ TlsTcpServer -------------------------------------------------
+
+pub struct TlsTcpServer<X, B, SH, CH>(Compose2<TlsServer<X, B, SH>,
+                                            TcpServer<X, CH>>)
+    where B: TlsBackend,
+          SH: AcceptHandler<B::Stream>,
+          CH: AcceptHandler<TcpStream>;
+
+impl<X, B: TlsBackend, SH, CH> TlsTcpServer<X, B, SH, CH>
+                where SH: AcceptHandler<B::Stream>,
+                      CH: AcceptHandler<TcpStream> {
+    pub fn new_tls<S>(sock: B::Listener, handler: SH, scope: &mut S)
+                      -> (Response<Self, Void>, TriggerSender)
+                   where S: GenericScope {
+        let (m, t) = TlsServer::new(sock, handler, scope);
+        (m.map_self(|m| TlsTcpServer((Compose2::A(m)))), t)
+    }
+
+    pub fn new_tls_with_config<S>(sock: B::Listener, handler: SH,
+                                  config: ServerConfig, scope: &mut S)
+                                  -> (Response<Self, Void>, TriggerSender)
+                               where S: GenericScope {
+        let (m, t) = TlsServer::new_with_config(sock, handler, config,
+                                                scope);
+        (m.map_self(|m| TlsTcpServer(Compose2::A(m))), t)
+    }
+
+    pub fn new_tcp<S>(sock: TcpListener, handler: CH, scope: &mut S)
+                      -> (Response<Self, Void>, TriggerSender)
+                   where S: GenericScope {
+        let (m, t) = TcpServer::new(sock, handler, scope);
+        (m.map_self(|m| TlsTcpServer(Compose2::B(m))), t)
+    }
+
+    pub fn new_tcp_with_config<S>(sock: TcpListener, handler: CH,
+                                  config: ServerConfig, scope: &mut S)
+                                  -> (Response<Self, Void>, TriggerSender)
+                               where S: GenericScope {
+        let (m, t) = TcpServer::new_with_config(sock, handler, config,
+                                                scope);
+        (m.map_self(|m| TlsTcpServer(Compose2::B(m))), t)
+    }
+}
+
+impl<X, B: TlsBackend, SH, CH> Machine for TlsTcpServer<X, B, SH, CH>
+                where SH: AcceptHandler<B::Stream>,
+                      CH: AcceptHandler<TcpStream> {
+    type Context = X;
+    type Seed = <Compose2<TlsServer<X, B, SH>,
+                          TcpServer<X, CH>> as Machine>::Seed;
+
+    wrapped_machine!(Compose2, TlsTcpServer);
+}
+
+
+//------------ TlsUdpServer -------------------------------------------------
+
+pub struct TlsUdpServer<X, B, AH, UH>(Compose2<TlsServer<X, B, AH>,
+                                            UdpTransport<X, UH>>)
+           where B: TlsBackend,
+                 AH: AcceptHandler<B::Stream>,
+                 UH: TransportHandler<UdpSocket>;
+
+impl<X, B: TlsBackend, AH, UH> TlsUdpServer<X, B, AH, UH>
+                where AH: AcceptHandler<B::Stream>,
+                      UH: TransportHandler<UdpSocket> {
+    pub fn new_tls<S>(sock: B::Listener, handler: AH, scope: &mut S)
+                      -> (Response<Self, Void>, TriggerSender)
+                   where S: GenericScope {
+        let (m, t) = TlsServer::new(sock, handler, scope);
+        (m.map_self(|m| TlsUdpServer((Compose2::A(m)))), t)
+    }
+
+    pub fn new_tls_with_config<S>(sock: B::Listener, handler: AH,
+                                  config: ServerConfig, scope: &mut S)
+                                  -> (Response<Self, Void>, TriggerSender)
+                               where S: GenericScope {
+        let (m, t) = TlsServer::new_with_config(sock, handler, config,
+                                                scope);
+        (m.map_self(|m| TlsUdpServer(Compose2::A(m))), t)
+    }
+
+    pub fn new_udp<S: GenericScope>(sock: UdpSocket, seed: UH::Seed,
+                                    scope: &mut S) -> Response<Self, Void> {
+        UdpTransport::new(sock, seed, scope)
+                  .map_self(|m| TlsUdpServer(Compose2::B(m)))
+    }
+}
+                
+impl<X, B: TlsBackend, AH, UH> Machine for TlsUdpServer<X, B, AH, UH>
+                where AH: AcceptHandler<B::Stream>,
+                      UH: TransportHandler<UdpSocket> {
+    type Context = X;
+    type Seed = <Compose2<TlsServer<X, B, AH>,
+                          UdpTransport<X, UH>> as Machine>::Seed;
+
+    wrapped_machine!(Compose2, TlsUdpServer);
+}
+
+
+//------------ StartTlsUdpServer --------------------------------------------
+
+pub struct StartTlsUdpServer<X, B, AH, UH>(Compose2<StartTlsServer<X, B, AH>,
+                                            UdpTransport<X, UH>>)
+           where B: TlsBackend,
+                 AH: AcceptHandler<B::StartTlsStream>,
+                 UH: TransportHandler<UdpSocket>;
+
+impl<X, B: TlsBackend, AH, UH> StartTlsUdpServer<X, B, AH, UH>
+                where AH: AcceptHandler<B::StartTlsStream>,
+                      UH: TransportHandler<UdpSocket> {
+    pub fn new_tls<S>(sock: B::StartTlsListener, handler: AH, scope: &mut S)
+                      -> (Response<Self, Void>, TriggerSender)
+                   where S: GenericScope {
+        let (m, t) = StartTlsServer::new(sock, handler, scope);
+        (m.map_self(|m| StartTlsUdpServer((Compose2::A(m)))), t)
+    }
+
+    pub fn new_tls_with_config<S>(sock: B::StartTlsListener, handler: AH,
+                                  config: ServerConfig, scope: &mut S)
+                                  -> (Response<Self, Void>, TriggerSender)
+                               where S: GenericScope {
+        let (m, t) = StartTlsServer::new_with_config(sock, handler, config,
+                                                     scope);
+        (m.map_self(|m| StartTlsUdpServer(Compose2::A(m))), t)
+    }
+
+    pub fn new_udp<S: GenericScope>(sock: UdpSocket, seed: UH::Seed,
+                                    scope: &mut S) -> Response<Self, Void> {
+        UdpTransport::new(sock, seed, scope)
+                  .map_self(|m| StartTlsUdpServer(Compose2::B(m)))
+    }
+}
+                
+impl<X, B: TlsBackend, AH, UH> Machine for StartTlsUdpServer<X, B, AH, UH>
+                where AH: AcceptHandler<B::StartTlsStream>,
+                      UH: TransportHandler<UdpSocket> {
+    type Context = X;
+    type Seed = <Compose2<StartTlsServer<X, B, AH>,
+                          UdpTransport<X, UH>> as Machine>::Seed;
+
+    wrapped_machine!(Compose2, StartTlsUdpServer);
+}
+
+
+//------------ TlsTcpUdpServer -----------------------------------------------
+
+pub struct TlsTcpUdpServer<X, B, SH, CH, UH>(Compose3<TlsServer<X, B, SH>,
+                                                   TcpServer<X, CH>,
+                                                   UdpTransport<X, UH>>)
+    where B: TlsBackend,
+          SH: AcceptHandler<B::Stream>,
+          CH: AcceptHandler<TcpStream>,
+          UH: TransportHandler<UdpSocket>;
+
+impl<X, B: TlsBackend, SH, CH, UH> TlsTcpUdpServer<X, B, SH, CH, UH>
+                    where SH: AcceptHandler<B::Stream>,
+                          CH: AcceptHandler<TcpStream>,
+                          UH: TransportHandler<UdpSocket> {
+    pub fn new_tls<S>(sock: B::Listener, handler: SH, scope: &mut S)
+                      -> (Response<Self, Void>, TriggerSender)
+                   where S: GenericScope {
+        let (m, t) = TlsServer::new(sock, handler, scope);
+        (m.map_self(|m| TlsTcpUdpServer((Compose3::A(m)))), t)
+    }
+
+    pub fn new_tls_with_config<S>(sock: B::Listener, handler: SH,
+                                  config: ServerConfig, scope: &mut S)
+                                  -> (Response<Self, Void>, TriggerSender)
+                               where S: GenericScope {
+        let (m, t) = TlsServer::new_with_config(sock, handler, config,
+                                                scope);
+        (m.map_self(|m| TlsTcpUdpServer(Compose3::A(m))), t)
+    }
+
+    pub fn new_tcp<S>(sock: TcpListener, handler: CH, scope: &mut S)
+                      -> (Response<Self, Void>, TriggerSender)
+                   where S: GenericScope {
+        let (m, t) = TcpServer::new(sock, handler, scope);
+        (m.map_self(|m| TlsTcpUdpServer(Compose3::B(m))), t)
+    }
+
+    pub fn new_tcp_with_config<S>(sock: TcpListener, handler: CH,
+                                  config: ServerConfig, scope: &mut S)
+                                  -> (Response<Self, Void>, TriggerSender)
+                               where S: GenericScope {
+        let (m, t) = TcpServer::new_with_config(sock, handler, config,
+                                                scope);
+        (m.map_self(|m| TlsTcpUdpServer(Compose3::B(m))), t)
+    }
+
+    pub fn new_udp<S: GenericScope>(sock: UdpSocket, seed: UH::Seed,
+                                    scope: &mut S) -> Response<Self, Void> {
+        UdpTransport::new(sock, seed, scope)
+                  .map_self(|m| TlsTcpUdpServer(Compose3::C(m)))
+    }
+}
+
+impl<X, B, SH, CH, UH> Machine for TlsTcpUdpServer<X, B, SH, CH, UH>
+                    where B: TlsBackend,
+                          SH: AcceptHandler<B::Stream>,
+                          CH: AcceptHandler<TcpStream>,
+                          UH: TransportHandler<UdpSocket> {
+    type Context = X;
+    type Seed = <Compose3<TlsServer<X, B, SH>, TcpServer<X, CH>,
+                          UdpTransport<X, UH>> as Machine>::Seed;
+
+    wrapped_machine!(Compose3, TlsTcpUdpServer);
+}
+
+
+//============ Client Machines ===============================================
+
+//------------ TlsClient -----------------------------------------------------
+
+pub struct TlsClient<X, B, RH, TH>(RequestMachine<X, TlsTransport<X, B, TH>,
+                                                  RH, TlsFactory<B, TH::Seed>>)
+    where B: TlsBackend,
+          RH: RequestHandler<Output=(SocketAddr, TH::Seed)>,
+          TH: TransportHandler<B::Stream>;
+
+impl<X, B: TlsBackend, RH, TH> TlsClient<X, B, RH, TH>
+                where RH: RequestHandler<Output=(SocketAddr, TH::Seed)>,
+                      TH: TransportHandler<B::Stream> {
+    pub fn new<S>(handler: RH, config: B::ClientConfig, scope: &mut S)
+                  -> (Response<Self, Void>, DuctSender<RH::Request>)
+               where S: GenericScope {
+        let (m, tx) = RequestMachine::new(handler, TlsFactory::new(config),
+                                          scope);
+        (m.map_self(TlsClient), tx)
+    }
+
+    pub fn with_config<S>(handler: RH, config: B::ClientConfig,
+                          machine: RequestMachineConfig, scope: &mut S)
+                          -> (Response<Self, Void>, DuctSender<RH::Request>)
+                       where S: GenericScope {
+        let factory = TlsFactory::new(config);
+        let (m, tx) = RequestMachine::with_config(handler, factory, machine,
+                                                  scope);
+        (m.map_self(TlsClient), tx)
+    }
+}
+
+impl<X, B: TlsBackend, RH, TH> Machine for TlsClient<X, B, RH, TH>
+                where RH: RequestHandler<Output=(SocketAddr, TH::Seed)>,
+                      TH: TransportHandler<B::Stream> {
+    type Context = X;
+    type Seed = RequestSeed<(B::Stream, TH::Seed)>;
+
+    wrapped_machine!(RequestMachine, TlsClient);
+}
+
+
+//------------ StartTlsClient -----------------------------------------------
+
+pub struct StartTlsClient<X, B, RH, TH>(
+    RequestMachine<X, StartTlsTransport<X, B, TH>, RH,
+                   StartTlsFactory<B, TH::Seed>>
+)
+    where B: TlsBackend,
+          RH: RequestHandler<Output=(SocketAddr, TH::Seed)>,
+          TH: TransportHandler<B::StartTlsStream>;
+
+impl<X, B: TlsBackend, RH, TH> StartTlsClient<X, B, RH, TH>
+                where RH: RequestHandler<Output=(SocketAddr, TH::Seed)>,
+                      TH: TransportHandler<B::StartTlsStream> {
+    pub fn new<S>(handler: RH, config: B::ClientConfig, scope: &mut S)
+                  -> (Response<Self, Void>, DuctSender<RH::Request>)
+               where S: GenericScope {
+        let factory = StartTlsFactory::new(config);
+        let (m, tx) = RequestMachine::new(handler, factory, scope);
+        (m.map_self(StartTlsClient), tx)
+    }
+
+    pub fn with_config<S>(handler: RH, config: B::ClientConfig,
+                          machine: RequestMachineConfig, scope: &mut S)
+                          -> (Response<Self, Void>, DuctSender<RH::Request>)
+                       where S: GenericScope {
+        let factory = StartTlsFactory::new(config);
+        let (m, tx) = RequestMachine::with_config(handler, factory, machine,
+                                                  scope);
+        (m.map_self(StartTlsClient), tx)
+    }
+}
+
+impl<X, B: TlsBackend, RH, TH> Machine for StartTlsClient<X, B, RH, TH>
+                where RH: RequestHandler<Output=(SocketAddr, TH::Seed)>,
+                      TH: TransportHandler<B::StartTlsStream> {
+    type Context = X;
+    type Seed = RequestSeed<(B::StartTlsStream, TH::Seed)>;
+    wrapped_machine!(RequestMachine, StartTlsClient);
+}
+
+
+//------------ TlsTcpClient -------------------------------------------------
+
+pub struct TlsTcpClient<X, B, RH, SH, CH>(
+    RequestMachine<X, TlsTcpTransport<X, B, SH, CH>, RH,
+                   TlsTcpFactory<B, SH::Seed, CH::Seed>>
+) where B: TlsBackend,
+        RH: RequestHandler<Output=TlsTcp<(SocketAddr, SH::Seed),
+                                         (SocketAddr, CH::Seed)>>,
+        SH: TransportHandler<B::Stream>,
+        CH: TransportHandler<TcpStream>;
+
+impl<X, B: TlsBackend, RH, SH, CH> TlsTcpClient<X, B, RH, SH, CH>
+            where RH: RequestHandler<Output=TlsTcp<(SocketAddr, SH::Seed),
+                                                   (SocketAddr, CH::Seed)>>,
+                  SH: TransportHandler<B::Stream>,
+                  CH: TransportHandler<TcpStream> {
+    pub fn new<S>(handler: RH, config: B::ClientConfig, scope: &mut S)
+                  -> (Response<Self, Void>, DuctSender<RH::Request>)
+               where S: GenericScope {
+        let (m, tx) = RequestMachine::new(handler, TlsTcpFactory::new(config),
+                                          scope);
+        (m.map_self(TlsTcpClient), tx)
+    }
+
+    pub fn with_config<S>(handler: RH, config: B::ClientConfig,
+                          machine: RequestMachineConfig, scope: &mut S)
+                          -> (Response<Self, Void>, DuctSender<RH::Request>)
+                       where S: GenericScope {
+        let (m, tx) = RequestMachine::with_config(handler,
+                                                  TlsTcpFactory::new(config),
+                                                  machine, scope);
+        (m.map_self(TlsTcpClient), tx)
+    }
+}
+
+impl<X, B: TlsBackend, RH, SH, CH> Machine for TlsTcpClient<X, B, RH, SH, CH>
+            where RH: RequestHandler<Output=TlsTcp<(SocketAddr, SH::Seed),
+                                                   (SocketAddr, CH::Seed)>>,
+                  SH: TransportHandler<B::Stream>,
+                  CH: TransportHandler<TcpStream> {
+    type Context = X;
+    type Seed = RequestSeed<TlsTcp<(B::Stream, SH::Seed),
+                                   (TcpStream, CH::Seed)>>;
+
+    wrapped_machine!(RequestMachine, TlsTcpClient);
+}
+
+
+//------------ TlsUdpClient -------------------------------------------------
+
+pub struct TlsUdpClient<X, B, RH, TH, UH>(
+    RequestMachine<X, TlsUdpTransport<X, B, TH, UH>, RH,
+                   TlsUdpFactory<B, TH::Seed, UH::Seed>>
+) where B: TlsBackend,
+        RH: RequestHandler<Output=TlsUdp<(SocketAddr, TH::Seed),
+                                         (SocketAddr, UH::Seed)>>,
+        TH: TransportHandler<B::Stream>,
+        UH: TransportHandler<UdpSocket>;
+
+impl<X, B: TlsBackend, RH, TH, UH> TlsUdpClient<X, B, RH, TH, UH>
+          where RH: RequestHandler<Output=TlsUdp<(SocketAddr, TH::Seed),
+                                                 (SocketAddr, UH::Seed)>>,
+                TH: TransportHandler<B::Stream>,
+                UH: TransportHandler<UdpSocket> {
+    pub fn new<S>(handler: RH, config: B::ClientConfig, scope: &mut S)
+                  -> (Response<Self, Void>, DuctSender<RH::Request>)
+               where S: GenericScope {
+        let (m, tx) = RequestMachine::new(handler, TlsUdpFactory::new(config),
+                                          scope);
+        (m.map_self(TlsUdpClient), tx)
+    }
+
+    pub fn with_config<S>(handler: RH, config: B::ClientConfig,
+                          machine: RequestMachineConfig, scope: &mut S)
+                          -> (Response<Self, Void>, DuctSender<RH::Request>)
+                       where S: GenericScope {
+        let (m, tx) = RequestMachine::with_config(handler,
+                                                  TlsUdpFactory::new(config),
+                                                  machine, scope);
+        (m.map_self(TlsUdpClient), tx)
+    }
+}
+
+impl<X, B: TlsBackend, RH, TH, UH> Machine for TlsUdpClient<X, B, RH, TH, UH>
+          where RH: RequestHandler<Output=TlsUdp<(SocketAddr, TH::Seed),
+                                                 (SocketAddr, UH::Seed)>>,
+                TH: TransportHandler<B::Stream>,
+                UH: TransportHandler<UdpSocket> {
+    type Context = X;
+    type Seed = RequestSeed<TlsUdp<(B::Stream, TH::Seed),
+                                   (UdpSocket, UH::Seed)>>;
+
+    wrapped_machine!(RequestMachine, TlsUdpClient);
+}
+
+
+//============ Socket Factories ==============================================
+
+//------------ TlsFactory ----------------------------------------------------
+
+struct TlsFactory<B: TlsBackend, S> {
+    config: B::ClientConfig,
+    marker: PhantomData<S>
+}
+
+impl<B: TlsBackend, S> TlsFactory<B, S> {
+    fn new(config: B::ClientConfig) -> Self {
+        TlsFactory { config: config, marker: PhantomData }
+    }
+}
+
+impl<B: TlsBackend, S> SeedFactory<(SocketAddr, S), (B::Stream, S)>
+        for TlsFactory<B, S> {
+    fn translate(&self, output: (SocketAddr, S))
+                 -> Result<(B::Stream, S), TranslateError<(SocketAddr, S)>> {
+        let (addr, seed) = output;
+        match B::connect(&addr, &self.config) {
+            Ok(sock) => Ok((sock, seed)),
+            Err(err) => {
+                Err(TranslateError::new((addr, seed), err.into(),
+                                        Transport::Tls, addr))
+            }
+        }
+    }
+}
+
+
+//------------ StartTlsFactory -----------------------------------------------
+
+struct StartTlsFactory<B: TlsBackend, S> {
+    config: B::ClientConfig,
+    marker: PhantomData<S>
+}
+
+impl<B: TlsBackend, S> StartTlsFactory<B, S> {
+    fn new(config: B::ClientConfig) -> Self {
+        StartTlsFactory { config: config, marker: PhantomData }
+    }
+}
+
+impl<B: TlsBackend, S> SeedFactory<(SocketAddr, S), (B::StartTlsStream, S)>
+        for StartTlsFactory<B, S> {
+    fn translate(&self, output: (SocketAddr, S))
+                 -> Result<(B::StartTlsStream, S),
+                           TranslateError<(SocketAddr, S)>> {
+        let (addr, seed) = output;
+        match B::connect_starttls(&addr, &self.config) {
+            Ok(sock) => Ok((sock, seed)),
+            Err(err) => {
+                Err(TranslateError::new((addr, seed), err.into(),
+                                        Transport::StartTls, addr))
+            }
+        }
+    }
+}
+
+
+//------------ TlsTcpFactory -------------------------------------------------
+
+struct TlsTcpFactory<B: TlsBackend, S, C> {
+    config: B::ClientConfig,
+    marker: PhantomData<(S, C)>
+}
+
+impl<B: TlsBackend, S, C> TlsTcpFactory<B, S, C> {
+    fn new(config: B::ClientConfig) -> Self {
+        TlsTcpFactory { config: config, marker: PhantomData }
+    }
+}
+
+impl<B: TlsBackend, S, C> SeedFactory<TlsTcp<(SocketAddr, S), (SocketAddr, C)>,
+                       TlsTcp<(B::Stream, S), (TcpStream, C)>>
+           for TlsTcpFactory<B, S, C> {
+    fn translate(&self, output: TlsTcp<(SocketAddr, S), (SocketAddr, C)>)
+                 -> Result<TlsTcp<(B::Stream, S), (TcpStream, C)>,
+                           TranslateError<TlsTcp<(SocketAddr, S),
+                                                 (SocketAddr, C)>>> {
+        use self::TlsTcp::*;
+
+        match output {
+            Tls((addr, seed)) => {
+                match B::connect(&addr, &self.config) {
+                    Ok(sock) => Ok(Tls((sock, seed))),
+                    Err(err) => {
+                        Err(TranslateError::new(Tls((addr, seed)),
+                                                err.into(), Transport::Tls,
+                                                addr))
+                    }
+                }
+            }
+            Tcp((addr, seed)) => {
+                match TcpStream::connect(&addr) {
+                    Ok(sock) => Ok(Tcp((sock, seed))),
+                    Err(err) => {
+                        Err(TranslateError::new(Tcp((addr, seed)),
+                                                err.into(), Transport::Tcp,
+                                                addr))
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+//------------ TlsUdpFactory -------------------------------------------------
+
+struct TlsUdpFactory<B: TlsBackend, T, U> {
+    config: B::ClientConfig,
+    marker: PhantomData<(T, U)>
+}
+
+impl<B: TlsBackend, T, U> TlsUdpFactory<B, T, U> {
+    fn new(config: B::ClientConfig) -> Self {
+        TlsUdpFactory { config: config, marker: PhantomData }
+    }
+}
+
+impl<B: TlsBackend, T, U> SeedFactory<TlsUdp<(SocketAddr, T), (SocketAddr, U)>,
+                       TlsUdp<(B::Stream, T), (UdpSocket, U)>>
+           for TlsUdpFactory<B, T, U> {
+    fn translate(&self, output: TlsUdp<(SocketAddr, T), (SocketAddr, U)>)
+                 -> Result<TlsUdp<(B::Stream, T), (UdpSocket, U)>,
+                           TranslateError<TlsUdp<(SocketAddr, T),
+                                                 (SocketAddr, U)>>> {
+        use self::TlsUdp::*;
+
+        match output {
+            Tls((addr, seed)) => {
+                match B::connect(&addr, &self.config) {
+                    Ok(sock) => Ok(Tls((sock, seed))),
+                    Err(err) => {
+                        Err(TranslateError::new(Tls((addr, seed)),
+                                                err.into(), Transport::Tls,
+                                                addr))
+                    }
+                }
+            }
+            Udp((addr, seed)) => {
+                match UdpSocket::bound(&addr) {
+                    Ok(sock) => Ok(Udp((sock, seed))),
+                    Err(err) => {
+                        Err(TranslateError::new(Udp((addr, seed)),
+                                                err.into(), Transport::Udp,
+                                                addr))
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+//============ Composition Types =============================================
+
+pub enum TlsTcp<S, C> {
+    Tls(S),
+    Tcp(C)
+}
+
+pub enum TlsUdp<T, U> {
+    Tls(T),
+    Udp(U)
+}
+
+pub enum TlsTcpOrUdp<S, C, U> {
+    Tls(S),
+    Tcp(C),
+    Udp(U)
+}
@@ -0,0 +1,859 @@
+//! A transport handler for the WebSocket protocol.
+//!
+//! WebSocket connections start out as an HTTP/1.1 request asking for an
+//! upgrade of the connection. Reading and judging that request is left to
+//! whoever handles HTTP. Once it decided to go ahead, it extracts the
+//! relevant information from the request into an [Upgrade] value, most
+//! conveniently through [Upgrade::from_request()]. Together with a
+//! [WebSocketHandler], this is the seed for the [WebSocket] transport
+//! handler.
+//!
+//! The [WebSocket] handler answers the upgrade request and then takes care
+//! of the framing defined in [RFC 6455]: it reassembles fragmented
+//! messages, unmasks incoming frames, answers pings, and performs the
+//! closing handshake. Complete text and binary messages are handed to the
+//! [WebSocketHandler] which can send messages of its own through the
+//! [Outgoing] value it is given.
+//!
+//! If the HTTP side has already read data beyond the upgrade request,
+//! such as frames the client sent right away, it has to hand that data on
+//! via [Upgrade::leftover()]. [Upgrade::from_buffer()] takes care of this
+//! for a buffer starting with the request.
+//!
+//! Outgoing messages are kept in a [WriteBuffer]. If the peer doesn’t
+//! read them fast enough, for instance while flooding the connection with
+//! pings that all need answering, the handler stops reading until most of
+//! the pending output has been written.
+//!
+//! The handler implements the server side of the protocol only.
+//!
+//! [RFC 6455]: https://tools.ietf.org/html/rfc6455
+//! [Upgrade]: struct.Upgrade.html
+//! [Upgrade::from_buffer()]: struct.Upgrade.html#method.from_buffer
+//! [Upgrade::from_request()]: struct.Upgrade.html#method.from_request
+//! [Upgrade::leftover()]: struct.Upgrade.html#method.leftover
+//! [WebSocket]: struct.WebSocket.html
+//! [WebSocketHandler]: trait.WebSocketHandler.html
+//! [Outgoing]: struct.Outgoing.html
+//! [WriteBuffer]: ../../utils/struct.WriteBuffer.html
+
+use std::{io, str};
+use base64;
+use rotor::Notifier;
+use sha1::Sha1;
+use ::error::Error;
+use ::handlers::TransportHandler;
+use ::next::Next;
+use ::sockets::Stream;
+use ::utils::WriteBuffer;
+use super::{READ_CHUNK, has_token};
+
+
+/// The default limit for the size of an incoming message.
+pub const DEFAULT_MESSAGE_LIMIT: usize = 16 * 1024 * 1024;
+
+/// The GUID appended to the key for calculating the accept value.
+const ACCEPT_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The amount of pending output at which the handler stops reading.
+const WRITE_HIGH: usize = 64 * 1024;
+
+/// The amount of pending output at which the handler reads again.
+const WRITE_LOW: usize = 16 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// Close code for a normal closure.
+pub const CLOSE_NORMAL: u16 = 1000;
+
+/// Close code for a protocol error.
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+/// Close code for a text message that isn’t valid UTF-8.
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+
+/// Close code for a message that is too big.
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+
+//------------ Upgrade -------------------------------------------------------
+
+/// The information from an HTTP upgrade request needed by WebSocket.
+#[derive(Clone, Debug)]
+pub struct Upgrade {
+    key: String,
+    limit: usize,
+    leftover: Vec<u8>,
+}
+
+impl Upgrade {
+    /// Creates a value from the `Sec-WebSocket-Key` header’s value.
+    ///
+    /// Returns `None` if the key isn’t a base64 encoded 16 byte value as
+    /// required by the protocol.
+    pub fn new(key: &str) -> Option<Self> {
+        let key = key.trim();
+        match base64::decode(key) {
+            Ok(ref value) if value.len() == 16 => { }
+            _ => return None
+        }
+        Some(Upgrade { key: key.into(), limit: DEFAULT_MESSAGE_LIMIT,
+                       leftover: Vec::new() })
+    }
+
+    /// Creates a value from the header section of an upgrade request.
+    ///
+    /// The `head` argument should contain the request line and all
+    /// header lines. Returns `None` if this isn’t a valid WebSocket
+    /// upgrade request.
+    pub fn from_request(head: &[u8]) -> Option<Self> {
+        let head = match str::from_utf8(head) {
+            Ok(head) => head,
+            Err(_) => return None
+        };
+        let mut key = None;
+        let mut upgrade = false;
+        let mut connection = false;
+        let mut version = false;
+        for line in head.lines().skip(1) {
+            let mut parts = line.splitn(2, ':');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name.trim(), value.trim()),
+                _ => continue
+            };
+            if name.eq_ignore_ascii_case("upgrade") {
+                upgrade = has_token(value, "websocket");
+            }
+            else if name.eq_ignore_ascii_case("connection") {
+                connection = has_token(value, "upgrade");
+            }
+            else if name.eq_ignore_ascii_case("sec-websocket-version") {
+                version = value == "13";
+            }
+            else if name.eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value);
+            }
+        }
+        match key {
+            Some(key) if upgrade && connection && version => {
+                Upgrade::new(key)
+            }
+            _ => None
+        }
+    }
+
+    /// Creates a value from a buffer starting with an upgrade request.
+    ///
+    /// The header section of the request ends with the first empty line.
+    /// Everything after it is kept as [leftover](#method.leftover) data.
+    /// Returns `None` if the buffer doesn’t contain a complete header
+    /// section or this isn’t a valid WebSocket upgrade request.
+    pub fn from_buffer(buf: &[u8]) -> Option<Self> {
+        let end = match buf.windows(4).position(|x| x == b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None => return None
+        };
+        Upgrade::from_request(&buf[..end]).map(|upgrade| {
+            upgrade.leftover(&buf[end..])
+        })
+    }
+
+    /// Adds data read after the upgrade request.
+    ///
+    /// Clients may send frames right after the request without waiting
+    /// for the response. If these have already been read, they need to be
+    /// given to the WebSocket handler through this method or they are
+    /// lost. The data is processed as soon as the connection has been
+    /// established.
+    pub fn leftover(mut self, data: &[u8]) -> Self {
+        self.leftover.extend_from_slice(data);
+        self
+    }
+
+    /// Sets the limit for the size of incoming messages.
+    ///
+    /// If a message is larger, the connection is closed. The default is
+    /// [DEFAULT_MESSAGE_LIMIT](constant.DEFAULT_MESSAGE_LIMIT.html).
+    pub fn message_limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Returns the value for the `Sec-WebSocket-Accept` header.
+    pub fn accept_key(&self) -> String {
+        let mut sha1 = Sha1::new();
+        sha1.update(self.key.as_bytes());
+        sha1.update(ACCEPT_GUID.as_bytes());
+        base64::encode(&sha1.digest().bytes())
+    }
+
+    /// Returns the response to the upgrade request.
+    fn response(&self) -> String {
+        format!("HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                self.accept_key())
+    }
+}
+
+
+//------------ Message -------------------------------------------------------
+
+/// A complete WebSocket message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+
+//------------ WebSocketHandler ----------------------------------------------
+
+/// The trait for handling the messages of a WebSocket connection.
+pub trait WebSocketHandler {
+    /// Called when the connection has been established.
+    ///
+    /// The notifier can be used to have [wakeup()](#method.wakeup) called
+    /// later. The default implementation does nothing.
+    fn start(&mut self, notifier: Notifier, out: &mut Outgoing) {
+        let _ = (notifier, out);
+    }
+
+    /// Called for every complete message received.
+    fn message(&mut self, msg: Message, out: &mut Outgoing);
+
+    /// Called when the connection was woken up via its notifier.
+    ///
+    /// The default implementation does nothing.
+    fn wakeup(&mut self, out: &mut Outgoing) {
+        let _ = out;
+    }
+
+    /// Called once when the connection is closing.
+    ///
+    /// The `code` argument contains the close code if there is one. It is
+    /// `None` if the connection was lost without a closing handshake.
+    /// The default implementation does nothing.
+    fn closed(&mut self, code: Option<u16>) {
+        let _ = code;
+    }
+}
+
+
+//------------ Outgoing ------------------------------------------------------
+
+/// The outgoing side of a WebSocket connection.
+///
+/// Messages are framed right away and queued for writing. Once a close
+/// frame has been sent, further messages are quietly dropped.
+#[derive(Debug)]
+pub struct Outgoing {
+    buf: WriteBuffer,
+    close_sent: bool,
+}
+
+impl Outgoing {
+    /// Returns whether so much output is pending that reading should stop.
+    fn is_full(&self) -> bool {
+        self.buf.is_paused()
+    }
+
+    /// Queues a text message.
+    pub fn text(&mut self, text: &str) {
+        self.frame(OP_TEXT, text.as_bytes())
+    }
+
+    /// Queues a binary message.
+    pub fn binary(&mut self, data: &[u8]) {
+        self.frame(OP_BINARY, data)
+    }
+
+    /// Queues a ping.
+    ///
+    /// # Panics
+    ///
+    /// The method panics if `data` is longer than 125 bytes.
+    pub fn ping(&mut self, data: &[u8]) {
+        assert!(data.len() <= 125, "ping payload too long");
+        self.frame(OP_PING, data)
+    }
+
+    /// Starts the closing handshake with the given close code.
+    pub fn close(&mut self, code: u16) {
+        let payload = [(code >> 8) as u8, code as u8];
+        self.frame(OP_CLOSE, &payload);
+        self.close_sent = true;
+    }
+
+    /// Returns whether a close frame has been queued.
+    pub fn is_closed(&self) -> bool {
+        self.close_sent
+    }
+
+    fn frame(&mut self, opcode: u8, payload: &[u8]) {
+        if !self.close_sent {
+            let mut frame = Vec::with_capacity(payload.len() + 10);
+            encode_frame(&mut frame, opcode, payload);
+            self.push(&frame)
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        // Without a write budget, pushing never fails. It merely pauses
+        // the buffer.
+        let _ = self.buf.push(data);
+    }
+}
+
+impl Default for Outgoing {
+    fn default() -> Self {
+        Outgoing {
+            buf: WriteBuffer::new(WRITE_HIGH, WRITE_LOW),
+            close_sent: false,
+        }
+    }
+}
+
+
+//------------ WebSocket -----------------------------------------------------
+
+/// The transport handler for a WebSocket connection.
+///
+/// The handler’s seed is a pair of the [Upgrade] describing the upgrade
+/// request and the [WebSocketHandler] processing the messages.
+///
+/// [Upgrade]: struct.Upgrade.html
+/// [WebSocketHandler]: trait.WebSocketHandler.html
+pub struct WebSocket<H: WebSocketHandler> {
+    handler: H,
+    out: Outgoing,
+    rbuf: Vec<u8>,
+    partial: Option<(u8, Vec<u8>)>,
+    limit: usize,
+    close_received: bool,
+    failed: bool,
+}
+
+impl<H: WebSocketHandler> WebSocket<H> {
+    /// Creates a new value answering `upgrade`.
+    ///
+    /// Any leftover data of the upgrade becomes the start of the read
+    /// buffer.
+    fn new(upgrade: Upgrade, handler: H) -> Self {
+        let mut out = Outgoing::default();
+        out.push(upgrade.response().as_bytes());
+        WebSocket {
+            handler: handler,
+            out: out,
+            rbuf: upgrade.leftover,
+            partial: None,
+            limit: upgrade.limit,
+            close_received: false,
+            failed: false,
+        }
+    }
+
+    /// Returns whether we still care about incoming data.
+    fn is_reading(&self) -> bool {
+        !self.close_received && !self.failed
+    }
+
+    /// Returns whether we want to process incoming data right now.
+    ///
+    /// We don’t while too much output is waiting to be written.
+    fn wants_read(&self) -> bool {
+        self.is_reading() && !self.out.is_full()
+    }
+
+    /// Returns what should happen next.
+    fn next(self) -> Next<Self> {
+        if !self.out.buf.is_empty() {
+            if self.wants_read() { Next::read_and_write(self) }
+            else { Next::write(self) }
+        }
+        else if self.is_reading() {
+            Next::read(self)
+        }
+        else {
+            Next::remove()
+        }
+    }
+
+    /// Ends the connection with a protocol error.
+    fn fail(&mut self, code: u16) {
+        self.out.close(code);
+        self.failed = true;
+        self.handler.closed(Some(code));
+    }
+
+    /// Processes all complete frames in the read buffer.
+    ///
+    /// Stops early if too much output is waiting to be written. The
+    /// remaining frames are processed once it has been.
+    fn process(&mut self) {
+        while self.wants_read() {
+            let (frame, used) = match parse_frame(&self.rbuf, self.limit) {
+                Ok(Some(res)) => res,
+                Ok(None) => return,
+                Err(code) => return self.fail(code)
+            };
+            self.rbuf.drain(..used);
+            match frame.opcode {
+                OP_CONTINUATION => {
+                    let (opcode, mut data) = match self.partial.take() {
+                        Some(partial) => partial,
+                        None => return self.fail(CLOSE_PROTOCOL_ERROR)
+                    };
+                    data.extend_from_slice(&frame.payload);
+                    if data.len() > self.limit {
+                        return self.fail(CLOSE_TOO_BIG)
+                    }
+                    if frame.fin { self.deliver(opcode, data) }
+                    else { self.partial = Some((opcode, data)) }
+                }
+                OP_TEXT | OP_BINARY => {
+                    if self.partial.is_some() {
+                        return self.fail(CLOSE_PROTOCOL_ERROR)
+                    }
+                    if frame.fin { self.deliver(frame.opcode, frame.payload) }
+                    else { self.partial = Some((frame.opcode,
+                                                frame.payload)) }
+                }
+                OP_CLOSE => {
+                    let code = match frame.payload.len() {
+                        0 => None,
+                        1 => return self.fail(CLOSE_PROTOCOL_ERROR),
+                        _ => Some((frame.payload[0] as u16) << 8
+                                  | frame.payload[1] as u16)
+                    };
+                    self.close_received = true;
+                    if !self.out.is_closed() {
+                        self.out.close(code.unwrap_or(CLOSE_NORMAL))
+                    }
+                    self.handler.closed(code);
+                }
+                OP_PING => self.out.frame(OP_PONG, &frame.payload),
+                _ => { } // OP_PONG, parse_frame() rejects anything else.
+            }
+        }
+    }
+
+    /// Hands a complete message to the handler.
+    fn deliver(&mut self, opcode: u8, data: Vec<u8>) {
+        let msg = if opcode == OP_TEXT {
+            match String::from_utf8(data) {
+                Ok(text) => Message::Text(text),
+                Err(_) => return self.fail(CLOSE_INVALID_DATA)
+            }
+        }
+        else {
+            Message::Binary(data)
+        };
+        self.handler.message(msg, &mut self.out)
+    }
+
+    /// Drops everything after the connection was lost.
+    fn lost(mut self) -> Next<Self> {
+        if self.is_reading() {
+            self.handler.closed(None)
+        }
+        Next::remove()
+    }
+}
+
+
+//--- TransportHandler
+
+impl<T: Stream, H: WebSocketHandler> TransportHandler<T> for WebSocket<H> {
+    type Seed = (Upgrade, H);
+
+    fn create(seed: Self::Seed, sock: &mut T, notifier: Notifier)
+              -> Next<Self> {
+        let _ = sock;
+        let (upgrade, handler) = seed;
+        let mut res = WebSocket::new(upgrade, handler);
+        res.handler.start(notifier, &mut res.out);
+        res.process();
+        res.next()
+    }
+
+    fn readable(mut self, sock: &mut T) -> Next<Self> {
+        while self.wants_read() {
+            let mut buf = [0u8; READ_CHUNK];
+            match sock.read(&mut buf) {
+                Ok(0) => return self.lost(),
+                Ok(len) => self.rbuf.extend_from_slice(&buf[..len]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    break
+                }
+                Err(_) => return self.lost()
+            }
+            self.process();
+        }
+        self.next()
+    }
+
+    fn writable(mut self, sock: &mut T) -> Next<Self> {
+        if self.out.buf.write_to(sock).is_err() {
+            return self.lost()
+        }
+        // If we stopped processing for too much output, there may be
+        // frames waiting in the read buffer.
+        self.process();
+        self.next()
+    }
+
+    fn wakeup(mut self, sock: &mut T) -> Next<Self> {
+        let _ = sock;
+        self.handler.wakeup(&mut self.out);
+        self.next()
+    }
+
+    fn error(self, err: Error) -> Next<Self> {
+        let _ = err;
+        self.lost()
+    }
+}
+
+
+//------------ Framing -------------------------------------------------------
+
+/// A single frame received from the peer.
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Parses a frame from the beginning of `buf`.
+///
+/// Returns the frame and the number of bytes it occupied or `None` if
+/// the buffer doesn’t contain a complete frame yet. If the frame is
+/// invalid, returns the close code to fail the connection with.
+fn parse_frame(buf: &[u8], limit: usize)
+               -> Result<Option<(Frame, usize)>, u16> {
+    if buf.len() < 2 {
+        return Ok(None)
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0F;
+    if buf[0] & 0x70 != 0 {
+        return Err(CLOSE_PROTOCOL_ERROR)
+    }
+    match opcode {
+        OP_CONTINUATION | OP_TEXT | OP_BINARY => { }
+        OP_CLOSE | OP_PING | OP_PONG => {
+            if !fin || buf[1] & 0x7F > 125 {
+                return Err(CLOSE_PROTOCOL_ERROR)
+            }
+        }
+        _ => return Err(CLOSE_PROTOCOL_ERROR)
+    }
+    // Clients must mask all their frames.
+    if buf[1] & 0x80 == 0 {
+        return Err(CLOSE_PROTOCOL_ERROR)
+    }
+    let (len, mut pos) = match buf[1] & 0x7F {
+        126 => {
+            if buf.len() < 4 {
+                return Ok(None)
+            }
+            ((buf[2] as u64) << 8 | buf[3] as u64, 4)
+        }
+        127 => {
+            if buf.len() < 10 {
+                return Ok(None)
+            }
+            let mut len = 0u64;
+            for &octet in &buf[2..10] {
+                len = len << 8 | octet as u64
+            }
+            if len & (1 << 63) != 0 {
+                return Err(CLOSE_PROTOCOL_ERROR)
+            }
+            (len, 10)
+        }
+        len => (len as u64, 2)
+    };
+    if len > limit as u64 {
+        return Err(CLOSE_TOO_BIG)
+    }
+    let len = len as usize;
+    if buf.len() < pos + 4 + len {
+        return Ok(None)
+    }
+    let mask = [buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]];
+    pos += 4;
+    let payload = unmask(&buf[pos..pos + len], mask);
+    Ok(Some((Frame { fin: fin, opcode: opcode, payload: payload },
+             pos + len)))
+}
+
+/// Returns the payload `data` unmasked with `mask`.
+///
+/// Since masking is an XOR with the repeated mask, this also masks.
+fn unmask(data: &[u8], mask: [u8; 4]) -> Vec<u8> {
+    data.iter().enumerate().map(|(i, octet)| octet ^ mask[i % 4]).collect()
+}
+
+/// Appends an unmasked, unfragmented frame to `buf`.
+fn encode_frame(buf: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    buf.push(0x80 | opcode);
+    let len = payload.len();
+    if len < 126 {
+        buf.push(len as u8);
+    }
+    else if len <= 0xFFFF {
+        buf.push(126);
+        buf.push((len >> 8) as u8);
+        buf.push(len as u8);
+    }
+    else {
+        buf.push(127);
+        let len = len as u64;
+        for shift in (0..8).rev() {
+            buf.push((len >> (shift * 8)) as u8)
+        }
+    }
+    buf.extend_from_slice(payload);
+}
+
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use ::handlers::TransportHandler;
+    use ::sockets::mock::MockStream;
+    use super::*;
+    use super::{OP_BINARY, OP_CLOSE, OP_CONTINUATION, OP_PING, OP_PONG,
+                OP_TEXT, encode_frame, parse_frame, unmask};
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    /// Returns a masked client frame.
+    fn masked(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut res = Vec::new();
+        encode_frame(&mut res, opcode, payload);
+        if !fin {
+            res[0] &= 0x7F;
+        }
+        let start = res.len() - payload.len();
+        res[1] |= 0x80;
+        let body = unmask(&res[start..], MASK);
+        res.truncate(start);
+        res.extend_from_slice(&MASK);
+        res.extend_from_slice(&body);
+        res
+    }
+
+    /// A handler collecting all messages.
+    #[derive(Default)]
+    struct Collect {
+        msgs: Vec<Message>,
+        closed: Option<Option<u16>>,
+    }
+
+    impl WebSocketHandler for Collect {
+        fn message(&mut self, msg: Message, _out: &mut Outgoing) {
+            self.msgs.push(msg)
+        }
+
+        fn closed(&mut self, code: Option<u16>) {
+            self.closed = Some(code)
+        }
+    }
+
+    fn upgrade() -> Upgrade {
+        Upgrade::new("dGhlIHNhbXBsZSBub25jZQ==").unwrap()
+    }
+
+    /// Takes all pending output.
+    fn output(ws: &mut WebSocket<Collect>) -> Vec<u8> {
+        let mut res = Vec::new();
+        ws.out.buf.write_to(&mut res).unwrap();
+        res
+    }
+
+    #[test]
+    fn accept_key() {
+        // The example from RFC 6455, section 1.3.
+        assert_eq!(upgrade().accept_key(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn invalid_key() {
+        assert!(Upgrade::new("dGhlIHNhbXBsZSBub25jZQ").is_none());
+        assert!(Upgrade::new("dGhlIHNhbXBsZSBub25j").is_none());
+        assert!(Upgrade::new("dGhlIHNhbXBsZSBub25j!Q==").is_none());
+    }
+
+    #[test]
+    fn parse_masked() {
+        // The example from RFC 6455, section 5.7.
+        let buf = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d,
+                   0x51, 0x58];
+        let (frame, used) = parse_frame(&buf, 100).unwrap().unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, OP_TEXT);
+        assert_eq!(frame.payload, b"Hello");
+        assert_eq!(used, buf.len());
+    }
+
+    #[test]
+    fn parse_incomplete() {
+        let buf = masked(true, OP_BINARY, &[7; 300]);
+        for len in 0..buf.len() {
+            assert!(parse_frame(&buf[..len], 1000).unwrap().is_none());
+        }
+        let (frame, used) = parse_frame(&buf, 1000).unwrap().unwrap();
+        assert_eq!(frame.payload, &[7; 300][..]);
+        assert_eq!(used, buf.len());
+    }
+
+    #[test]
+    fn parse_invalid() {
+        // Unmasked frame.
+        let mut buf = Vec::new();
+        encode_frame(&mut buf, OP_TEXT, b"Hello");
+        assert_eq!(parse_frame(&buf, 100).err(), Some(CLOSE_PROTOCOL_ERROR));
+
+        // Reserved bits.
+        let mut buf = masked(true, OP_TEXT, b"Hello");
+        buf[0] |= 0x40;
+        assert_eq!(parse_frame(&buf, 100).err(), Some(CLOSE_PROTOCOL_ERROR));
+
+        // Unknown opcode.
+        let buf = masked(true, 0x3, b"Hello");
+        assert_eq!(parse_frame(&buf, 100).err(), Some(CLOSE_PROTOCOL_ERROR));
+
+        // Fragmented and overlong control frames.
+        let buf = masked(false, OP_PING, b"Hello");
+        assert_eq!(parse_frame(&buf, 100).err(), Some(CLOSE_PROTOCOL_ERROR));
+        let buf = masked(true, OP_PING, &[0; 126]);
+        assert_eq!(parse_frame(&buf, 1000).err(), Some(CLOSE_PROTOCOL_ERROR));
+
+        // Too big.
+        let buf = masked(true, OP_BINARY, &[0; 101]);
+        assert_eq!(parse_frame(&buf, 100).err(), Some(CLOSE_TOO_BIG));
+    }
+
+    #[test]
+    fn masking() {
+        let data = b"masking is its own inverse";
+        let once = unmask(data, MASK);
+        assert!(once != &data[..]);
+        assert_eq!(unmask(&once, MASK), &data[..]);
+    }
+
+    #[test]
+    fn encode_lengths() {
+        for &(len, header) in &[(125, 2), (126, 4), (0xFFFF, 4),
+                                (0x10000, 10)] {
+            let mut buf = Vec::new();
+            encode_frame(&mut buf, OP_BINARY, &vec![0; len]);
+            assert_eq!(buf.len(), header + len);
+            assert_eq!(buf[0], 0x80 | OP_BINARY);
+        }
+    }
+
+    #[test]
+    fn fragments_and_control_frames() {
+        let mut data = masked(false, OP_TEXT, b"Hel");
+        data.extend(masked(true, OP_PING, b"ping"));
+        data.extend(masked(true, OP_CONTINUATION, b"lo"));
+        data.extend(masked(true, OP_BINARY, b"\x01\x02"));
+        let mut ws = WebSocket::new(upgrade(), Collect::default());
+        ws.rbuf = data;
+        ws.process();
+        assert_eq!(ws.handler.msgs,
+                   vec![Message::Text("Hello".into()),
+                        Message::Binary(vec![1, 2])]);
+        assert!(ws.rbuf.is_empty());
+
+        // The response is followed by the pong.
+        let mut pong = Vec::new();
+        encode_frame(&mut pong, OP_PONG, b"ping");
+        assert!(output(&mut ws).ends_with(&pong));
+    }
+
+    #[test]
+    fn unexpected_continuation() {
+        let mut ws = WebSocket::new(upgrade(), Collect::default());
+        ws.rbuf = masked(true, OP_CONTINUATION, b"lo");
+        ws.process();
+        assert!(ws.handler.msgs.is_empty());
+        assert_eq!(ws.handler.closed, Some(Some(CLOSE_PROTOCOL_ERROR)));
+        assert!(ws.out.is_closed());
+    }
+
+    #[test]
+    fn closing_handshake() {
+        let mut ws = WebSocket::new(upgrade(), Collect::default());
+        ws.rbuf = masked(true, OP_CLOSE, &[0x03, 0xe8]);
+        ws.process();
+        assert_eq!(ws.handler.closed, Some(Some(CLOSE_NORMAL)));
+        assert!(ws.out.is_closed());
+        assert!(!ws.is_reading());
+    }
+
+    #[test]
+    fn leftover_handover() {
+        let mut buf = b"GET / HTTP/1.1\r\n\
+                        Host: example.com\r\n\
+                        Upgrade: websocket\r\n\
+                        Connection: Upgrade\r\n\
+                        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                        Sec-WebSocket-Version: 13\r\n\
+                        \r\n".to_vec();
+        buf.extend(masked(true, OP_TEXT, b"early"));
+        let upgrade = Upgrade::from_buffer(&buf).unwrap();
+        let mut ws = WebSocket::new(upgrade, Collect::default());
+        ws.process();
+        assert_eq!(ws.handler.msgs, vec![Message::Text("early".into())]);
+        assert!(output(&mut ws)
+                    .starts_with(b"HTTP/1.1 101 Switching Protocols"));
+    }
+
+    #[test]
+    fn incomplete_request() {
+        assert!(Upgrade::from_buffer(b"GET / HTTP/1.1\r\n\
+                                       Upgrade: websocket\r\n").is_none());
+    }
+
+    #[test]
+    fn ping_flood_stops_reading() {
+        let mut sock = MockStream::new();
+        let ping = masked(true, OP_PING, &[0; 125]);
+        for _ in 0..2000 {
+            sock.push_inbound(&ping);
+        }
+        sock.set_writable(false);
+        let ws = WebSocket::new(upgrade(), Collect::default());
+        let next = ws.readable(&mut sock);
+        assert_eq!(format!("{:?}", next), "Next::Write");
+        let ws = next.into_handler().unwrap();
+        assert!(ws.out.is_full());
+        assert!(sock.inbound_len() + ws.rbuf.len() > 0);
+
+        // Once the peer reads, all pings are answered.
+        sock.set_writable(true);
+        let mut ws = ws;
+        let mut written = 0;
+        while sock.inbound_len() > 0 || !ws.out.buf.is_empty() {
+            ws = ws.writable(&mut sock).into_handler().unwrap();
+            ws = ws.readable(&mut sock).into_handler().unwrap();
+            written += sock.take_outbound().len();
+        }
+        let mut pong = Vec::new();
+        encode_frame(&mut pong, OP_PONG, &[0; 125]);
+        let response = upgrade().response().len();
+        assert_eq!(written, response + 2000 * pong.len());
+    }
+}
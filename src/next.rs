@@ -4,6 +4,9 @@ use std::cmp::min;
 use std::fmt;
 use std::time::Duration;
 use rotor::{EventSet, GenericScope, Time};
+use ::clock;
+use ::error::Error;
+use ::sockets::Blocked;
 
 
 //------------ Next ---------------------------------------------------------
@@ -12,13 +15,18 @@ use rotor::{EventSet, GenericScope, Time};
 #[derive(Clone)]
 pub struct Next<T> {
     interest: Option<(Interest, T)>,
-    timeout: Option<Duration>,
+    timeouts: Timers<Duration>,
+    tick: Option<Tick>,
+
+    /// Whether to pause or resume reading or `None` for no change.
+    pause_read: Option<bool>,
 }
 
 
 impl<T> Next<T> {
     fn new(interest: Interest, t: T) -> Self {
-        Next { interest: Some((interest, t)), timeout: None }
+        Next { interest: Some((interest, t)), timeouts: Timers::default(),
+               tick: None, pause_read: None }
     }
 
     pub fn wait(t: T) -> Self { Next::new(Interest::Wait, t) }
@@ -29,10 +37,142 @@ impl<T> Next<T> {
     
     pub fn read_and_write(t: T) -> Self { Next::new(Interest::ReadWrite, t) }
     
-    pub fn remove() -> Self { Next { interest: None, timeout: None } }
+    /// Ends processing and closes the socket.
+    ///
+    /// Any output buffered by the socket itself is flushed before closing
+    /// as far as possible without waiting. Output the handler hasn’t
+    /// written to the socket yet, however, is abandoned.
+    pub fn remove() -> Self {
+        Next { interest: None, timeouts: Timers::default(), tick: None,
+               pause_read: None }
+    }
+
+    /// Ends processing but hands the socket back for reuse.
+    ///
+    /// Instead of closing the socket, the machine will call the handler’s
+    /// [recycle()] method and, if that returns the socket and a seed,
+    /// deregister the socket and send the pair to the machine’s pool.
+    ///
+    /// [recycle()]: ../handlers/trait.TransportHandler.html#method.recycle
+    pub fn recycle(t: T) -> Self { Next::new(Interest::Recycle, t) }
+
+    /// Ends processing gracefully once all output has been written.
+    ///
+    /// The machine stops calling the handler for socket events. Instead,
+    /// it waits for the socket to become writable until any output
+    /// buffered by the socket itself has been flushed. It then shuts down
+    /// writing, so the peer sees a clean end of stream, and closes the
+    /// socket. This is the common case of a server sending a final
+    /// response and closing the connection behind it.
+    ///
+    /// Because all the handler’s output needs to be written to the socket
+    /// before, this is typically returned from `writable()` after the
+    /// last write succeeded in full. Timeouts and ticks still reach the
+    /// handler while the machine is waiting.
+    pub fn write_then_close(t: T) -> Self { Next::new(Interest::Close, t) }
+
+    /// Ends processing gracefully once the handler has written everything.
+    ///
+    /// Unlike [write_then_close()](#method.write_then_close), this can be
+    /// returned while the handler still has output of its own. The
+    /// machine keeps calling the handler’s `writable()` but no longer its
+    /// `readable()`. After every call, it asks the handler’s
+    /// [has_pending_output()] whether there is anything left. Once there
+    /// isn’t, the socket is closed just like with `write_then_close()`:
+    /// output buffered by the socket is flushed, writing is shut down,
+    /// which sends a TLS close_notify for encrypted sockets, and the
+    /// socket is removed.
+    ///
+    /// Once returned, this sticks. Whatever the handler returns later is
+    /// merged into it, except for `Next::remove()` which still removes the
+    /// socket right away. Timeouts and ticks still reach the handler.
+    ///
+    /// [has_pending_output()]:
+    ///     ../handlers/trait.TransportHandler.html#method.has_pending_output
+    pub fn flush_then_remove(t: T) -> Self { Next::new(Interest::Flush, t) }
+
+    /// Stops reading for now and waits.
+    ///
+    /// This is for a handler that has read more than it can process right
+    /// away and doesn’t want anything else to arrive until it has caught
+    /// up. Until the handler returns
+    /// [resume_read()](#method.resume_read), the machine doesn’t wait for
+    /// the socket to become readable, even if later values ask for it.
+    /// Pausing is a flag kept on top of the handler’s interest, so a
+    /// handler that still has output returns `Next::write()` afterwards
+    /// and reading stays paused. Writable events, wakeups, timeouts, and
+    /// ticks still reach the handler as usual. Unread data piles up in the
+    /// socket’s receive buffer and, once that is full, the peer is made to
+    /// wait.
+    ///
+    /// If the socket itself needs to read in order to write, for instance
+    /// during a TLS renegotiation, it will still do so.
+    pub fn pause_read(t: T) -> Self {
+        let mut res = Next::new(Interest::Wait, t);
+        res.pause_read = Some(true);
+        res
+    }
 
+    /// Resumes reading after an earlier `Next::pause_read()`.
+    ///
+    /// The machine waits for the socket to become readable again. If
+    /// reading wasn’t paused, this is the same as `Next::read()`.
+    pub fn resume_read(t: T) -> Self {
+        let mut res = Next::new(Interest::Read, t);
+        res.pause_read = Some(false);
+        res
+    }
+
+    /// Waits and starts a recurring tick.
+    ///
+    /// From now on, the handler’s [tick()] method will be called every
+    /// `interval`, regardless of what it returns in between. Returning
+    /// another `Next::tick()` replaces the interval, calling
+    /// [cancel_tick()](#method.cancel_tick) stops the ticking.
+    ///
+    /// Unlike a timeout, a tick is not an error and doesn’t need to be
+    /// requested again after it fired.
+    ///
+    /// [tick()]: ../handlers/trait.TransportHandler.html#method.tick
+    pub fn tick(t: T, interval: Duration) -> Self {
+        let mut res = Next::new(Interest::Wait, t);
+        res.tick = Some(Tick::Start(interval));
+        res
+    }
+
+    /// Sets a timeout.
+    ///
+    /// If nothing happens on the socket for `duration`, the handler’s
+    /// `error()` method is called with `Error::Timeout`.
     pub fn timeout(mut self, duration: Duration) -> Self {
-        self.timeout = Some(duration);
+        self.timeouts.any = Some(duration);
+        self
+    }
+
+    /// Sets a timeout for reading.
+    ///
+    /// This is like [timeout()](#method.timeout) but will be signalled as
+    /// `Error::ReadTimeout`. Together with
+    /// [write_timeout()](#method.write_timeout), this allows a handler to
+    /// have different deadlines for the two directions and still tell
+    /// which one has passed.
+    pub fn read_timeout(mut self, duration: Duration) -> Self {
+        self.timeouts.read = Some(duration);
+        self
+    }
+
+    /// Sets a timeout for writing.
+    ///
+    /// This is like [timeout()](#method.timeout) but will be signalled as
+    /// `Error::WriteTimeout`.
+    pub fn write_timeout(mut self, duration: Duration) -> Self {
+        self.timeouts.write = Some(duration);
+        self
+    }
+
+    /// Stops a recurring tick started earlier via `Next::tick()`.
+    pub fn cancel_tick(mut self) -> Self {
+        self.tick = Some(Tick::Cancel);
         self
     }
 }
@@ -42,9 +182,21 @@ impl<T> Next<T> {
            where F: FnOnce(T) -> U {
         Next {
             interest: self.interest.map(|(i, t)| (i, op(t))),
-            timeout: self.timeout
+            timeouts: self.timeouts,
+            tick: self.tick,
+            pause_read: self.pause_read
         }
     }
+
+    /// Returns a reference to the handler unless this is a remove.
+    pub fn handler(&self) -> Option<&T> {
+        self.interest.as_ref().map(|&(_, ref t)| t)
+    }
+
+    /// Trades the value for the handler unless this is a remove.
+    pub fn into_handler(self) -> Option<T> {
+        self.interest.map(|(_, t)| t)
+    }
 }
 
 
@@ -58,8 +210,23 @@ impl<T> fmt::Debug for Next<T> {
         else {
             try!(write!(f, "Next::Remove"));
         }
-        match self.timeout {
-            Some(ref d) => write!(f, "({:?})", d),
+        if let Some(ref d) = self.timeouts.any {
+            try!(write!(f, "({:?})", d));
+        }
+        if let Some(ref d) = self.timeouts.read {
+            try!(write!(f, "(read {:?})", d));
+        }
+        if let Some(ref d) = self.timeouts.write {
+            try!(write!(f, "(write {:?})", d));
+        }
+        match self.pause_read {
+            Some(true) => try!(write!(f, "[pause read]")),
+            Some(false) => try!(write!(f, "[resume read]")),
+            None => { }
+        }
+        match self.tick {
+            Some(Tick::Start(ref d)) => write!(f, "[tick {:?}]", d),
+            Some(Tick::Cancel) => write!(f, "[no tick]"),
             None => Ok(())
         }
     }
@@ -73,82 +240,372 @@ enum Interest {
     Wait,
     Read,
     Write,
-    ReadWrite
+    ReadWrite,
+    Recycle,
+    Flush,
+    Close
+}
+
+
+//------------ Tick ---------------------------------------------------------
+
+/// A change to the recurring tick requested through a `Next`.
+#[derive(Clone, Copy, Debug)]
+enum Tick {
+    Start(Duration),
+    Cancel
+}
+
+
+//------------ Timers -------------------------------------------------------
+
+/// Something for each of the three kinds of timeouts.
+///
+/// This is used with durations in `Next` and with deadlines in `Intent`.
+#[derive(Clone, Copy, Debug)]
+struct Timers<T> {
+    any: Option<T>,
+    read: Option<T>,
+    write: Option<T>
+}
+
+impl<T> Default for Timers<T> {
+    fn default() -> Self {
+        Timers { any: None, read: None, write: None }
+    }
+}
+
+impl Timers<Time> {
+    /// Merges in timeouts starting at `now`, keeping the earlier deadline.
+    fn merge(self, timeouts: Timers<Duration>, now: Time) -> Self {
+        fn one(deadline: Option<Time>, timeout: Option<Duration>, now: Time)
+               -> Option<Time> {
+            match (deadline, timeout) {
+                (Some(deadline), Some(timeout)) => {
+                    Some(min(deadline, now + timeout))
+                }
+                (None, Some(timeout)) => Some(now + timeout),
+                (deadline, None) => deadline
+            }
+        }
+
+        Timers {
+            any: one(self.any, timeouts.any, now),
+            read: one(self.read, timeouts.read, now),
+            write: one(self.write, timeouts.write, now)
+        }
+    }
+
+    /// Returns the earliest of the deadlines.
+    fn earliest(&self) -> Option<Time> {
+        [self.any, self.read, self.write].iter()
+                                         .filter_map(|t| *t).min()
+    }
 }
 
 
 //------------ Intent -------------------------------------------------------
 
+/// What a transport handler wants its machine to do next.
+///
+/// A handler tells its machine what it wants through the `Next` value
+/// returned by each of its methods. The machine keeps track of these
+/// wishes in an intent: which events to wait for, the deadlines for the
+/// various timeouts, and the recurring tick.
+///
+/// The transport machines of this crate are built on this type. If you
+/// write your own machine around a [TransportHandler], you can use it,
+/// too, rather than interpreting `Next` values yourself. The supported
+/// surface is small:
+///
+/// * [from_next()](#method.from_next) creates the intent from the value
+///   returned by the handler’s `create()`,
+/// * [carry_over()](#method.carry_over) starts over for a new event and
+///   [merge()](#method.merge) merges in the value returned by each
+///   handler method called for that event,
+/// * [to_events()](#method.to_events) provides the events to register
+///   the socket for, while [timer()](#method.timer) gives the deadline
+///   for the machine’s response,
+/// * when that deadline passes, [tick_due()](#method.tick_due) tells
+///   whether to call the handler’s `tick()`, followed by
+///   [rearm_tick()](#method.rearm_tick), or otherwise
+///   [expire()](#method.expire) provides the error for the handler’s
+///   `error()`, and
+/// * [is_recycle()](#method.is_recycle) tells whether the handler wants
+///   to give up its socket, [is_flush()](#method.is_flush) whether it
+///   wants to finish writing before that, and [is_close()](#method.is_close)
+///   whether it wants it closed once all output is written.
+///
+/// Whether the handler has paused reading is kept across events until it
+/// resumes; [is_read_paused()](#method.is_read_paused) tells.
+///
+/// If any of the functions returns `None` instead of an intent, the
+/// handler has asked for the socket to be removed.
+///
+/// [TransportHandler]: ../handlers/trait.TransportHandler.html
 #[derive(Clone, Copy, Debug)]
 pub struct Intent {
     interest: Interest,
-    deadline: Option<Time>
+    deadlines: Timers<Time>,
+
+    /// The tick interval and the time the next tick is due.
+    tick: Option<(Duration, Time)>,
+
+    /// Whether the handler has paused reading.
+    read_paused: bool,
 }
 
 impl Intent {
-    fn make(interest: Interest, deadline: Option<Time>) -> Self {
-        Intent { interest: interest, deadline: deadline }
+    fn make(interest: Interest, deadlines: Timers<Time>,
+            tick: Option<(Duration, Time)>, read_paused: bool) -> Self {
+        Intent { interest: interest, deadlines: deadlines, tick: tick,
+                 read_paused: read_paused }
+    }
+
+    /// Returns the tick resulting from applying `change` to `tick`.
+    fn update_tick<S: GenericScope>(tick: Option<(Duration, Time)>,
+                                    change: Option<Tick>, scope: &mut S)
+                                    -> Option<(Duration, Time)> {
+        match change {
+            Some(Tick::Start(interval)) => {
+                Some((interval, clock::now(scope) + interval))
+            }
+            Some(Tick::Cancel) => None,
+            None => tick
+        }
+    }
+
+    /// Creates an intent from a handler’s first `Next` value.
+    ///
+    /// Returns the intent and the handler or `None` if the handler asked
+    /// for removal.
+    pub fn from_next<T, S>(next: Next<T>, scope: &mut S)
+                           -> Option<(Self, T)>
+                        where S: GenericScope {
+        Intent::new(next, scope)
     }
 
+    /// Creates an intent from a handler’s first `Next` value.
+    ///
+    /// This is the same as [from_next()](#method.from_next).
     pub fn new<T, S: GenericScope>(next: Next<T>, scope: &mut S)
                                    -> Option<(Self, T)> {
-        use self::Interest::*;
-
-        let dl = next.timeout.map(|dur| scope.now() + dur);
-        match next.interest {
-            Some((Wait, t)) => Some((Intent::make(Wait, dl), t)),
-            Some((Read, t)) => Some((Intent::make(Read, dl), t)),
-            Some((Write, t)) => Some((Intent::make(Write, dl), t)),
-            Some((ReadWrite, t)) => Some((Intent::make(ReadWrite, dl), t)),
-            None => None
-        }
+        let dl = Timers::default().merge(next.timeouts, clock::now(scope));
+        let tk = Intent::update_tick(None, next.tick, scope);
+        let paused = next.pause_read.unwrap_or(false);
+        next.interest.map(|(interest, t)| {
+            (Intent::make(interest, dl, tk, paused), t)
+        })
     }
 
+    /// Merges a handler’s `Next` value into the intent.
+    ///
+    /// The resulting intent waits for the events of both, keeps the
+    /// earlier of each kind of deadline, and applies changes to the tick
+    /// and to pausing reading. Returns the new intent and the handler or
+    /// `None` if the handler asked for removal.
     pub fn merge<T, S: GenericScope>(self, other: Next<T>, scope: &mut S)
                                      -> Option<(Self, T)> {
         use self::Interest::*;
 
         if let Some((interest, t)) = other.interest {
             let interest = match (self.interest, interest) {
+                (Close, _) | (_, Close) => Close,
+                (Flush, _) | (_, Flush) => Flush,
+                (Recycle, _) | (_, Recycle) => Recycle,
                 (ReadWrite, _) | (_, ReadWrite) |
                 (Read, Write) | (Write, Read) => ReadWrite,
                 (Read, _) | (_, Read) => Read,
                 (Write, _) | (_, Write) => Write,
                 (Wait, Wait) => Wait
             };
-            let deadline = match (self.deadline, other.timeout) {
-                (Some(deadline), Some(timeout)) => {
-                    Some(min(deadline, scope.now() + timeout))
-                }
-                (None, Some(timeout)) => Some(scope.now() + timeout),
-                (deadline, None) => deadline
-            };
-            Some((Intent::make(interest, deadline), t))
+            let deadlines = self.deadlines.merge(other.timeouts,
+                                                 clock::now(scope));
+            let tick = Intent::update_tick(self.tick, other.tick, scope);
+            let paused = other.pause_read.unwrap_or(self.read_paused);
+            Some((Intent::make(interest, deadlines, tick, paused), t))
         }
         else {
             None
         }
     }
 
+    /// Returns an intent for starting over with a new event.
+    ///
+    /// Only the recurring tick, whether reading is paused, and a pending
+    /// flush before removal survive, everything else is reset to the
+    /// default.
+    pub fn carry_over(&self) -> Self {
+        let interest = if self.is_flush() { Interest::Flush }
+                       else { Interest::Wait };
+        Intent { interest: interest, tick: self.tick,
+                 read_paused: self.read_paused, .. Intent::default() }
+    }
+
+    /// Returns the earliest deadline of any kind.
     pub fn deadline(&self) -> Option<Time> {
-        self.deadline
+        self.deadlines.earliest()
+    }
+
+    /// Returns the earliest time the machine needs to be woken up.
+    ///
+    /// This is the earlier of the deadline and the next tick.
+    pub fn timer(&self) -> Option<Time> {
+        match (self.deadline(), self.tick) {
+            (Some(deadline), Some((_, tick))) => Some(min(deadline, tick)),
+            (Some(deadline), None) => Some(deadline),
+            (None, Some((_, tick))) => Some(tick),
+            (None, None) => None
+        }
+    }
+
+    /// Returns whether a tick is due at `now`.
+    pub fn tick_due(&self, now: Time) -> bool {
+        match self.tick {
+            Some((_, tick)) => tick <= now,
+            None => false
+        }
+    }
+
+    /// Returns the intent with the tick rearmed for the next interval.
+    pub fn rearm_tick(self, now: Time) -> Self {
+        Intent {
+            tick: self.tick.map(|(interval, _)| (interval, now + interval)),
+            .. self
+        }
+    }
+
+    /// Expires the earliest deadline.
+    ///
+    /// Returns the intent with that deadline removed and the error to
+    /// report to the handler. If there are several deadlines at the same
+    /// time, the general one wins over the read one which wins over the
+    /// write one.
+    pub fn expire(self, now: Time) -> (Self, Error) {
+        let mut deadlines = self.deadlines;
+        let earliest = deadlines.earliest().unwrap_or(now);
+        let err = if deadlines.any == Some(earliest) {
+            deadlines.any = None;
+            Error::Timeout
+        }
+        else if deadlines.read == Some(earliest) {
+            deadlines.read = None;
+            Error::ReadTimeout
+        }
+        else if deadlines.write == Some(earliest) {
+            deadlines.write = None;
+            Error::WriteTimeout
+        }
+        else {
+            Error::Timeout
+        };
+        (Intent { deadlines: deadlines, .. self }, err)
+    }
+
+    /// Returns whether the handler wants its socket recycled.
+    pub fn is_recycle(&self) -> bool {
+        self.interest == Interest::Recycle
+    }
+
+    /// Returns whether the handler wants its socket closed after writing.
+    pub fn is_close(&self) -> bool {
+        self.interest == Interest::Close
+    }
+
+    /// Returns whether the handler wants to finish writing before removal.
+    pub fn is_flush(&self) -> bool {
+        self.interest == Interest::Flush
+    }
+
+    /// Returns an intent for closing the socket after writing.
+    ///
+    /// This is what a pending flush turns into once the handler has
+    /// written everything.
+    pub fn into_close(self) -> Self {
+        Intent { interest: Interest::Close, .. self }
+    }
+
+    /// Returns whether the handler has paused reading.
+    pub fn is_read_paused(&self) -> bool {
+        self.read_paused
+    }
+
+    /// Returns the events to register a socket for.
+    ///
+    /// If the socket is `blocked` on a certain direction, it needs to
+    /// wait for that direction, or both, regardless of what the handler
+    /// wants. Otherwise the events are those of the intent.
+    pub fn to_events(&self, blocked: Option<Blocked>) -> EventSet {
+        match blocked {
+            Some(Blocked::Read) => EventSet::readable(),
+            Some(Blocked::Write) => EventSet::writable(),
+            Some(Blocked::Both) => {
+                EventSet::readable() | EventSet::writable()
+            }
+            None => self.events()
+        }
     }
 
     /// Returns the events for self.
+    ///
+    /// If reading is paused, the socket isn’t registered for readable.
     pub fn events(&self) -> EventSet {
-        match self.interest {
-            Interest::Wait => EventSet::none(),
+        let interest = match (self.interest, self.read_paused) {
+            (Interest::Read, true) => Interest::Wait,
+            (Interest::ReadWrite, true) => Interest::Write,
+            (interest, _) => interest
+        };
+        match interest {
+            Interest::Wait | Interest::Recycle => EventSet::none(),
             Interest::Read => EventSet::readable(),
-            Interest::Write => EventSet::writable(),
+            Interest::Write | Interest::Flush | Interest::Close => {
+                EventSet::writable()
+            }
             Interest::ReadWrite => {
                 EventSet::readable() | EventSet::writable()
             }
         }
     }
+
+    /// Returns a human readable description of the intent.
+    ///
+    /// This is intended for debugging, for instance for finding out what
+    /// a stuck connection is waiting for. The description contains the
+    /// interest, the deadlines, and the tick, if there are any. The exact
+    /// format may change at any time.
+    pub fn describe(&self) -> String {
+        let mut res = match self.interest {
+            Interest::Wait => "waiting",
+            Interest::Read => "waiting for readable",
+            Interest::Write => "waiting for writable",
+            Interest::ReadWrite => "waiting for readable or writable",
+            Interest::Recycle => "recycling",
+            Interest::Flush => "writing before removal",
+            Interest::Close => "closing after output is written",
+        }.to_string();
+        if self.read_paused {
+            res.push_str(", reading paused");
+        }
+        if let Some(deadline) = self.deadlines.any {
+            res.push_str(&format!(", deadline {:?}", deadline));
+        }
+        if let Some(deadline) = self.deadlines.read {
+            res.push_str(&format!(", read deadline {:?}", deadline));
+        }
+        if let Some(deadline) = self.deadlines.write {
+            res.push_str(&format!(", write deadline {:?}", deadline));
+        }
+        if let Some((interval, tick)) = self.tick {
+            res.push_str(&format!(", tick every {:?}, next {:?}",
+                                  interval, tick));
+        }
+        res
+    }
 }
 
 impl Default for Intent {
     fn default() -> Self {
-        Intent { interest: Interest::Wait, deadline: None }
+        Intent { interest: Interest::Wait, deadlines: Timers::default(),
+                 tick: None, read_paused: false }
     }
 }
@@ -1,22 +1,230 @@
 //! Fundamental machines.
 
 use std::marker::PhantomData;
-use rotor::{GenericScope, EventSet, Machine, Response, Scope, Void};
+use std::net::SocketAddr;
+use std::time::Duration;
+use rotor::{GenericScope, EventSet, Machine, Response, Scope, SpawnError,
+            Time, Void};
+use ::clock;
 use ::error::Error;
 use ::handlers::RequestHandler;
+use ::net::machines::MachineStats;
 use ::sync::{DuctReceiver, DuctSender, duct};
 use ::utils::ResponseExt;
 
 pub trait SeedFactory<O, S> {
     fn translate(&self, output: O) -> Result<S, TranslateError<O>>;
+
+    /// Turns a seed back into the output it was translated from.
+    ///
+    /// This is used if the machine for a seed couldn’t be spawned, so the
+    /// request handler can learn which output failed. Factories that
+    /// can’t tell return `None` in which case the request is dropped.
+    /// This is what the default implementation does.
+    fn recover(&self, seed: S) -> Option<O> {
+        let _ = seed;
+        None
+    }
+}
+
+/// The error returned by a seed factory if translating failed.
+///
+/// It contains the original output of the request handler, the error
+/// that happened, and the context of the failure: which kind of socket
+/// the factory tried to create and for which address. Factories for
+/// combined machines return the output unchanged, ie., with the variant
+/// the request handler chose. Together with the context, the handler’s
+/// `translate_error()` method can tell which variant failed and pick a
+/// fallback.
+pub struct TranslateError<O> {
+    /// The output that failed to translate.
+    pub output: O,
+
+    /// The error that happened.
+    pub error: Error,
+
+    /// What the factory was trying to do.
+    pub context: TranslateContext,
+}
+
+impl<O> TranslateError<O> {
+    /// Creates a new error for a `transport` socket for `addr`.
+    pub fn new(output: O, error: Error, transport: Transport,
+               addr: SocketAddr) -> Self {
+        TranslateError {
+            output: output,
+            error: error,
+            context: TranslateContext { transport: transport, addr: addr }
+        }
+    }
+}
+
+
+//------------ TranslateContext ---------------------------------------------
+
+/// The context of a failed translation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TranslateContext {
+    /// The kind of socket the factory tried to create.
+    pub transport: Transport,
+
+    /// The address the socket was to connect or bind to.
+    pub addr: SocketAddr,
+}
+
+
+//------------ Transport ----------------------------------------------------
+
+/// The kind of socket a seed factory creates.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Transport {
+    /// A TCP socket connecting to the address.
+    Tcp,
+
+    /// A UDP socket bound to the address.
+    Udp,
+
+    /// A TLS connection to the address.
+    Tls,
+
+    /// A TCP connection to the address that may start TLS later.
+    StartTls,
+}
+
+
+//------------ RequestMachineConfig -----------------------------------------
+
+/// The configuration of a request machine.
+///
+/// A value starts out with the defaults which are also what
+/// [RequestMachine::new()] uses. These can be changed through the
+/// builder-style methods and the result given to
+/// [RequestMachine::with_config()].
+///
+/// [RequestMachine::new()]: struct.RequestMachine.html#method.new
+/// [RequestMachine::with_config()]:
+///     struct.RequestMachine.html#method.with_config
+#[derive(Clone, Debug, Default)]
+pub struct RequestMachineConfig {
+    connect_timeout: Option<Duration>,
+    max_retries: Option<usize>,
+    idle_shutdown: Option<Duration>,
+    stats: Option<MachineStats>,
 }
 
-pub struct TranslateError<O>(pub O, pub Error);
+impl RequestMachineConfig {
+    /// Creates a new default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits how long connecting a socket may take.
+    ///
+    /// If there hasn’t been any event on a newly created socket after
+    /// `timeout`, its transport handler’s `error()` method is called with
+    /// `Error::Timeout`. This only applies to machines whose sockets
+    /// actually connect, see [ConnectMachine]. By default, there is no
+    /// limit beyond what the operating system imposes.
+    ///
+    /// [ConnectMachine]: trait.ConnectMachine.html
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits how often a single request may fall back.
+    ///
+    /// Each time creating a socket for a request fails, the request
+    /// handler’s [fallback()] method may return a new output to try
+    /// instead. With a limit of `max_retries`, only that many fallbacks are
+    /// tried before the request is dropped. By default, there is no limit.
+    ///
+    /// [fallback()]: ../handlers/trait.RequestHandler.html#method.fallback
+    pub fn max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Shuts the machine down after a time without requests.
+    ///
+    /// If no request arrives for `idle` time, the request machine ends
+    /// and further attempts to send requests will fail. Machines for
+    /// sockets created earlier are not affected. By default, the machine
+    /// runs for as long as there are senders.
+    pub fn idle_shutdown(mut self, idle: Duration) -> Self {
+        self.idle_shutdown = Some(idle);
+        self
+    }
+
+    /// Sets the statistics to update.
+    ///
+    /// The request machine records its wakeups and timers. Wakeups
+    /// without any requests waiting count as spurious. Machines for the
+    /// sockets created by the machine don’t record anything.
+    pub fn stats(mut self, stats: MachineStats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+}
+
+
+//------------ ConnectMachine -----------------------------------------------
+
+/// A machine that can be created for a socket that is still connecting.
+///
+/// A request machine spawns machines of this kind for the sockets its
+/// seed factory creates. If it has a connect timeout, it creates them
+/// through `create_connecting()` rather than `create()`. The default
+/// implementation ignores the timeout which is correct for machines
+/// whose sockets don’t need to connect, such as datagram sockets.
+pub trait ConnectMachine: Machine {
+    /// Creates a machine that gives up on connecting after `timeout`.
+    fn create_connecting(seed: Self::Seed, timeout: Duration,
+                         scope: &mut Scope<Self::Context>)
+                         -> Response<Self, Void> {
+        let _ = timeout;
+        Self::create(seed, scope)
+    }
+}
+
+
+//------------ RequestSeed --------------------------------------------------
+
+/// The seed of a request machine.
+///
+/// This is the seed of the machine to create for a request together
+/// with the connect timeout to create it with.
+pub struct RequestSeed<S> {
+    seed: S,
+    connect_timeout: Option<Duration>,
+}
+
+impl<S> RequestSeed<S> {
+    /// Creates a new seed without a connect timeout.
+    pub fn new(seed: S) -> Self {
+        RequestSeed { seed: seed, connect_timeout: None }
+    }
+
+    /// Creates a new seed with a connect timeout.
+    pub fn with_connect_timeout(seed: S, timeout: Duration) -> Self {
+        RequestSeed { seed: seed, connect_timeout: Some(timeout) }
+    }
+
+    /// Returns the connect timeout if there is one.
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout
+    }
+
+    /// Trades the value for the machine’s seed.
+    pub fn into_seed(self) -> S {
+        self.seed
+    }
+}
 
 //------------ RequestMachine -----------------------------------------------
 
 pub struct RequestMachine<X, M, H, F>(Inner<M, H, F>, PhantomData<X>)
-                          where M: Machine<Context=X>,
+                          where M: ConnectMachine<Context=X>,
                                 H: RequestHandler,
                                 F: SeedFactory<H::Output, M::Seed>;
 
@@ -26,20 +234,29 @@ enum Inner<M: Machine, H: RequestHandler, F: SeedFactory<H::Output, M::Seed>> {
 }
 
 impl<X, M, H, F> RequestMachine<X, M, H, F>
-                 where M: Machine<Context=X>,
+                 where M: ConnectMachine<Context=X>,
                        H: RequestHandler,
                        F: SeedFactory<H::Output, M::Seed> {
     pub fn new<S>(handler: H, factory: F, scope: &mut S)
                   -> (Response<Self, Void>, DuctSender<H::Request>)
                where S: GenericScope {
+        RequestMachine::with_config(handler, factory,
+                                    RequestMachineConfig::default(), scope)
+    }
+
+    /// Creates a new machine using the given configuration.
+    pub fn with_config<S>(handler: H, factory: F,
+                          config: RequestMachineConfig, scope: &mut S)
+                          -> (Response<Self, Void>, DuctSender<H::Request>)
+                       where S: GenericScope {
         let (tx, rx) = duct(scope.notifier());
-        (Response::ok(RequestMachine::req(Req::new(rx, handler, factory))),
-         tx)
+        let req = Req::new(rx, handler, factory, config, clock::now(scope));
+        (req.response().map_self(RequestMachine::req), tx)
     }
 }
 
 impl<X, M, H, F> RequestMachine<X, M, H, F>
-                 where M: Machine<Context=X>,
+                 where M: ConnectMachine<Context=X>,
                        H: RequestHandler,
                        F: SeedFactory<H::Output, M::Seed> {
     fn req(req: Req<H, M::Seed, F>) -> Self {
@@ -52,15 +269,19 @@ impl<X, M, H, F> RequestMachine<X, M, H, F>
 }
 
 impl<X, M, H, F> Machine for RequestMachine<X, M, H, F>
-                 where M: Machine<Context=X>,
+                 where M: ConnectMachine<Context=X>,
                        H: RequestHandler,
                        F: SeedFactory<H::Output, M::Seed> {
     type Context = X;
-    type Seed = M::Seed;
+    type Seed = RequestSeed<M::Seed>;
 
     fn create(seed: Self::Seed, scope: &mut Scope<X>)
               -> Response<Self, Void> {
-        M::create(seed, scope).map_self(RequestMachine::m)
+        let res = match seed.connect_timeout {
+            Some(timeout) => M::create_connecting(seed.seed, timeout, scope),
+            None => M::create(seed.seed, scope)
+        };
+        res.map_self(RequestMachine::m)
     }
 
     fn ready(self, events: EventSet, scope: &mut Scope<X>)
@@ -70,7 +291,8 @@ impl<X, M, H, F> Machine for RequestMachine<X, M, H, F>
                 unreachable!("Request handler can’t be ready")
             }
             Inner::M(machine) => {
-                machine.ready(events, scope).map_self(RequestMachine::m)
+                machine.ready(events, scope)
+                       .map(RequestMachine::m, RequestSeed::new)
             }
         }
     }
@@ -78,21 +300,23 @@ impl<X, M, H, F> Machine for RequestMachine<X, M, H, F>
     fn spawned(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
         match self.0 {
             Inner::Req(req) => {
-                req.process_requests().map_self(RequestMachine::req)
+                req.process_requests(scope).map_self(RequestMachine::req)
             }
             Inner::M(machine) => {
-                machine.spawned(scope).map_self(RequestMachine::m)
+                machine.spawned(scope)
+                       .map(RequestMachine::m, RequestSeed::new)
             }
         }
     }
 
     fn timeout(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
         match self.0 {
-            Inner::Req(_) => {
-                unreachable!("Request handler can’t time out")
+            Inner::Req(req) => {
+                req.timeout(scope).map_self(RequestMachine::req)
             }
             Inner::M(machine) => {
-                machine.timeout(scope).map_self(RequestMachine::m)
+                machine.timeout(scope)
+                       .map(RequestMachine::m, RequestSeed::new)
             }
         }
     }
@@ -100,10 +324,31 @@ impl<X, M, H, F> Machine for RequestMachine<X, M, H, F>
     fn wakeup(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
         match self.0 {
             Inner::Req(req) => {
-                req.process_requests().map_self(RequestMachine::req)
+                req.wakeup(scope).map_self(RequestMachine::req)
+            }
+            Inner::M(machine) => {
+                machine.wakeup(scope)
+                       .map(RequestMachine::m, RequestSeed::new)
+            }
+        }
+    }
+
+    fn spawn_error(self, scope: &mut Scope<X>,
+                   error: SpawnError<Self::Seed>)
+                   -> Response<Self, Self::Seed> {
+        match self.0 {
+            Inner::Req(req) => {
+                req.spawn_error(error, scope).map_self(RequestMachine::req)
             }
             Inner::M(machine) => {
-                machine.wakeup(scope).map_self(RequestMachine::m)
+                let error = match error {
+                    SpawnError::NoSlabSpace(seed) => {
+                        SpawnError::NoSlabSpace(seed.seed)
+                    }
+                    SpawnError::UserError(err) => SpawnError::UserError(err)
+                };
+                machine.spawn_error(scope, error)
+                       .map(RequestMachine::m, RequestSeed::new)
             }
         }
     }
@@ -116,30 +361,178 @@ struct Req<H: RequestHandler, S, F: SeedFactory<H::Output, S>> {
     rx: DuctReceiver<H::Request>,
     handler: H,
     factory: F,
+    config: RequestMachineConfig,
+
+    /// When the last request arrived.
+    last_active: Time,
     marker: PhantomData<S>
 }
 
 impl<H: RequestHandler, S, F: SeedFactory<H::Output, S>> Req<H, S, F> {
-    fn new(rx: DuctReceiver<H::Request>, handler: H, factory: F) -> Self {
-        Req { rx: rx, handler: handler, factory: factory,
-              marker: PhantomData }
+    fn new(rx: DuctReceiver<H::Request>, handler: H, factory: F,
+           config: RequestMachineConfig, now: Time) -> Self {
+        Req { rx: rx, handler: handler, factory: factory, config: config,
+              last_active: now, marker: PhantomData }
     }
 
-    fn process_requests(mut self) -> Response<Self, S> {
+    fn process_requests<G: GenericScope>(mut self, scope: &mut G)
+                                         -> Response<Self, RequestSeed<S>> {
         loop {
             match self.rx.try_recv() {
                 Ok(Some(request)) => {
-                    if let Some(output) = self.handler.request(request) {
-                        match self.factory.translate(output) {
-                            Ok(seed) => return Response::spawn(self, seed),
-                            Err(err) => self.handler.error(err.0, err.1)
+                    self.last_active = clock::now(scope);
+                    let mut output = self.handler.request(request);
+                    let mut retries = 0;
+                    while let Some(item) = output {
+                        match self.factory.translate(item) {
+                            Ok(seed) => {
+                                let seed = RequestSeed {
+                                    seed: seed,
+                                    connect_timeout:
+                                        self.config.connect_timeout
+                                };
+                                return Response::spawn(self, seed)
+                            }
+                            Err(err) => {
+                                debug!("creating {:?} socket for {} failed",
+                                       err.context.transport,
+                                       err.context.addr);
+                                output = self.handler.translate_error(err);
+                                if let Some(max) = self.config.max_retries {
+                                    if retries >= max {
+                                        debug!("giving up on request after \
+                                                {} retries", retries);
+                                        output = None
+                                    }
+                                }
+                                retries += 1;
+                            }
                         }
                     }
                 }
-                Ok(None) => return Response::ok(self),
+                Ok(None) => return self.response(),
                 Err(_) => return Response::done()
             }
         }
     }
+
+    fn wakeup<G: GenericScope>(self, scope: &mut G)
+                               -> Response<Self, RequestSeed<S>> {
+        if let Some(ref stats) = self.config.stats {
+            stats.record_wakeup(self.rx.is_empty())
+        }
+        self.process_requests(scope)
+    }
+
+    /// Processes a failure to spawn the machine for a request.
+    ///
+    /// If the loop is out of slab space, the request handler learns
+    /// about it through an `Error::NoSlabSpace` if the factory can turn
+    /// the seed back into the output. Since spawning again right away
+    /// would fail just the same, any new output is ignored.
+    fn spawn_error<G>(mut self, error: SpawnError<RequestSeed<S>>,
+                      scope: &mut G) -> Response<Self, RequestSeed<S>>
+                   where G: GenericScope {
+        match error {
+            SpawnError::NoSlabSpace(seed) => {
+                match self.factory.recover(seed.seed) {
+                    Some(output) => {
+                        if self.handler.fallback(output, Error::NoSlabSpace)
+                                       .is_some() {
+                            debug!("ignoring fallback for request \
+                                    without slab space");
+                        }
+                    }
+                    None => {
+                        debug!("no slab space, dropping request")
+                    }
+                }
+            }
+            SpawnError::UserError(err) => {
+                debug!("spawning machine for request failed: {}", err)
+            }
+        }
+        // There may be more requests waiting and nobody will wake us
+        // up for them.
+        self.process_requests(scope)
+    }
+
+    fn timeout<G: GenericScope>(self, scope: &mut G)
+                                -> Response<Self, RequestSeed<S>> {
+        if let Some(ref stats) = self.config.stats {
+            stats.record_timer()
+        }
+        match self.idle_deadline() {
+            Some(deadline) if deadline <= clock::now(scope) => {
+                debug!("request machine idle, shutting down");
+                Response::done()
+            }
+            _ => self.response()
+        }
+    }
+
+    /// Returns when the machine will shut down for being idle.
+    fn idle_deadline(&self) -> Option<Time> {
+        self.config.idle_shutdown.map(|idle| self.last_active + idle)
+    }
+
+    fn response<R>(self) -> Response<Self, R> {
+        match self.idle_deadline() {
+            Some(deadline) => Response::ok(self).deadline(deadline),
+            None => Response::ok(self)
+        }
+    }
 }
 
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use ::error::Error;
+    use ::handlers::RequestHandler;
+    use super::{TranslateContext, TranslateError, Transport};
+
+    /// A handler that falls back to UDP and remembers what failed.
+    struct Fallback(Vec<(SocketAddr, bool)>);
+
+    impl RequestHandler for Fallback {
+        type Request = SocketAddr;
+        type Output = (SocketAddr, bool);
+
+        fn request(&mut self, addr: SocketAddr) -> Option<Self::Output> {
+            Some((addr, true))
+        }
+
+        fn fallback(&mut self, output: Self::Output, _err: Error)
+                    -> Option<Self::Output> {
+            self.0.push(output);
+            if output.1 { Some((output.0, false)) } else { None }
+        }
+    }
+
+    #[test]
+    fn context() {
+        let addr = SocketAddr::from_str("192.0.2.1:79").unwrap();
+        let err = TranslateError::new((addr, true), Error::Timeout,
+                                      Transport::Tcp, addr);
+        assert_eq!(err.context,
+                   TranslateContext { transport: Transport::Tcp,
+                                      addr: addr });
+    }
+
+    #[test]
+    fn translate_error_defaults_to_fallback() {
+        let addr = SocketAddr::from_str("192.0.2.1:79").unwrap();
+        let mut handler = Fallback(Vec::new());
+        let err = TranslateError::new((addr, true), Error::Timeout,
+                                      Transport::Tcp, addr);
+        assert_eq!(handler.translate_error(err), Some((addr, false)));
+        let err = TranslateError::new((addr, false), Error::Timeout,
+                                      Transport::Udp, addr);
+        assert_eq!(handler.translate_error(err), None);
+        assert_eq!(handler.0, vec![(addr, true), (addr, false)]);
+    }
+}
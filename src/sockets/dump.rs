@@ -0,0 +1,447 @@
+//! Sockets logging all data for protocol debugging.
+//!
+//! When debugging a protocol implementation, nothing beats looking at
+//! the actual bytes going over the wire. The [TracingSocket] wraps any
+//! transport socket and logs every buffer read from or written to it via
+//! the `log` crate at trace level. The data itself passes through
+//! unchanged.
+//!
+//! By default, data is logged as a hex dump with an ASCII column,
+//! sixteen bytes per line, and only the first 256 bytes of each buffer
+//! are logged. Both can be changed via the builder-style methods on the
+//! socket. Reads and writes that fail with `WouldBlock` aren’t logged
+//! since they would only drown out everything else.
+//!
+//! To also see what the transport handler makes of the data, wrap it
+//! into a [Tracer].
+//!
+//! [TracingSocket]: struct.TracingSocket.html
+//! [Tracer]: ../../handlers/struct.Tracer.html
+
+use std::cmp;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+use rotor::{Evented, EventSet, PollOpt};
+use rotor::mio::{Selector, Token};
+use super::{Blocked, ClearStream, Dgram, SecureStream, Stream, TcpInfo,
+            Transport};
+
+
+/// The number of bytes logged per buffer by default.
+const DEFAULT_LIMIT: usize = 256;
+
+/// The number of bytes per line of a hex dump.
+const HEX_LINE: usize = 16;
+
+
+//------------ TracingSocket -------------------------------------------------
+
+/// A socket logging all data read and written.
+///
+/// See the [module documentation](index.html) for details.
+pub struct TracingSocket<T> {
+    sock: T,
+    label: String,
+    limit: usize,
+    hex: bool,
+}
+
+/// # Creation and Configuration
+///
+impl<T> TracingSocket<T> {
+    /// Creates a new socket wrapping `sock` with the default settings.
+    pub fn new(sock: T) -> Self {
+        TracingSocket { sock: sock, label: String::new(),
+                        limit: DEFAULT_LIMIT, hex: true }
+    }
+
+    /// Sets a label to prefix all log messages with.
+    ///
+    /// This helps telling connections apart if there are several.
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = label.into();
+        self
+    }
+
+    /// Sets the maximum number of bytes logged for each buffer.
+    ///
+    /// Anything beyond is left out and only its length is logged.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Sets whether to log data as a hex dump.
+    ///
+    /// If `hex` is `false`, data is logged as a single string with all
+    /// non-printable bytes escaped instead.
+    pub fn hex(mut self, hex: bool) -> Self {
+        self.hex = hex;
+        self
+    }
+}
+
+/// # Access to the Socket
+///
+impl<T> TracingSocket<T> {
+    /// Returns a reference to the wrapped socket.
+    pub fn get_ref(&self) -> &T {
+        &self.sock
+    }
+
+    /// Returns a mutable reference to the wrapped socket.
+    ///
+    /// Data read or written through this reference isn’t logged.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.sock
+    }
+
+    /// Trades the socket for the wrapped socket.
+    pub fn into_inner(self) -> T {
+        self.sock
+    }
+}
+
+/// # Internal Helpers
+///
+impl<T> TracingSocket<T> {
+    /// Logs the result of an operation transferring `data`.
+    ///
+    /// Only the part of `data` that was actually transferred according
+    /// to `res` is logged.
+    fn log(&self, what: &str, data: &[u8], res: &io::Result<usize>) {
+        match *res {
+            Ok(len) => {
+                trace!("{}{} {} bytes{}", self.prefix(), what, len,
+                       self.dump(&data[..cmp::min(len, data.len())]));
+            }
+            Err(ref err) => self.log_err(what, err)
+        }
+    }
+
+    /// Logs a failed operation unless it merely would block.
+    fn log_err(&self, what: &str, err: &io::Error) {
+        if err.kind() != io::ErrorKind::WouldBlock {
+            trace!("{}{} failed: {}", self.prefix(), what, err);
+        }
+    }
+
+    /// Returns the prefix for log messages.
+    fn prefix(&self) -> String {
+        if self.label.is_empty() { String::new() }
+        else { format!("{}: ", self.label) }
+    }
+
+    /// Formats `data` for logging.
+    fn dump(&self, data: &[u8]) -> String {
+        let shown = &data[..cmp::min(data.len(), self.limit)];
+        let mut res = String::new();
+        if self.hex {
+            hex_dump(&mut res, shown)
+        }
+        else {
+            res.push_str(": ");
+            escape(&mut res, shown)
+        }
+        if shown.len() < data.len() {
+            let _ = write!(res, "{}... ({} more bytes)",
+                           if self.hex { "\n" } else { " " },
+                           data.len() - shown.len());
+        }
+        res
+    }
+}
+
+/// Appends a hex dump of `data` to `res`.
+///
+/// Each line starts with a line break, so the dump ends up below the
+/// message it is appended to.
+fn hex_dump(res: &mut String, data: &[u8]) {
+    for (i, line) in data.chunks(HEX_LINE).enumerate() {
+        let _ = write!(res, "\n{:04x} ", i * HEX_LINE);
+        for pos in 0..HEX_LINE {
+            match line.get(pos) {
+                Some(ch) => { let _ = write!(res, " {:02x}", ch); }
+                None => res.push_str("   ")
+            }
+        }
+        res.push_str("  |");
+        for &ch in line {
+            res.push(if ch >= 0x20 && ch < 0x7f { ch as char } else { '.' })
+        }
+        res.push('|');
+    }
+}
+
+/// Appends `data` to `res` with all non-printable bytes escaped.
+fn escape(res: &mut String, data: &[u8]) {
+    res.push('"');
+    for &ch in data {
+        match ch {
+            b'\r' => res.push_str("\\r"),
+            b'\n' => res.push_str("\\n"),
+            b'\t' => res.push_str("\\t"),
+            b'"' => res.push_str("\\\""),
+            b'\\' => res.push_str("\\\\"),
+            0x20...0x7e => res.push(ch as char),
+            _ => { let _ = write!(res, "\\x{:02x}", ch); }
+        }
+    }
+    res.push('"');
+}
+
+
+//--- Read
+
+impl<T: Read> Read for TracingSocket<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let res = self.sock.read(buf);
+        match res {
+            Ok(0) if !buf.is_empty() => {
+                trace!("{}read end of stream", self.prefix())
+            }
+            _ => self.log("read", buf, &res)
+        }
+        res
+    }
+}
+
+
+//--- Write
+
+impl<T: Write> Write for TracingSocket<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let res = self.sock.write(buf);
+        self.log("wrote", buf, &res);
+        res
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+
+//--- Evented
+
+impl<T: Evented> Evented for TracingSocket<T> {
+    fn register(&self, selector: &mut Selector, token: Token,
+                interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token,
+                  interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.sock.deregister(selector)
+    }
+}
+
+
+//--- Transport, Streams, and Dgram
+
+impl<T: Transport> Transport for TracingSocket<T> {
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        self.sock.take_socket_error()
+    }
+
+    fn blocked(&self) -> Option<Blocked> {
+        self.sock.blocked()
+    }
+
+    fn is_usable(&self) -> bool {
+        self.sock.is_usable()
+    }
+
+    fn urgent_pending(&self) -> bool {
+        self.sock.urgent_pending()
+    }
+
+    fn flush_output(&mut self) -> io::Result<bool> {
+        self.sock.flush_output()
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        trace!("{}shutting down writing", self.prefix());
+        self.sock.shutdown_write()
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        self.sock.set_nodelay(nodelay)
+    }
+
+    fn set_keepalive(&mut self, keepalive: Option<u32>) -> io::Result<()> {
+        self.sock.set_keepalive(keepalive)
+    }
+
+    fn set_linger(&mut self, linger: Option<Duration>) -> io::Result<()> {
+        self.sock.set_linger(linger)
+    }
+
+    fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        self.sock.set_recv_buffer_size(size)
+    }
+
+    fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        self.sock.set_send_buffer_size(size)
+    }
+
+    fn set_user_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.sock.set_user_timeout(timeout)
+    }
+
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        self.sock.set_tos(tos)
+    }
+
+    fn tos(&self) -> io::Result<u8> {
+        self.sock.tos()
+    }
+
+    fn set_congestion_control(&mut self, name: &str) -> io::Result<()> {
+        self.sock.set_congestion_control(name)
+    }
+
+    fn congestion_control(&self) -> io::Result<String> {
+        self.sock.congestion_control()
+    }
+
+    fn tcp_info(&self) -> io::Result<TcpInfo> {
+        self.sock.tcp_info()
+    }
+
+    fn write_ready_hint(&self) -> bool {
+        self.sock.write_ready_hint()
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        self.sock.is_connected()
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        self.sock.pending_write_bytes()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.peer_addr()
+    }
+
+    fn take_secured(&mut self) -> bool {
+        self.sock.take_secured()
+    }
+}
+
+impl<T: Stream> Stream for TracingSocket<T> {
+    fn writev(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        let res = self.sock.writev(bufs);
+        if let Ok(mut left) = res {
+            for buf in bufs {
+                if left == 0 {
+                    break
+                }
+                let len = cmp::min(left, buf.len());
+                self.log("wrote", buf, &Ok(len));
+                left -= len;
+            }
+        }
+        else if let Err(ref err) = res {
+            self.log_err("writing", err)
+        }
+        res
+    }
+}
+
+impl<T: ClearStream> ClearStream for TracingSocket<T> {
+    fn recv_oob(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let res = self.sock.recv_oob(buf);
+        if let Ok(Some(len)) = res {
+            self.log("read urgent", buf, &Ok(len))
+        }
+        else if let Err(ref err) = res {
+            self.log_err("reading urgent data", err)
+        }
+        res
+    }
+
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        self.sock.peek(buf)
+    }
+}
+
+impl<T: SecureStream> SecureStream for TracingSocket<T> {
+    type Certificate = T::Certificate;
+
+    fn get_peer_cert(&self) -> Self::Certificate {
+        self.sock.get_peer_cert()
+    }
+
+    fn selected_protocol(&self) -> Option<Vec<u8>> {
+        self.sock.selected_protocol()
+    }
+
+    fn peer_certificate(&self) -> Option<Vec<u8>> {
+        self.sock.peer_certificate()
+    }
+}
+
+impl<T: Dgram> Dgram for TracingSocket<T> {
+    fn recv_from(&self, buf: &mut [u8])
+                 -> io::Result<Option<(usize, SocketAddr)>> {
+        let res = self.sock.recv_from(buf);
+        match res {
+            Ok(Some((len, addr))) => {
+                trace!("{}received {} bytes from {}{}", self.prefix(), len,
+                       addr, self.dump(&buf[..cmp::min(len, buf.len())]))
+            }
+            Err(ref err) => self.log_err("receiving", err),
+            Ok(None) => { }
+        }
+        res
+    }
+
+    fn recv_from_trunc(&self, buf: &mut [u8])
+                       -> io::Result<Option<(usize, SocketAddr, bool)>> {
+        let res = self.sock.recv_from_trunc(buf);
+        match res {
+            Ok(Some((len, addr, trunc))) => {
+                trace!("{}received {} bytes from {}{}{}", self.prefix(), len,
+                       addr, if trunc { " (truncated)" } else { "" },
+                       self.dump(&buf[..cmp::min(len, buf.len())]))
+            }
+            Err(ref err) => self.log_err("receiving", err),
+            Ok(None) => { }
+        }
+        res
+    }
+
+    fn send_to(&self, buf: &[u8], target: &SocketAddr)
+               -> io::Result<Option<usize>> {
+        let res = self.sock.send_to(buf, target);
+        match res {
+            Ok(Some(len)) => {
+                trace!("{}sent {} bytes to {}{}", self.prefix(), len, target,
+                       self.dump(&buf[..cmp::min(len, buf.len())]))
+            }
+            Err(ref err) => self.log_err("sending", err),
+            Ok(None) => { }
+        }
+        res
+    }
+
+    fn max_send_size(&self) -> usize {
+        self.sock.max_send_size()
+    }
+
+    fn set_dont_fragment(&self, dont_fragment: bool) -> io::Result<()> {
+        self.sock.set_dont_fragment(dont_fragment)
+    }
+
+    fn path_mtu(&self) -> io::Result<Option<usize>> {
+        self.sock.path_mtu()
+    }
+}
@@ -0,0 +1,442 @@
+//! Injecting faults into stream sockets for testing.
+//!
+//! Handlers need to cope with sockets that don’t always behave nicely:
+//! reads and writes that would block, that only transfer part of the
+//! data, or connections that are reset by the peer. With real sockets,
+//! these situations are hard to bring about on demand. The
+//! [FaultySocket] type wraps any stream socket, real or mock, and
+//! injects such faults according to a [FaultScript], so that a handler’s
+//! error and backpressure paths can be exercised deterministically.
+//!
+//! # Scripting Format
+//!
+//! A script consists of two queues of [Fault]s, one for reads and one
+//! for writes. Each call to `read()` or `write()` takes the next fault
+//! off the front of its queue and behaves accordingly. Once a queue is
+//! empty, calls are passed through to the socket unchanged.
+//!
+//! Scripts can be built step by step via the builder-style methods of
+//! [FaultScript] or parsed from a string with [FaultScript::parse()].
+//! The string format is a sequence of items separated by white space or
+//! semicolons. Each item has the form `op:fault` or `op:fault*count`
+//! where `op` is `r` or `read` for the read queue and `w` or `write`
+//! for the write queue, and `fault` is one of
+//!
+//! * `pass` for performing the operation normally,
+//! * `block` for failing with `WouldBlock` without touching the socket,
+//! * `partial=n` for transferring at most `n` bytes,
+//! * `slow` for slow delivery, which is a `block` followed by a
+//!   `partial=1`, and
+//! * `reset` for failing with `ConnectionReset`.
+//!
+//! The optional `count` repeats the fault that many times. For instance,
+//! `"r:block*2; r:partial=10 w:slow*100 w:reset"` makes the first two
+//! reads block and the third read return at most ten bytes, while
+//! writes trickle out one byte at a time for a while until the
+//! connection is reset.
+//!
+//! A reset is final: once it has been injected, all further reads and
+//! writes fail with `ConnectionReset` and the socket claims to be
+//! unusable, just like a real socket after the peer reset the
+//! connection. Faults only ever apply to `read()` and `write()`. Note
+//! that the default `writev()` of the [Stream] trait writes buffer by
+//! buffer and thus takes one fault for each buffer it writes.
+//!
+//! The module is only available with the `test-util` feature.
+//!
+//! [FaultySocket]: struct.FaultySocket.html
+//! [FaultScript]: struct.FaultScript.html
+//! [FaultScript::parse()]: struct.FaultScript.html#method.parse
+//! [Fault]: enum.Fault.html
+//! [Stream]: ../trait.Stream.html
+
+use std::{cmp, error, fmt};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+use rotor::{Evented, EventSet, PollOpt};
+use rotor::mio::{Selector, Token};
+use super::{Blocked, ClearStream, SecureStream, Stream, TcpInfo,
+            Transport};
+
+
+//------------ Fault ---------------------------------------------------------
+
+/// A fault to be injected into a single read or write.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Fault {
+    /// Perform the operation normally.
+    Pass,
+
+    /// Fail with `WouldBlock` without touching the socket.
+    Block,
+
+    /// Transfer at most the given number of bytes.
+    ///
+    /// A limit of zero is treated as one since transferring nothing would
+    /// look like the peer having closed the connection.
+    Partial(usize),
+
+    /// Fail with `ConnectionReset` now and forever.
+    Reset,
+}
+
+
+//------------ FaultScript ---------------------------------------------------
+
+/// The script of faults to inject into a socket.
+///
+/// See the [module documentation](index.html) for the details of how
+/// scripts work and their string format.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FaultScript {
+    reads: VecDeque<Fault>,
+    writes: VecDeque<Fault>,
+}
+
+impl FaultScript {
+    /// Creates a new, empty script.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses a script from its string representation.
+    pub fn parse(s: &str) -> Result<Self, ScriptError> {
+        let mut res = FaultScript::new();
+        for item in s.split(|ch: char| ch == ';' || ch.is_whitespace()) {
+            if !item.is_empty() {
+                try!(res.parse_item(item))
+            }
+        }
+        Ok(res)
+    }
+
+    /// Appends a fault for the next read not yet scripted.
+    pub fn read(mut self, fault: Fault) -> Self {
+        self.reads.push_back(fault);
+        self
+    }
+
+    /// Appends a fault for the next write not yet scripted.
+    pub fn write(mut self, fault: Fault) -> Self {
+        self.writes.push_back(fault);
+        self
+    }
+
+    /// Returns whether all scripted faults have been used up.
+    pub fn is_empty(&self) -> bool {
+        self.reads.is_empty() && self.writes.is_empty()
+    }
+
+    /// Parses a single item of the string representation.
+    fn parse_item(&mut self, item: &str) -> Result<(), ScriptError> {
+        let err = || ScriptError(item.into());
+        let colon = match item.find(':') {
+            Some(colon) => colon,
+            None => return Err(err())
+        };
+        let (op, rest) = (&item[..colon], &item[colon + 1..]);
+        let (fault, count) = match rest.find('*') {
+            Some(star) => {
+                let count = try!(rest[star + 1..].parse::<usize>()
+                                                 .map_err(|_| err()));
+                (&rest[..star], count)
+            }
+            None => (rest, 1)
+        };
+        let faults = match fault {
+            "pass" => vec![Fault::Pass],
+            "block" => vec![Fault::Block],
+            "slow" => vec![Fault::Block, Fault::Partial(1)],
+            "reset" => vec![Fault::Reset],
+            _ if fault.starts_with("partial=") => {
+                let len = try!(fault["partial=".len()..].parse::<usize>()
+                                                        .map_err(|_| err()));
+                vec![Fault::Partial(len)]
+            }
+            _ => return Err(err())
+        };
+        let queue = match op {
+            "r" | "read" => &mut self.reads,
+            "w" | "write" => &mut self.writes,
+            _ => return Err(err())
+        };
+        for _ in 0..count {
+            queue.extend(faults.iter().cloned())
+        }
+        Ok(())
+    }
+}
+
+
+//------------ FaultySocket --------------------------------------------------
+
+/// A stream socket injecting faults according to a script.
+///
+/// See the [module documentation](index.html) for details.
+pub struct FaultySocket<T: Stream> {
+    sock: T,
+    script: FaultScript,
+    reset: bool,
+}
+
+impl<T: Stream> FaultySocket<T> {
+    /// Creates a new socket wrapping `sock` and following `script`.
+    pub fn new(sock: T, script: FaultScript) -> Self {
+        FaultySocket { sock: sock, script: script, reset: false }
+    }
+
+    /// Returns a reference to the wrapped socket.
+    pub fn get_ref(&self) -> &T {
+        &self.sock
+    }
+
+    /// Returns a mutable reference to the wrapped socket.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.sock
+    }
+
+    /// Returns a reference to what is left of the script.
+    pub fn script(&self) -> &FaultScript {
+        &self.script
+    }
+
+    /// Replaces what is left of the script with `script`.
+    ///
+    /// This can be used to script new faults as a test moves on.
+    pub fn set_script(&mut self, script: FaultScript) {
+        self.script = script
+    }
+
+    /// Returns whether a reset has been injected.
+    pub fn is_reset(&self) -> bool {
+        self.reset
+    }
+
+    /// Trades the socket for the wrapped socket.
+    pub fn into_inner(self) -> T {
+        self.sock
+    }
+
+    /// Applies the next fault from `queue` to an operation on `len` bytes.
+    ///
+    /// Returns the number of bytes the operation may transfer or the
+    /// error to fail with.
+    fn apply(reset: &mut bool, queue: &mut VecDeque<Fault>, len: usize)
+             -> io::Result<usize> {
+        if *reset {
+            return Err(reset_error())
+        }
+        match queue.pop_front().unwrap_or(Fault::Pass) {
+            Fault::Pass => Ok(len),
+            Fault::Block => {
+                Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                   "injected fault"))
+            }
+            Fault::Partial(limit) => Ok(cmp::min(cmp::max(limit, 1), len)),
+            Fault::Reset => {
+                *reset = true;
+                Err(reset_error())
+            }
+        }
+    }
+}
+
+/// Creates the error for a reset connection.
+fn reset_error() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "injected fault")
+}
+
+
+//--- Read
+
+impl<T: Stream> Read for FaultySocket<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = try!(Self::apply(&mut self.reset, &mut self.script.reads,
+                                   buf.len()));
+        self.sock.read(&mut buf[..len])
+    }
+}
+
+
+//--- Write
+
+impl<T: Stream> Write for FaultySocket<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = try!(Self::apply(&mut self.reset, &mut self.script.writes,
+                                   buf.len()));
+        self.sock.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.reset {
+            return Err(reset_error())
+        }
+        self.sock.flush()
+    }
+}
+
+
+//--- Evented
+
+impl<T: Stream> Evented for FaultySocket<T> {
+    fn register(&self, selector: &mut Selector, token: Token,
+                interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token,
+                  interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.sock.deregister(selector)
+    }
+}
+
+
+//--- Transport and Streams
+
+impl<T: Stream> Transport for FaultySocket<T> {
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        self.sock.take_socket_error()
+    }
+
+    fn blocked(&self) -> Option<Blocked> {
+        self.sock.blocked()
+    }
+
+    fn is_usable(&self) -> bool {
+        !self.reset && self.sock.is_usable()
+    }
+
+    fn urgent_pending(&self) -> bool {
+        self.sock.urgent_pending()
+    }
+
+    fn flush_output(&mut self) -> io::Result<bool> {
+        if self.reset {
+            return Err(reset_error())
+        }
+        self.sock.flush_output()
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.sock.shutdown_write()
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        self.sock.set_nodelay(nodelay)
+    }
+
+    fn set_keepalive(&mut self, keepalive: Option<u32>) -> io::Result<()> {
+        self.sock.set_keepalive(keepalive)
+    }
+
+    fn set_linger(&mut self, linger: Option<Duration>) -> io::Result<()> {
+        self.sock.set_linger(linger)
+    }
+
+    fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        self.sock.set_recv_buffer_size(size)
+    }
+
+    fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        self.sock.set_send_buffer_size(size)
+    }
+
+    fn set_user_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.sock.set_user_timeout(timeout)
+    }
+
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        self.sock.set_tos(tos)
+    }
+
+    fn tos(&self) -> io::Result<u8> {
+        self.sock.tos()
+    }
+
+    fn set_congestion_control(&mut self, name: &str) -> io::Result<()> {
+        self.sock.set_congestion_control(name)
+    }
+
+    fn congestion_control(&self) -> io::Result<String> {
+        self.sock.congestion_control()
+    }
+
+    fn tcp_info(&self) -> io::Result<TcpInfo> {
+        self.sock.tcp_info()
+    }
+
+    fn write_ready_hint(&self) -> bool {
+        self.sock.write_ready_hint()
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        self.sock.is_connected()
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        self.sock.pending_write_bytes()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.peer_addr()
+    }
+
+    fn take_secured(&mut self) -> bool {
+        self.sock.take_secured()
+    }
+}
+
+impl<T: Stream> Stream for FaultySocket<T> { }
+
+impl<T: ClearStream> ClearStream for FaultySocket<T> {
+    fn recv_oob(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        self.sock.recv_oob(buf)
+    }
+
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        self.sock.peek(buf)
+    }
+}
+
+impl<T: SecureStream> SecureStream for FaultySocket<T> {
+    type Certificate = T::Certificate;
+
+    fn get_peer_cert(&self) -> Self::Certificate {
+        self.sock.get_peer_cert()
+    }
+
+    fn selected_protocol(&self) -> Option<Vec<u8>> {
+        self.sock.selected_protocol()
+    }
+
+    fn peer_certificate(&self) -> Option<Vec<u8>> {
+        self.sock.peer_certificate()
+    }
+}
+
+
+//------------ ScriptError ---------------------------------------------------
+
+/// A fault script string could not be parsed.
+///
+/// The value contains the offending item.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ScriptError(String);
+
+impl error::Error for ScriptError {
+    fn description(&self) -> &str {
+        "invalid fault script"
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid fault script item '{}'", self.0)
+    }
+}
@@ -0,0 +1,540 @@
+//! Mock sockets for testing.
+//!
+//! There are three mock sockets: [MockStream] for stream sockets,
+//! [MockListener] for accepting those, and [MockDgram] for datagram
+//! sockets.
+//!
+//! A [MockStream] is an unencrypted stream socket that isn’t connected to
+//! anything. Instead, it keeps everything in memory: a test pushes bytes
+//! into its inbound buffer, as if the peer had sent them, and inspects
+//! the bytes the code under test has written to the outbound buffer. It
+//! implements [ClearStream], so a transport handler generic over that
+//! trait can be driven by calling its `readable()` and `writable()`
+//! methods directly without running a loop. Since a notifier can only be
+//! had from a loop, the test needs to construct the handler itself rather
+//! than through `TransportHandler::create()`.
+//!
+//! Whether the socket is ready for reading and writing can be switched
+//! off, in which case the operations fail with `WouldBlock` just like
+//! they would on a real non-blocking socket. Reading also fails with
+//! `WouldBlock` if the inbound buffer is empty, unless the inbound side
+//! has been closed in which case it returns zero for end of file.
+//!
+//! A [MockListener] hands out mock streams queued up by a test, one per
+//! call to `accept()`, and then reports that nothing is pending. Clones of
+//! a listener share their queue, so a test can keep one and add further
+//! connections after handing the other to a server machine.
+//!
+//! A [MockDgram] works like a mock stream but for messages: a test pushes
+//! inbound messages together with their source address and collects the
+//! messages sent together with their target address.
+//!
+//! Since the sockets don’t have a file descriptor, registering them with
+//! a loop succeeds but doesn’t do anything. For more unpleasant
+//! behaviour, wrap a mock socket into a [FaultySocket].
+//!
+//! The module is only available with the `test-util` feature and for
+//! the crate’s own tests.
+//!
+//! [MockStream]: struct.MockStream.html
+//! [MockListener]: struct.MockListener.html
+//! [MockDgram]: struct.MockDgram.html
+//! [ClearStream]: ../trait.ClearStream.html
+//! [FaultySocket]: ../fault/struct.FaultySocket.html
+
+use std::cmp;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use rotor::{Evented, EventSet, PollOpt};
+use rotor::mio::{Selector, Token};
+use ::error::Result;
+use super::{Accept, ClearStream, Dgram, PeerAddr, Stream, Transport,
+            check_send_size};
+
+
+//------------ MockStream ----------------------------------------------------
+
+/// An in-memory stream socket.
+///
+/// See the [module documentation](index.html) for details.
+#[derive(Clone, Debug)]
+pub struct MockStream {
+    /// The bytes waiting to be read.
+    inbound: VecDeque<u8>,
+
+    /// The bytes written so far.
+    outbound: VecDeque<u8>,
+
+    /// Whether reading is possible at all.
+    readable: bool,
+
+    /// Whether writing is possible at all.
+    writable: bool,
+
+    /// Whether the peer has stopped sending.
+    inbound_closed: bool,
+
+    /// Whether writing has been shut down.
+    write_shutdown: bool,
+
+    /// The most bytes a single write accepts.
+    write_limit: Option<usize>,
+
+    /// The peer address to report.
+    peer: Option<SocketAddr>,
+}
+
+/// # Creation and Setup
+///
+impl MockStream {
+    /// Creates a new mock stream ready for reading and writing.
+    pub fn new() -> Self {
+        MockStream {
+            inbound: VecDeque::new(),
+            outbound: VecDeque::new(),
+            readable: true,
+            writable: true,
+            inbound_closed: false,
+            write_shutdown: false,
+            write_limit: None,
+            peer: None,
+        }
+    }
+
+    /// Sets whether the socket is ready for both reading and writing.
+    pub fn set_ready(&mut self, ready: bool) {
+        self.readable = ready;
+        self.writable = ready;
+    }
+
+    /// Sets whether the socket is ready for reading.
+    pub fn set_readable(&mut self, readable: bool) {
+        self.readable = readable;
+    }
+
+    /// Sets whether the socket is ready for writing.
+    pub fn set_writable(&mut self, writable: bool) {
+        self.writable = writable;
+    }
+
+    /// Limits how many bytes a single write accepts.
+    ///
+    /// This is useful for exercising the handling of partial writes.
+    /// A limit of zero is treated as one since accepting nothing would
+    /// look like the peer having closed the connection.
+    pub fn set_write_limit(&mut self, limit: Option<usize>) {
+        self.write_limit = limit;
+    }
+
+    /// Sets the address returned by `Transport::peer_addr()`.
+    pub fn set_peer_addr(&mut self, addr: Option<SocketAddr>) {
+        self.peer = addr;
+    }
+}
+
+/// # The Peer’s Side
+///
+impl MockStream {
+    /// Adds bytes to the end of the inbound buffer.
+    pub fn push_inbound(&mut self, data: &[u8]) {
+        self.inbound.extend(data)
+    }
+
+    /// Returns the number of bytes waiting to be read.
+    pub fn inbound_len(&self) -> usize {
+        self.inbound.len()
+    }
+
+    /// Closes the inbound side.
+    ///
+    /// Once everything has been read from the inbound buffer, reading
+    /// returns zero for end of file.
+    pub fn close_inbound(&mut self) {
+        self.inbound_closed = true
+    }
+
+    /// Returns the bytes written so far without removing them.
+    pub fn outbound(&self) -> Vec<u8> {
+        self.outbound.iter().cloned().collect()
+    }
+
+    /// Returns the number of bytes written so far.
+    pub fn outbound_len(&self) -> usize {
+        self.outbound.len()
+    }
+
+    /// Removes and returns the bytes written so far.
+    pub fn take_outbound(&mut self) -> Vec<u8> {
+        self.outbound.drain(..).collect()
+    }
+
+    /// Returns whether writing has been shut down.
+    pub fn is_write_shutdown(&self) -> bool {
+        self.write_shutdown
+    }
+}
+
+/// # Internal Helpers
+///
+impl MockStream {
+    /// Copies inbound bytes into `buf`, removing them if `consume` is set.
+    fn fetch(&mut self, buf: &mut [u8], consume: bool) -> io::Result<usize> {
+        if !self.readable {
+            return Err(would_block())
+        }
+        if self.inbound.is_empty() {
+            if self.inbound_closed || buf.is_empty() {
+                return Ok(0)
+            }
+            return Err(would_block())
+        }
+        let len = cmp::min(buf.len(), self.inbound.len());
+        for (target, source) in buf.iter_mut().zip(self.inbound.iter()) {
+            *target = *source
+        }
+        if consume {
+            self.inbound.drain(..len);
+        }
+        Ok(len)
+    }
+}
+
+
+//--- Default
+
+impl Default for MockStream {
+    fn default() -> Self {
+        MockStream::new()
+    }
+}
+
+
+//--- Read and Write
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fetch(buf, true)
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.write_shutdown {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe,
+                                      "writing has been shut down"))
+        }
+        if !self.writable {
+            return Err(would_block())
+        }
+        let len = match self.write_limit {
+            Some(limit) => cmp::min(buf.len(), cmp::max(limit, 1)),
+            None => buf.len()
+        };
+        self.outbound.extend(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+//--- Evented
+
+impl Evented for MockStream {
+    fn register(&self, _selector: &mut Selector, _token: Token,
+                _interest: EventSet, _opts: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reregister(&self, _selector: &mut Selector, _token: Token,
+                  _interest: EventSet, _opts: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&self, _selector: &mut Selector) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+//--- Transport, Stream, and ClearStream
+
+impl Transport for MockStream {
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.write_shutdown = true;
+        Ok(())
+    }
+
+    fn write_ready_hint(&self) -> bool {
+        self.writable
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.peer.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "no peer address")
+        })
+    }
+}
+
+impl Stream for MockStream { }
+
+impl ClearStream for MockStream {
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match self.fetch(buf, false) {
+            Ok(len) => Ok(Some(len)),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                Ok(None)
+            }
+            Err(err) => Err(err)
+        }
+    }
+}
+
+
+//------------ MockListener --------------------------------------------------
+
+/// A listener handing out mock streams.
+///
+/// See the [module documentation](index.html) for details.
+#[derive(Clone, Debug, Default)]
+pub struct MockListener {
+    /// The connections waiting to be accepted.
+    queue: Rc<RefCell<VecDeque<(MockStream, PeerAddr)>>>,
+}
+
+impl MockListener {
+    /// Creates a new listener without any pending connections.
+    pub fn new() -> Self {
+        MockListener { queue: Rc::new(RefCell::new(VecDeque::new())) }
+    }
+
+    /// Queues up a connection from the peer `addr`.
+    pub fn push(&self, sock: MockStream, addr: SocketAddr) {
+        self.queue.borrow_mut().push_back((sock, PeerAddr::Inet(addr)))
+    }
+
+    /// Queues up a connection with an arbitrary peer address.
+    pub fn push_peer(&self, sock: MockStream, addr: PeerAddr) {
+        self.queue.borrow_mut().push_back((sock, addr))
+    }
+
+    /// Returns the number of connections waiting to be accepted.
+    pub fn pending(&self) -> usize {
+        self.queue.borrow().len()
+    }
+}
+
+
+//--- Evented
+
+impl Evented for MockListener {
+    fn register(&self, _selector: &mut Selector, _token: Token,
+                _interest: EventSet, _opts: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reregister(&self, _selector: &mut Selector, _token: Token,
+                  _interest: EventSet, _opts: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&self, _selector: &mut Selector) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+//--- Accept
+
+impl Accept for MockListener {
+    type Output = MockStream;
+
+    fn accept(&self) -> Result<Option<(Self::Output, PeerAddr)>> {
+        Ok(self.queue.borrow_mut().pop_front())
+    }
+}
+
+
+//------------ MockDgram -----------------------------------------------------
+
+/// An in-memory datagram socket.
+///
+/// See the [module documentation](index.html) for details.
+#[derive(Clone, Debug)]
+pub struct MockDgram {
+    /// The messages waiting to be received and their source.
+    inbound: RefCell<VecDeque<(Vec<u8>, SocketAddr)>>,
+
+    /// The messages sent so far and their target.
+    outbound: RefCell<VecDeque<(Vec<u8>, SocketAddr)>>,
+
+    /// Whether receiving is possible at all.
+    readable: bool,
+
+    /// Whether sending is possible at all.
+    writable: bool,
+
+    /// The size of the largest message that can be sent.
+    max_send_size: usize,
+}
+
+/// # Creation and Setup
+///
+impl MockDgram {
+    /// Creates a new mock socket ready for receiving and sending.
+    pub fn new() -> Self {
+        MockDgram {
+            inbound: RefCell::new(VecDeque::new()),
+            outbound: RefCell::new(VecDeque::new()),
+            readable: true,
+            writable: true,
+            max_send_size: usize::max_value(),
+        }
+    }
+
+    /// Sets whether the socket is ready for both receiving and sending.
+    pub fn set_ready(&mut self, ready: bool) {
+        self.readable = ready;
+        self.writable = ready;
+    }
+
+    /// Sets whether the socket is ready for receiving.
+    pub fn set_readable(&mut self, readable: bool) {
+        self.readable = readable;
+    }
+
+    /// Sets whether the socket is ready for sending.
+    pub fn set_writable(&mut self, writable: bool) {
+        self.writable = writable;
+    }
+
+    /// Sets the size of the largest message that can be sent.
+    ///
+    /// Sending a larger message fails just like it would on a real
+    /// socket.
+    pub fn set_max_send_size(&mut self, size: usize) {
+        self.max_send_size = size;
+    }
+}
+
+/// # The Peers’ Side
+///
+impl MockDgram {
+    /// Adds a message from `source` to the end of the inbound queue.
+    pub fn push_inbound(&self, data: &[u8], source: SocketAddr) {
+        self.inbound.borrow_mut().push_back((data.into(), source))
+    }
+
+    /// Returns the number of messages waiting to be received.
+    pub fn inbound_len(&self) -> usize {
+        self.inbound.borrow().len()
+    }
+
+    /// Returns the number of messages sent so far.
+    pub fn outbound_len(&self) -> usize {
+        self.outbound.borrow().len()
+    }
+
+    /// Removes and returns the messages sent so far and their target.
+    pub fn take_outbound(&self) -> Vec<(Vec<u8>, SocketAddr)> {
+        self.outbound.borrow_mut().drain(..).collect()
+    }
+}
+
+
+//--- Default
+
+impl Default for MockDgram {
+    fn default() -> Self {
+        MockDgram::new()
+    }
+}
+
+
+//--- Evented
+
+impl Evented for MockDgram {
+    fn register(&self, _selector: &mut Selector, _token: Token,
+                _interest: EventSet, _opts: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reregister(&self, _selector: &mut Selector, _token: Token,
+                  _interest: EventSet, _opts: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&self, _selector: &mut Selector) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+//--- Transport and Dgram
+
+impl Transport for MockDgram {
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+impl Dgram for MockDgram {
+    fn recv_from(&self, buf: &mut [u8])
+                 -> io::Result<Option<(usize, SocketAddr)>> {
+        let res = try!(self.recv_from_trunc(buf));
+        Ok(res.map(|(len, addr, _)| (len, addr)))
+    }
+
+    fn recv_from_trunc(&self, buf: &mut [u8])
+                       -> io::Result<Option<(usize, SocketAddr, bool)>> {
+        if !self.readable {
+            return Ok(None)
+        }
+        let (data, addr) = match self.inbound.borrow_mut().pop_front() {
+            Some(item) => item,
+            None => return Ok(None)
+        };
+        let len = cmp::min(buf.len(), data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(Some((len, addr, len < data.len())))
+    }
+
+    fn send_to(&self, buf: &[u8], target: &SocketAddr)
+               -> io::Result<Option<usize>> {
+        try!(check_send_size(self, buf.len()));
+        if !self.writable {
+            return Ok(None)
+        }
+        self.outbound.borrow_mut().push_back((buf.into(), *target));
+        Ok(Some(buf.len()))
+    }
+
+    fn max_send_size(&self) -> usize {
+        self.max_send_size
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// Returns a `WouldBlock` error.
+fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "mock socket not ready")
+}
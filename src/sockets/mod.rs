@@ -21,29 +21,61 @@
 //!
 //! When implementing handlers, always make the implementation generic over
 //! one of these traits so that you can use them with real networked sockets
-//! and mock sockets.
+//! and mock sockets. With the `test-util` feature, the [mock] module
+//! provides an in-memory stream socket for exactly this purpose.
 //!
 //! In addition, there is an [Accept] trait which defines the listener
 //! socket for the stream sockets. This is only used to implement the
 //! various state machines. You won’t need to worry about it when
-//! implementing handlers.
+//! implementing handlers. The peer address of an accepted socket is a
+//! [PeerAddr] which covers both network sockets and, on Unix, Unix domain
+//! sockets.
 //!
 //! [ClearStream]: trait.ClearStream.html
 //! [SecureStream]: trait.SecureStream.html
 //! [HybridStream]: trait.HybridStream.html
 //! [Dgram]: trait.ClearDgram.html
 //! [Accept]: trait.Accept.html
+//! [PeerAddr]: enum.PeerAddr.html
+//! [mock]: mock/index.html
 
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::fmt;
 use std::io::{self, Read, Write};
-use std::net::SocketAddr;
-use rotor::mio::{Evented, TryRead, TryWrite};
+use std::net::{self, IpAddr, Shutdown, SocketAddr};
+#[cfg(unix)] use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::PathBuf;
+#[cfg(unix)] use libc;
+use std::time::Duration;
+use rotor::{EventSet, PollOpt};
+use rotor::mio::{Evented, Selector, Token, TryRead, TryWrite};
 use rotor::mio::tcp::{TcpListener, TcpStream};
 use rotor::mio::udp::UdpSocket;
-use ::error::Result;
+#[cfg(unix)] use rotor::mio::unix::{UnixListener, UnixStream};
+use ::error::{Error, Result};
+
+pub mod dump;
+
+#[cfg(feature = "test-util")]
+pub mod fault;
+
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
 
 #[cfg(feature = "openssl")]
 pub mod openssl;
 
+#[cfg(feature = "rustls")]
+pub mod rustls;
+
+#[cfg(feature = "security-framework")]
+pub mod security_framework;
+
+pub mod tls;
+
+mod sockopt;
+
 
 //------------ Accept -------------------------------------------------------
 
@@ -67,7 +99,7 @@ pub trait Accept: Evented {
     ///
     /// The method may also fail with various IO errors. Generally, just
     /// shrugging and trying again later is fine.
-    fn accept(&self) -> Result<Option<(Self::Output, SocketAddr)>>;
+    fn accept(&self) -> Result<Option<(Self::Output, PeerAddr)>>;
 }
 
 
@@ -76,8 +108,145 @@ pub trait Accept: Evented {
 impl Accept for TcpListener {
     type Output = TcpStream;
 
-    fn accept(&self) -> Result<Option<(Self::Output, SocketAddr)>> {
-        Ok(try!(self.accept()))
+    fn accept(&self) -> Result<Option<(Self::Output, PeerAddr)>> {
+        match try!(self.accept()) {
+            Some((sock, addr)) => Ok(Some((sock, PeerAddr::Inet(addr)))),
+            None => Ok(None)
+        }
+    }
+}
+
+
+//--- impl for UnixListener
+
+#[cfg(unix)]
+impl Accept for UnixListener {
+    type Output = UnixStream;
+
+    fn accept(&self) -> Result<Option<(Self::Output, PeerAddr)>> {
+        match try!(self.accept()) {
+            Some(sock) => {
+                let path = sockopt::unix_peer_path(&sock);
+                Ok(Some((sock, PeerAddr::Unix(path))))
+            }
+            None => Ok(None)
+        }
+    }
+}
+
+
+/// Creates a TCP listener from a raw file descriptor.
+///
+/// The file descriptor must be a stream socket that is already bound and
+/// listening. This is checked and the function fails if it isn’t. It is
+/// typically used by services that receive their listening sockets from
+/// the outside, for instance from systemd socket activation. See the
+/// [net::systemd] module for this particular case.
+///
+/// # Safety
+///
+/// The function takes ownership of the file descriptor. It will be closed
+/// when the listener is dropped or if the function fails. Nobody else must
+/// use the descriptor afterwards.
+///
+/// [net::systemd]: ../net/systemd/index.html
+#[cfg(unix)]
+pub unsafe fn tcp_listener_from_raw_fd(fd: RawFd) -> io::Result<TcpListener> {
+    let lsnr = net::TcpListener::from_raw_fd(fd);
+    try!(sockopt::check_listener(&lsnr));
+    let addr = try!(lsnr.local_addr());
+    TcpListener::from_listener(lsnr, &addr)
+}
+
+/// Creates a Unix domain socket listener from a raw file descriptor.
+///
+/// This is the same as [tcp_listener_from_raw_fd()] but for a Unix
+/// domain socket. The same requirements apply.
+///
+/// [tcp_listener_from_raw_fd()]: fn.tcp_listener_from_raw_fd.html
+#[cfg(unix)]
+pub unsafe fn unix_listener_from_raw_fd(fd: RawFd)
+                                        -> io::Result<UnixListener> {
+    let lsnr = UnixListener::from_raw_fd(fd);
+    try!(sockopt::check_listener(&lsnr));
+    Ok(lsnr)
+}
+
+/// Creates a TCP listener bound to an address that others can share.
+///
+/// The listener has the `SO_REUSEPORT` option set before binding to
+/// `addr`. Any number of listeners created this way can be bound to the
+/// same address and the kernel distributes incoming connections between
+/// them. This is typically used to run a server on several threads, each
+/// with its own loop and listener. See [net::MultiLoop] for a convenient
+/// way to do so.
+///
+/// [net::MultiLoop]: ../net/multi/struct.MultiLoop.html
+#[cfg(unix)]
+pub fn tcp_listener_reuse_port(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let lsnr = try!(sockopt::bind_reuse_port(addr));
+    let addr = try!(lsnr.local_addr());
+    TcpListener::from_listener(lsnr, &addr)
+}
+
+
+//------------ PeerAddr -----------------------------------------------------
+
+/// The address of the peer of an accepted socket.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum PeerAddr {
+    /// The peer of a network socket.
+    Inet(SocketAddr),
+
+    /// The peer of a Unix domain socket.
+    ///
+    /// Clients usually don’t bind their sockets to a path, so this is
+    /// `None` most of the time.
+    Unix(Option<PathBuf>),
+}
+
+impl PeerAddr {
+    /// Returns the socket address of a network peer.
+    pub fn inet(&self) -> Option<&SocketAddr> {
+        match *self {
+            PeerAddr::Inet(ref addr) => Some(addr),
+            PeerAddr::Unix(_) => None
+        }
+    }
+
+    /// Returns the IP address of a network peer.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.inet().map(SocketAddr::ip)
+    }
+
+    /// Returns whether this is the peer of a Unix domain socket.
+    pub fn is_unix(&self) -> bool {
+        match *self {
+            PeerAddr::Inet(_) => false,
+            PeerAddr::Unix(_) => true
+        }
+    }
+}
+
+
+//--- From
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        PeerAddr::Inet(addr)
+    }
+}
+
+
+//--- Display
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PeerAddr::Inet(ref addr) => addr.fmt(f),
+            PeerAddr::Unix(Some(ref path)) => path.display().fmt(f),
+            PeerAddr::Unix(None) => f.write_str("(unnamed)")
+        }
     }
 }
 
@@ -85,15 +254,256 @@ impl Accept for TcpListener {
 //------------ Transport ----------------------------------------------------
 
 /// A trait for any transport socket.
+///
+/// Besides the methods needed by the machines, the trait provides access to
+/// a number of common socket options. These are provided with default
+/// implementations that fail, so socket types only need to implement those
+/// that make sense for them.
 pub trait Transport: Evented {
     fn take_socket_error(&mut self) -> io::Result<()>;
     fn blocked(&self) -> Option<Blocked> { None }
+
+    /// Tries to write out output buffered within the socket itself.
+    ///
+    /// This is called by the machines before closing a socket. Returns
+    /// `Ok(true)` if there is nothing left or `Ok(false)` if the socket
+    /// would block before everything was written.
+    ///
+    /// The default implementation is for sockets without their own
+    /// buffering and simply returns `Ok(true)`.
+    fn flush_output(&mut self) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    /// Returns whether urgent data is waiting to be received.
+    ///
+    /// The default implementation returns `false`.
+    fn urgent_pending(&self) -> bool {
+        false
+    }
+
+    /// Returns whether the socket can still be used at all.
+    ///
+    /// Some sockets end up in a state where every operation fails, for
+    /// instance after a failed TLS handshake. The machines check this
+    /// after the handler is done and, if the socket has become unusable,
+    /// signal a single error to the handler and close the socket.
+    ///
+    /// The default implementation returns `true`.
+    fn is_usable(&self) -> bool {
+        true
+    }
+
+    /// Shuts down the writing half of the socket.
+    ///
+    /// After this, the peer will see an end of file when reading while
+    /// the socket can still be read from. The default implementation
+    /// fails as unsupported.
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        Err(sockopt::unsupported())
+    }
+
+    /// Disables or enables Nagle’s algorithm.
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        let _ = nodelay;
+        Err(sockopt::unsupported())
+    }
+
+    /// Sets the keepalive interval in seconds or disables keepalive.
+    fn set_keepalive(&mut self, keepalive: Option<u32>) -> io::Result<()> {
+        let _ = keepalive;
+        Err(sockopt::unsupported())
+    }
+
+    /// Sets how long closing the socket may linger to send remaining data.
+    fn set_linger(&mut self, linger: Option<Duration>) -> io::Result<()> {
+        let _ = linger;
+        Err(sockopt::unsupported())
+    }
+
+    /// Sets the size of the kernel’s receive buffer for the socket.
+    fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        let _ = size;
+        Err(sockopt::unsupported())
+    }
+
+    /// Sets the size of the kernel’s send buffer for the socket.
+    fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        let _ = size;
+        Err(sockopt::unsupported())
+    }
+
+    /// Sets how long sent data may remain unacknowledged.
+    ///
+    /// If data isn’t acknowledged by the peer within this time, the
+    /// connection is closed by the kernel and the handler receives an
+    /// error. This detects dead peers more reliably than timeouts in the
+    /// handler.
+    ///
+    /// When keepalive is enabled, the user timeout also applies to the
+    /// keepalive probes and overrides the number of probes. It should
+    /// therefore be larger than the keepalive interval.
+    ///
+    /// The option is only available on Linux. Elsewhere, TCP streams
+    /// accept the option but quietly ignore it.
+    fn set_user_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        let _ = timeout;
+        Err(sockopt::unsupported())
+    }
+
+    /// Sets the IP type of service field for outgoing packets.
+    ///
+    /// For IPv6 sockets, this sets the traffic class instead. Either way,
+    /// the upper six bits are the DSCP value used for prioritizing traffic.
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        let _ = tos;
+        Err(sockopt::unsupported())
+    }
+
+    /// Returns the currently set IP type of service or traffic class.
+    fn tos(&self) -> io::Result<u8> {
+        Err(sockopt::unsupported())
+    }
+
+    /// Selects the TCP congestion control algorithm by name.
+    ///
+    /// This is only available on Linux. The algorithm has to be known to
+    /// the kernel, ie., its module has to be loaded, and, unless the
+    /// process is privileged, be listed in
+    /// `net.ipv4.tcp_allowed_congestion_control`. Otherwise, this fails.
+    ///
+    /// The default implementation fails as unsupported.
+    fn set_congestion_control(&mut self, name: &str) -> io::Result<()> {
+        let _ = name;
+        Err(sockopt::unsupported())
+    }
+
+    /// Returns the name of the TCP congestion control algorithm in use.
+    fn congestion_control(&self) -> io::Result<String> {
+        Err(sockopt::unsupported())
+    }
+
+    /// Returns round-trip time and congestion statistics of a TCP socket.
+    ///
+    /// Handlers can use this to adapt their sending rate or to log the
+    /// quality of a connection. This is only available on Linux.
+    ///
+    /// The default implementation fails as unsupported.
+    fn tcp_info(&self) -> io::Result<TcpInfo> {
+        Err(sockopt::unsupported())
+    }
+
+    /// Returns whether another write is likely to make progress.
+    ///
+    /// After a partial write, the socket’s send buffer probably is full
+    /// and trying again right away will only fail with `WouldBlock`. If
+    /// the operating system allows a look at the send buffer, this method
+    /// returns `false` in this case so that callers can wait for the
+    /// socket to become writable instead of retrying. On Linux, TCP
+    /// sockets compare the amount of data queued for sending with the
+    /// size of the send buffer.
+    ///
+    /// The default implementation always returns `true`.
+    fn write_ready_hint(&self) -> bool {
+        true
+    }
+
+    /// Returns whether the socket is connected to a peer.
+    ///
+    /// For stream sockets, this turns `true` once an outgoing connect has
+    /// completed which is a more reliable signal than the first writable
+    /// event. For datagram sockets, it tells whether the socket has been
+    /// connected to a fixed peer address.
+    ///
+    /// Note that, depending on the platform, this may fail rather than
+    /// return `Ok(false)` while a connect is still pending. Use
+    /// `take_socket_error()` to learn whether a connect actually failed.
+    ///
+    /// The default implementation fails as unsupported.
+    fn is_connected(&self) -> io::Result<bool> {
+        Err(sockopt::unsupported())
+    }
+
+    /// Returns the number of bytes written but not yet sent.
+    ///
+    /// This is the output buffered within the socket itself, for
+    /// instance by a TLS session, plus, where the operating system
+    /// tells, the data waiting in the kernel’s send queue. The latter is
+    /// currently only available on Linux. Together with the size of its
+    /// own buffer, a handler can use this to limit how much output it
+    /// queues up for a slow peer.
+    ///
+    /// The default implementation returns zero.
+    fn pending_write_bytes(&self) -> usize {
+        0
+    }
+
+    /// Returns the address of the peer.
+    ///
+    /// For an accepted stream socket, this is the address the connection
+    /// came from, so a handler can learn it without having the accept
+    /// handler put it into its seed. A datagram socket only has a peer if
+    /// it has been connected and fails with `NotConnected` otherwise.
+    ///
+    /// The default implementation fails as unsupported.
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(sockopt::unsupported())
+    }
+
+    /// Returns whether the socket has become encrypted since last asked.
+    ///
+    /// For a [HybridStream], this returns `true` exactly once after the
+    /// handshake started by `connect_secure()` or `accept_secure()` has
+    /// completed successfully. The transport machine uses it to call the
+    /// handler’s `secured()` method.
+    ///
+    /// The default implementation always returns `false`.
+    ///
+    /// [HybridStream]: trait.HybridStream.html
+    fn take_secured(&mut self) -> bool {
+        false
+    }
 }
 
 
 //------------ Stream -------------------------------------------------------
 
-pub trait Stream: Read + Write + TryRead + TryWrite + Transport { }
+/// A trait for stream sockets.
+pub trait Stream: Read + Write + TryRead + TryWrite + Transport {
+    /// Writes a sequence of buffers as if they were one.
+    ///
+    /// This allows writing a queue of messages without copying them into
+    /// a single buffer first. The method returns the total number of bytes
+    /// written which may end in the middle of any of the buffers. The
+    /// caller can use it to drop the buffers that were written completely.
+    ///
+    /// Like `write()`, the method fails with `WouldBlock` if nothing at
+    /// all could be written.
+    ///
+    /// The default implementation writes the buffers one by one and stops
+    /// at the first one that wasn’t written completely.
+    fn writev(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        let mut total = 0;
+        for buf in bufs {
+            if buf.is_empty() {
+                continue
+            }
+            match self.write(buf) {
+                Ok(len) => {
+                    total += len;
+                    if len < buf.len() {
+                        break
+                    }
+                }
+                Err(err) => {
+                    if total > 0 { break }
+                    else { return Err(err) }
+                }
+            }
+        }
+        Ok(total)
+    }
+}
 
 
 //------------ ClearStream --------------------------------------------------
@@ -109,7 +519,32 @@ pub trait Stream: Read + Write + TryRead + TryWrite + Transport { }
 /// Note further that if reading or writing of non-empty buffers return
 /// `Ok(0)`, the other side has performed an orderly shutdown of the
 /// socket and it is time to let go.
-pub trait ClearStream: Stream { }
+pub trait ClearStream: Stream {
+    /// Receives out-of-band data, also known as TCP urgent data.
+    ///
+    /// Returns `Ok(None)` if there is no urgent data available. Note that
+    /// TCP only really supports a single byte of urgent data and that the
+    /// data is delivered inline instead if `SO_OOBINLINE` is set.
+    ///
+    /// The default implementation fails as unsupported.
+    fn recv_oob(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let _ = buf;
+        Err(sockopt::unsupported())
+    }
+
+    /// Receives data without consuming it.
+    ///
+    /// The data stays in the socket and will be returned again by the
+    /// next read. This is useful for classifying a connection by its
+    /// first few bytes. Returns `Ok(None)` if there is no data available
+    /// yet and `Ok(Some(0))` if the peer has shut down the connection.
+    ///
+    /// The default implementation fails as unsupported.
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let _ = buf;
+        Err(sockopt::unsupported())
+    }
+}
 
 
 //--- impl for TcpStream
@@ -118,11 +553,145 @@ impl Transport for TcpStream {
     fn take_socket_error(&mut self) -> io::Result<()> {
         TcpStream::take_socket_error(self)
     }
+
+    fn urgent_pending(&self) -> bool {
+        let mut buf = [0u8];
+        match sockopt::recv_oob(self, &mut buf, true) {
+            Ok(Some(_)) => true,
+            _ => false
+        }
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Write)
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+
+    fn set_keepalive(&mut self, keepalive: Option<u32>) -> io::Result<()> {
+        TcpStream::set_keepalive(self, keepalive)
+    }
+
+    fn set_linger(&mut self, linger: Option<Duration>) -> io::Result<()> {
+        sockopt::set_linger(&*self, linger)
+    }
+
+    fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        sockopt::set_recv_buffer_size(&*self, size)
+    }
+
+    fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        sockopt::set_send_buffer_size(&*self, size)
+    }
+
+    fn set_user_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        sockopt::set_user_timeout(&*self, timeout)
+    }
+
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        let v6 = try!(self.local_addr()).is_ipv6();
+        sockopt::set_tos(&*self, v6, tos)
+    }
+
+    fn tos(&self) -> io::Result<u8> {
+        sockopt::tos(self, try!(self.local_addr()).is_ipv6())
+    }
+
+    fn set_congestion_control(&mut self, name: &str) -> io::Result<()> {
+        sockopt::set_congestion_control(&*self, name)
+    }
+
+    fn congestion_control(&self) -> io::Result<String> {
+        sockopt::congestion_control(self)
+    }
+
+    fn tcp_info(&self) -> io::Result<TcpInfo> {
+        sockopt::tcp_info(self)
+    }
+
+    fn write_ready_hint(&self) -> bool {
+        sockopt::write_ready_hint(self)
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        sockopt::peer_result(self.peer_addr())
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        sockopt::send_queue_len(self)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
+
+impl Stream for TcpStream {
+    #[cfg(unix)]
+    fn writev(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        writev(&*self, bufs)
+    }
+}
+
+impl ClearStream for TcpStream {
+    fn recv_oob(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        sockopt::recv_oob(&*self, buf, false)
+    }
+
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        sockopt::peek(&*self, buf)
+    }
+}
+
+
+//--- impl for UnixStream
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        sockopt::take_error(self)
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        sockopt::shutdown(&*self, Shutdown::Write)
+    }
+
+    fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        sockopt::set_recv_buffer_size(&*self, size)
+    }
+
+    fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        sockopt::set_send_buffer_size(&*self, size)
+    }
+
+    fn write_ready_hint(&self) -> bool {
+        sockopt::write_ready_hint(self)
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        sockopt::is_connected(self)
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        sockopt::send_queue_len(self)
+    }
 }
 
-impl Stream for TcpStream { }
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn writev(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        writev(&*self, bufs)
+    }
+}
 
-impl ClearStream for TcpStream { }
+#[cfg(unix)]
+impl ClearStream for UnixStream {
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        sockopt::peek(&*self, buf)
+    }
+}
 
 
 //------------ SecureStream -------------------------------------------------
@@ -148,6 +717,25 @@ pub trait SecureStream: Stream {
     type Certificate: Certificate;
 
     fn get_peer_cert(&self) -> Self::Certificate;
+
+    /// Returns the protocol selected via ALPN during the handshake.
+    ///
+    /// This is `None` if the handshake hasn’t completed yet, if the peers
+    /// didn’t agree on a protocol, or if the backend doesn’t support ALPN.
+    /// The default implementation always returns `None`.
+    fn selected_protocol(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Returns the DER encoded certificate of the peer.
+    ///
+    /// This is `None` if the handshake hasn’t completed yet, if the peer
+    /// didn’t present a certificate, or if the backend can’t provide it.
+    /// Servers only get a certificate if the listener asked the client
+    /// for one. The default implementation always returns `None`.
+    fn peer_certificate(&self) -> Option<Vec<u8>> {
+        None
+    }
 }
 
 
@@ -161,9 +749,10 @@ pub trait SecureStream: Stream {
 /// succeeds, the sockets are encrypted akin to [SecureStream] sockets. If
 /// the handshake fails, the socket becomes unusable.
 ///
-/// Since the handshake happens asynchronously, a failure is signalled to
-/// the [TransportHandler]. Success isn’t signalled at all, operation will
-/// just continue.
+/// Since the handshake happens asynchronously, both its outcomes are
+/// signalled to the [TransportHandler]: a failure through `error()` and
+/// success through `secured()`. Data that must only be sent encrypted
+/// should wait for the latter.
 ///
 /// [ClearStream]: trait.ClearStream.html
 /// [SecureStream]: trait.SecureStream.html
@@ -223,15 +812,40 @@ pub trait Dgram: Transport {
     /// message was longer than the provided buffer, excess bytes will be
     /// discarded quietly. Zero-length messages are valid, so
     /// `Ok(Some((0, _))` is a perfectly fine result and (unlike with stream
-    /// sockets) has no special meaning attached.
+    /// sockets) has no special meaning attached. If a handler has no use
+    /// for them, a [FilteredDgram] can drop them instead.
     ///
     /// If there are no pending messages, returns `Ok(None)` and doesn’t do
     /// anything else.
     ///
     /// Any other returned error condition is likely fatal.
+    ///
+    /// If you need to know whether a message was truncated, use
+    /// [recv_from_trunc()](#method.recv_from_trunc) instead.
+    ///
+    /// [FilteredDgram]: struct.FilteredDgram.html
     fn recv_from(&self, buf: &mut [u8])
                  -> io::Result<Option<(usize, SocketAddr)>>;
 
+    /// Retrieves an incoming message and tells whether it was truncated.
+    ///
+    /// This is like [recv_from()](#tymethod.recv_from) but the result has
+    /// a third element that is `true` if the message was longer than
+    /// `buf` and the excess bytes were discarded. A protocol like DNS can
+    /// use this to fall back to a stream transport for the full message.
+    ///
+    /// Where the operating system reports truncation, as it does via
+    /// `MSG_TRUNC` on Unix systems, this is exact. The default
+    /// implementation can’t know and guesses instead: it considers a
+    /// message truncated if it filled all of `buf`. This is wrong for a
+    /// message that happens to be exactly as long as the buffer, so use a
+    /// buffer one byte longer than the longest message you expect.
+    fn recv_from_trunc(&self, buf: &mut [u8])
+                       -> io::Result<Option<(usize, SocketAddr, bool)>> {
+        let res = try!(self.recv_from(buf));
+        Ok(res.map(|(len, addr)| (len, addr, len == buf.len())))
+    }
+
     /// Sends a message to the socket.
     ///
     /// The message content is given in `buf` and the remote address to
@@ -244,12 +858,88 @@ pub trait Dgram: Transport {
     ///
     /// If the socket is not writable, returns `Ok(None)`.
     ///
-    /// If the buffer is too large to be sent, the method will fail with
-    /// `Other` (XXX presumably, someone should try that).
+    /// If the buffer is larger than [max_send_size()], the method fails
+    /// without sending anything. The error has kind `InvalidInput` and
+    /// converts into `Error::MessageTooLarge`. If the don’t-fragment bit
+    /// is set and the message is larger than the path MTU, the error is
+    /// passed on from the operating system unchanged. You can check for
+    /// either with [is_message_too_large()] and try again with a smaller
+    /// message.
+    ///
+    /// [max_send_size()]: #method.max_send_size
+    /// [is_message_too_large()]: fn.is_message_too_large.html
     fn send_to(&self, buf: &[u8], target: &SocketAddr)
                -> io::Result<Option<usize>>;
+
+    /// Returns the size of the largest message the socket can send.
+    ///
+    /// Messages of this size may still be too large for the path MTU if
+    /// the don’t-fragment bit is set. The limit only says what can be
+    /// sent at all.
+    ///
+    /// The default implementation returns `usize::max_value()`, ie., it
+    /// doesn’t know of a limit.
+    fn max_send_size(&self) -> usize {
+        usize::max_value()
+    }
+
+    /// Sets or clears the don’t-fragment bit for outgoing messages.
+    ///
+    /// With the bit set, messages are never fragmented and sending
+    /// messages larger than the path MTU fails instead. This is needed
+    /// for path MTU discovery.
+    ///
+    /// The default implementation fails as unsupported.
+    fn set_dont_fragment(&self, dont_fragment: bool) -> io::Result<()> {
+        let _ = dont_fragment;
+        Err(sockopt::unsupported())
+    }
+
+    /// Returns the path MTU for a connected socket if it is known.
+    ///
+    /// This is only available on some platforms. Elsewhere and in the
+    /// default implementation, `Ok(None)` is returned.
+    fn path_mtu(&self) -> io::Result<Option<usize>> {
+        Ok(None)
+    }
+}
+
+/// Returns whether an error means a datagram was too large to be sent.
+///
+/// This happens when the message is larger than the socket’s
+/// [Dgram::max_send_size()] or when sending on a socket with the
+/// don’t-fragment bit set, see [Dgram::set_dont_fragment()], and the
+/// message is larger than the path MTU.
+///
+/// [Dgram::max_send_size()]: trait.Dgram.html#method.max_send_size
+/// [Dgram::set_dont_fragment()]: trait.Dgram.html#method.set_dont_fragment
+pub fn is_message_too_large(err: &io::Error) -> bool {
+    Error::is_message_too_large_io(err) || sockopt::is_message_too_large(err)
 }
 
+/// Checks that a message of `len` bytes can be sent on `sock`.
+fn check_send_size<D: Dgram>(sock: &D, len: usize) -> io::Result<()> {
+    if len > sock.max_send_size() {
+        Err(io::Error::new(io::ErrorKind::InvalidInput,
+                           Error::MessageTooLarge))
+    }
+    else {
+        Ok(())
+    }
+}
+
+/// The largest UDP payload over IPv4.
+///
+/// This is the maximum IP packet size less 20 bytes IPv4 header and
+/// eight bytes UDP header.
+const MAX_UDP4_PAYLOAD: usize = 65_507;
+
+/// The largest UDP payload over IPv6 without jumbograms.
+///
+/// The IPv6 payload length doesn’t include the 40 bytes IPv6 header, so
+/// only the eight bytes UDP header are deducted.
+const MAX_UDP6_PAYLOAD: usize = 65_527;
+
 
 //--- impl for UdpSocket
 
@@ -257,6 +947,35 @@ impl Transport for UdpSocket {
     fn take_socket_error(&mut self) -> io::Result<()> {
         Ok(())
     }
+
+    fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        sockopt::set_recv_buffer_size(&*self, size)
+    }
+
+    fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        sockopt::set_send_buffer_size(&*self, size)
+    }
+
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        let v6 = try!(self.local_addr()).is_ipv6();
+        sockopt::set_tos(&*self, v6, tos)
+    }
+
+    fn tos(&self) -> io::Result<u8> {
+        sockopt::tos(self, try!(self.local_addr()).is_ipv6())
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        sockopt::is_connected(self)
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        sockopt::send_queue_len(self)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        sockopt::peer_addr(self)
+    }
 }
 
 impl Dgram for UdpSocket {
@@ -265,10 +984,289 @@ impl Dgram for UdpSocket {
         self.recv_from(buf)
     }
 
+    #[cfg(unix)]
+    fn recv_from_trunc(&self, buf: &mut [u8])
+                       -> io::Result<Option<(usize, SocketAddr, bool)>> {
+        sockopt::recv_from_trunc(self, buf)
+    }
+
     fn send_to(&self, buf: &[u8], target: &SocketAddr)
                -> io::Result<Option<usize>> {
+        try!(check_send_size(self, buf.len()));
         self.send_to(buf, target)
     }
+
+    fn max_send_size(&self) -> usize {
+        // If we can’t tell the address family, the smaller limit is the
+        // safer choice.
+        match self.local_addr() {
+            Ok(ref addr) if addr.is_ipv6() => MAX_UDP6_PAYLOAD,
+            _ => MAX_UDP4_PAYLOAD
+        }
+    }
+
+    fn set_dont_fragment(&self, dont_fragment: bool) -> io::Result<()> {
+        let v6 = try!(self.local_addr()).is_ipv6();
+        sockopt::set_dont_fragment(self, v6, dont_fragment)
+    }
+
+    fn path_mtu(&self) -> io::Result<Option<usize>> {
+        sockopt::path_mtu(self, try!(self.local_addr()).is_ipv6())
+    }
+}
+
+
+//------------ SourceFilter --------------------------------------------------
+
+/// A filter for the source addresses of incoming datagrams.
+///
+/// The filter is either a set of allowed addresses or a predicate
+/// deciding for each address whether it is allowed.
+pub struct SourceFilter(FilterInner);
+
+enum FilterInner {
+    Addrs(HashSet<SocketAddr>),
+    Predicate(Box<Fn(&SocketAddr) -> bool + Send>)
+}
+
+impl SourceFilter {
+    /// Creates a filter allowing all addresses.
+    ///
+    /// This is useful if a [FilteredDgram] is only used for dropping
+    /// zero-length datagrams.
+    ///
+    /// [FilteredDgram]: struct.FilteredDgram.html
+    pub fn all() -> Self {
+        SourceFilter::predicate(|_| true)
+    }
+
+    /// Creates a filter allowing only the given addresses.
+    pub fn addrs<I: IntoIterator<Item=SocketAddr>>(addrs: I) -> Self {
+        SourceFilter(FilterInner::Addrs(addrs.into_iter().collect()))
+    }
+
+    /// Creates a filter allowing only a single peer address.
+    pub fn peer(addr: SocketAddr) -> Self {
+        SourceFilter::addrs(Some(addr))
+    }
+
+    /// Creates a filter allowing all addresses for which `op` is true.
+    pub fn predicate<F>(op: F) -> Self
+                     where F: Fn(&SocketAddr) -> bool + Send + 'static {
+        SourceFilter(FilterInner::Predicate(Box::new(op)))
+    }
+
+    /// Returns whether datagrams from `addr` are allowed.
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        match self.0 {
+            FilterInner::Addrs(ref addrs) => addrs.contains(addr),
+            FilterInner::Predicate(ref op) => op(addr)
+        }
+    }
+}
+
+impl fmt::Debug for SourceFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            FilterInner::Addrs(ref addrs) => {
+                f.debug_tuple("SourceFilter").field(addrs).finish()
+            }
+            FilterInner::Predicate(_) => {
+                f.write_str("SourceFilter(<predicate>)")
+            }
+        }
+    }
+}
+
+
+//------------ FilteredDgram -------------------------------------------------
+
+/// A datagram socket that drops datagrams from unexpected sources.
+///
+/// The type wraps another datagram socket `D` and a [SourceFilter].
+/// Datagrams received from addresses not allowed by the filter are
+/// dropped by [recv_from()] before they ever reach the handler.
+///
+/// Zero-length datagrams are valid and are passed on by default. Some
+/// protocols use them as keepalives, for others they are just noise. By
+/// enabling [drop_empty()](#method.drop_empty), they are dropped, too.
+///
+/// The number of dropped datagrams is available via
+/// [dropped()](#method.dropped).
+///
+/// [SourceFilter]: struct.SourceFilter.html
+/// [recv_from()]: trait.Dgram.html#tymethod.recv_from
+#[derive(Debug)]
+pub struct FilteredDgram<D: Dgram> {
+    sock: D,
+    filter: SourceFilter,
+    drop_empty: bool,
+    dropped: Cell<u64>,
+}
+
+impl<D: Dgram> FilteredDgram<D> {
+    /// Creates a new filtered socket.
+    pub fn new(sock: D, filter: SourceFilter) -> Self {
+        FilteredDgram { sock: sock, filter: filter, drop_empty: false,
+                        dropped: Cell::new(0) }
+    }
+
+    /// Sets whether zero-length datagrams should be dropped.
+    pub fn drop_empty(mut self, drop_empty: bool) -> Self {
+        self.drop_empty = drop_empty;
+        self
+    }
+
+    /// Returns whether zero-length datagrams are dropped.
+    pub fn drops_empty(&self) -> bool {
+        self.drop_empty
+    }
+
+    /// Returns a reference to the filter.
+    pub fn filter(&self) -> &SourceFilter {
+        &self.filter
+    }
+
+    /// Returns the number of datagrams dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.get()
+    }
+
+    /// Returns a reference to the underlying socket.
+    pub fn get_ref(&self) -> &D {
+        &self.sock
+    }
+
+    /// Trades the filtered socket for the underlying socket.
+    pub fn into_inner(self) -> D {
+        self.sock
+    }
+
+    /// Returns whether to pass on a received datagram.
+    ///
+    /// Datagrams not passed on are counted as dropped.
+    fn keep(&self, len: usize, addr: &SocketAddr) -> bool {
+        if len == 0 && self.drop_empty {
+            debug!("dropping empty datagram from {}", addr);
+        }
+        else if self.filter.allows(addr) {
+            return true
+        }
+        else {
+            debug!("dropping datagram from {}", addr);
+        }
+        self.dropped.set(self.dropped.get() + 1);
+        false
+    }
+}
+
+impl<D: Dgram> Evented for FilteredDgram<D> {
+    fn register(&self, selector: &mut Selector, token: Token,
+                interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token,
+                  interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.sock.deregister(selector)
+    }
+}
+
+impl<D: Dgram> Transport for FilteredDgram<D> {
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        self.sock.take_socket_error()
+    }
+
+    fn is_usable(&self) -> bool {
+        self.sock.is_usable()
+    }
+
+    fn blocked(&self) -> Option<Blocked> {
+        self.sock.blocked()
+    }
+
+    fn flush_output(&mut self) -> io::Result<bool> {
+        self.sock.flush_output()
+    }
+
+    fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        self.sock.set_recv_buffer_size(size)
+    }
+
+    fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        self.sock.set_send_buffer_size(size)
+    }
+
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        self.sock.set_tos(tos)
+    }
+
+    fn tos(&self) -> io::Result<u8> {
+        self.sock.tos()
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        self.sock.is_connected()
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        self.sock.pending_write_bytes()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.peer_addr()
+    }
+}
+
+impl<D: Dgram> Dgram for FilteredDgram<D> {
+    fn recv_from(&self, buf: &mut [u8])
+                 -> io::Result<Option<(usize, SocketAddr)>> {
+        loop {
+            match try!(self.sock.recv_from(buf)) {
+                Some((len, addr)) => {
+                    if self.keep(len, &addr) {
+                        return Ok(Some((len, addr)))
+                    }
+                }
+                None => return Ok(None)
+            }
+        }
+    }
+
+    fn recv_from_trunc(&self, buf: &mut [u8])
+                       -> io::Result<Option<(usize, SocketAddr, bool)>> {
+        loop {
+            match try!(self.sock.recv_from_trunc(buf)) {
+                Some((len, addr, trunc)) => {
+                    if self.keep(len, &addr) {
+                        return Ok(Some((len, addr, trunc)))
+                    }
+                }
+                None => return Ok(None)
+            }
+        }
+    }
+
+    fn send_to(&self, buf: &[u8], target: &SocketAddr)
+               -> io::Result<Option<usize>> {
+        self.sock.send_to(buf, target)
+    }
+
+    fn max_send_size(&self) -> usize {
+        self.sock.max_send_size()
+    }
+
+    fn set_dont_fragment(&self, dont_fragment: bool) -> io::Result<()> {
+        self.sock.set_dont_fragment(dont_fragment)
+    }
+
+    fn path_mtu(&self) -> io::Result<Option<usize>> {
+        self.sock.path_mtu()
+    }
 }
 
 
@@ -282,11 +1280,286 @@ pub trait Certificate { }
 impl Certificate for () { }
 
 
+//------------ SocketOptions -------------------------------------------------
+
+/// A collection of socket options to be applied to a transport socket.
+///
+/// Rather than setting options one by one on every new socket, you can
+/// collect them in a value of this type and have them all applied at once
+/// via the [apply()](#method.apply) method. Server machines can be given
+/// such a value upon creation and will then apply it to every accepted
+/// socket.
+///
+/// A new value is created with no options set at all. You can then set
+/// each option via the builder-style methods. Only the options set are
+/// applied.
+#[derive(Clone, Debug, Default)]
+pub struct SocketOptions {
+    nodelay: Option<bool>,
+    keepalive: Option<Option<u32>>,
+    linger: Option<Option<Duration>>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    user_timeout: Option<Duration>,
+    tos: Option<u8>,
+    congestion_control: Option<String>,
+}
+
+impl SocketOptions {
+    /// Creates a new value with no options set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether to disable Nagle’s algorithm.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Sets the keepalive interval in seconds or disables keepalive.
+    pub fn keepalive(mut self, keepalive: Option<u32>) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Sets the linger time or disables lingering.
+    pub fn linger(mut self, linger: Option<Duration>) -> Self {
+        self.linger = Some(linger);
+        self
+    }
+
+    /// Sets the size of the receive buffer.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the size of the send buffer.
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the TCP user timeout.
+    pub fn user_timeout(mut self, timeout: Duration) -> Self {
+        self.user_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the IP type of service or traffic class.
+    pub fn tos(mut self, tos: u8) -> Self {
+        self.tos = Some(tos);
+        self
+    }
+
+    /// Sets the TCP congestion control algorithm.
+    pub fn congestion_control(mut self, name: &str) -> Self {
+        self.congestion_control = Some(name.into());
+        self
+    }
+
+    /// Applies all set options to the socket.
+    ///
+    /// Stops and returns the error at the first option that fails.
+    pub fn apply<T: Transport>(&self, sock: &mut T) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            try!(sock.set_nodelay(nodelay));
+        }
+        if let Some(keepalive) = self.keepalive {
+            try!(sock.set_keepalive(keepalive));
+        }
+        if let Some(linger) = self.linger {
+            try!(sock.set_linger(linger));
+        }
+        if let Some(size) = self.recv_buffer_size {
+            try!(sock.set_recv_buffer_size(size));
+        }
+        if let Some(size) = self.send_buffer_size {
+            try!(sock.set_send_buffer_size(size));
+        }
+        if let Some(timeout) = self.user_timeout {
+            try!(sock.set_user_timeout(timeout));
+        }
+        if let Some(tos) = self.tos {
+            try!(sock.set_tos(tos));
+        }
+        if let Some(ref name) = self.congestion_control {
+            try!(sock.set_congestion_control(name));
+        }
+        Ok(())
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// The maximum number of buffers we pass to a single `writev()`.
+///
+/// POSIX only guarantees 16, but all relevant systems allow 1024.
+#[cfg(unix)]
+const IOV_MAX: usize = 1024;
+
+/// Writes `bufs` to `sock` via the `writev()` system call.
+#[cfg(unix)]
+fn writev<S: AsRawFd>(sock: &S, bufs: &[&[u8]]) -> io::Result<usize> {
+    let iovs: Vec<_> = bufs.iter().take(IOV_MAX).map(|buf| {
+        libc::iovec { iov_base: buf.as_ptr() as *mut libc::c_void,
+                      iov_len: buf.len() }
+    }).collect();
+    let res = unsafe {
+        libc::writev(sock.as_raw_fd(), iovs.as_ptr(),
+                     iovs.len() as libc::c_int)
+    };
+    if res == -1 { Err(io::Error::last_os_error()) }
+    else { Ok(res as usize) }
+}
+
+
+//------------ TcpInfo -------------------------------------------------------
+
+/// Round-trip time and congestion statistics of a TCP connection.
+///
+/// A value of this type is returned by [Transport::tcp_info()].
+///
+/// [Transport::tcp_info()]: trait.Transport.html#method.tcp_info
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TcpInfo {
+    rtt: Duration,
+    rtt_var: Duration,
+    retransmits: u32,
+    cwnd: u32,
+}
+
+impl TcpInfo {
+    /// Returns the smoothed round-trip time.
+    pub fn rtt(&self) -> Duration {
+        self.rtt
+    }
+
+    /// Returns the variance of the round-trip time.
+    pub fn rtt_var(&self) -> Duration {
+        self.rtt_var
+    }
+
+    /// Returns the number of segments retransmitted so far.
+    pub fn retransmits(&self) -> u32 {
+        self.retransmits
+    }
+
+    /// Returns the congestion window in segments.
+    pub fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+}
+
+
 //------------ Blocked -------------------------------------------------------
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Blocked {
     Read,
-    Write
+    Write,
+
+    /// The socket needs to both read and write before it can progress.
+    ///
+    /// This happens, for instance, if a TLS renegotiation interrupted a
+    /// read that now waits for writing while a write waits for reading.
+    Both
+}
+
+impl Blocked {
+    /// Combines the direction with an earlier one, if there is one.
+    pub fn combine(self, other: Option<Blocked>) -> Blocked {
+        match other {
+            Some(other) if other != self => Blocked::Both,
+            _ => self
+        }
+    }
 }
 
+
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net;
+    use std::thread;
+    use std::time::Duration;
+    use rotor::mio::tcp::TcpStream;
+    use super::ClearStream;
+    use super::mock::MockStream;
+
+    #[test]
+    fn mock_peek() {
+        let mut sock = MockStream::new();
+        let mut buf = [0u8; 4];
+        assert_eq!(sock.peek(&mut buf).unwrap(), None);
+        sock.push_inbound(b"hello");
+        assert_eq!(sock.peek(&mut buf).unwrap(), Some(4));
+        assert_eq!(&buf, b"hell");
+        assert_eq!(sock.inbound_len(), 5);
+        let mut buf = [0u8; 8];
+        assert_eq!(sock.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(sock.peek(&mut buf).unwrap(), None);
+        sock.close_inbound();
+        assert_eq!(sock.peek(&mut buf).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn tcp_peek() {
+        let lsnr = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sock = TcpStream::connect(&lsnr.local_addr().unwrap())
+                                .unwrap();
+        let (mut peer, _) = lsnr.accept().unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(sock.peek(&mut buf).unwrap(), None);
+        peer.write_all(b"hello").unwrap();
+        let mut res = None;
+        for _ in 0..100 {
+            res = sock.peek(&mut buf).unwrap();
+            if res.is_some() {
+                break
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(res, Some(4));
+        assert_eq!(&buf, b"hell");
+        let mut buf = [0u8; 8];
+        assert_eq!(sock.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        drop(peer);
+        let mut res = None;
+        for _ in 0..100 {
+            res = sock.peek(&mut buf).unwrap();
+            if res.is_some() {
+                break
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(res, Some(0));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_listener_from_raw_fd() {
+        use std::env;
+        use std::fs;
+        use std::os::unix::io::IntoRawFd;
+        use std::os::unix::net::{UnixDatagram, UnixListener};
+        use libc;
+
+        let pid = unsafe { libc::getpid() };
+        let path = env::temp_dir().join(format!("netmachines-lsnr-{}", pid));
+        let _ = fs::remove_file(&path);
+        let fd = UnixListener::bind(&path).unwrap().into_raw_fd();
+        assert!(unsafe { super::unix_listener_from_raw_fd(fd) }.is_ok());
+        fs::remove_file(&path).unwrap();
+
+        let fd = UnixDatagram::unbound().unwrap().into_raw_fd();
+        assert!(unsafe { super::unix_listener_from_raw_fd(fd) }.is_err());
+    }
+}
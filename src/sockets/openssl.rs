@@ -3,14 +3,57 @@
 use std::io;
 use std::mem;
 use std::net::{self, SocketAddr};
+use std::time::Duration;
+#[cfg(unix)] use std::os::unix::io::{AsRawFd, RawFd};
+use openssl::crypto::pkey::PKey;
 use openssl::ssl::{self, SslContext, SslStream};
+use openssl::x509::X509;
 use rotor::{Evented, EventSet, PollOpt};
 use rotor::mio::{Selector, Token};
 use rotor::mio::tcp::{TcpListener, TcpStream};
-use super::{Accept, Blocked, HybridStream, SecureStream, Stream, Transport};
+#[cfg(unix)] use super::tcp_listener_from_raw_fd;
+use super::{Accept, Blocked, HybridStream, PeerAddr, SecureStream, Stream,
+            TcpInfo, Transport};
+use super::tls::TlsBackend;
 use ::error::Result;
 
 
+//------------ Openssl -------------------------------------------------------
+
+/// The TLS backend using OpenSSL.
+///
+/// Both client and server configuration are an `SslContext`.
+pub struct Openssl;
+
+impl TlsBackend for Openssl {
+    type Listener = TlsListener;
+    type Stream = TlsStream;
+    type StartTlsListener = StartTlsListener;
+    type StartTlsStream = StartTlsStream;
+    type ClientConfig = SslContext;
+    type ServerConfig = SslContext;
+
+    fn bind(addr: &SocketAddr, config: SslContext) -> Result<TlsListener> {
+        TlsListener::bind(addr, config)
+    }
+
+    fn bind_starttls(addr: &SocketAddr, config: SslContext)
+                     -> Result<StartTlsListener> {
+        StartTlsListener::bind(addr, config)
+    }
+
+    fn connect(addr: &SocketAddr, config: &SslContext)
+               -> Result<TlsStream> {
+        TlsStream::connect(addr, config)
+    }
+
+    fn connect_starttls(addr: &SocketAddr, config: &SslContext)
+                        -> Result<StartTlsStream> {
+        StartTlsStream::connect(addr, config.clone())
+    }
+}
+
+
 //------------ TlsListener ---------------------------------------------------
 
 pub struct TlsListener {
@@ -29,16 +72,85 @@ impl TlsListener {
         Ok(TlsListener { sock: try!(TcpListener::from_listener(lsnr, addr)),
                          ctx: ctx })
     }
+
+    /// Creates a listener offering `protocols` via ALPN.
+    ///
+    /// The protocols are given in order of preference, for instance
+    /// `&[b"h2", b"http/1.1"]`. Once the handshake has completed, the
+    /// protocol agreed upon is available through
+    /// `SecureStream::selected_protocol()`.
+    ///
+    /// This needs OpenSSL 1.0.2 or later and the `alpn` feature.
+    #[cfg(feature = "alpn")]
+    pub fn bind_alpn(addr: &SocketAddr, mut ctx: SslContext,
+                     protocols: &[&[u8]]) -> Result<Self> {
+        ctx.set_alpn_protocols(protocols);
+        TlsListener::bind(addr, ctx)
+    }
+
+    /// Adds a certificate and its private key.
+    ///
+    /// The listener can hold one certificate per key type. By adding,
+    /// say, both an RSA and an ECDSA certificate, OpenSSL picks the one
+    /// best suited for each client based on the signature algorithms the
+    /// client supports. This needs OpenSSL 1.0.2 or later. Adding a
+    /// certificate with the same key type as an earlier one replaces the
+    /// earlier one.
+    ///
+    /// Intermediate certificates added to the context via
+    /// `add_extra_chain_cert()` are shared by all certificates.
+    ///
+    /// The method fails if the key doesn’t match the certificate.
+    pub fn add_certificate(&mut self, cert: &X509, key: &PKey)
+                           -> Result<()> {
+        try!(self.ctx.set_certificate(cert));
+        try!(self.ctx.set_private_key(key));
+        try!(self.ctx.check_private_key());
+        Ok(())
+    }
+
+    /// Requires clients to present a certificate.
+    ///
+    /// The handshake fails if the client doesn’t present a certificate or
+    /// if it can’t be verified against the trusted certificates of the
+    /// context, which need to be set via `SslContext::set_CA_file()`
+    /// beforehand. Handlers can get the client’s certificate through
+    /// `SecureStream::peer_certificate()`.
+    pub fn require_client_cert(&mut self) {
+        self.ctx.set_verify(ssl::SSL_VERIFY_PEER |
+                            ssl::SSL_VERIFY_FAIL_IF_NO_PEER_CERT, None);
+    }
+}
+
+// XXX There should be a way for handlers to learn which certificate was
+//     used for a connection. That needs SSL_get_certificate() which the
+//     openssl crate 0.7 doesn’t offer.
+
+impl TlsListener {
+    /// Creates a listener from a raw file descriptor.
+    ///
+    /// See [tcp_listener_from_raw_fd()] for the requirements of the file
+    /// descriptor.
+    ///
+    /// # Safety
+    ///
+    /// The function takes ownership of the file descriptor.
+    ///
+    /// [tcp_listener_from_raw_fd()]: ../fn.tcp_listener_from_raw_fd.html
+    #[cfg(unix)]
+    pub unsafe fn from_raw_fd(fd: RawFd, ctx: SslContext) -> Result<Self> {
+        Ok(TlsListener { sock: try!(tcp_listener_from_raw_fd(fd)), ctx: ctx })
+    }
 }
 
 impl Accept for TlsListener {
     type Output = TlsStream;
 
-    fn accept(&self) -> Result<Option<(TlsStream, SocketAddr)>> {
+    fn accept(&self) -> Result<Option<(TlsStream, PeerAddr)>> {
         match self.sock.accept() {
             Ok(Some((stream, addr))) => {
                 Ok(Some((try!(TlsStream::accept(stream, &self.ctx)),
-                         addr)))
+                         PeerAddr::Inet(addr))))
             }
             Ok(None) => Ok(None),
             Err(err) => Err(err.into())
@@ -46,6 +158,13 @@ impl Accept for TlsListener {
     }
 }
 
+#[cfg(unix)]
+impl AsRawFd for TlsListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
 impl Evented for TlsListener {
     fn register(&self, selector: &mut Selector, token: Token,
                 interest: EventSet, opts: PollOpt) -> io::Result<()> {
@@ -62,9 +181,28 @@ impl Evented for TlsListener {
     }
 }
 
+// XXX OCSP stapling should go here: a way to give the listener a
+//     DER-encoded OCSP response, either fixed or through a refresh
+//     callback, for the handshake to staple, and a matching callback for
+//     clients to verify a stapled response. The openssl crate 0.7 we are
+//     using has neither SslContext::set_status_callback() nor
+//     Ssl::set_ocsp_status(), so this has to wait until we move to a
+//     version that has them. Keeping the response fresh before it expires
+//     will be the user’s job.
+
 
 //------------ TlsStream -----------------------------------------------------
 
+/// A TLS stream socket.
+///
+/// The TLS handshake doesn’t block the loop. Accepting or connecting only
+/// starts it: since the underlying socket is non-blocking, OpenSSL stops
+/// as soon as it would have to wait for the peer and every read or write
+/// continues the handshake until it is complete. Until then, they fail
+/// with `WouldBlock` and [blocked()] tells the machine which event the
+/// handshake is waiting for, so handlers don’t need to know about it.
+///
+/// [blocked()]: ../trait.Transport.html#method.blocked
 pub struct TlsStream {
     sock: SslStream<TcpStream>,
     blocked: Option<Blocked>,
@@ -84,15 +222,20 @@ impl TlsStream {
                         blocked: None })
     }
 
+    /// Translates an OpenSSL error, noting the direction we are blocked on.
+    ///
+    /// The direction is only cleared once an operation succeeds. So if a
+    /// read and a write are blocked on different directions, the stream
+    /// is blocked on both.
     fn translate_error(&mut self, err: ssl::Error) -> io::Result<usize> {
         match err {
             ssl::Error::ZeroReturn => Ok(0),
             ssl::Error::WantWrite(err) => {
-                self.blocked = Some(Blocked::Write);
+                self.blocked = Some(Blocked::Write.combine(self.blocked));
                 Err(err)
             }
             ssl::Error::WantRead(err) => {
-                self.blocked = Some(Blocked::Read);
+                self.blocked = Some(Blocked::Read.combine(self.blocked));
                 Err(err)
             }
             ssl::Error::Stream(err) => Err(err),
@@ -101,27 +244,54 @@ impl TlsStream {
     }
 }
 
+// XXX An explicit handshaking state that keeps socket events from the
+//     handler until the handshake is complete needs SSL_do_handshake() or
+//     SSL_is_init_finished() which the openssl crate 0.7 doesn’t offer.
+
 impl SecureStream for TlsStream {
     type Certificate = ();
 
     fn get_peer_cert(&self) -> Self::Certificate {
         ()
     }
+
+    #[cfg(feature = "alpn")]
+    fn selected_protocol(&self) -> Option<Vec<u8>> {
+        self.sock.ssl().get_selected_alpn_protocol().map(|p| p.to_vec())
+    }
+
+    fn peer_certificate(&self) -> Option<Vec<u8>> {
+        self.sock.ssl().peer_certificate()
+                       .and_then(|cert| cert.save_der().ok())
+    }
 }
 
 impl Stream for TlsStream { }
 
+// XXX Peeking at decrypted data as with ClearStream::peek() needs
+//     SSL_peek() which the openssl crate 0.7 doesn’t offer.
+
 impl io::Read for TlsStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.blocked = None;
-        self.sock.ssl_read(buf).or_else(|err| self.translate_error(err))
+        match self.sock.ssl_read(buf) {
+            Ok(len) => {
+                self.blocked = None;
+                Ok(len)
+            }
+            Err(err) => self.translate_error(err)
+        }
     }
 }
 
 impl io::Write for TlsStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.blocked = None;
-        self.sock.ssl_write(buf).or_else(|err| self.translate_error(err))
+        match self.sock.ssl_write(buf) {
+            Ok(len) => {
+                self.blocked = None;
+                Ok(len)
+            }
+            Err(err) => self.translate_error(err)
+        }
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -137,6 +307,70 @@ impl Transport for TlsStream {
     fn blocked(&self) -> Option<Blocked> {
         self.blocked
     }
+
+    fn flush_output(&mut self) -> io::Result<bool> {
+        flush_result(io::Write::flush(&mut self.sock))
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        Transport::set_nodelay(self.sock.get_mut(), nodelay)
+    }
+
+    fn set_keepalive(&mut self, keepalive: Option<u32>) -> io::Result<()> {
+        Transport::set_keepalive(self.sock.get_mut(), keepalive)
+    }
+
+    fn set_linger(&mut self, linger: Option<Duration>) -> io::Result<()> {
+        Transport::set_linger(self.sock.get_mut(), linger)
+    }
+
+    fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        Transport::set_recv_buffer_size(self.sock.get_mut(), size)
+    }
+
+    fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        Transport::set_send_buffer_size(self.sock.get_mut(), size)
+    }
+
+    fn set_user_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Transport::set_user_timeout(self.sock.get_mut(), timeout)
+    }
+
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        Transport::set_tos(self.sock.get_mut(), tos)
+    }
+
+    fn tos(&self) -> io::Result<u8> {
+        Transport::tos(self.sock.get_ref())
+    }
+
+    fn set_congestion_control(&mut self, name: &str) -> io::Result<()> {
+        Transport::set_congestion_control(self.sock.get_mut(), name)
+    }
+
+    fn congestion_control(&self) -> io::Result<String> {
+        Transport::congestion_control(self.sock.get_ref())
+    }
+
+    fn tcp_info(&self) -> io::Result<TcpInfo> {
+        Transport::tcp_info(self.sock.get_ref())
+    }
+
+    fn write_ready_hint(&self) -> bool {
+        Transport::write_ready_hint(self.sock.get_ref())
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        Transport::is_connected(self.sock.get_ref())
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        Transport::pending_write_bytes(self.sock.get_ref())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Transport::peer_addr(self.sock.get_ref())
+    }
 }
 
 
@@ -178,14 +412,32 @@ impl StartTlsListener {
     }
 }
 
+impl StartTlsListener {
+    /// Creates a listener from a raw file descriptor.
+    ///
+    /// See [tcp_listener_from_raw_fd()] for the requirements of the file
+    /// descriptor.
+    ///
+    /// # Safety
+    ///
+    /// The function takes ownership of the file descriptor.
+    ///
+    /// [tcp_listener_from_raw_fd()]: ../fn.tcp_listener_from_raw_fd.html
+    #[cfg(unix)]
+    pub unsafe fn from_raw_fd(fd: RawFd, ctx: SslContext) -> Result<Self> {
+        Ok(StartTlsListener { sock: try!(tcp_listener_from_raw_fd(fd)),
+                              ctx: ctx })
+    }
+}
+
 impl Accept for StartTlsListener {
     type Output = StartTlsStream;
 
-    fn accept(&self) -> Result<Option<(StartTlsStream, SocketAddr)>> {
+    fn accept(&self) -> Result<Option<(StartTlsStream, PeerAddr)>> {
         match self.sock.accept() {
             Ok(Some((stream, addr))) => {
                 Ok(Some((StartTlsStream::new(stream, self.ctx.clone()),
-                         addr)))
+                         PeerAddr::Inet(addr))))
             }
             Ok(None) => Ok(None),
             Err(err) => Err(err.into())
@@ -193,6 +445,13 @@ impl Accept for StartTlsListener {
     }
 }
 
+#[cfg(unix)]
+impl AsRawFd for StartTlsListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
 impl Evented for StartTlsListener {
     fn register(&self, selector: &mut Selector, token: Token,
                 interest: EventSet, opts: PollOpt) -> io::Result<()> {
@@ -215,7 +474,10 @@ impl Evented for StartTlsListener {
 pub struct StartTlsStream {
     sock: Option<StartTlsSock>,
     ctx: SslContext,
-    blocked: Option<Blocked>
+    blocked: Option<Blocked>,
+
+    /// Whether `take_secured()` has reported the handshake already.
+    secured: bool,
 }
 
 enum StartTlsSock {
@@ -234,22 +496,27 @@ impl StartTlsStream {
         StartTlsStream {
             sock: Some(StartTlsSock::Clear(stream)),
             ctx: ctx,
-            blocked: None
+            blocked: None,
+            secured: false,
         }
     }
 
+    /// Translates an OpenSSL result like `TlsStream::translate_error()`.
     fn translate_result(&mut self,
                         res: ::std::result::Result<usize, ssl::Error>)
                         -> io::Result<usize> {
         match res {
-            Ok(res) => Ok(res),
+            Ok(res) => {
+                self.blocked = None;
+                Ok(res)
+            }
             Err(ssl::Error::ZeroReturn) => Ok(0),
             Err(ssl::Error::WantWrite(err)) => {
-                self.blocked = Some(Blocked::Write);
+                self.blocked = Some(Blocked::Write.combine(self.blocked));
                 Err(err)
             }
             Err(ssl::Error::WantRead(err)) => {
-                self.blocked = Some(Blocked::Read);
+                self.blocked = Some(Blocked::Read.combine(self.blocked));
                 Err(err)
             }
             Err(ssl::Error::Stream(err)) => Err(err),
@@ -276,6 +543,11 @@ impl StartTlsStream {
     }
 }
 
+/// Switching to encryption doesn’t block the loop. As with a
+/// [TlsStream], `connect_secure()` and `accept_secure()` only start the
+/// handshake which is then continued by reads and writes.
+///
+/// [TlsStream]: struct.TlsStream.html
 impl HybridStream for StartTlsStream {
     type Certificate = ();
 
@@ -322,10 +594,7 @@ impl io::Read for StartTlsStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let res = match self.sock {
             Some(StartTlsSock::Clear(ref mut sock)) => return sock.read(buf),
-            Some(StartTlsSock::Secure(ref mut sock)) => {
-                self.blocked = None;
-                sock.ssl_read(buf)
-            }
+            Some(StartTlsSock::Secure(ref mut sock)) => sock.ssl_read(buf),
             None => return Err(io::Error::new(io::ErrorKind::ConnectionAborted,
                                               "stream unusable"))
         };
@@ -337,10 +606,7 @@ impl io::Write for StartTlsStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let res = match self.sock {
             Some(StartTlsSock::Clear(ref mut sock)) => return sock.write(buf),
-            Some(StartTlsSock::Secure(ref mut sock)) => {
-                self.blocked = None;
-                sock.ssl_write(buf)
-            }
+            Some(StartTlsSock::Secure(ref mut sock)) => sock.ssl_write(buf),
             None => return Err(io::Error::new(io::ErrorKind::ConnectionAborted,
                                               "stream unusable"))
         };
@@ -353,6 +619,22 @@ impl io::Write for StartTlsStream {
 }
 
 impl Transport for StartTlsStream {
+    /// The openssl crate 0.7 doesn’t offer SSL_is_init_finished(), so
+    /// this looks at the state string instead. It is “SSLOK ” once the
+    /// handshake has completed.
+    fn take_secured(&mut self) -> bool {
+        if self.secured {
+            return false
+        }
+        self.secured = match self.sock {
+            Some(StartTlsSock::Secure(ref sock)) => {
+                sock.ssl().state_string() == "SSLOK "
+            }
+            _ => false
+        };
+        self.secured
+    }
+
     fn take_socket_error(&mut self) -> io::Result<()> {
         match self.sock {
             Some(StartTlsSock::Clear(ref mut sock)) => {
@@ -371,6 +653,79 @@ impl Transport for StartTlsStream {
     fn blocked(&self) -> Option<Blocked> {
         self.blocked
     }
+
+    fn is_usable(&self) -> bool {
+        self.sock.is_some()
+    }
+
+    fn flush_output(&mut self) -> io::Result<bool> {
+        match self.sock {
+            Some(StartTlsSock::Secure(ref mut sock)) => {
+                flush_result(io::Write::flush(sock))
+            }
+            _ => Ok(true)
+        }
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        Transport::set_nodelay(try!(self.get_mut_sock()), nodelay)
+    }
+
+    fn set_keepalive(&mut self, keepalive: Option<u32>) -> io::Result<()> {
+        Transport::set_keepalive(try!(self.get_mut_sock()), keepalive)
+    }
+
+    fn set_linger(&mut self, linger: Option<Duration>) -> io::Result<()> {
+        Transport::set_linger(try!(self.get_mut_sock()), linger)
+    }
+
+    fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        Transport::set_recv_buffer_size(try!(self.get_mut_sock()), size)
+    }
+
+    fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        Transport::set_send_buffer_size(try!(self.get_mut_sock()), size)
+    }
+
+    fn set_user_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Transport::set_user_timeout(try!(self.get_mut_sock()), timeout)
+    }
+
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        Transport::set_tos(try!(self.get_mut_sock()), tos)
+    }
+
+    fn tos(&self) -> io::Result<u8> {
+        Transport::tos(try!(self.get_sock()))
+    }
+
+    fn set_congestion_control(&mut self, name: &str) -> io::Result<()> {
+        Transport::set_congestion_control(try!(self.get_mut_sock()), name)
+    }
+
+    fn congestion_control(&self) -> io::Result<String> {
+        Transport::congestion_control(try!(self.get_sock()))
+    }
+
+    fn tcp_info(&self) -> io::Result<TcpInfo> {
+        Transport::tcp_info(try!(self.get_sock()))
+    }
+
+    fn write_ready_hint(&self) -> bool {
+        self.get_sock().map(Transport::write_ready_hint).unwrap_or(true)
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        Transport::is_connected(try!(self.get_sock()))
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        self.get_sock().map(Transport::pending_write_bytes).unwrap_or(0)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Transport::peer_addr(try!(self.get_sock()))
+    }
 }
 
 impl Evented for StartTlsStream {
@@ -389,3 +744,14 @@ impl Evented for StartTlsStream {
     }
 }
 
+
+//------------ Helpers -------------------------------------------------------
+
+/// Translates the result of flushing for `Transport::flush_output()`.
+fn flush_result(res: io::Result<()>) -> io::Result<bool> {
+    match res {
+        Ok(()) => Ok(true),
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(err) => Err(err)
+    }
+}
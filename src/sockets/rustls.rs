@@ -0,0 +1,877 @@
+//! Secure sockets using rustls.
+//!
+//! Unlike OpenSSL, rustls doesn’t do any IO itself. It only turns
+//! plaintext into TLS records and back and leaves moving the records
+//! between it and the socket to us. The sockets in here do this as part
+//! of every read and write, so the handshake progresses just as it does
+//! with the OpenSSL sockets: reads and writes fail with `WouldBlock`
+//! until the handshake is complete and [blocked()] tells the machine
+//! which event the handshake is waiting for.
+//!
+//! Clients need to know the name of the server they are talking to for
+//! verifying its certificate. Since the [TlsBackend] trait only gives an
+//! address for connecting, the client configuration of this backend is a
+//! [TlsClientConfig] which combines a rustls client configuration with
+//! the server name.
+//!
+//! [blocked()]: ../trait.Transport.html#method.blocked
+//! [TlsBackend]: ../tls/trait.TlsBackend.html
+//! [TlsClientConfig]: struct.TlsClientConfig.html
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{self, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(unix)] use std::os::unix::io::{AsRawFd, RawFd};
+use rustls::{self, ClientSession, ServerSession, Session, TLSError};
+use rotor::{Evented, EventSet, PollOpt};
+use rotor::mio::{Selector, Token};
+use rotor::mio::tcp::{TcpListener, TcpStream};
+#[cfg(unix)] use super::tcp_listener_from_raw_fd;
+use super::{Accept, Blocked, HybridStream, PeerAddr, SecureStream, Stream,
+            TcpInfo, Transport};
+use super::tls::TlsBackend;
+use ::error::{Error, Result};
+
+
+//------------ Rustls --------------------------------------------------------
+
+/// The TLS backend using rustls.
+///
+/// The server configuration is a rustls `ServerConfig` behind an `Arc`,
+/// the client configuration is a [TlsClientConfig].
+///
+/// [TlsClientConfig]: struct.TlsClientConfig.html
+pub struct Rustls;
+
+impl TlsBackend for Rustls {
+    type Listener = TlsListener;
+    type Stream = TlsStream;
+    type StartTlsListener = StartTlsListener;
+    type StartTlsStream = StartTlsStream;
+    type ClientConfig = TlsClientConfig;
+    type ServerConfig = Arc<rustls::ServerConfig>;
+
+    fn bind(addr: &SocketAddr, config: Arc<rustls::ServerConfig>)
+            -> Result<TlsListener> {
+        TlsListener::bind(addr, config)
+    }
+
+    fn bind_starttls(addr: &SocketAddr, config: Arc<rustls::ServerConfig>)
+                     -> Result<StartTlsListener> {
+        StartTlsListener::bind(addr, config)
+    }
+
+    fn connect(addr: &SocketAddr, config: &TlsClientConfig)
+               -> Result<TlsStream> {
+        TlsStream::connect(addr, config)
+    }
+
+    fn connect_starttls(addr: &SocketAddr, config: &TlsClientConfig)
+                        -> Result<StartTlsStream> {
+        StartTlsStream::connect(addr, config.clone())
+    }
+}
+
+
+//------------ TlsClientConfig -----------------------------------------------
+
+/// The configuration for client sockets.
+///
+/// This is a rustls client configuration plus the name of the server
+/// which is used for verifying its certificate and for SNI.
+#[derive(Clone)]
+pub struct TlsClientConfig {
+    config: Arc<rustls::ClientConfig>,
+    hostname: String,
+}
+
+impl TlsClientConfig {
+    /// Creates a new value from a rustls config and the server name.
+    pub fn new(config: Arc<rustls::ClientConfig>, hostname: String)
+               -> Self {
+        TlsClientConfig { config: config, hostname: hostname }
+    }
+
+    /// Returns the rustls configuration.
+    pub fn config(&self) -> &Arc<rustls::ClientConfig> {
+        &self.config
+    }
+
+    /// Returns the name of the server.
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// Creates a new client session.
+    fn session(&self) -> Box<Session> {
+        Box::new(ClientSession::new(&self.config, &self.hostname))
+    }
+}
+
+
+//------------ TlsListener ---------------------------------------------------
+
+pub struct TlsListener {
+    sock: TcpListener,
+    config: Arc<rustls::ServerConfig>,
+}
+
+impl TlsListener {
+    pub fn bind(addr: &SocketAddr, config: Arc<rustls::ServerConfig>)
+                -> Result<Self> {
+        Ok(TlsListener { sock: try!(TcpListener::bind(addr)),
+                         config: config })
+    }
+
+    pub fn from_listener(lsnr: net::TcpListener, addr: &SocketAddr,
+                         config: Arc<rustls::ServerConfig>) -> Result<Self> {
+        Ok(TlsListener { sock: try!(TcpListener::from_listener(lsnr, addr)),
+                         config: config })
+    }
+
+    /// Creates a listener from a raw file descriptor.
+    ///
+    /// See [tcp_listener_from_raw_fd()] for the requirements of the file
+    /// descriptor.
+    ///
+    /// # Safety
+    ///
+    /// The function takes ownership of the file descriptor.
+    ///
+    /// [tcp_listener_from_raw_fd()]: ../fn.tcp_listener_from_raw_fd.html
+    #[cfg(unix)]
+    pub unsafe fn from_raw_fd(fd: RawFd, config: Arc<rustls::ServerConfig>)
+                              -> Result<Self> {
+        Ok(TlsListener { sock: try!(tcp_listener_from_raw_fd(fd)),
+                         config: config })
+    }
+}
+
+impl Accept for TlsListener {
+    type Output = TlsStream;
+
+    fn accept(&self) -> Result<Option<(TlsStream, PeerAddr)>> {
+        match try!(self.sock.accept()) {
+            Some((stream, addr)) => {
+                let session = Box::new(ServerSession::new(&self.config));
+                Ok(Some((TlsStream { conn: TlsConn::new(stream, session) },
+                         PeerAddr::Inet(addr))))
+            }
+            None => Ok(None)
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for TlsListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+impl Evented for TlsListener {
+    fn register(&self, selector: &mut Selector, token: Token,
+                interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token,
+                  interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.sock.deregister(selector)
+    }
+}
+
+
+//------------ TlsStream -----------------------------------------------------
+
+/// A TLS stream socket.
+///
+/// As described in the [module documentation](index.html), the handshake
+/// happens as part of reading and writing and doesn’t block the loop.
+pub struct TlsStream {
+    conn: TlsConn,
+}
+
+impl TlsStream {
+    pub fn connect(addr: &SocketAddr, config: &TlsClientConfig)
+                   -> Result<Self> {
+        let sock = try!(TcpStream::connect(addr));
+        Ok(TlsStream { conn: TlsConn::new(sock, config.session()) })
+    }
+}
+
+impl SecureStream for TlsStream {
+    type Certificate = ();
+
+    fn get_peer_cert(&self) -> Self::Certificate {
+        ()
+    }
+
+    fn selected_protocol(&self) -> Option<Vec<u8>> {
+        self.conn.session.get_alpn_protocol().map(String::into_bytes)
+    }
+
+    fn peer_certificate(&self) -> Option<Vec<u8>> {
+        self.conn.session.get_peer_certificates()
+                         .and_then(|certs| certs.into_iter().next())
+                         .map(|cert| cert.0)
+    }
+}
+
+impl Stream for TlsStream { }
+
+impl io::Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.conn.read(buf)
+    }
+}
+
+impl io::Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.conn.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.flush()
+    }
+}
+
+impl Transport for TlsStream {
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        self.conn.sock.take_socket_error()
+    }
+
+    fn blocked(&self) -> Option<Blocked> {
+        self.conn.blocked
+    }
+
+    fn flush_output(&mut self) -> io::Result<bool> {
+        flush_result(self.conn.flush())
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.conn.close_notify()
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        Transport::set_nodelay(&mut self.conn.sock, nodelay)
+    }
+
+    fn set_keepalive(&mut self, keepalive: Option<u32>) -> io::Result<()> {
+        Transport::set_keepalive(&mut self.conn.sock, keepalive)
+    }
+
+    fn set_linger(&mut self, linger: Option<Duration>) -> io::Result<()> {
+        Transport::set_linger(&mut self.conn.sock, linger)
+    }
+
+    fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        Transport::set_recv_buffer_size(&mut self.conn.sock, size)
+    }
+
+    fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        Transport::set_send_buffer_size(&mut self.conn.sock, size)
+    }
+
+    fn set_user_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Transport::set_user_timeout(&mut self.conn.sock, timeout)
+    }
+
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        Transport::set_tos(&mut self.conn.sock, tos)
+    }
+
+    fn tos(&self) -> io::Result<u8> {
+        Transport::tos(&self.conn.sock)
+    }
+
+    fn set_congestion_control(&mut self, name: &str) -> io::Result<()> {
+        Transport::set_congestion_control(&mut self.conn.sock, name)
+    }
+
+    fn congestion_control(&self) -> io::Result<String> {
+        Transport::congestion_control(&self.conn.sock)
+    }
+
+    fn tcp_info(&self) -> io::Result<TcpInfo> {
+        Transport::tcp_info(&self.conn.sock)
+    }
+
+    fn write_ready_hint(&self) -> bool {
+        Transport::write_ready_hint(&self.conn.sock)
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        Transport::is_connected(&self.conn.sock)
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        Transport::pending_write_bytes(&self.conn.sock)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Transport::peer_addr(&self.conn.sock)
+    }
+}
+
+impl Evented for TlsStream {
+    fn register(&self, selector: &mut Selector, token: Token,
+                interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.conn.sock.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token,
+                  interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.conn.sock.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.conn.sock.deregister(selector)
+    }
+}
+
+
+//------------ StartTlsListener ----------------------------------------------
+
+pub struct StartTlsListener {
+    sock: TcpListener,
+    config: Arc<rustls::ServerConfig>,
+}
+
+impl StartTlsListener {
+    pub fn bind(addr: &SocketAddr, config: Arc<rustls::ServerConfig>)
+                -> Result<Self> {
+        Ok(StartTlsListener { sock: try!(TcpListener::bind(addr)),
+                              config: config })
+    }
+
+    pub fn from_listener(lsnr: net::TcpListener, addr: &SocketAddr,
+                         config: Arc<rustls::ServerConfig>) -> Result<Self> {
+        Ok(StartTlsListener { sock: try!(TcpListener::from_listener(lsnr,
+                                                                    addr)),
+                              config: config })
+    }
+
+    /// Creates a listener from a raw file descriptor.
+    ///
+    /// See [tcp_listener_from_raw_fd()] for the requirements of the file
+    /// descriptor.
+    ///
+    /// # Safety
+    ///
+    /// The function takes ownership of the file descriptor.
+    ///
+    /// [tcp_listener_from_raw_fd()]: ../fn.tcp_listener_from_raw_fd.html
+    #[cfg(unix)]
+    pub unsafe fn from_raw_fd(fd: RawFd, config: Arc<rustls::ServerConfig>)
+                              -> Result<Self> {
+        Ok(StartTlsListener { sock: try!(tcp_listener_from_raw_fd(fd)),
+                              config: config })
+    }
+}
+
+impl Accept for StartTlsListener {
+    type Output = StartTlsStream;
+
+    fn accept(&self) -> Result<Option<(StartTlsStream, PeerAddr)>> {
+        match try!(self.sock.accept()) {
+            Some((stream, addr)) => {
+                let config = Config::Server(self.config.clone());
+                Ok(Some((StartTlsStream::new(stream, config),
+                         PeerAddr::Inet(addr))))
+            }
+            None => Ok(None)
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for StartTlsListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+impl Evented for StartTlsListener {
+    fn register(&self, selector: &mut Selector, token: Token,
+                interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token,
+                  interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.sock.deregister(selector)
+    }
+}
+
+
+//------------ StartTlsStream ------------------------------------------------
+
+/// A stream socket that can start encryption later.
+///
+/// Streams accepted by a [StartTlsListener] can only switch to
+/// encryption via `accept_secure()`, streams created via
+/// [connect()](#method.connect) only via `connect_secure()` since that is
+/// the only configuration they have. Calling the other method fails with
+/// `Error::Tls`.
+///
+/// [StartTlsListener]: struct.StartTlsListener.html
+pub struct StartTlsStream {
+    sock: Option<StartTlsSock>,
+    config: Config,
+
+    /// Whether `take_secured()` has reported the handshake already.
+    secured: bool,
+}
+
+enum StartTlsSock {
+    Clear(TcpStream),
+    Secure(TlsConn)
+}
+
+/// The configuration a start-TLS stream can switch to encryption with.
+enum Config {
+    Client(TlsClientConfig),
+    Server(Arc<rustls::ServerConfig>),
+}
+
+impl StartTlsStream {
+    pub fn connect(addr: &SocketAddr, config: TlsClientConfig)
+                   -> Result<Self> {
+        Ok(StartTlsStream::new(try!(TcpStream::connect(addr)),
+                               Config::Client(config)))
+    }
+}
+
+impl StartTlsStream {
+    fn new(stream: TcpStream, config: Config) -> StartTlsStream {
+        StartTlsStream {
+            sock: Some(StartTlsSock::Clear(stream)),
+            config: config,
+            secured: false,
+        }
+    }
+
+    /// Switches to encryption using `session`.
+    fn start(&mut self, session: Box<Session>) {
+        let sock = mem::replace(&mut self.sock, None);
+        if let Some(StartTlsSock::Clear(sock)) = sock {
+            self.sock = Some(StartTlsSock::Secure(TlsConn::new(sock,
+                                                               session)));
+        }
+        else {
+            panic!("Stream is already encrypted.")
+        }
+    }
+
+    fn get_sock(&self) -> io::Result<&TcpStream> {
+        match self.sock {
+            Some(StartTlsSock::Clear(ref sock)) => Ok(sock),
+            Some(StartTlsSock::Secure(ref conn)) => Ok(&conn.sock),
+            None => Err(unusable())
+        }
+    }
+
+    fn get_mut_sock(&mut self) -> io::Result<&mut TcpStream> {
+        match self.sock {
+            Some(StartTlsSock::Clear(ref mut sock)) => Ok(sock),
+            Some(StartTlsSock::Secure(ref mut conn)) => Ok(&mut conn.sock),
+            None => Err(unusable())
+        }
+    }
+}
+
+/// Switching to encryption doesn’t block the loop. As with a
+/// [TlsStream], `connect_secure()` and `accept_secure()` only start the
+/// handshake which is then continued by reads and writes.
+///
+/// [TlsStream]: struct.TlsStream.html
+impl HybridStream for StartTlsStream {
+    type Certificate = ();
+
+    fn connect_secure(&mut self) -> Result<()> {
+        let session = match self.config {
+            Config::Client(ref config) => config.session(),
+            Config::Server(_) => return Err(Error::Tls)
+        };
+        self.start(session);
+        Ok(())
+    }
+
+    fn accept_secure(&mut self) -> Result<()> {
+        let session: Box<Session> = match self.config {
+            Config::Server(ref config) => {
+                Box::new(ServerSession::new(config))
+            }
+            Config::Client(_) => return Err(Error::Tls)
+        };
+        self.start(session);
+        Ok(())
+    }
+
+    fn is_secure(&self) -> bool {
+        match self.sock {
+            Some(StartTlsSock::Secure(_)) => true,
+            _ => false,
+        }
+    }
+
+    fn get_peer_cert(&self) -> Option<Self::Certificate> {
+        if self.is_secure() { Some(()) }
+        else { None }
+    }
+}
+
+impl Stream for StartTlsStream { }
+
+impl io::Read for StartTlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.sock {
+            Some(StartTlsSock::Clear(ref mut sock)) => sock.read(buf),
+            Some(StartTlsSock::Secure(ref mut conn)) => conn.read(buf),
+            None => Err(unusable())
+        }
+    }
+}
+
+impl io::Write for StartTlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.sock {
+            Some(StartTlsSock::Clear(ref mut sock)) => sock.write(buf),
+            Some(StartTlsSock::Secure(ref mut conn)) => conn.write(buf),
+            None => Err(unusable())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.sock {
+            Some(StartTlsSock::Clear(ref mut sock)) => sock.flush(),
+            Some(StartTlsSock::Secure(ref mut conn)) => conn.flush(),
+            None => Err(unusable())
+        }
+    }
+}
+
+impl Transport for StartTlsStream {
+    fn take_secured(&mut self) -> bool {
+        if self.secured {
+            return false
+        }
+        self.secured = match self.sock {
+            Some(StartTlsSock::Secure(ref conn)) => {
+                !conn.failed && !conn.session.is_handshaking()
+            }
+            _ => false
+        };
+        self.secured
+    }
+
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        try!(self.get_mut_sock()).take_socket_error()
+    }
+
+    fn blocked(&self) -> Option<Blocked> {
+        match self.sock {
+            Some(StartTlsSock::Secure(ref conn)) => conn.blocked,
+            _ => None
+        }
+    }
+
+    fn is_usable(&self) -> bool {
+        self.sock.is_some()
+    }
+
+    fn flush_output(&mut self) -> io::Result<bool> {
+        match self.sock {
+            Some(StartTlsSock::Secure(ref mut conn)) => {
+                flush_result(conn.flush())
+            }
+            _ => Ok(true)
+        }
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        match self.sock {
+            Some(StartTlsSock::Clear(ref mut sock)) => {
+                Transport::shutdown_write(sock)
+            }
+            Some(StartTlsSock::Secure(ref mut conn)) => conn.close_notify(),
+            None => Err(unusable())
+        }
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        Transport::set_nodelay(try!(self.get_mut_sock()), nodelay)
+    }
+
+    fn set_keepalive(&mut self, keepalive: Option<u32>) -> io::Result<()> {
+        Transport::set_keepalive(try!(self.get_mut_sock()), keepalive)
+    }
+
+    fn set_linger(&mut self, linger: Option<Duration>) -> io::Result<()> {
+        Transport::set_linger(try!(self.get_mut_sock()), linger)
+    }
+
+    fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        Transport::set_recv_buffer_size(try!(self.get_mut_sock()), size)
+    }
+
+    fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        Transport::set_send_buffer_size(try!(self.get_mut_sock()), size)
+    }
+
+    fn set_user_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Transport::set_user_timeout(try!(self.get_mut_sock()), timeout)
+    }
+
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        Transport::set_tos(try!(self.get_mut_sock()), tos)
+    }
+
+    fn tos(&self) -> io::Result<u8> {
+        Transport::tos(try!(self.get_sock()))
+    }
+
+    fn set_congestion_control(&mut self, name: &str) -> io::Result<()> {
+        Transport::set_congestion_control(try!(self.get_mut_sock()), name)
+    }
+
+    fn congestion_control(&self) -> io::Result<String> {
+        Transport::congestion_control(try!(self.get_sock()))
+    }
+
+    fn tcp_info(&self) -> io::Result<TcpInfo> {
+        Transport::tcp_info(try!(self.get_sock()))
+    }
+
+    fn write_ready_hint(&self) -> bool {
+        self.get_sock().map(Transport::write_ready_hint).unwrap_or(true)
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        Transport::is_connected(try!(self.get_sock()))
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        self.get_sock().map(Transport::pending_write_bytes).unwrap_or(0)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Transport::peer_addr(try!(self.get_sock()))
+    }
+}
+
+impl Evented for StartTlsStream {
+    fn register(&self, selector: &mut Selector, token: Token,
+                interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        try!(self.get_sock()).register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token,
+                  interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        try!(self.get_sock()).reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        try!(self.get_sock()).deregister(selector)
+    }
+}
+
+
+//------------ TlsConn -------------------------------------------------------
+
+/// A TCP socket with a rustls session atop.
+///
+/// This does the actual work for both stream types.
+struct TlsConn {
+    sock: TcpStream,
+    session: Box<Session>,
+
+    /// The direction we are blocked on.
+    ///
+    /// As with the OpenSSL sockets, this is only cleared once an
+    /// operation succeeds.
+    blocked: Option<Blocked>,
+
+    /// Whether the peer has closed the TCP connection.
+    eof: bool,
+
+    /// Whether the peer has ended the session with a close_notify alert.
+    ///
+    /// Reading returns end of file from then on.
+    closed: bool,
+
+    /// Whether the session has failed.
+    ///
+    /// Everything fails with `ConnectionAborted` after that.
+    failed: bool,
+}
+
+impl TlsConn {
+    fn new(sock: TcpStream, session: Box<Session>) -> Self {
+        TlsConn { sock: sock, session: session, blocked: None, eof: false,
+                  closed: false, failed: false }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        try!(self.check_failed());
+        if self.closed {
+            return Ok(0)
+        }
+        try!(self.read_tls());
+        // The handshake may have produced a reply.
+        try!(self.write_tls());
+        if self.session.is_handshaking() {
+            if self.eof {
+                return Ok(0)
+            }
+            return Err(self.would_block())
+        }
+        match self.session.read(buf) {
+            Ok(0) if !buf.is_empty() && !self.eof => Err(self.would_block()),
+            Ok(len) => {
+                self.blocked = None;
+                Ok(len)
+            }
+            // Once all plaintext has been read, rustls reports a received
+            // close_notify as an error. For us, it is a clean end of file.
+            Err(ref err) if err.kind() == io::ErrorKind::ConnectionAborted => {
+                self.closed = true;
+                self.blocked = None;
+                Ok(0)
+            }
+            Err(err) => Err(err)
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.check_failed());
+        if self.session.is_handshaking() {
+            try!(self.read_tls());
+            try!(self.write_tls());
+            if self.session.is_handshaking() {
+                return Err(self.would_block())
+            }
+        }
+        // Only take new data once everything from before is out. This
+        // keeps rustls from buffering without limit.
+        if !try!(self.write_tls()) {
+            return Err(self.would_block())
+        }
+        let len = try!(self.session.write(buf));
+        try!(self.write_tls());
+        self.blocked = None;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.check_failed());
+        if try!(self.write_tls()) {
+            Ok(())
+        }
+        else {
+            Err(self.would_block())
+        }
+    }
+
+    /// Sends a close_notify alert to the peer.
+    ///
+    /// Anything that can’t be written right away goes out with the next
+    /// flush. The TCP connection itself stays open for the peer’s reply.
+    fn close_notify(&mut self) -> io::Result<()> {
+        try!(self.check_failed());
+        self.session.send_close_notify();
+        try!(self.write_tls());
+        Ok(())
+    }
+
+    /// Feeds rustls everything waiting on the socket.
+    fn read_tls(&mut self) -> io::Result<()> {
+        while !self.eof && self.session.wants_read() {
+            match self.session.read_tls(&mut self.sock) {
+                Ok(0) => self.eof = true,
+                Ok(_) => {
+                    if let Err(err) = self.session.process_new_packets() {
+                        // Try to get the alert out to the peer.
+                        let _ = self.write_tls();
+                        self.failed = true;
+                        return Err(tls_error(err))
+                    }
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    break
+                }
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes as many TLS records to the socket as possible.
+    ///
+    /// Returns whether everything has been written.
+    fn write_tls(&mut self) -> io::Result<bool> {
+        while self.session.wants_write() {
+            match self.session.write_tls(&mut self.sock) {
+                Ok(_) => { }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(false)
+                }
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(true)
+    }
+
+    /// Notes the direction we are blocked on and returns the error.
+    ///
+    /// If rustls has records to send, we are waiting to be able to
+    /// write. Otherwise, we are waiting for the peer.
+    fn would_block(&mut self) -> io::Error {
+        let blocked = if self.session.wants_write() { Blocked::Write }
+                      else { Blocked::Read };
+        self.blocked = Some(blocked.combine(self.blocked));
+        io::Error::new(io::ErrorKind::WouldBlock, "TLS session blocked")
+    }
+
+    fn check_failed(&self) -> io::Result<()> {
+        if self.failed { Err(unusable()) }
+        else { Ok(()) }
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// Translates a rustls error into an IO error.
+///
+/// Older rustls versions don’t implement `std::error::Error` for their
+/// error type, so we use its debug output.
+fn tls_error(err: TLSError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("TLS error: {:?}", err))
+}
+
+/// Returns the error for a stream that can’t be used anymore.
+fn unusable() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "stream unusable")
+}
+
+/// Translates the result of flushing for `Transport::flush_output()`.
+fn flush_result(res: io::Result<()>) -> io::Result<bool> {
+    match res {
+        Ok(()) => Ok(true),
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(err) => Err(err)
+    }
+}
@@ -0,0 +1,864 @@
+//! Secure sockets using Apple’s Secure Transport.
+//!
+//! This uses the [security-framework] crate and thus only works on macOS
+//! and iOS.
+//!
+//! Secure Transport contexts can’t be shared between connections, so the
+//! configuration for both clients and servers is a [ContextConfig] which
+//! sets up a fresh context for every new connection.
+//!
+//! As with the OpenSSL sockets, the handshake doesn’t block the loop but
+//! happens as part of reading and writing. Secure Transport only tells us
+//! that it was interrupted, not what it is waiting for. So the TCP socket
+//! is wrapped into a type that remembers which direction last failed with
+//! `WouldBlock` and that is what [blocked()] reports.
+//!
+//! [security-framework]: https://crates.io/crates/security-framework
+//! [ContextConfig]: struct.ContextConfig.html
+//! [blocked()]: ../trait.Transport.html#method.blocked
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{self, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(unix)] use std::os::unix::io::{AsRawFd, RawFd};
+use security_framework::base;
+use security_framework::secure_transport::{ConnectionType, HandshakeError,
+                                           MidHandshakeSslStream,
+                                           ProtocolSide, SslContext,
+                                           SslStream};
+use rotor::{Evented, EventSet, PollOpt};
+use rotor::mio::{Selector, Token};
+use rotor::mio::tcp::{TcpListener, TcpStream};
+#[cfg(unix)] use super::tcp_listener_from_raw_fd;
+use super::{Accept, Blocked, HybridStream, PeerAddr, SecureStream, Stream,
+            TcpInfo, Transport};
+use super::tls::TlsBackend;
+use ::error::Result;
+
+
+//------------ SecurityFramework ---------------------------------------------
+
+/// The TLS backend using Secure Transport.
+///
+/// Both client and server configuration are a [ContextConfig].
+///
+/// [ContextConfig]: struct.ContextConfig.html
+pub struct SecurityFramework;
+
+impl TlsBackend for SecurityFramework {
+    type Listener = TlsListener;
+    type Stream = TlsStream;
+    type StartTlsListener = StartTlsListener;
+    type StartTlsStream = StartTlsStream;
+    type ClientConfig = ContextConfig;
+    type ServerConfig = ContextConfig;
+
+    fn bind(addr: &SocketAddr, config: ContextConfig)
+            -> Result<TlsListener> {
+        TlsListener::bind(addr, config)
+    }
+
+    fn bind_starttls(addr: &SocketAddr, config: ContextConfig)
+                     -> Result<StartTlsListener> {
+        StartTlsListener::bind(addr, config)
+    }
+
+    fn connect(addr: &SocketAddr, config: &ContextConfig)
+               -> Result<TlsStream> {
+        TlsStream::connect(addr, config)
+    }
+
+    fn connect_starttls(addr: &SocketAddr, config: &ContextConfig)
+                        -> Result<StartTlsStream> {
+        StartTlsStream::connect(addr, config.clone())
+    }
+}
+
+
+//------------ ContextConfig -------------------------------------------------
+
+/// The configuration for Secure Transport sockets.
+///
+/// This wraps a closure that is called with every freshly created
+/// context. It can set a certificate for servers via
+/// `SslContext::set_certificate()` or the domain name of the server for
+/// clients via `SslContext::set_peer_domain_name()`. For the latter, there
+/// is the [client()](#method.client) shortcut.
+#[derive(Clone)]
+pub struct ContextConfig {
+    setup: Arc<Fn(&mut SslContext) -> base::Result<()>>,
+}
+
+impl ContextConfig {
+    /// Creates a configuration using `setup` for each new context.
+    pub fn new<F>(setup: F) -> Self
+               where F: Fn(&mut SslContext) -> base::Result<()> + 'static {
+        ContextConfig { setup: Arc::new(setup) }
+    }
+
+    /// Creates a client configuration for talking to `domain`.
+    pub fn client(domain: String) -> Self {
+        ContextConfig::new(move |ctx| ctx.set_peer_domain_name(&domain))
+    }
+
+    /// Creates a new context for the given side.
+    fn context(&self, side: ProtocolSide) -> Result<SslContext> {
+        let mut ctx = try!(SslContext::new(side, ConnectionType::Stream));
+        try!((self.setup)(&mut ctx));
+        Ok(ctx)
+    }
+}
+
+
+//------------ TlsListener ---------------------------------------------------
+
+pub struct TlsListener {
+    sock: TcpListener,
+    config: ContextConfig,
+}
+
+impl TlsListener {
+    pub fn bind(addr: &SocketAddr, config: ContextConfig) -> Result<Self> {
+        Ok(TlsListener { sock: try!(TcpListener::bind(addr)),
+                         config: config })
+    }
+
+    pub fn from_listener(lsnr: net::TcpListener, addr: &SocketAddr,
+                         config: ContextConfig) -> Result<Self> {
+        Ok(TlsListener { sock: try!(TcpListener::from_listener(lsnr, addr)),
+                         config: config })
+    }
+
+    /// Creates a listener from a raw file descriptor.
+    ///
+    /// See [tcp_listener_from_raw_fd()] for the requirements of the file
+    /// descriptor.
+    ///
+    /// # Safety
+    ///
+    /// The function takes ownership of the file descriptor.
+    ///
+    /// [tcp_listener_from_raw_fd()]: ../fn.tcp_listener_from_raw_fd.html
+    #[cfg(unix)]
+    pub unsafe fn from_raw_fd(fd: RawFd, config: ContextConfig)
+                              -> Result<Self> {
+        Ok(TlsListener { sock: try!(tcp_listener_from_raw_fd(fd)),
+                         config: config })
+    }
+}
+
+impl Accept for TlsListener {
+    type Output = TlsStream;
+
+    fn accept(&self) -> Result<Option<(TlsStream, PeerAddr)>> {
+        match try!(self.sock.accept()) {
+            Some((stream, addr)) => {
+                let ctx = try!(self.config.context(ProtocolSide::Server));
+                Ok(Some((TlsStream { sock: try!(SecureSock::new(ctx,
+                                                                stream)) },
+                         PeerAddr::Inet(addr))))
+            }
+            None => Ok(None)
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for TlsListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+impl Evented for TlsListener {
+    fn register(&self, selector: &mut Selector, token: Token,
+                interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token,
+                  interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.sock.deregister(selector)
+    }
+}
+
+
+//------------ TlsStream -----------------------------------------------------
+
+/// A TLS stream socket.
+///
+/// The TLS handshake doesn’t block the loop. Accepting or connecting only
+/// starts it and every read or write continues it until it is complete.
+/// Until then, they fail with `WouldBlock` and [blocked()] tells the
+/// machine which event the handshake is waiting for.
+///
+/// If the handshake fails, the stream becomes unusable.
+///
+/// [blocked()]: ../trait.Transport.html#method.blocked
+pub struct TlsStream {
+    sock: SecureSock,
+}
+
+impl TlsStream {
+    pub fn connect(addr: &SocketAddr, config: &ContextConfig)
+                   -> Result<Self> {
+        let ctx = try!(config.context(ProtocolSide::Client));
+        let sock = try!(TcpStream::connect(addr));
+        Ok(TlsStream { sock: try!(SecureSock::new(ctx, sock)) })
+    }
+}
+
+impl SecureStream for TlsStream {
+    type Certificate = ();
+
+    fn get_peer_cert(&self) -> Self::Certificate {
+        ()
+    }
+}
+
+impl Stream for TlsStream { }
+
+impl io::Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.sock.read(buf)
+    }
+}
+
+impl io::Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sock.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+impl Transport for TlsStream {
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        try!(self.sock.get_mut()).take_socket_error()
+    }
+
+    fn blocked(&self) -> Option<Blocked> {
+        self.sock.blocked
+    }
+
+    fn is_usable(&self) -> bool {
+        self.sock.is_usable()
+    }
+
+    fn flush_output(&mut self) -> io::Result<bool> {
+        flush_result(self.sock.flush())
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        Transport::set_nodelay(try!(self.sock.get_mut()), nodelay)
+    }
+
+    fn set_keepalive(&mut self, keepalive: Option<u32>) -> io::Result<()> {
+        Transport::set_keepalive(try!(self.sock.get_mut()), keepalive)
+    }
+
+    fn set_linger(&mut self, linger: Option<Duration>) -> io::Result<()> {
+        Transport::set_linger(try!(self.sock.get_mut()), linger)
+    }
+
+    fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        Transport::set_recv_buffer_size(try!(self.sock.get_mut()), size)
+    }
+
+    fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        Transport::set_send_buffer_size(try!(self.sock.get_mut()), size)
+    }
+
+    fn set_user_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Transport::set_user_timeout(try!(self.sock.get_mut()), timeout)
+    }
+
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        Transport::set_tos(try!(self.sock.get_mut()), tos)
+    }
+
+    fn tos(&self) -> io::Result<u8> {
+        Transport::tos(try!(self.sock.get_ref()))
+    }
+
+    fn set_congestion_control(&mut self, name: &str) -> io::Result<()> {
+        Transport::set_congestion_control(try!(self.sock.get_mut()), name)
+    }
+
+    fn congestion_control(&self) -> io::Result<String> {
+        Transport::congestion_control(try!(self.sock.get_ref()))
+    }
+
+    fn tcp_info(&self) -> io::Result<TcpInfo> {
+        Transport::tcp_info(try!(self.sock.get_ref()))
+    }
+
+    fn write_ready_hint(&self) -> bool {
+        self.sock.get_ref().map(Transport::write_ready_hint).unwrap_or(true)
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        Transport::is_connected(try!(self.sock.get_ref()))
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        self.sock.get_ref().map(Transport::pending_write_bytes).unwrap_or(0)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Transport::peer_addr(try!(self.sock.get_ref()))
+    }
+}
+
+impl Evented for TlsStream {
+    fn register(&self, selector: &mut Selector, token: Token,
+                interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        try!(self.sock.get_ref()).register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token,
+                  interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        try!(self.sock.get_ref()).reregister(selector, token, interest,
+                                               opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        try!(self.sock.get_ref()).deregister(selector)
+    }
+}
+
+
+//------------ StartTlsListener ----------------------------------------------
+
+pub struct StartTlsListener {
+    sock: TcpListener,
+    config: ContextConfig,
+}
+
+impl StartTlsListener {
+    pub fn bind(addr: &SocketAddr, config: ContextConfig) -> Result<Self> {
+        Ok(StartTlsListener { sock: try!(TcpListener::bind(addr)),
+                              config: config })
+    }
+
+    pub fn from_listener(lsnr: net::TcpListener, addr: &SocketAddr,
+                         config: ContextConfig) -> Result<Self> {
+        Ok(StartTlsListener { sock: try!(TcpListener::from_listener(lsnr,
+                                                                    addr)),
+                              config: config })
+    }
+
+    /// Creates a listener from a raw file descriptor.
+    ///
+    /// See [tcp_listener_from_raw_fd()] for the requirements of the file
+    /// descriptor.
+    ///
+    /// # Safety
+    ///
+    /// The function takes ownership of the file descriptor.
+    ///
+    /// [tcp_listener_from_raw_fd()]: ../fn.tcp_listener_from_raw_fd.html
+    #[cfg(unix)]
+    pub unsafe fn from_raw_fd(fd: RawFd, config: ContextConfig)
+                              -> Result<Self> {
+        Ok(StartTlsListener { sock: try!(tcp_listener_from_raw_fd(fd)),
+                              config: config })
+    }
+}
+
+impl Accept for StartTlsListener {
+    type Output = StartTlsStream;
+
+    fn accept(&self) -> Result<Option<(StartTlsStream, PeerAddr)>> {
+        match try!(self.sock.accept()) {
+            Some((stream, addr)) => {
+                Ok(Some((StartTlsStream::new(stream, self.config.clone()),
+                         PeerAddr::Inet(addr))))
+            }
+            None => Ok(None)
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for StartTlsListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+impl Evented for StartTlsListener {
+    fn register(&self, selector: &mut Selector, token: Token,
+                interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token,
+                  interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.sock.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.sock.deregister(selector)
+    }
+}
+
+
+//------------ StartTlsStream ------------------------------------------------
+
+pub struct StartTlsStream {
+    sock: Option<StartTlsSock>,
+    config: ContextConfig,
+
+    /// Whether `take_secured()` has reported the handshake already.
+    secured: bool,
+}
+
+enum StartTlsSock {
+    Clear(TcpStream),
+    Secure(SecureSock)
+}
+
+impl StartTlsStream {
+    pub fn connect(addr: &SocketAddr, config: ContextConfig)
+                   -> Result<Self> {
+        Ok(StartTlsStream::new(try!(TcpStream::connect(addr)), config))
+    }
+}
+
+impl StartTlsStream {
+    fn new(stream: TcpStream, config: ContextConfig) -> StartTlsStream {
+        StartTlsStream {
+            sock: Some(StartTlsSock::Clear(stream)),
+            config: config,
+            secured: false,
+        }
+    }
+
+    /// Starts the handshake for the given side.
+    fn start(&mut self, side: ProtocolSide) -> Result<()> {
+        let sock = mem::replace(&mut self.sock, None);
+        if let Some(StartTlsSock::Clear(sock)) = sock {
+            let ctx = try!(self.config.context(side));
+            let sock = try!(SecureSock::new(ctx, sock));
+            self.sock = Some(StartTlsSock::Secure(sock));
+            Ok(())
+        }
+        else {
+            panic!("Stream is already encrypted.")
+        }
+    }
+
+    fn get_sock(&self) -> io::Result<&TcpStream> {
+        match self.sock {
+            Some(StartTlsSock::Clear(ref sock)) => Ok(sock),
+            Some(StartTlsSock::Secure(ref sock)) => sock.get_ref(),
+            None => Err(unusable())
+        }
+    }
+
+    fn get_mut_sock(&mut self) -> io::Result<&mut TcpStream> {
+        match self.sock {
+            Some(StartTlsSock::Clear(ref mut sock)) => Ok(sock),
+            Some(StartTlsSock::Secure(ref mut sock)) => sock.get_mut(),
+            None => Err(unusable())
+        }
+    }
+}
+
+/// Switching to encryption doesn’t block the loop. As with a
+/// [TlsStream], `connect_secure()` and `accept_secure()` only start the
+/// handshake which is then continued by reads and writes.
+///
+/// [TlsStream]: struct.TlsStream.html
+impl HybridStream for StartTlsStream {
+    type Certificate = ();
+
+    fn connect_secure(&mut self) -> Result<()> {
+        self.start(ProtocolSide::Client)
+    }
+
+    fn accept_secure(&mut self) -> Result<()> {
+        self.start(ProtocolSide::Server)
+    }
+
+    fn is_secure(&self) -> bool {
+        match self.sock {
+            Some(StartTlsSock::Secure(_)) => true,
+            _ => false,
+        }
+    }
+
+    fn get_peer_cert(&self) -> Option<Self::Certificate> {
+        if self.is_secure() { Some(()) }
+        else { None }
+    }
+}
+
+impl Stream for StartTlsStream { }
+
+impl io::Read for StartTlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.sock {
+            Some(StartTlsSock::Clear(ref mut sock)) => sock.read(buf),
+            Some(StartTlsSock::Secure(ref mut sock)) => sock.read(buf),
+            None => Err(unusable())
+        }
+    }
+}
+
+impl io::Write for StartTlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.sock {
+            Some(StartTlsSock::Clear(ref mut sock)) => sock.write(buf),
+            Some(StartTlsSock::Secure(ref mut sock)) => sock.write(buf),
+            None => Err(unusable())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.sock {
+            Some(StartTlsSock::Clear(ref mut sock)) => sock.flush(),
+            Some(StartTlsSock::Secure(ref mut sock)) => sock.flush(),
+            None => Err(unusable())
+        }
+    }
+}
+
+impl Transport for StartTlsStream {
+    fn take_secured(&mut self) -> bool {
+        if self.secured {
+            return false
+        }
+        self.secured = match self.sock {
+            Some(StartTlsSock::Secure(ref sock)) => sock.is_open(),
+            _ => false
+        };
+        self.secured
+    }
+
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        try!(self.get_mut_sock()).take_socket_error()
+    }
+
+    fn blocked(&self) -> Option<Blocked> {
+        match self.sock {
+            Some(StartTlsSock::Secure(ref sock)) => sock.blocked,
+            _ => None
+        }
+    }
+
+    fn is_usable(&self) -> bool {
+        match self.sock {
+            Some(StartTlsSock::Clear(_)) => true,
+            Some(StartTlsSock::Secure(ref sock)) => sock.is_usable(),
+            None => false
+        }
+    }
+
+    fn flush_output(&mut self) -> io::Result<bool> {
+        match self.sock {
+            Some(StartTlsSock::Secure(ref mut sock)) => {
+                flush_result(sock.flush())
+            }
+            _ => Ok(true)
+        }
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        Transport::set_nodelay(try!(self.get_mut_sock()), nodelay)
+    }
+
+    fn set_keepalive(&mut self, keepalive: Option<u32>) -> io::Result<()> {
+        Transport::set_keepalive(try!(self.get_mut_sock()), keepalive)
+    }
+
+    fn set_linger(&mut self, linger: Option<Duration>) -> io::Result<()> {
+        Transport::set_linger(try!(self.get_mut_sock()), linger)
+    }
+
+    fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        Transport::set_recv_buffer_size(try!(self.get_mut_sock()), size)
+    }
+
+    fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+        Transport::set_send_buffer_size(try!(self.get_mut_sock()), size)
+    }
+
+    fn set_user_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Transport::set_user_timeout(try!(self.get_mut_sock()), timeout)
+    }
+
+    fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        Transport::set_tos(try!(self.get_mut_sock()), tos)
+    }
+
+    fn tos(&self) -> io::Result<u8> {
+        Transport::tos(try!(self.get_sock()))
+    }
+
+    fn set_congestion_control(&mut self, name: &str) -> io::Result<()> {
+        Transport::set_congestion_control(try!(self.get_mut_sock()), name)
+    }
+
+    fn congestion_control(&self) -> io::Result<String> {
+        Transport::congestion_control(try!(self.get_sock()))
+    }
+
+    fn tcp_info(&self) -> io::Result<TcpInfo> {
+        Transport::tcp_info(try!(self.get_sock()))
+    }
+
+    fn write_ready_hint(&self) -> bool {
+        self.get_sock().map(Transport::write_ready_hint).unwrap_or(true)
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        Transport::is_connected(try!(self.get_sock()))
+    }
+
+    fn pending_write_bytes(&self) -> usize {
+        self.get_sock().map(Transport::pending_write_bytes).unwrap_or(0)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Transport::peer_addr(try!(self.get_sock()))
+    }
+}
+
+impl Evented for StartTlsStream {
+    fn register(&self, selector: &mut Selector, token: Token,
+                interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        try!(self.get_sock()).register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token,
+                  interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        try!(self.get_sock()).reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        try!(self.get_sock()).deregister(selector)
+    }
+}
+
+
+//------------ SecureSock ----------------------------------------------------
+
+/// A TCP socket with Secure Transport atop.
+///
+/// This does the actual work for both stream types.
+struct SecureSock {
+    /// The socket in its current state.
+    ///
+    /// This is `None` if the handshake has failed.
+    state: Option<SecureState>,
+
+    /// The direction we are blocked on.
+    ///
+    /// As with the OpenSSL sockets, this is only cleared once an
+    /// operation succeeds.
+    blocked: Option<Blocked>,
+}
+
+enum SecureState {
+    Handshake(MidHandshakeSslStream<TrackedSock>),
+    Open(SslStream<TrackedSock>),
+}
+
+impl SecureSock {
+    /// Starts the handshake for `sock` using `ctx`.
+    fn new(ctx: SslContext, sock: TcpStream) -> Result<Self> {
+        let mut res = SecureSock { state: None, blocked: None };
+        match ctx.handshake(TrackedSock::new(sock)) {
+            Ok(sock) => res.state = Some(SecureState::Open(sock)),
+            Err(HandshakeError::Interrupted(mut sock)) => {
+                res.block(sock.get_mut().take_blocked());
+                res.state = Some(SecureState::Handshake(sock));
+            }
+            Err(HandshakeError::Failure(err)) => return Err(err.into())
+        }
+        Ok(res)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io(|sock| sock.read(buf))
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io(|sock| sock.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.handshake());
+        match self.state {
+            Some(SecureState::Open(ref mut sock)) => sock.flush(),
+            _ => Err(unusable())
+        }
+    }
+
+    /// Continues the handshake if it is still going on.
+    ///
+    /// Fails with `WouldBlock` if it hasn’t completed yet.
+    fn handshake(&mut self) -> io::Result<()> {
+        let sock = match mem::replace(&mut self.state, None) {
+            Some(SecureState::Handshake(sock)) => sock,
+            state => {
+                self.state = state;
+                return Ok(())
+            }
+        };
+        match sock.handshake() {
+            Ok(sock) => {
+                self.state = Some(SecureState::Open(sock));
+                Ok(())
+            }
+            Err(HandshakeError::Interrupted(mut sock)) => {
+                self.block(sock.get_mut().take_blocked());
+                self.state = Some(SecureState::Handshake(sock));
+                Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                   "TLS handshake in progress"))
+            }
+            Err(HandshakeError::Failure(err)) => {
+                Err(io::Error::new(io::ErrorKind::Other, err))
+            }
+        }
+    }
+
+    /// Performs an operation on an established session.
+    fn io<F, T>(&mut self, op: F) -> io::Result<T>
+          where F: FnOnce(&mut SslStream<TrackedSock>) -> io::Result<T> {
+        try!(self.handshake());
+        let (res, blocked) = match self.state {
+            Some(SecureState::Open(ref mut sock)) => {
+                let res = op(sock);
+                (res, sock.get_mut().take_blocked())
+            }
+            _ => return Err(unusable())
+        };
+        match res {
+            Ok(res) => {
+                self.blocked = None;
+                Ok(res)
+            }
+            Err(err) => {
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    self.block(blocked)
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn block(&mut self, blocked: Option<Blocked>) {
+        if let Some(blocked) = blocked {
+            self.blocked = Some(blocked.combine(self.blocked))
+        }
+    }
+
+    fn is_usable(&self) -> bool {
+        self.state.is_some()
+    }
+
+    /// Returns whether the handshake has completed successfully.
+    fn is_open(&self) -> bool {
+        match self.state {
+            Some(SecureState::Open(_)) => true,
+            _ => false
+        }
+    }
+
+    fn get_ref(&self) -> io::Result<&TcpStream> {
+        match self.state {
+            Some(SecureState::Handshake(ref sock)) => Ok(&sock.get_ref().sock),
+            Some(SecureState::Open(ref sock)) => Ok(&sock.get_ref().sock),
+            None => Err(unusable())
+        }
+    }
+
+    fn get_mut(&mut self) -> io::Result<&mut TcpStream> {
+        match self.state {
+            Some(SecureState::Handshake(ref mut sock)) => {
+                Ok(&mut sock.get_mut().sock)
+            }
+            Some(SecureState::Open(ref mut sock)) => {
+                Ok(&mut sock.get_mut().sock)
+            }
+            None => Err(unusable())
+        }
+    }
+}
+
+
+//------------ TrackedSock ---------------------------------------------------
+
+/// A TCP socket that remembers which direction would have blocked.
+struct TrackedSock {
+    sock: TcpStream,
+    blocked: Option<Blocked>,
+}
+
+impl TrackedSock {
+    fn new(sock: TcpStream) -> Self {
+        TrackedSock { sock: sock, blocked: None }
+    }
+
+    /// Returns and clears the direction that last would have blocked.
+    fn take_blocked(&mut self) -> Option<Blocked> {
+        self.blocked.take()
+    }
+
+    fn track<T>(&mut self, res: io::Result<T>, dir: Blocked)
+                -> io::Result<T> {
+        if let Err(ref err) = res {
+            if err.kind() == io::ErrorKind::WouldBlock {
+                self.blocked = Some(dir)
+            }
+        }
+        res
+    }
+}
+
+impl io::Read for TrackedSock {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let res = self.sock.read(buf);
+        self.track(res, Blocked::Read)
+    }
+}
+
+impl io::Write for TrackedSock {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let res = self.sock.write(buf);
+        self.track(res, Blocked::Write)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let res = self.sock.flush();
+        self.track(res, Blocked::Write)
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// Returns the error for a stream that can’t be used anymore.
+fn unusable() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "stream unusable")
+}
+
+/// Translates the result of flushing for `Transport::flush_output()`.
+fn flush_result(res: io::Result<()>) -> io::Result<bool> {
+    match res {
+        Ok(()) => Ok(true),
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(err) => Err(err)
+    }
+}
@@ -0,0 +1,776 @@
+//! Access to socket options.
+//!
+//! Neither mio nor the standard library provide access to all the socket
+//! options we want to offer, so for some of them we have to go all the way
+//! down to `setsockopt()`. This module contains the necessary helpers as
+//! well as those for other socket features we need libc for. On
+//! platforms we don’t have an implementation for, they simply fail with an
+//! error.
+
+use std::io;
+use std::net::{self, SocketAddr};
+use std::time::Duration;
+use super::TcpInfo;
+
+#[cfg(unix)] use std::mem;
+#[cfg(unix)] use std::ffi::OsStr;
+#[cfg(unix)] use std::net::Shutdown;
+#[cfg(unix)] use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)] use std::os::unix::io::{AsRawFd, FromRawFd};
+#[cfg(unix)] use std::path::PathBuf;
+#[cfg(unix)] use libc;
+
+
+//------------ Generic Helpers ----------------------------------------------
+
+/// Returns the error for an option not supported on this platform.
+pub fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Other,
+                   "socket option not supported on this platform")
+}
+
+/// Sets the socket option `name` at `level` to `value`.
+#[cfg(unix)]
+pub fn set<S: AsRawFd, T>(sock: &S, level: libc::c_int, name: libc::c_int,
+                          value: T) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(sock.as_raw_fd(), level, name,
+                         &value as *const T as *const libc::c_void,
+                         mem::size_of::<T>() as libc::socklen_t)
+    };
+    if res == -1 { Err(io::Error::last_os_error()) }
+    else { Ok(()) }
+}
+
+/// Returns the value of socket option `name` at `level`.
+#[cfg(unix)]
+pub fn get<S: AsRawFd, T: Copy>(sock: &S, level: libc::c_int,
+                                name: libc::c_int) -> io::Result<T> {
+    let mut value: T = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<T>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(sock.as_raw_fd(), level, name,
+                         &mut value as *mut T as *mut libc::c_void,
+                         &mut len)
+    };
+    if res == -1 { Err(io::Error::last_os_error()) }
+    else { Ok(value) }
+}
+
+
+//------------ Linger -------------------------------------------------------
+
+#[cfg(unix)]
+pub fn set_linger<S: AsRawFd>(sock: &S, linger: Option<Duration>)
+                              -> io::Result<()> {
+    let value = match linger {
+        Some(dur) => libc::linger { l_onoff: 1,
+                                    l_linger: dur.as_secs() as libc::c_int },
+        None => libc::linger { l_onoff: 0, l_linger: 0 }
+    };
+    set(sock, libc::SOL_SOCKET, libc::SO_LINGER, value)
+}
+
+#[cfg(not(unix))]
+pub fn set_linger<S>(_sock: &S, _linger: Option<Duration>)
+                     -> io::Result<()> {
+    Err(unsupported())
+}
+
+
+//------------ Buffer Sizes -------------------------------------------------
+
+#[cfg(unix)]
+pub fn set_recv_buffer_size<S: AsRawFd>(sock: &S, size: usize)
+                                        -> io::Result<()> {
+    set(sock, libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int)
+}
+
+#[cfg(not(unix))]
+pub fn set_recv_buffer_size<S>(_sock: &S, _size: usize) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(unix)]
+pub fn set_send_buffer_size<S: AsRawFd>(sock: &S, size: usize)
+                                        -> io::Result<()> {
+    set(sock, libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int)
+}
+
+#[cfg(not(unix))]
+pub fn set_send_buffer_size<S>(_sock: &S, _size: usize) -> io::Result<()> {
+    Err(unsupported())
+}
+
+
+//------------ User Timeout -------------------------------------------------
+
+#[cfg(target_os = "linux")]
+pub fn set_user_timeout<S: AsRawFd>(sock: &S, timeout: Duration)
+                                    -> io::Result<()> {
+    let millis = timeout.as_secs().saturating_mul(1000)
+                        .saturating_add(timeout.subsec_nanos() as u64
+                                        / 1_000_000);
+    let millis = if millis > libc::c_uint::max_value() as u64 {
+        libc::c_uint::max_value()
+    }
+    else {
+        millis as libc::c_uint
+    };
+    set(sock, libc::IPPROTO_TCP, libc::TCP_USER_TIMEOUT, millis)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_user_timeout<S>(_sock: &S, _timeout: Duration) -> io::Result<()> {
+    Ok(())
+}
+
+
+//------------ Congestion Control -------------------------------------------
+
+/// The maximum length of a congestion control algorithm’s name.
+#[cfg(target_os = "linux")]
+const TCP_CA_NAME_MAX: usize = 16;
+
+#[cfg(target_os = "linux")]
+pub fn set_congestion_control<S: AsRawFd>(sock: &S, name: &str)
+                                          -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(sock.as_raw_fd(), libc::IPPROTO_TCP,
+                         libc::TCP_CONGESTION,
+                         name.as_ptr() as *const libc::c_void,
+                         name.len() as libc::socklen_t)
+    };
+    if res == -1 { Err(io::Error::last_os_error()) }
+    else { Ok(()) }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_congestion_control<S>(_sock: &S, _name: &str) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(target_os = "linux")]
+pub fn congestion_control<S: AsRawFd>(sock: &S) -> io::Result<String> {
+    let mut buf = [0u8; TCP_CA_NAME_MAX];
+    let mut len = buf.len() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(sock.as_raw_fd(), libc::IPPROTO_TCP,
+                         libc::TCP_CONGESTION,
+                         buf.as_mut_ptr() as *mut libc::c_void, &mut len)
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    let name = &buf[..len as usize];
+    let name = match name.iter().position(|&ch| ch == 0) {
+        Some(end) => &name[..end],
+        None => name
+    };
+    Ok(String::from_utf8_lossy(name).into_owned())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn congestion_control<S>(_sock: &S) -> io::Result<String> {
+    Err(unsupported())
+}
+
+
+//------------ TCP Info -----------------------------------------------------
+
+/// The beginning of Linux’s `struct tcp_info`.
+///
+/// The kernel has been adding fields to the end over time and copies
+/// only as much as we ask for, so we only declare what we need.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[allow(dead_code)]
+struct RawTcpInfo {
+    state: u8,
+    ca_state: u8,
+    retransmits: u8,
+    probes: u8,
+    backoff: u8,
+    options: u8,
+    wscale: u8,
+    flags: u8,
+
+    rto: u32,
+    ato: u32,
+    snd_mss: u32,
+    rcv_mss: u32,
+
+    unacked: u32,
+    sacked: u32,
+    lost: u32,
+    retrans: u32,
+    fackets: u32,
+
+    last_data_sent: u32,
+    last_ack_sent: u32,
+    last_data_recv: u32,
+    last_ack_recv: u32,
+
+    pmtu: u32,
+    rcv_ssthresh: u32,
+    rtt: u32,
+    rttvar: u32,
+    snd_ssthresh: u32,
+    snd_cwnd: u32,
+    advmss: u32,
+    reordering: u32,
+
+    rcv_rtt: u32,
+    rcv_space: u32,
+
+    total_retrans: u32,
+}
+
+#[cfg(target_os = "linux")]
+pub fn tcp_info<S: AsRawFd>(sock: &S) -> io::Result<TcpInfo> {
+    let mut info: RawTcpInfo = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<RawTcpInfo>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(sock.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_INFO,
+                         &mut info as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(TcpInfo {
+        rtt: micros(info.rtt),
+        rtt_var: micros(info.rttvar),
+        retransmits: info.total_retrans,
+        cwnd: info.snd_cwnd,
+    })
+}
+
+/// Converts microseconds as reported by the kernel into a duration.
+#[cfg(target_os = "linux")]
+fn micros(us: u32) -> Duration {
+    Duration::new((us / 1_000_000) as u64, (us % 1_000_000) * 1000)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn tcp_info<S>(_sock: &S) -> io::Result<TcpInfo> {
+    Err(unsupported())
+}
+
+
+//------------ Send Queue ---------------------------------------------------
+
+/// The ioctl for the amount of data in the send queue.
+#[cfg(target_os = "linux")]
+const SIOCOUTQ: libc::c_ulong = 0x5411;
+
+/// Returns the amount of data in the kernel’s send queue.
+#[cfg(target_os = "linux")]
+fn outq<S: AsRawFd>(sock: &S) -> io::Result<libc::c_int> {
+    let mut queued: libc::c_int = 0;
+    let res = unsafe {
+        libc::ioctl(sock.as_raw_fd(), SIOCOUTQ, &mut queued)
+    };
+    if res == -1 { Err(io::Error::last_os_error()) }
+    else { Ok(queued) }
+}
+
+/// Returns the number of bytes queued in the kernel but not yet sent.
+///
+/// If we can’t tell, we say zero.
+#[cfg(target_os = "linux")]
+pub fn send_queue_len<S: AsRawFd>(sock: &S) -> usize {
+    match outq(sock) {
+        Ok(queued) if queued > 0 => queued as usize,
+        _ => 0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_queue_len<S>(_sock: &S) -> usize {
+    0
+}
+
+/// Returns whether the send buffer has room for more data.
+///
+/// If we can’t tell, we optimistically say yes.
+#[cfg(target_os = "linux")]
+pub fn write_ready_hint<S: AsRawFd>(sock: &S) -> bool {
+    let queued = match outq(sock) {
+        Ok(queued) => queued,
+        Err(_) => return true
+    };
+    let size: libc::c_int = match get(sock, libc::SOL_SOCKET,
+                                      libc::SO_SNDBUF) {
+        Ok(size) => size,
+        Err(_) => return true
+    };
+    // Linux doubles the configured size to make room for its own
+    // bookkeeping, so only about half of it is available for data.
+    queued < size / 2
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn write_ready_hint<S>(_sock: &S) -> bool {
+    true
+}
+
+
+//------------ Peer ---------------------------------------------------------
+
+/// Returns whether the socket is connected to a peer.
+#[cfg(unix)]
+pub fn is_connected<S: AsRawFd>(sock: &S) -> io::Result<bool> {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>()
+                      as libc::socklen_t;
+    let res = unsafe {
+        libc::getpeername(sock.as_raw_fd(),
+                          &mut addr as *mut _ as *mut libc::sockaddr,
+                          &mut len)
+    };
+    if res == -1 { peer_result::<()>(Err(io::Error::last_os_error())) }
+    else { Ok(true) }
+}
+
+#[cfg(not(unix))]
+pub fn is_connected<S>(_sock: &S) -> io::Result<bool> {
+    Err(unsupported())
+}
+
+/// Returns the address of the socket’s peer.
+#[cfg(unix)]
+pub fn peer_addr<S: AsRawFd>(sock: &S) -> io::Result<SocketAddr> {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>()
+                      as libc::socklen_t;
+    let res = unsafe {
+        libc::getpeername(sock.as_raw_fd(),
+                          &mut addr as *mut _ as *mut libc::sockaddr,
+                          &mut len)
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    to_socket_addr(&addr)
+}
+
+#[cfg(not(unix))]
+pub fn peer_addr<S>(_sock: &S) -> io::Result<SocketAddr> {
+    Err(unsupported())
+}
+
+/// Returns the path a Unix domain socket’s peer is bound to, if any.
+#[cfg(unix)]
+pub fn unix_peer_path<S: AsRawFd>(sock: &S) -> Option<PathBuf> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getpeername(sock.as_raw_fd(),
+                          &mut addr as *mut _ as *mut libc::sockaddr,
+                          &mut len)
+    };
+    if res == -1 {
+        return None
+    }
+    let offset = {
+        let base = &addr as *const _ as usize;
+        let path = &addr.sun_path as *const _ as usize;
+        path - base
+    };
+    let len = (len as usize).saturating_sub(offset);
+    let path: &[u8] = unsafe {
+        ::std::slice::from_raw_parts(addr.sun_path.as_ptr() as *const u8,
+                                     len)
+    };
+    // The path may or may not be NUL terminated. An empty path is an
+    // unnamed socket, one starting with NUL is in the Linux abstract
+    // namespace and not a path at all.
+    let path = match path.iter().position(|&ch| ch == 0) {
+        Some(0) => return None,
+        Some(end) => &path[..end],
+        None => path
+    };
+    if path.is_empty() {
+        None
+    }
+    else {
+        Some(PathBuf::from(OsStr::from_bytes(path)))
+    }
+}
+
+/// Translates the result of asking for the peer address.
+///
+/// Not being connected is a valid answer, not an error.
+pub fn peer_result<T>(res: io::Result<T>) -> io::Result<bool> {
+    match res {
+        Ok(_) => Ok(true),
+        Err(ref err) if err.kind() == io::ErrorKind::NotConnected => {
+            Ok(false)
+        }
+        Err(err) => Err(err)
+    }
+}
+
+
+//------------ Truncation ---------------------------------------------------
+
+/// Receives a datagram and tells whether it was truncated.
+///
+/// This uses `recvmsg()` which reports truncation via `MSG_TRUNC` in the
+/// flags of the received message. Returns `Ok(None)` if receiving would
+/// block.
+#[cfg(unix)]
+pub fn recv_from_trunc<S: AsRawFd>(sock: &S, buf: &mut [u8])
+                       -> io::Result<Option<(usize, SocketAddr, bool)>> {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len()
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of_val(&addr) as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    let res = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, 0) };
+    if res == -1 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Ok(None)
+        }
+        return Err(err)
+    }
+    let addr = try!(to_socket_addr(&addr));
+    Ok(Some((res as usize, addr, msg.msg_flags & libc::MSG_TRUNC != 0)))
+}
+
+
+//------------ Address Conversion -------------------------------------------
+
+/// Converts a socket address from libc into a socket address.
+#[cfg(unix)]
+fn to_socket_addr(addr: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sa: &libc::sockaddr_in = unsafe {
+                &*(addr as *const _ as *const libc::sockaddr_in)
+            };
+            let ip = u32::from_be(sa.sin_addr.s_addr);
+            let ip = net::Ipv4Addr::new((ip >> 24) as u8, (ip >> 16) as u8,
+                                        (ip >> 8) as u8, ip as u8);
+            Ok(SocketAddr::V4(net::SocketAddrV4::new(
+                ip, u16::from_be(sa.sin_port)
+            )))
+        }
+        libc::AF_INET6 => {
+            let sa: &libc::sockaddr_in6 = unsafe {
+                &*(addr as *const _ as *const libc::sockaddr_in6)
+            };
+            let a = &sa.sin6_addr.s6_addr;
+            let seg = |i: usize| (a[i] as u16) << 8 | a[i + 1] as u16;
+            let ip = net::Ipv6Addr::new(seg(0), seg(2), seg(4), seg(6),
+                                        seg(8), seg(10), seg(12), seg(14));
+            Ok(SocketAddr::V6(net::SocketAddrV6::new(
+                ip, u16::from_be(sa.sin6_port), sa.sin6_flowinfo,
+                sa.sin6_scope_id
+            )))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                "not an IP address"))
+    }
+}
+
+
+//------------ Socket Errors ------------------------------------------------
+
+/// Takes the pending error of a socket via `SO_ERROR`.
+///
+/// Returns `Ok(())` if there isn’t one.
+#[cfg(unix)]
+pub fn take_error<S: AsRawFd>(sock: &S) -> io::Result<()> {
+    let err: libc::c_int = try!(get(sock, libc::SOL_SOCKET,
+                                    libc::SO_ERROR));
+    if err == 0 { Ok(()) }
+    else { Err(io::Error::from_raw_os_error(err)) }
+}
+
+
+//------------ Shutdown -----------------------------------------------------
+
+/// Shuts down one or both halves of a socket.
+#[cfg(unix)]
+pub fn shutdown<S: AsRawFd>(sock: &S, how: Shutdown) -> io::Result<()> {
+    let how = match how {
+        Shutdown::Read => libc::SHUT_RD,
+        Shutdown::Write => libc::SHUT_WR,
+        Shutdown::Both => libc::SHUT_RDWR
+    };
+    if unsafe { libc::shutdown(sock.as_raw_fd(), how) } == -1 {
+        Err(io::Error::last_os_error())
+    }
+    else {
+        Ok(())
+    }
+}
+
+
+//------------ Type of Service ----------------------------------------------
+
+#[cfg(unix)]
+pub fn set_tos<S: AsRawFd>(sock: &S, v6: bool, tos: u8) -> io::Result<()> {
+    if v6 {
+        set(sock, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos as libc::c_int)
+    }
+    else {
+        set(sock, libc::IPPROTO_IP, libc::IP_TOS, tos as libc::c_int)
+    }
+}
+
+#[cfg(not(unix))]
+pub fn set_tos<S>(_sock: &S, _v6: bool, _tos: u8) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(unix)]
+pub fn tos<S: AsRawFd>(sock: &S, v6: bool) -> io::Result<u8> {
+    let res: libc::c_int = if v6 {
+        try!(get(sock, libc::IPPROTO_IPV6, libc::IPV6_TCLASS))
+    }
+    else {
+        try!(get(sock, libc::IPPROTO_IP, libc::IP_TOS))
+    };
+    Ok(res as u8)
+}
+
+#[cfg(not(unix))]
+pub fn tos<S>(_sock: &S, _v6: bool) -> io::Result<u8> {
+    Err(unsupported())
+}
+
+
+//------------ Don’t Fragment -----------------------------------------------
+
+#[cfg(target_os = "linux")]
+pub fn set_dont_fragment<S: AsRawFd>(sock: &S, v6: bool, df: bool)
+                                     -> io::Result<()> {
+    if v6 {
+        let value = if df { libc::IPV6_PMTUDISC_DO }
+                    else { libc::IPV6_PMTUDISC_DONT };
+        set(sock, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, value)
+    }
+    else {
+        let value = if df { libc::IP_PMTUDISC_DO }
+                    else { libc::IP_PMTUDISC_DONT };
+        set(sock, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, value)
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios",
+          target_os = "freebsd"))]
+pub fn set_dont_fragment<S: AsRawFd>(sock: &S, v6: bool, df: bool)
+                                     -> io::Result<()> {
+    let value = if df { 1 as libc::c_int } else { 0 };
+    if v6 {
+        set(sock, libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG, value)
+    }
+    else {
+        set(sock, libc::IPPROTO_IP, libc::IP_DONTFRAG, value)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "ios",
+              target_os = "freebsd")))]
+pub fn set_dont_fragment<S>(_sock: &S, _v6: bool, _df: bool)
+                            -> io::Result<()> {
+    Err(unsupported())
+}
+
+/// Returns the path MTU known for a connected socket.
+#[cfg(target_os = "linux")]
+pub fn path_mtu<S: AsRawFd>(sock: &S, v6: bool) -> io::Result<Option<usize>> {
+    let res: libc::c_int = if v6 {
+        try!(get(sock, libc::IPPROTO_IPV6, libc::IPV6_MTU))
+    }
+    else {
+        try!(get(sock, libc::IPPROTO_IP, libc::IP_MTU))
+    };
+    Ok(Some(res as usize))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn path_mtu<S>(_sock: &S, _v6: bool) -> io::Result<Option<usize>> {
+    Ok(None)
+}
+
+/// Returns whether `err` says that a datagram was too large to be sent.
+#[cfg(unix)]
+pub fn is_message_too_large(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+#[cfg(not(unix))]
+pub fn is_message_too_large(_err: &io::Error) -> bool {
+    false
+}
+
+
+//------------ Listener Check -----------------------------------------------
+
+/// Checks that `sock` is a stream socket that is listening.
+#[cfg(unix)]
+pub fn check_listener<S: AsRawFd>(sock: &S) -> io::Result<()> {
+    let kind: libc::c_int = try!(get(sock, libc::SOL_SOCKET,
+                                     libc::SO_TYPE));
+    if kind != libc::SOCK_STREAM {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "not a stream socket"))
+    }
+    let listening: libc::c_int = try!(get(sock, libc::SOL_SOCKET,
+                                          libc::SO_ACCEPTCONN));
+    if listening == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "socket is not listening"))
+    }
+    Ok(())
+}
+
+
+//------------ Reuse Port ---------------------------------------------------
+
+/// The backlog for listeners we bind ourselves.
+#[cfg(unix)]
+const LISTEN_BACKLOG: libc::c_int = 1024;
+
+/// Creates a listening TCP socket with `SO_REUSEPORT` set.
+///
+/// This allows several sockets, typically one per thread, to be bound to
+/// the same address with the kernel spreading incoming connections
+/// between them.
+#[cfg(unix)]
+pub fn bind_reuse_port(addr: &SocketAddr) -> io::Result<net::TcpListener> {
+    let family = match *addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    // From here on, the listener owns the descriptor and closes it if
+    // anything goes wrong.
+    let lsnr = unsafe { net::TcpListener::from_raw_fd(fd) };
+    let res = unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    if res == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    try!(set(&lsnr, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1 as libc::c_int));
+    try!(set(&lsnr, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1 as libc::c_int));
+    let res = match *addr {
+        SocketAddr::V4(ref addr) => {
+            let mut sa: libc::sockaddr_in = unsafe { mem::zeroed() };
+            let ip = addr.ip().octets();
+            let ip = (ip[0] as u32) << 24 | (ip[1] as u32) << 16
+                   | (ip[2] as u32) << 8 | ip[3] as u32;
+            sa.sin_family = libc::AF_INET as libc::sa_family_t;
+            sa.sin_port = addr.port().to_be();
+            sa.sin_addr.s_addr = ip.to_be();
+            unsafe {
+                libc::bind(fd, &sa as *const _ as *const libc::sockaddr,
+                           mem::size_of_val(&sa) as libc::socklen_t)
+            }
+        }
+        SocketAddr::V6(ref addr) => {
+            let mut sa: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            sa.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sa.sin6_port = addr.port().to_be();
+            sa.sin6_flowinfo = addr.flowinfo();
+            sa.sin6_addr.s6_addr = addr.ip().octets();
+            sa.sin6_scope_id = addr.scope_id();
+            unsafe {
+                libc::bind(fd, &sa as *const _ as *const libc::sockaddr,
+                           mem::size_of_val(&sa) as libc::socklen_t)
+            }
+        }
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    if unsafe { libc::listen(fd, LISTEN_BACKLOG) } == -1 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(lsnr)
+}
+
+#[cfg(not(unix))]
+pub fn bind_reuse_port(_addr: &SocketAddr) -> io::Result<net::TcpListener> {
+    Err(unsupported())
+}
+
+
+//------------ Urgent Data --------------------------------------------------
+
+/// Receives urgent data, possibly only peeking at it.
+///
+/// Returns `Ok(None)` if there is no urgent data.
+#[cfg(unix)]
+pub fn recv_oob<S: AsRawFd>(sock: &S, buf: &mut [u8], peek: bool)
+                            -> io::Result<Option<usize>> {
+    let flags = if peek { libc::MSG_OOB | libc::MSG_PEEK }
+                else { libc::MSG_OOB };
+    let res = unsafe {
+        libc::recv(sock.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void,
+                   buf.len(), flags)
+    };
+    if res == -1 {
+        let err = io::Error::last_os_error();
+        // EINVAL means there is no urgent data (or it is inline).
+        if err.kind() == io::ErrorKind::WouldBlock
+                || err.raw_os_error() == Some(libc::EINVAL) {
+            Ok(None)
+        }
+        else {
+            Err(err)
+        }
+    }
+    else {
+        Ok(Some(res as usize))
+    }
+}
+
+#[cfg(not(unix))]
+pub fn recv_oob<S>(_sock: &S, _buf: &mut [u8], _peek: bool)
+                   -> io::Result<Option<usize>> {
+    Err(unsupported())
+}
+
+
+//------------ Peeking ------------------------------------------------------
+
+/// Receives data without removing it from the socket.
+///
+/// Returns `Ok(None)` if receiving would block.
+#[cfg(unix)]
+pub fn peek<S: AsRawFd>(sock: &S, buf: &mut [u8])
+                        -> io::Result<Option<usize>> {
+    let res = unsafe {
+        libc::recv(sock.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void,
+                   buf.len(), libc::MSG_PEEK)
+    };
+    if res == -1 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            Ok(None)
+        }
+        else {
+            Err(err)
+        }
+    }
+    else {
+        Ok(Some(res as usize))
+    }
+}
+
+#[cfg(not(unix))]
+pub fn peek<S>(_sock: &S, _buf: &mut [u8]) -> io::Result<Option<usize>> {
+    Err(unsupported())
+}
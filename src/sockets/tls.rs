@@ -0,0 +1,71 @@
+//! Abstracting over TLS backends.
+//!
+//! There is more than one crate providing TLS. Each of them is supported
+//! through its own module that defines the socket types for that crate,
+//! such as [openssl], [rustls], or [security_framework]. In order to
+//! allow code to be written independently of any particular of these
+//! crates, this module defines the [TlsBackend] trait which collects all
+//! the types of a backend. Each backend module provides a type
+//! implementing it, for instance [Openssl] for OpenSSL and [Rustls] for
+//! rustls.
+//!
+//! The TLS machines in [net::tls] are generic over a backend. The modules
+//! for the specific backends in [net], such as [net::openssl], provide
+//! type aliases for the machines using that backend.
+//!
+//! [openssl]: ../openssl/index.html
+//! [TlsBackend]: trait.TlsBackend.html
+//! [Openssl]: ../openssl/struct.Openssl.html
+//! [rustls]: ../rustls/index.html
+//! [Rustls]: ../rustls/struct.Rustls.html
+//! [security_framework]: ../security_framework/index.html
+//! [net]: ../../net/index.html
+//! [net::tls]: ../../net/tls/index.html
+//! [net::openssl]: ../../net/openssl/index.html
+
+use std::net::SocketAddr;
+use ::error::Result;
+use super::{Accept, HybridStream, SecureStream};
+
+
+//------------ TlsBackend ----------------------------------------------------
+
+/// A trait naming the types of a TLS backend.
+///
+/// The trait is only ever implemented by a marker type that is used as a
+/// type argument for the TLS machines.
+pub trait TlsBackend {
+    /// The type of a listening socket for encrypted streams.
+    type Listener: Accept<Output=Self::Stream>;
+
+    /// The type of an encrypted stream socket.
+    type Stream: SecureStream;
+
+    /// The type of a listening socket for streams encrypted later.
+    type StartTlsListener: Accept<Output=Self::StartTlsStream>;
+
+    /// The type of a stream socket that can start encryption later.
+    type StartTlsStream: HybridStream;
+
+    /// The configuration for client sockets.
+    type ClientConfig;
+
+    /// The configuration for server sockets.
+    type ServerConfig;
+
+    /// Creates a listener for encrypted streams bound to `addr`.
+    fn bind(addr: &SocketAddr, config: Self::ServerConfig)
+            -> Result<Self::Listener>;
+
+    /// Creates a listener for streams encrypted later bound to `addr`.
+    fn bind_starttls(addr: &SocketAddr, config: Self::ServerConfig)
+                     -> Result<Self::StartTlsListener>;
+
+    /// Connects an encrypted stream to `addr`.
+    fn connect(addr: &SocketAddr, config: &Self::ClientConfig)
+               -> Result<Self::Stream>;
+
+    /// Connects a stream that can be encrypted later to `addr`.
+    fn connect_starttls(addr: &SocketAddr, config: &Self::ClientConfig)
+                        -> Result<Self::StartTlsStream>;
+}
@@ -1,10 +1,34 @@
 //! Synchronization.
+//!
+//! The types in this module connect the rest of the program with the
+//! machines of a loop. Their sending ends wake up the receiving machine
+//! through its notifier whenever there is something new.
+//!
+//! Waking up a machine has a cost, so all types coalesce wakeups: at most
+//! one wakeup is pending for the receiver at any time. For a [duct], a
+//! send only wakes up the receiver if it has looked at the duct since the
+//! last wakeup, so while the receiving machine hasn’t had its turn yet,
+//! many sends result in a single wakeup. A [gate] can only ever be sent
+//! to once and thus causes at most one wakeup. A [trigger] only wakes up
+//! the receiver the first time it is triggered; since it can’t be reset,
+//! later calls don’t do anything. A [reusable gate] can be sent to many
+//! times but, like a duct, only wakes up the receiver if it has looked
+//! at the gate since the last wakeup.
+//!
+//! If waking up fails, the value sent is still available to the
+//! receiver. See the error types for details.
+//!
+//! [duct]: fn.duct.html
+//! [gate]: fn.gate.html
+//! [trigger]: fn.trigger.html
+//! [reusable gate]: fn.reusable_gate.html
 
 use std::mem;
 use std::ops::DerefMut;
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::{self, TryRecvError}; 
+use std::thread;
 use rotor::{Notifier, WakeupError};
 
 pub use std::sync::mpsc::{RecvError, SendError};
@@ -56,24 +80,93 @@ impl<T> Receiver<T> {
 
 //------------ Duct ----------------------------------------------------------
 
+/// Creates a new duct, a queue waking up its receiving machine.
+///
+/// A send only wakes up the machine if the receiver has called
+/// `try_recv()` since the previous wakeup. Receivers should therefore
+/// always empty the duct when woken up.
 pub fn duct<T>(notifier: Notifier) -> (DuctSender<T>, DuctReceiver<T>) {
     let awake = Arc::new(AtomicBool::new(false));
+    let len = Arc::new(AtomicUsize::new(0));
     let (tx, rx) = mpsc::channel();
-    (DuctSender { awake: awake.clone(), notifier: notifier, tx: tx },
-     DuctReceiver { awake: awake, rx: rx })
+    (DuctSender { awake: awake.clone(), len: len.clone(),
+                  notifier: notifier, tx: tx },
+     DuctReceiver { awake: awake, len: len, rx: rx })
 }
 
 pub struct DuctSender<T> {
     awake: Arc<AtomicBool>,
+    len: Arc<AtomicUsize>,
     notifier: Notifier,
     tx: mpsc::Sender<T>
 }
 
 impl<T: Send> DuctSender<T> {
     pub fn send(&self, value: T) -> Result<(), DuctSendError<T>> {
-        try!(self.tx.send(value));
+        // Count first so the receiver never sees a value before its count.
+        self.len.fetch_add(1, Ordering::Relaxed);
+        if let Err(err) = self.tx.send(value) {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            return Err(err.into())
+        }
+        self.wakeup().map_err(Into::into)
+    }
+
+    /// Sends all values produced by `values`, waking up at most once.
+    ///
+    /// This is cheaper than calling [send()](#method.send) for each value
+    /// when there are a lot of them since the receiver is only woken up
+    /// after all values have been queued.
+    ///
+    /// If the receiver goes away halfway through, sending stops. The
+    /// error then contains the number of values that made it into the
+    /// duct as well as the value that didn’t. All values not yet taken
+    /// from `values` are dropped. If waking up fails, all values have
+    /// been queued and the error says so.
+    pub fn send_batch<I>(&self, values: I) -> Result<(), DuctBatchError<T>>
+                      where I: IntoIterator<Item=T> {
+        let mut sent = 0;
+        for value in values {
+            self.len.fetch_add(1, Ordering::Relaxed);
+            if let Err(err) = self.tx.send(value) {
+                self.len.fetch_sub(1, Ordering::Relaxed);
+                return Err(DuctBatchError { sent: sent, error: err.into() })
+            }
+            sent += 1;
+        }
+        if sent == 0 {
+            return Ok(())
+        }
+        self.wakeup().map_err(|err| {
+            DuctBatchError { sent: sent, error: err.into() }
+        })
+    }
+
+    /// Returns the number of values waiting in the duct.
+    ///
+    /// The number is only approximate if other threads are sending or
+    /// receiving at the same time. It is good enough for deciding whether
+    /// to shed load, though.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the duct is empty.
+    ///
+    /// The same caveats as for [len()](#method.len) apply.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wakes up the receiver unless a wakeup is still pending.
+    fn wakeup(&self) -> Result<(), WakeupError> {
         if !self.awake.swap(true, Ordering::SeqCst) {
-            try!(self.notifier.wakeup());
+            if let Err(err) = self.notifier.wakeup() {
+                // Clear the flag again so the next send tries again.
+                // Otherwise, the receiver would never be woken up.
+                self.awake.store(false, Ordering::SeqCst);
+                return Err(err)
+            }
         }
         Ok(())
     }
@@ -83,6 +176,7 @@ impl<T> Clone for DuctSender<T> {
     fn clone(&self) -> Self {
         DuctSender {
             awake: self.awake.clone(),
+            len: self.len.clone(),
             notifier: self.notifier.clone(),
             tx: self.tx.clone()
         }
@@ -91,26 +185,120 @@ impl<T> Clone for DuctSender<T> {
 
 pub struct DuctReceiver<T> {
     awake: Arc<AtomicBool>,
+    len: Arc<AtomicUsize>,
     rx: mpsc::Receiver<T>
 }
 
 impl<T: Send> DuctReceiver<T> {
+    /// Returns the number of values waiting in the duct.
+    ///
+    /// The number is only approximate if other threads are sending at the
+    /// same time.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the duct is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn try_recv(&self) -> Result<Option<T>, RecvError> {
-        self.awake.store(false, Ordering::Relaxed);
+        self.clear_awake();
         match self.rx.try_recv() {
-            Ok(t) => Ok(Some(t)),
+            Ok(t) => {
+                self.len.fetch_sub(1, Ordering::Relaxed);
+                Ok(Some(t))
+            }
             Err(TryRecvError::Empty) => Ok(None),
             Err(TryRecvError::Disconnected) => Err(RecvError)
         }
     }
+
+    /// Returns an iterator over all values currently in the duct.
+    ///
+    /// This is like calling [try_recv()](#method.try_recv) until the duct
+    /// is empty but clears the wakeup flag only once up front. The
+    /// iterator ends at the first empty. If all senders have gone away,
+    /// it ends, too, and its [disconnected()] method returns `true`.
+    /// Values sent while iterating are returned by the iterator.
+    ///
+    /// [disconnected()]: struct.DuctIter.html#method.disconnected
+    pub fn try_iter(&self) -> DuctIter<T> {
+        self.clear_awake();
+        DuctIter { rx: self, disconnected: false }
+    }
+
+    /// Clears the wakeup flag before looking at the channel.
+    ///
+    /// This must use the same ordering as the sender’s swap. A sender
+    /// queues its value before swapping the flag. If the clear here were
+    /// relaxed, it could become visible only after we found the channel
+    /// empty, the sender could still see the flag set and skip the
+    /// wakeup, and its value would sit in the duct unnoticed.
+    fn clear_awake(&self) {
+        self.awake.store(false, Ordering::SeqCst);
+    }
+}
+
+
+//------------ DuctIter ------------------------------------------------------
+
+/// An iterator draining a duct.
+///
+/// This is returned by [DuctReceiver::try_iter()].
+///
+/// [DuctReceiver::try_iter()]: struct.DuctReceiver.html#method.try_iter
+pub struct DuctIter<'a, T: 'a> {
+    rx: &'a DuctReceiver<T>,
+    disconnected: bool,
+}
+
+impl<'a, T: Send> DuctIter<'a, T> {
+    /// Returns whether the iterator has ended because the duct is closed.
+    ///
+    /// Once this is `true`, no more values will ever arrive and the
+    /// receiving machine may as well go away.
+    pub fn disconnected(&self) -> bool {
+        self.disconnected
+    }
+}
+
+impl<'a, T: Send> Iterator for DuctIter<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.disconnected {
+            return None
+        }
+        match self.rx.rx.try_recv() {
+            Ok(t) => {
+                self.rx.len.fetch_sub(1, Ordering::Relaxed);
+                Some(t)
+            }
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.disconnected = true;
+                None
+            }
+        }
+    }
 }
 
 
 //------------ DuctSendError -------------------------------------------------
 
+/// An error happened while sending to a duct.
 #[derive(Debug)]
 pub enum DuctSendError<T> {
+    /// The receiver is gone. The value is returned.
     SendError(T),
+
+    /// The value was queued but waking up the receiver failed.
+    ///
+    /// This typically happens when the loop is shutting down. The value
+    /// will still be found by the receiver next time it looks and the
+    /// next send will try to wake it up again.
     WakeupError,
 }
 
@@ -127,12 +315,35 @@ impl<T> From<WakeupError> for DuctSendError<T> {
 }
 
 
+//------------ DuctBatchError ------------------------------------------------
+
+/// An error happened while sending a batch of values to a duct.
+#[derive(Debug)]
+pub struct DuctBatchError<T> {
+    /// The number of values that have been queued.
+    pub sent: usize,
+
+    /// What went wrong with the value after those.
+    ///
+    /// If this is `DuctSendError::WakeupError`, all values of the batch
+    /// have been queued.
+    pub error: DuctSendError<T>,
+}
+
+
 //------------ Gate ---------------------------------------------------------
 
+/// Creates a new gate for passing a single value to a machine.
+///
+/// Since the sender is consumed by sending, there is exactly one wakeup
+/// per gate. If a machine makes several round trips, it can use a
+/// [reusable gate](fn.reusable_gate.html) instead of creating a new gate
+/// for each. If it needs to see every value in order, use a
+/// [duct](fn.duct.html).
 pub fn gate<T>(notifier: Notifier) -> (GateSender<T>, GateReceiver<T>) {
     let item = Arc::new(Mutex::new(None));
     (GateSender { item: item.clone(), notifier: notifier },
-     GateReceiver(item))
+     GateReceiver { item: item, pool: None })
 }
 
 pub struct GateSender<T> {
@@ -145,17 +356,26 @@ impl<T: Send> GateSender<T> {
         match self.item.lock() {
             Ok(mut guard) => {
                 let _ = mem::replace(guard.deref_mut(), Some(value));
-                try!(self.notifier.wakeup());
-                Ok(())
             }
-            Err(_) => Err(GateSendError::Poisoned(value))
+            Err(_) => return Err(GateSendError::Poisoned(value))
         }
+        // Wake up only after the lock is released so the receiver can
+        // get at the value right away.
+        try!(self.notifier.wakeup());
+        Ok(())
     }
 }
 
 
+/// An error happened while sending through a gate.
+#[derive(Debug)]
 pub enum GateSendError<T> {
+    /// The gate’s lock was poisoned. The value is returned.
     Poisoned(T),
+
+    /// The value was stored but waking up the receiver failed.
+    ///
+    /// The receiver will still find the value when it calls `try_get()`.
     WakeupError,
 }
 
@@ -166,11 +386,16 @@ impl<T> From<WakeupError> for GateSendError<T> {
 }
 
 
-pub struct GateReceiver<T>(Arc<Mutex<Option<T>>>);
+pub struct GateReceiver<T> {
+    item: Slot<T>,
+
+    /// The pool to return the slot to when done.
+    pool: Option<SlotPool<T>>,
+}
 
 impl<T: Send> GateReceiver<T> {
     pub fn try_get(&self) -> Result<Option<T>, GateRecvError> {
-        match self.0.lock() {
+        match self.item.lock() {
             Ok(mut guard) => {
                 match mem::replace(guard.deref_mut(), None) {
                     Some(t) => Ok(Some(t)),
@@ -182,11 +407,230 @@ impl<T: Send> GateReceiver<T> {
     }
 }
 
+impl<T> Drop for GateReceiver<T> {
+    fn drop(&mut self) {
+        if let Some(ref pool) = self.pool {
+            pool.put(&mut self.item)
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct GateRecvError;
 
 
+//------------ ReusableGate -------------------------------------------------
+
+/// Creates a new gate that can be sent to any number of times.
+///
+/// This is for machines that make several sequential round trips over
+/// their lifetime, for instance to a worker thread, and would rather not
+/// allocate a new gate for each of them.
+///
+/// The gate holds at most one value. If a new value is sent before the
+/// receiver has taken the previous one, the previous value is dropped:
+/// the latest value wins. The receiver is only woken up if it has looked
+/// at the gate since the last wakeup, so while it hasn’t had its turn
+/// yet, many sends result in a single wakeup. If values must not be lost,
+/// use a [duct](fn.duct.html) which queues them instead.
+pub fn reusable_gate<T>(notifier: Notifier)
+                        -> (ReusableGateSender<T>, ReusableGateReceiver<T>) {
+    let item = Arc::new(Mutex::new(ReusableSlot {
+        value: None, wakeup_pending: false
+    }));
+    (ReusableGateSender { item: item.clone(), notifier: notifier },
+     ReusableGateReceiver { item: item })
+}
+
+/// The shared value of a reusable gate.
+struct ReusableSlot<T> {
+    /// The latest value sent.
+    value: Option<T>,
+
+    /// Whether the receiver has been woken up and not looked yet.
+    wakeup_pending: bool,
+}
+
+pub struct ReusableGateSender<T> {
+    item: Arc<Mutex<ReusableSlot<T>>>,
+    notifier: Notifier
+}
+
+impl<T: Send> ReusableGateSender<T> {
+    /// Stores a value in the gate, replacing any value still there.
+    ///
+    /// The receiver is woken up unless a wakeup is still pending. If
+    /// waking up fails, the value stays in the gate and the next send
+    /// will try to wake up the receiver again.
+    pub fn send(&self, value: T) -> Result<(), GateSendError<T>> {
+        let wakeup = match self.item.lock() {
+            Ok(mut guard) => {
+                guard.value = Some(value);
+                !mem::replace(&mut guard.wakeup_pending, true)
+            }
+            Err(_) => return Err(GateSendError::Poisoned(value))
+        };
+        // Wake up only after the lock is released so the receiver can
+        // get at the value right away.
+        if wakeup {
+            if let Err(err) = self.notifier.wakeup() {
+                // Clear the flag again so the next send tries again.
+                if let Ok(mut guard) = self.item.lock() {
+                    guard.wakeup_pending = false;
+                }
+                return Err(err.into())
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T> Clone for ReusableGateSender<T> {
+    fn clone(&self) -> Self {
+        ReusableGateSender {
+            item: self.item.clone(),
+            notifier: self.notifier.clone()
+        }
+    }
+}
+
+
+pub struct ReusableGateReceiver<T> {
+    item: Arc<Mutex<ReusableSlot<T>>>,
+}
+
+impl<T: Send> ReusableGateReceiver<T> {
+    /// Takes the latest value out of the gate if there is one.
+    ///
+    /// This also allows the next send to wake up the receiver again.
+    pub fn try_get(&self) -> Result<Option<T>, GateRecvError> {
+        match self.item.lock() {
+            Ok(mut guard) => {
+                guard.wakeup_pending = false;
+                Ok(guard.value.take())
+            }
+            Err(_) => Err(GateRecvError)
+        }
+    }
+}
+
+
+//------------ SlotPool -----------------------------------------------------
+
+/// The shared value of a gate.
+type Slot<T> = Arc<Mutex<Option<T>>>;
+
+/// A pool of gates for reuse.
+///
+/// Each call to [gate()](fn.gate.html) allocates the shared value behind
+/// the gate, a mutex in an `Arc`. This is fine for the occasional gate
+/// but under high request rates when every request gets a gate for its
+/// response, these allocations add up. A slot pool keeps the shared values
+/// of gates that are done and hands them out again for new gates created
+/// through its own [gate()](#method.gate) method.
+///
+/// A gate is done once its receiver has been dropped after the sender was
+/// used or dropped, too. So the receiving machine should drop the
+/// receiver after it has received the value. If the receiver goes away
+/// first, the slot is simply freed.
+///
+/// The pool is cheap to clone and all clones share the same slots. It
+/// keeps at most the number of free slots given when creating it. Any
+/// slots beyond that are freed.
+pub struct SlotPool<T>(Arc<SlotPoolInner<T>>);
+
+struct SlotPoolInner<T> {
+    /// The free slots.
+    free: Mutex<Vec<Slot<T>>>,
+
+    /// The maximum number of free slots to keep.
+    max: usize,
+
+    /// The number of slots allocated over the lifetime of the pool.
+    allocated: AtomicUsize,
+}
+
+impl<T: Send> SlotPool<T> {
+    /// Creates a new pool keeping up to `max` free slots.
+    pub fn new(max: usize) -> Self {
+        SlotPool(Arc::new(SlotPoolInner {
+            free: Mutex::new(Vec::new()),
+            max: max,
+            allocated: AtomicUsize::new(0)
+        }))
+    }
+
+    /// Creates a new gate using a slot from the pool.
+    ///
+    /// If there is no free slot, a new one is allocated.
+    pub fn gate(&self, notifier: Notifier)
+                -> (GateSender<T>, GateReceiver<T>) {
+        let item = self.take();
+        (GateSender { item: item.clone(), notifier: notifier },
+         GateReceiver { item: item, pool: Some(self.clone()) })
+    }
+
+    /// Returns the number of free slots.
+    pub fn free(&self) -> usize {
+        match self.0.free.lock() {
+            Ok(free) => free.len(),
+            Err(_) => 0
+        }
+    }
+
+    /// Returns the number of slots allocated so far.
+    ///
+    /// Comparing this to the number of gates created shows how well the
+    /// pool works.
+    pub fn allocated(&self) -> usize {
+        self.0.allocated.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> SlotPool<T> {
+    /// Takes a free slot or allocates a new one.
+    fn take(&self) -> Slot<T> {
+        if let Ok(mut free) = self.0.free.lock() {
+            if let Some(slot) = free.pop() {
+                return slot
+            }
+        }
+        self.0.allocated.fetch_add(1, Ordering::Relaxed);
+        Arc::new(Mutex::new(None))
+    }
+
+    /// Returns a slot to the pool if nobody else is using it.
+    ///
+    /// Any value still in the slot is dropped.
+    fn put(&self, slot: &mut Slot<T>) {
+        match Arc::get_mut(slot) {
+            Some(item) => match item.get_mut() {
+                Ok(value) => { value.take(); }
+                Err(_) => return
+            },
+            None => return
+        }
+        if let Ok(mut free) = self.0.free.lock() {
+            if free.len() < self.0.max {
+                free.push(slot.clone())
+            }
+        }
+    }
+}
+
+impl<T> Clone for SlotPool<T> {
+    fn clone(&self) -> Self {
+        SlotPool(self.0.clone())
+    }
+}
+
+
 //------------ Trigger ------------------------------------------------------
 
+/// Creates a new trigger for signalling a machine once.
+///
+/// Only the first call to `trigger()` wakes up the machine, no matter how
+/// many clones of the sender there are.
 pub fn trigger(notifier: Notifier) -> (TriggerSender, TriggerReceiver) {
     let flag = Arc::new(AtomicBool::new(false));
     (TriggerSender { flag: flag.clone(), notifier: notifier },
@@ -200,6 +644,11 @@ pub struct TriggerSender {
 }
 
 impl TriggerSender {
+    /// Triggers the trigger and wakes up the receiver.
+    ///
+    /// Only the first call actually wakes up the receiver. If that fails,
+    /// the trigger stays triggered, so the receiver will still notice it
+    /// whenever it is woken up for some other reason or checks anyway.
     pub fn trigger(&self) -> Result<(), WakeupError> {
         if !self.flag.swap(true, Ordering::SeqCst) {
             try!(self.notifier.wakeup());
@@ -216,3 +665,676 @@ impl TriggerReceiver {
     }
 }
 
+
+
+//------------ Shared -------------------------------------------------------
+
+/// A shared value that can be replaced at any time.
+///
+/// This is intended for configuration that should be changeable while
+/// the program is running without dropping any connections. The accept
+/// handler keeps a `Shared<Config>` and clones it into the seed of every
+/// transport handler it creates. Whenever a handler needs the
+/// configuration, it calls [load()](#method.load) and receives a
+/// snapshot of the current value. Some other part of the program can
+/// call [update()](#method.update) to replace the value for everyone.
+/// Existing connections will see the new value the next time they load
+/// it.
+///
+/// For instance, a rate limited server would keep the limits, say a
+/// [TokenBucket], in the shared value and load them each time a request
+/// arrives. After an update, the new limits apply to the next request on
+/// every connection.
+///
+/// Since snapshots are reference counted, loading is cheap and a handler
+/// can keep a snapshot for as long as it wants a consistent view.
+///
+/// [TokenBucket]: ../utils/struct.TokenBucket.html
+pub struct Shared<T>(Arc<RwLock<Arc<T>>>);
+
+impl<T> Shared<T> {
+    /// Creates a new shared value.
+    pub fn new(value: T) -> Self {
+        Shared(Arc::new(RwLock::new(Arc::new(value))))
+    }
+
+    /// Returns a snapshot of the current value.
+    pub fn load(&self) -> Arc<T> {
+        match self.0.read() {
+            Ok(guard) => guard.clone(),
+            Err(poisoned) => poisoned.into_inner().clone()
+        }
+    }
+
+    /// Replaces the value for everyone.
+    pub fn update(&self, value: T) {
+        let value = Arc::new(value);
+        match self.0.write() {
+            Ok(mut guard) => *guard = value,
+            Err(poisoned) => *poisoned.into_inner() = value
+        }
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Shared(self.0.clone())
+    }
+}
+
+
+//------------ Pool ---------------------------------------------------------
+
+/// A job for a pool worker.
+///
+/// Since we can’t call a boxed `FnOnce` yet, the closure is wrapped into
+/// an `FnMut` that only does something the first time.
+type Job = Box<FnMut() + Send>;
+
+/// A fixed-size pool of worker threads for blocking work.
+///
+/// Transport handlers must never block since that would stall all other
+/// machines of the loop. If they need to do something that may take a
+/// while, such as heavy crypto or disk access, they can hand it to a pool
+/// via [submit()](#method.submit) and wait for the result to arrive
+/// through the returned gate receiver. The notifier given to `submit()`
+/// will be woken up once the result is ready.
+///
+/// The pool can be shared between many handlers by wrapping it into an
+/// `Arc`. When the pool is dropped, it waits for all submitted jobs to
+/// finish.
+///
+/// If a job panics, the worker running it is lost and the job’s gate will
+/// never receive a value.
+pub struct Pool {
+    tx: Option<Mutex<mpsc::Sender<Job>>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl Pool {
+    /// Creates a new pool with `size` worker threads.
+    ///
+    /// # Panics
+    ///
+    /// The function panics if `size` is zero.
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0, "pool without workers");
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        let workers = (0..size).map(|_| {
+            let rx = rx.clone();
+            thread::spawn(move || {
+                loop {
+                    let job = match rx.lock() {
+                        Ok(rx) => rx.recv(),
+                        Err(_) => return
+                    };
+                    match job {
+                        Ok(mut job) => job(),
+                        Err(_) => return
+                    }
+                }
+            })
+        }).collect();
+        Pool { tx: Some(Mutex::new(tx)), workers: workers }
+    }
+
+    /// Returns the number of worker threads.
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Runs `f` on one of the workers.
+    ///
+    /// The result of `f` is delivered through the returned gate receiver
+    /// and `notifier` is woken up when it has arrived.
+    pub fn submit<F, R>(&self, notifier: Notifier, f: F) -> GateReceiver<R>
+                  where F: FnOnce() -> R + Send + 'static,
+                        R: Send + 'static {
+        let (tx, rx) = gate(notifier);
+        let mut job = Some((f, tx));
+        let job: Job = Box::new(move || {
+            if let Some((f, tx)) = job.take() {
+                let _ = tx.send(f());
+            }
+        });
+        if let Some(ref sender) = self.tx {
+            if let Ok(sender) = sender.lock() {
+                // This only fails if all workers are gone in which case
+                // the gate simply never fires.
+                let _ = sender.send(job);
+            }
+        }
+        rx
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        // Dropping the sender ends the workers’ loops once the queue has
+        // been emptied.
+        self.tx = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, mpsc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use rotor::{Config, EventSet, GenericScope, Loop, Machine, Notifier,
+                Response, Scope, Void};
+    use ::utils::TokenBucket;
+    use super::*;
+
+    /// A machine that does nothing.
+    struct Idle;
+
+    impl Machine for Idle {
+        type Context = ();
+        type Seed = Void;
+
+        fn create(seed: Void, _scope: &mut Scope<()>)
+                  -> Response<Self, Void> {
+            match seed { }
+        }
+
+        fn ready(self, _events: EventSet, _scope: &mut Scope<()>)
+                 -> Response<Self, Void> {
+            Response::ok(self)
+        }
+
+        fn spawned(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            Response::ok(self)
+        }
+
+        fn timeout(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            Response::ok(self)
+        }
+
+        fn wakeup(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            Response::ok(self)
+        }
+    }
+
+    /// Returns a notifier for a machine of a loop that never runs.
+    ///
+    /// The loop needs to be kept around for as long as the notifier is
+    /// used.
+    fn idle_notifier() -> (Loop<Idle>, Notifier) {
+        let mut lc = Loop::new(&Config::new()).unwrap();
+        let mut notifier = None;
+        lc.add_machine_with(|scope| {
+            notifier = Some(scope.notifier());
+            Response::ok(Idle)
+        }).unwrap();
+        (lc, notifier.unwrap())
+    }
+
+    /// Wakes up until the notification queue is full.
+    ///
+    /// Returns the number of wakeups that still fit into the queue.
+    fn fill(notifier: &Notifier) -> usize {
+        let mut sent = 0;
+        while notifier.wakeup().is_ok() {
+            sent += 1;
+            assert!(sent < 1_000_000, "notification queue never fills");
+        }
+        sent
+    }
+
+    /// Returns a notifier whose wakeups fail.
+    ///
+    /// The loop is never run, so once its notification queue is full,
+    /// every further wakeup fails.
+    fn failing_notifier() -> (Loop<Idle>, Notifier) {
+        let (lc, notifier) = idle_notifier();
+        fill(&notifier);
+        (lc, notifier)
+    }
+
+    #[test]
+    fn duct_wakeup_error() {
+        let (_lc, notifier) = failing_notifier();
+        let (tx, rx) = duct(notifier);
+        match tx.send(1) {
+            Err(DuctSendError::WakeupError) => { }
+            _ => panic!("expected wakeup error")
+        }
+        // The flag has been cleared, so the next send tries again.
+        match tx.send(2) {
+            Err(DuctSendError::WakeupError) => { }
+            _ => panic!("expected another wakeup error")
+        }
+        assert_eq!(rx.try_recv().unwrap(), Some(1));
+        assert_eq!(rx.try_recv().unwrap(), Some(2));
+        assert_eq!(rx.try_recv().unwrap(), None);
+    }
+
+    #[test]
+    fn duct_len() {
+        let (_lc, notifier) = idle_notifier();
+        let (tx, rx) = duct(notifier);
+        assert!(tx.is_empty());
+        for i in 0..3 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(tx.len(), 3);
+        assert_eq!(rx.len(), 3);
+        assert_eq!(rx.try_recv().unwrap(), Some(0));
+        assert_eq!(tx.len(), 2);
+        while let Some(_) = rx.try_recv().unwrap() { }
+        assert!(tx.is_empty());
+        assert!(rx.is_empty());
+
+        // A failed send isn’t counted.
+        drop(rx);
+        assert!(tx.send(3).is_err());
+        assert_eq!(tx.len(), 0);
+    }
+
+    #[test]
+    fn duct_try_iter() {
+        let (_lc, notifier) = idle_notifier();
+        let (tx, rx) = duct(notifier);
+        tx.send_batch(vec![1, 2, 3]).unwrap();
+        {
+            let mut iter = rx.try_iter();
+            assert_eq!(iter.by_ref().collect::<Vec<_>>(), vec![1, 2, 3]);
+            assert!(!iter.disconnected());
+        }
+        assert!(rx.is_empty());
+
+        // Values still queued are returned before the end is noticed.
+        tx.send(4).unwrap();
+        drop(tx);
+        let mut iter = rx.try_iter();
+        assert_eq!(iter.next(), Some(4));
+        assert!(!iter.disconnected());
+        assert_eq!(iter.next(), None);
+        assert!(iter.disconnected());
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn duct_batch_closed() {
+        let (_lc, notifier) = idle_notifier();
+        let (tx, rx) = duct(notifier);
+        let mut rx = Some(rx);
+        // The receiver goes away while the batch is being sent.
+        let values = (0..5).map(|i| {
+            if i == 2 {
+                rx.take();
+            }
+            i
+        });
+        match tx.send_batch(values) {
+            Err(DuctBatchError { sent: 2, error: DuctSendError::SendError(2) })
+                => { }
+            _ => panic!("expected send error after two values")
+        }
+    }
+
+    #[test]
+    fn duct_batch_wakeup_error() {
+        let (_lc, notifier) = failing_notifier();
+        let (tx, rx) = duct(notifier);
+        match tx.send_batch(vec![1, 2, 3]) {
+            Err(DuctBatchError { sent: 3, error: DuctSendError::WakeupError })
+                => { }
+            _ => panic!("expected wakeup error after all values")
+        }
+        assert_eq!(rx.len(), 3);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn gate_wakeup_error() {
+        let (_lc, notifier) = failing_notifier();
+        let (tx, rx) = gate(notifier);
+        match tx.send(1) {
+            Err(GateSendError::WakeupError) => { }
+            _ => panic!("expected wakeup error")
+        }
+        assert_eq!(rx.try_get().unwrap(), Some(1));
+    }
+
+    #[test]
+    fn reusable_gate_latest_value_wins() {
+        let (_lc, notifier) = idle_notifier();
+        let (tx, rx) = reusable_gate(notifier);
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        tx.clone().send(3).unwrap();
+        assert_eq!(rx.try_get().unwrap(), Some(3));
+        assert_eq!(rx.try_get().unwrap(), None);
+        tx.send(4).unwrap();
+        assert_eq!(rx.try_get().unwrap(), Some(4));
+    }
+
+    #[test]
+    fn reusable_gate_single_wakeup() {
+        let (_lc, notifier) = idle_notifier();
+        let capacity = fill(&idle_notifier().1);
+        let (tx, rx) = reusable_gate(notifier.clone());
+        for i in 0..10 {
+            tx.send(i).unwrap();
+        }
+        // Taking the value allows one more wakeup.
+        assert_eq!(rx.try_get().unwrap(), Some(9));
+        for i in 0..10 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(fill(&notifier) + 2, capacity);
+    }
+
+    #[test]
+    fn reusable_gate_wakeup_error() {
+        let (_lc, notifier) = failing_notifier();
+        let (tx, rx) = reusable_gate(notifier);
+        match tx.send(1) {
+            Err(GateSendError::WakeupError) => { }
+            _ => panic!("expected wakeup error")
+        }
+        // The flag has been cleared, so the next send tries again.
+        match tx.send(2) {
+            Err(GateSendError::WakeupError) => { }
+            _ => panic!("expected another wakeup error")
+        }
+        assert_eq!(rx.try_get().unwrap(), Some(2));
+        assert_eq!(rx.try_get().unwrap(), None);
+    }
+
+    #[test]
+    fn slot_pool_reuses_slots() {
+        let (_lc, notifier) = idle_notifier();
+        let pool = SlotPool::new(4);
+        for i in 0..10 {
+            let (tx, rx) = pool.gate(notifier.clone());
+            tx.send(i).unwrap();
+            assert_eq!(rx.try_get().unwrap(), Some(i));
+        }
+        assert_eq!(pool.allocated(), 1);
+        assert_eq!(pool.free(), 1);
+    }
+
+    #[test]
+    fn slot_pool_receiver_dropped_first() {
+        let (_lc, notifier) = idle_notifier();
+        let pool = SlotPool::<u32>::new(4);
+        let (tx, rx) = pool.gate(notifier.clone());
+        drop(rx);
+        assert_eq!(pool.free(), 0);
+        drop(tx);
+        assert_eq!(pool.free(), 0);
+        let _ = pool.gate(notifier);
+        assert_eq!(pool.allocated(), 2);
+    }
+
+    #[test]
+    fn slot_pool_max() {
+        let (_lc, notifier) = idle_notifier();
+        let pool = SlotPool::<u32>::new(2);
+        let gates = (0..4).map(|_| {
+            pool.gate(notifier.clone())
+        }).collect::<Vec<_>>();
+        assert_eq!(pool.allocated(), 4);
+        for (tx, rx) in gates {
+            drop(tx);
+            drop(rx);
+        }
+        assert_eq!(pool.free(), 2);
+    }
+
+    #[test]
+    fn trigger_wakeup_error() {
+        let (_lc, notifier) = failing_notifier();
+        let (tx, rx) = trigger(notifier);
+        assert!(tx.trigger().is_err());
+        assert!(rx.triggered());
+    }
+
+    #[test]
+    fn pool_runs_jobs() {
+        let (_lc, notifier) = idle_notifier();
+        let pool = Pool::new(2);
+        assert_eq!(pool.size(), 2);
+        let results = (0..8).map(|i| {
+            pool.submit(notifier.clone(), move || i * i)
+        }).collect::<Vec<_>>();
+        drop(pool);
+        for (i, rx) in results.into_iter().enumerate() {
+            assert_eq!(rx.try_get().unwrap(), Some(i * i));
+        }
+    }
+
+    #[test]
+    fn pool_drop_joins_workers() {
+        let (_lc, notifier) = idle_notifier();
+        let done = Arc::new(AtomicUsize::new(0));
+        let pool = Pool::new(2);
+        for _ in 0..4 {
+            let done = done.clone();
+            let _ = pool.submit(notifier.clone(), move || {
+                thread::sleep(Duration::from_millis(50));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+        assert_eq!(done.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    #[should_panic]
+    fn pool_without_workers() {
+        let _ = Pool::new(0);
+    }
+
+    /// A machine that counts the values arriving through a duct.
+    ///
+    /// It reports the count once all values have arrived or, if it hasn’t
+    /// been woken up for a while, whatever it has seen so far.
+    struct Drain {
+        rx: DuctReceiver<usize>,
+        seen: usize,
+        total: usize,
+        report: mpsc::Sender<usize>
+    }
+
+    impl Drain {
+        fn wait(self, scope: &mut Scope<()>) -> Response<Self, Void> {
+            let deadline = scope.now() + Duration::from_secs(10);
+            Response::ok(self).deadline(deadline)
+        }
+
+        fn finish(self, scope: &mut Scope<()>) -> Response<Self, Void> {
+            self.report.send(self.seen).unwrap();
+            scope.shutdown_loop();
+            Response::done()
+        }
+    }
+
+    impl Machine for Drain {
+        type Context = ();
+        type Seed = Void;
+
+        fn create(seed: Void, _scope: &mut Scope<()>)
+                  -> Response<Self, Void> {
+            match seed { }
+        }
+
+        fn ready(self, _events: EventSet, scope: &mut Scope<()>)
+                 -> Response<Self, Void> {
+            self.wait(scope)
+        }
+
+        fn spawned(self, scope: &mut Scope<()>) -> Response<Self, Void> {
+            self.wait(scope)
+        }
+
+        fn timeout(self, scope: &mut Scope<()>) -> Response<Self, Void> {
+            // Don’t look at the duct: values still sitting in there
+            // mean a wakeup got lost.
+            self.finish(scope)
+        }
+
+        fn wakeup(mut self, scope: &mut Scope<()>) -> Response<Self, Void> {
+            while let Ok(Some(_)) = self.rx.try_recv() {
+                self.seen += 1;
+            }
+            if self.seen == self.total {
+                self.finish(scope)
+            }
+            else {
+                self.wait(scope)
+            }
+        }
+    }
+
+    /// Several threads hammer a duct while the loop drains it.
+    ///
+    /// Every value has to arrive through a wakeup. If the receiver
+    /// clearing the flag could race with a sender setting it, some
+    /// values would be stranded and the machine would give up.
+    #[test]
+    fn duct_stress() {
+        const THREADS: usize = 4;
+        const VALUES: usize = 20_000;
+
+        let (report_tx, report_rx) = mpsc::channel();
+        let (duct_tx, duct_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut lc = Loop::new(&Config::new()).unwrap();
+            lc.add_machine_with(|scope| {
+                let (tx, rx) = duct(scope.notifier());
+                duct_tx.send(tx).unwrap();
+                Response::ok(Drain { rx: rx, seen: 0,
+                                     total: THREADS * VALUES,
+                                     report: report_tx })
+            }).unwrap();
+            lc.run(()).unwrap();
+        });
+        let tx = duct_rx.recv().unwrap();
+        let senders = (0..THREADS).map(|_| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for i in 0..VALUES {
+                    match tx.send(i) {
+                        Ok(()) | Err(DuctSendError::WakeupError) => { }
+                        Err(_) => panic!("duct closed early")
+                    }
+                }
+            })
+        }).collect::<Vec<_>>();
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        let seen = report_rx.recv_timeout(Duration::from_secs(30)).unwrap();
+        assert_eq!(seen, THREADS * VALUES);
+    }
+
+    /// A machine rate limiting requests arriving through a duct.
+    ///
+    /// The limits are kept in a shared token bucket. Whenever they
+    /// change, the machine starts over with a fresh copy of the new
+    /// bucket. It reports for each request whether it was allowed.
+    struct Limited {
+        rx: DuctReceiver<()>,
+        limits: Shared<TokenBucket>,
+        bucket: TokenBucket,
+        report: mpsc::Sender<bool>
+    }
+
+    impl Machine for Limited {
+        type Context = ();
+        type Seed = Void;
+
+        fn create(seed: Void, _scope: &mut Scope<()>)
+                  -> Response<Self, Void> {
+            match seed { }
+        }
+
+        fn ready(self, _events: EventSet, _scope: &mut Scope<()>)
+                 -> Response<Self, Void> {
+            Response::ok(self)
+        }
+
+        fn spawned(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            Response::ok(self)
+        }
+
+        fn timeout(self, _scope: &mut Scope<()>) -> Response<Self, Void> {
+            Response::ok(self)
+        }
+
+        fn wakeup(mut self, scope: &mut Scope<()>) -> Response<Self, Void> {
+            loop {
+                match self.rx.try_recv() {
+                    Ok(Some(())) => { }
+                    Ok(None) => return Response::ok(self),
+                    Err(_) => {
+                        scope.shutdown_loop();
+                        return Response::done()
+                    }
+                }
+                let limits = self.limits.load();
+                if limits.capacity() != self.bucket.capacity()
+                        || limits.refill_rate() != self.bucket.refill_rate() {
+                    self.bucket = (*limits).clone();
+                }
+                let allowed = self.bucket.try_take(1, scope.now());
+                self.report.send(allowed).unwrap();
+            }
+        }
+    }
+
+    /// The limits of a running machine are replaced through `Shared`.
+    #[test]
+    fn shared_rate_limit() {
+        let limits = Shared::new(TokenBucket::new(1, 1));
+        let (report_tx, report_rx) = mpsc::channel();
+        let (duct_tx, duct_rx) = mpsc::channel();
+        let machine_limits = limits.clone();
+        let handle = thread::spawn(move || {
+            let mut lc = Loop::new(&Config::new()).unwrap();
+            lc.add_machine_with(|scope| {
+                let (tx, rx) = duct(scope.notifier());
+                duct_tx.send(tx).unwrap();
+                let bucket = (*machine_limits.load()).clone();
+                Response::ok(Limited { rx: rx, limits: machine_limits,
+                                       bucket: bucket, report: report_tx })
+            }).unwrap();
+            lc.run(()).unwrap();
+        });
+        let tx = duct_rx.recv().unwrap();
+        {
+            let request = || {
+                tx.send(()).unwrap();
+                report_rx.recv_timeout(Duration::from_secs(10)).unwrap()
+            };
+
+            // One token, refilled once a second: the second request is
+            // refused.
+            assert!(request());
+            assert!(!request());
+
+            // Raise the limit while the machine keeps running.
+            limits.update(TokenBucket::new(3, 1));
+            assert!(request());
+            assert!(request());
+            assert!(request());
+            assert!(!request());
+        }
+
+        // Closing the duct ends the loop.
+        drop(tx);
+        handle.join().unwrap();
+    }
+}
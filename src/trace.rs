@@ -0,0 +1,92 @@
+//! Optional integration with the tracing crate.
+//!
+//! If the `tracing` feature is enabled, each transport machine keeps a
+//! span for the lifetime of its connection and emits events for the
+//! things happening to it. Without the feature, the types in here are
+//! empty and all functions do nothing, so the machines can use them
+//! unconditionally without paying for it.
+
+pub use self::imp::*;
+
+
+//------------ With tracing -------------------------------------------------
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use ::sockets::PeerAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// The source of connection IDs.
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    /// The span of a connection.
+    #[derive(Clone)]
+    pub struct ConnSpan(::tracing::Span);
+
+    impl ConnSpan {
+        /// Creates a new span with a fresh connection ID.
+        pub fn new() -> Self {
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            ConnSpan(::tracing::info_span!(
+                "connection", id = id, peer = ::tracing::field::Empty
+            ))
+        }
+
+        /// Creates a new span for a connection accepted from `addr`.
+        ///
+        /// The span records the peer address and starts out with an
+        /// event saying that the connection has been accepted.
+        pub fn accepted(addr: &PeerAddr) -> Self {
+            let span = ConnSpan::new();
+            span.0.record("peer", &::tracing::field::display(addr));
+            {
+                let _enter = span.enter();
+                ::tracing::debug!("accepted");
+            }
+            span
+        }
+
+        /// Enters the span until the returned guard is dropped.
+        pub fn enter(&self) -> ::tracing::span::Entered {
+            self.0.enter()
+        }
+    }
+
+    /// Records an event on the current connection.
+    pub fn event(what: &'static str) {
+        ::tracing::trace!("{}", what);
+    }
+
+    /// Records that the current connection is being closed.
+    pub fn closed() {
+        ::tracing::debug!("closed");
+    }
+}
+
+
+//------------ Without tracing ----------------------------------------------
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use ::sockets::PeerAddr;
+
+    #[derive(Clone)]
+    pub struct ConnSpan;
+
+    impl ConnSpan {
+        #[inline]
+        pub fn new() -> Self { ConnSpan }
+
+        #[inline]
+        pub fn accepted(_addr: &PeerAddr) -> Self { ConnSpan }
+
+        #[inline]
+        pub fn enter(&self) { }
+    }
+
+    #[inline]
+    pub fn event(_what: &'static str) { }
+
+    #[inline]
+    pub fn closed() { }
+}
@@ -1,6 +1,18 @@
 //! Miscellany.
 
-use rotor::Response;
+use std::{error, fmt};
+use std::cmp::{max, min};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rotor::{Notifier, Response, Time};
+use rotor::mio::TryRead;
+use ::error::Error;
+use ::handlers::TransportHandler;
+use ::next::Next;
+use ::sockets::{Stream, Transport};
 
 
 //------------ ResponseExt -----------------------------------------------
@@ -17,3 +29,1671 @@ impl<M: Sized, N: Sized> ResponseExt<M, N> for Response<M, N> {
     }
 }
 
+
+
+//------------ Halves --------------------------------------------------------
+
+/// Bookkeeping for the two directions of a transport socket.
+///
+/// A relay or proxy typically drives reading and writing of a stream
+/// independently and needs to finish one direction while the other one
+/// continues. Since the handler only ever gets the socket by reference,
+/// it can’t actually split it. Instead, it can keep a value of this type
+/// and record which half is done.
+///
+/// The read half is done once the handler has seen the end of the stream
+/// and calls [finish_read()](#method.finish_read). The write half is done
+/// after [finish_write()](#method.finish_write) shut down writing on the
+/// socket. Once both halves are done, the connection is done, too.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Halves {
+    read_done: bool,
+    write_done: bool,
+}
+
+impl Halves {
+    /// Creates a new value with both halves still open.
+    pub fn new() -> Self {
+        Halves::default()
+    }
+
+    /// Returns whether the read half is done.
+    pub fn is_read_done(&self) -> bool {
+        self.read_done
+    }
+
+    /// Returns whether the write half is done.
+    pub fn is_write_done(&self) -> bool {
+        self.write_done
+    }
+
+    /// Returns whether both halves are done.
+    pub fn is_done(&self) -> bool {
+        self.read_done && self.write_done
+    }
+
+    /// Records that there is nothing more to read.
+    pub fn finish_read(&mut self) {
+        self.read_done = true
+    }
+
+    /// Shuts down writing on `sock` and records that the write half is done.
+    ///
+    /// Calling this again after the first success does nothing. If the
+    /// shutdown fails, the write half is still considered done since there
+    /// is no sensible way to continue writing anyway.
+    pub fn finish_write<T: Transport>(&mut self, sock: &mut T)
+                                      -> io::Result<()> {
+        if self.write_done {
+            return Ok(())
+        }
+        self.write_done = true;
+        sock.shutdown_write()
+    }
+
+    /// Returns what should happen next for the given handler.
+    ///
+    /// The handler is interested in reading while the read half is open
+    /// and in writing if it `wants_write` and the write half is open. If
+    /// both halves are done, the handler is removed.
+    pub fn next<H>(&self, handler: H, wants_write: bool) -> Next<H> {
+        let write = wants_write && !self.write_done;
+        match (self.read_done, write) {
+            (true, _) if self.write_done => Next::remove(),
+            (false, true) => Next::read_and_write(handler),
+            (false, false) => Next::read(handler),
+            (true, true) => Next::write(handler),
+            (true, false) => Next::wait(handler),
+        }
+    }
+}
+
+
+//------------ WriteBuffer ---------------------------------------------------
+
+/// A buffer for output waiting to be written with flow control.
+///
+/// When a handler relays data from one socket to another and the writing
+/// side is slower than the reading side, the data piles up in memory. To
+/// avoid this, the handler should stop reading until the writing side has
+/// caught up. Doing so means that the peer it is reading from will
+/// eventually be told to slow down by TCP itself.
+///
+/// This type keeps the pending output and tracks a high and a low water
+/// mark. Once the amount of pending output reaches the high water mark,
+/// the buffer is *paused.* It stays paused until writing has brought the
+/// amount down to the low water mark. The [next()](#method.next) method
+/// turns this into the handler’s interest: while paused, only writing is
+/// requested, so the machine stops reading from the socket.
+///
+/// In addition, the buffer can be part of a [WriteBudget] limiting the
+/// amount of pending output across many buffers. In this case, pushing
+/// data fails if it would exceed the budget.
+///
+/// [WriteBudget]: struct.WriteBudget.html
+#[derive(Debug)]
+pub struct WriteBuffer {
+    buf: Vec<u8>,
+    high: usize,
+    low: usize,
+    paused: bool,
+    budget: Option<WriteBudget>,
+}
+
+impl WriteBuffer {
+    /// Creates a new buffer with the given water marks.
+    ///
+    /// # Panics
+    ///
+    /// The function panics if `low` is larger than `high`.
+    pub fn new(high: usize, low: usize) -> Self {
+        assert!(low <= high, "low water mark above high water mark");
+        WriteBuffer { buf: Vec::new(), high: high, low: low, paused: false,
+                      budget: None }
+    }
+
+    /// Creates a new buffer that is part of a write budget.
+    ///
+    /// The water marks work exactly as with [new()](#method.new).
+    pub fn with_budget(high: usize, low: usize, budget: WriteBudget)
+                       -> Self {
+        let mut res = WriteBuffer::new(high, low);
+        res.budget = Some(budget);
+        res
+    }
+
+    /// Returns the amount of pending output.
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns whether there is no pending output.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Returns whether reading should pause.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Returns the amount of output not yet sent over `sock`.
+    ///
+    /// This is the pending output in the buffer plus whatever `sock`
+    /// reports via [Transport::pending_write_bytes()].
+    ///
+    /// [Transport::pending_write_bytes()]:
+    ///     ../sockets/trait.Transport.html#method.pending_write_bytes
+    pub fn pending_bytes<T: Transport>(&self, sock: &T) -> usize {
+        self.buf.len() + sock.pending_write_bytes()
+    }
+
+    /// Appends data to the buffer.
+    ///
+    /// If the buffer is part of a budget and the data would exceed it,
+    /// nothing is appended and an error is returned. The buffer is paused
+    /// in this case, too, so that handlers using
+    /// [next()](#method.next) stop reading until output has been written.
+    pub fn push(&mut self, data: &[u8]) -> Result<(), BudgetExceeded> {
+        if let Some(ref budget) = self.budget {
+            if !budget.acquire(data.len()) {
+                self.paused = true;
+                return Err(BudgetExceeded)
+            }
+        }
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= self.high {
+            self.paused = true
+        }
+        Ok(())
+    }
+
+    /// Writes as much pending output as possible to `sock`.
+    ///
+    /// Returns `Ok(true)` if all data has been written or `Ok(false)` if
+    /// the socket would block before that. Any other error is returned.
+    pub fn write_to<W: Write>(&mut self, sock: &mut W) -> io::Result<bool> {
+        let before = self.buf.len();
+        let res = self.drain(sock);
+        if let Some(ref budget) = self.budget {
+            budget.release(before - self.buf.len())
+        }
+        if self.buf.len() <= self.low {
+            self.paused = false
+        }
+        res
+    }
+
+    /// Returns what should happen next for the given handler.
+    ///
+    /// While paused, the handler is only interested in writing. Otherwise
+    /// it wants to read and, if there is pending output, write.
+    pub fn next<H>(&self, handler: H) -> Next<H> {
+        if self.paused {
+            Next::write(handler)
+        }
+        else if self.buf.is_empty() {
+            Next::read(handler)
+        }
+        else {
+            Next::read_and_write(handler)
+        }
+    }
+
+    fn drain<W: Write>(&mut self, sock: &mut W) -> io::Result<bool> {
+        while !self.buf.is_empty() {
+            match sock.write(&self.buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "failed to write output"))
+                }
+                Ok(len) => { self.buf.drain(..len); }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(false)
+                }
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(true)
+    }
+}
+
+
+impl Drop for WriteBuffer {
+    fn drop(&mut self) {
+        if let Some(ref budget) = self.budget {
+            budget.release(self.buf.len())
+        }
+    }
+}
+
+
+//------------ WriteBudget ---------------------------------------------------
+
+/// A limit for the pending output of many write buffers together.
+///
+/// Values of this type are cheap to clone and all clones share the same
+/// budget. Typically, a budget is given to a server via its [ServerStats].
+/// The server passes it on to its accept handler which hands a clone to
+/// each transport handler’s seed which uses it for its [WriteBuffer].
+/// Anyone with a clone, including the server’s statistics, can use
+/// [total()](#method.total) to learn how much output is pending across
+/// all connections.
+///
+/// [ServerStats]: ../net/machines/struct.ServerStats.html
+/// [WriteBuffer]: struct.WriteBuffer.html
+#[derive(Clone, Debug)]
+pub struct WriteBudget {
+    total: Arc<AtomicUsize>,
+    cap: usize,
+}
+
+impl WriteBudget {
+    /// Creates a new budget of `cap` bytes.
+    pub fn new(cap: usize) -> Self {
+        WriteBudget { total: Arc::new(AtomicUsize::new(0)), cap: cap }
+    }
+
+    /// Returns the amount of pending output of all buffers.
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Returns the size of the budget.
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+
+    /// Tries to take `len` bytes from the budget.
+    fn acquire(&self, len: usize) -> bool {
+        let mut current = self.total.load(Ordering::Relaxed);
+        loop {
+            let new = match current.checked_add(len) {
+                Some(new) if new <= self.cap => new,
+                _ => return false
+            };
+            match self.total.compare_exchange(current, new,
+                                              Ordering::Relaxed,
+                                              Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(old) => current = old
+            }
+        }
+    }
+
+    /// Returns `len` bytes to the budget.
+    fn release(&self, len: usize) {
+        if len > 0 {
+            self.total.fetch_sub(len, Ordering::Relaxed);
+        }
+    }
+}
+
+
+//------------ BudgetExceeded ------------------------------------------------
+
+/// Pushing to a write buffer would have exceeded its budget.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BudgetExceeded;
+
+impl error::Error for BudgetExceeded {
+    fn description(&self) -> &str {
+        "write budget exceeded"
+    }
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(error::Error::description(self))
+    }
+}
+
+
+//------------ PriorityWriteQueue --------------------------------------------
+
+/// A queue for output of different priorities.
+///
+/// Some protocols multiplex several kinds of output over one stream and
+/// want some of it to go first. HTTP/2, for instance, should send its
+/// control frames before any pending bulk data. This type keeps output in
+/// a number of *bands,* one per priority, with band 0 having the highest
+/// priority.
+///
+/// The handler adds chunks of output to a band via
+/// [push()](#method.push) and calls [write_to()](#method.write_to) when
+/// the socket becomes writable. Output is written in *rounds:* during a
+/// round, each band may write up to its weight in bytes, with bands of
+/// higher priority going first. Once all bands with pending output have
+/// used up their share, a new round starts. This way, low priority output
+/// isn’t starved entirely even if there is always something more urgent.
+/// Giving the higher bands much larger weights comes close to strict
+/// priority.
+///
+/// A chunk is always written completely before any other chunk is
+/// started, even if the socket only accepts part of it. Chunks therefore
+/// are never interleaved and can safely contain complete protocol frames.
+/// This also means that a band may overrun its share by up to one chunk.
+///
+/// To avoid allocating for every chunk, the queue keeps some of the
+/// buffers of chunks it is done with. A new buffer can be had via
+/// [buffer()](#method.buffer), filled, and then pushed.
+///
+/// Instead of writing the queue itself, a transport handler can leave
+/// this to the transport machine by handing out a [QueueWriter] from its
+/// [write_queue()] method. The machine then waits for writable whenever
+/// the queue has output and writes it before calling the handler’s
+/// `writable()`.
+///
+/// [QueueWriter]: struct.QueueWriter.html
+/// [write_queue()]: ../handlers/trait.TransportHandler.html#method.write_queue
+#[derive(Debug)]
+pub struct PriorityWriteQueue {
+    bands: Vec<Band>,
+
+    /// The band whose front chunk has been partially written.
+    current: Option<usize>,
+
+    /// The total amount of pending output.
+    len: usize,
+
+    /// Empty buffers for reuse.
+    spare: Vec<Vec<u8>>,
+}
+
+/// A single priority band.
+#[derive(Debug)]
+struct Band {
+    chunks: VecDeque<Vec<u8>>,
+
+    /// The number of bytes of the front chunk written already.
+    pos: usize,
+
+    /// The amount of pending output in this band.
+    len: usize,
+
+    /// The number of bytes the band may write per round.
+    weight: usize,
+
+    /// The number of bytes the band may still write in this round.
+    credit: usize,
+}
+
+/// The maximum number of spare buffers kept for reuse.
+const MAX_SPARE_BUFFERS: usize = 16;
+
+impl PriorityWriteQueue {
+    /// Creates a new queue with one band for each of the weights given.
+    ///
+    /// The first weight is for band 0 which has the highest priority.
+    ///
+    /// # Panics
+    ///
+    /// The function panics if `weights` is empty or any of the weights is
+    /// zero.
+    pub fn new(weights: &[usize]) -> Self {
+        assert!(!weights.is_empty(), "priority write queue without bands");
+        PriorityWriteQueue {
+            bands: weights.iter().map(|&weight| {
+                assert!(weight > 0, "priority band with zero weight");
+                Band { chunks: VecDeque::new(), pos: 0, len: 0,
+                       weight: weight, credit: weight }
+            }).collect(),
+            current: None,
+            len: 0,
+            spare: Vec::new(),
+        }
+    }
+
+    /// Returns the number of bands.
+    pub fn bands(&self) -> usize {
+        self.bands.len()
+    }
+
+    /// Returns the total amount of pending output.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether there is no pending output.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the amount of output not yet sent over `sock`.
+    ///
+    /// This is the pending output of all bands plus whatever `sock`
+    /// reports via [Transport::pending_write_bytes()].
+    ///
+    /// [Transport::pending_write_bytes()]:
+    ///     ../sockets/trait.Transport.html#method.pending_write_bytes
+    pub fn pending_bytes<T: Transport>(&self, sock: &T) -> usize {
+        self.len + sock.pending_write_bytes()
+    }
+
+    /// Returns the amount of pending output in the band for `priority`.
+    ///
+    /// # Panics
+    ///
+    /// The method panics if there is no such band.
+    pub fn band_len(&self, priority: usize) -> usize {
+        self.bands[priority].len
+    }
+
+    /// Returns an empty buffer for a new chunk.
+    ///
+    /// This is a buffer of an earlier chunk if there is one.
+    pub fn buffer(&mut self) -> Vec<u8> {
+        self.spare.pop().unwrap_or_else(Vec::new)
+    }
+
+    /// Adds a chunk of output to the band for `priority`.
+    ///
+    /// Empty chunks are ignored.
+    ///
+    /// # Panics
+    ///
+    /// The method panics if there is no such band.
+    pub fn push(&mut self, priority: usize, chunk: Vec<u8>) {
+        if chunk.is_empty() {
+            assert!(priority < self.bands.len(), "no such priority band");
+            self.recycle(chunk);
+            return
+        }
+        let band = &mut self.bands[priority];
+        band.len += chunk.len();
+        self.len += chunk.len();
+        band.chunks.push_back(chunk);
+    }
+
+    /// Writes as much pending output as possible to `sock`.
+    ///
+    /// Returns `Ok(true)` if all data has been written or `Ok(false)` if
+    /// the socket would block before that. Any other error is returned.
+    pub fn write_to<W: Write>(&mut self, sock: &mut W) -> io::Result<bool> {
+        while self.len > 0 {
+            let idx = self.pick();
+            let res = {
+                let band = &self.bands[idx];
+                sock.write(&band.chunks[0][band.pos..])
+            };
+            match res {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "failed to write output"))
+                }
+                Ok(len) => self.advance(idx, len),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(false)
+                }
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns what should happen next for the given handler.
+    ///
+    /// The handler always wants to read and, if there is pending output,
+    /// write.
+    pub fn next<H>(&self, handler: H) -> Next<H> {
+        if self.is_empty() {
+            Next::read(handler)
+        }
+        else {
+            Next::read_and_write(handler)
+        }
+    }
+}
+
+/// # Internal Helpers
+///
+impl PriorityWriteQueue {
+    /// Returns the index of the band to write from next.
+    ///
+    /// There must be pending output.
+    fn pick(&mut self) -> usize {
+        if let Some(idx) = self.current {
+            return idx
+        }
+        loop {
+            if let Some(idx) = self.bands.iter().position(|band| {
+                band.len > 0 && band.credit > 0
+            }) {
+                return idx
+            }
+            for band in &mut self.bands {
+                band.credit = band.weight
+            }
+        }
+    }
+
+    /// Records that `len` bytes of band `idx` have been written.
+    fn advance(&mut self, idx: usize, len: usize) {
+        let done = {
+            let band = &mut self.bands[idx];
+            let len = min(len, band.chunks[0].len() - band.pos);
+            band.pos += len;
+            band.len -= len;
+            band.credit = band.credit.saturating_sub(len);
+            self.len -= len;
+            if band.pos == band.chunks[0].len() {
+                band.pos = 0;
+                band.chunks.pop_front()
+            }
+            else {
+                None
+            }
+        };
+        match done {
+            Some(chunk) => {
+                self.current = None;
+                self.recycle(chunk)
+            }
+            None => self.current = Some(idx)
+        }
+    }
+
+    /// Keeps the buffer of `chunk` for reuse if there is room.
+    fn recycle(&mut self, mut chunk: Vec<u8>) {
+        if self.spare.len() < MAX_SPARE_BUFFERS {
+            chunk.clear();
+            self.spare.push(chunk)
+        }
+    }
+}
+
+
+//------------ QueueWriter ---------------------------------------------------
+
+/// A priority write queue ready to be written to a socket of type `T`.
+///
+/// This is what a transport handler returns from its [write_queue()]
+/// method in order to have the transport machine write its queue. Since
+/// the machine itself doesn’t know whether its socket can be written to,
+/// the value can only be created for sockets that implement `Write`.
+///
+/// [write_queue()]: ../handlers/trait.TransportHandler.html#method.write_queue
+pub struct QueueWriter<'a, T> {
+    queue: &'a mut PriorityWriteQueue,
+    write: fn(&mut PriorityWriteQueue, &mut T) -> io::Result<bool>,
+}
+
+impl<'a, T: Write> QueueWriter<'a, T> {
+    /// Creates a new value for `queue`.
+    pub fn new(queue: &'a mut PriorityWriteQueue) -> Self {
+        QueueWriter { queue: queue, write: write_queue::<T> }
+    }
+}
+
+impl<'a, T> QueueWriter<'a, T> {
+    /// Returns whether the queue has no pending output.
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Writes as much of the queue as possible to `sock`.
+    ///
+    /// This is the same as the queue’s own
+    /// [write_to()](struct.PriorityWriteQueue.html#method.write_to).
+    pub fn write_to(self, sock: &mut T) -> io::Result<bool> {
+        (self.write)(self.queue, sock)
+    }
+
+    /// Trades the writer for the queue it writes.
+    ///
+    /// This is for wrapping handlers that give the handler they wrap a
+    /// different socket and need to write its queue themselves.
+    pub fn into_queue(self) -> &'a mut PriorityWriteQueue {
+        self.queue
+    }
+}
+
+fn write_queue<T: Write>(queue: &mut PriorityWriteQueue, sock: &mut T)
+                         -> io::Result<bool> {
+    queue.write_to(sock)
+}
+
+
+//------------ TokenBucket ---------------------------------------------------
+
+/// A token bucket for rate limiting.
+///
+/// The bucket holds up to `capacity` tokens and is refilled at a rate of
+/// `refill_rate` tokens per second. Taking tokens out of it succeeds only
+/// if there are enough. What a token stands for is up to the user: it can
+/// be a connection for limiting the accept rate or a byte for throttling
+/// bandwidth.
+///
+/// The bucket doesn’t have a clock of its own. Instead, all methods take
+/// the current time as provided by the machine’s scope. A new bucket
+/// starts out full.
+///
+/// If the clock jumps forward, the bucket simply is full again. If it
+/// jumps backward, no tokens are added until it has caught up again.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    /// The maximum number of tokens.
+    capacity: u64,
+
+    /// The number of tokens added per second.
+    refill_rate: u64,
+
+    /// The number of whole tokens at `last`.
+    tokens: u64,
+
+    /// The fraction of a token at `last` in billionths.
+    fraction: u64,
+
+    /// The time the bucket was last refilled.
+    ///
+    /// This is `None` for a new, full bucket.
+    last: Option<Time>,
+}
+
+impl TokenBucket {
+    /// Creates a new, full bucket.
+    ///
+    /// # Panics
+    ///
+    /// The function panics if `refill_rate` is zero.
+    pub fn new(capacity: u64, refill_rate: u64) -> Self {
+        assert!(refill_rate > 0, "token bucket with zero refill rate");
+        TokenBucket { capacity: capacity, refill_rate: refill_rate,
+                      tokens: capacity, fraction: 0, last: None }
+    }
+
+    /// Returns the maximum number of tokens.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Returns the number of tokens added per second.
+    pub fn refill_rate(&self) -> u64 {
+        self.refill_rate
+    }
+
+    /// Returns the number of tokens available at `now`.
+    pub fn available(&self, now: Time) -> u64 {
+        self.refilled(now).0
+    }
+
+    /// Attempts to take `n` tokens at `now`.
+    ///
+    /// Returns whether there were enough tokens. If not, nothing is taken
+    /// at all. If `n` is larger than the capacity, this never succeeds.
+    pub fn try_take(&mut self, n: u64, now: Time) -> bool {
+        let (tokens, fraction) = self.refilled(now);
+        self.fraction = fraction;
+        self.last = Some(self.last.map_or(now, |last| max(last, now)));
+        if tokens >= n {
+            self.tokens = tokens - n;
+            true
+        }
+        else {
+            self.tokens = tokens;
+            false
+        }
+    }
+
+    /// Returns how long to wait at `now` until `n` tokens are available.
+    ///
+    /// If there are enough tokens already, the duration is zero. If `n`
+    /// is larger than the capacity, returns how long it takes until the
+    /// bucket is full.
+    pub fn time_until(&self, n: u64, now: Time) -> Duration {
+        let (tokens, fraction) = self.refilled(now);
+        let n = min(n, self.capacity);
+        if tokens >= n {
+            return Duration::new(0, 0)
+        }
+        // Billionths of a token missing, then nanoseconds rounded up.
+        let missing = (n - tokens).saturating_mul(NANOS) - fraction;
+        let nanos = missing.saturating_add(self.refill_rate - 1)
+                  / self.refill_rate;
+        Duration::new(nanos / NANOS, (nanos % NANOS) as u32)
+    }
+}
+
+/// # Internal Helpers
+///
+impl TokenBucket {
+    /// Returns tokens and fraction after refilling up to `now`.
+    fn refilled(&self, now: Time) -> (u64, u64) {
+        let last = match self.last {
+            Some(last) => last,
+            None => return (self.tokens, self.fraction)
+        };
+        if self.tokens >= self.capacity {
+            return (self.capacity, 0)
+        }
+        let elapsed = time_since(now, last);
+
+        // Check against the time needed to fill the bucket first so the
+        // multiplication below can’t overflow after a clock jump. For
+        // very large buckets or jumps, the values saturate which only
+        // ever makes the bucket fuller.
+        let missing = (self.capacity - self.tokens).saturating_mul(NANOS)
+                    - self.fraction;
+        let fill = missing / self.refill_rate;
+        let elapsed = elapsed.as_secs().saturating_mul(NANOS)
+                             .saturating_add(elapsed.subsec_nanos() as u64);
+        if elapsed >= fill {
+            return (self.capacity, 0)
+        }
+        let added = self.fraction + elapsed * self.refill_rate;
+        (min(self.tokens + added / NANOS, self.capacity), added % NANOS)
+    }
+}
+
+/// Nanoseconds per second and billionths per token.
+const NANOS: u64 = 1_000_000_000;
+
+/// Returns the time passed from `earlier` to `later`.
+///
+/// Rotor’s `Time` only supports adding durations and comparing, so the
+/// difference is found by bisection. Since `Time` has millisecond
+/// precision, so does the result. If `later` isn’t actually later, the
+/// result is zero.
+fn time_since(later: Time, earlier: Time) -> Duration {
+    if later <= earlier {
+        return Duration::new(0, 0)
+    }
+    // Invariant: earlier + lo < later <= earlier + hi
+    let mut lo = 0;
+    let mut hi = 1;
+    while earlier + Duration::from_millis(hi) < later {
+        lo = hi;
+        hi *= 2;
+    }
+    while hi - lo > 1 {
+        let mid = lo + (hi - lo) / 2;
+        if earlier + Duration::from_millis(mid) < later {
+            lo = mid
+        }
+        else {
+            hi = mid
+        }
+    }
+    Duration::from_millis(hi)
+}
+
+
+//------------ Backoff -------------------------------------------------------
+
+/// Exponential backoff for retrying something that keeps failing.
+///
+/// Each call to [next_delay()](#method.next_delay) returns how long to
+/// wait before the next attempt. The delay starts out at an initial value
+/// and doubles with every attempt until it reaches a maximum.
+/// [reset()](#method.reset) starts over once an attempt has succeeded.
+///
+/// By default, jitter is applied to the delays so that many clients that
+/// failed at the same time don’t all come back at the same time, too:
+/// each delay is picked at random between half and all of the nominal
+/// delay. The random numbers aren’t particularly good but they don’t need
+/// to be.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    /// The delay before the first retry.
+    initial: Duration,
+
+    /// The maximum delay.
+    max: Duration,
+
+    /// Whether to apply jitter.
+    jitter: bool,
+
+    /// The number of delays handed out since the last reset.
+    attempts: u32,
+
+    /// The state of the random number generator for the jitter.
+    rng: u64,
+}
+
+impl Backoff {
+    /// Creates a new backoff doubling from `initial` up to `limit`.
+    ///
+    /// If `limit` is smaller than `initial`, the delay is always
+    /// `initial`.
+    pub fn new(initial: Duration, limit: Duration) -> Self {
+        Backoff { initial: initial, max: max(initial, limit), jitter: true,
+                  attempts: 0, rng: backoff_seed() }
+    }
+
+    /// Creates a new backoff always waiting for `delay` without jitter.
+    pub fn constant(delay: Duration) -> Self {
+        Backoff::new(delay, delay).jitter(false)
+    }
+
+    /// Sets whether to apply jitter to the delays.
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Returns the delay before the first retry.
+    pub fn initial(&self) -> Duration {
+        self.initial
+    }
+
+    /// Returns the maximum delay.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Returns the number of delays handed out since the last reset.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Returns the delay before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let max = to_nanos(self.max);
+        let mut delay = to_nanos(self.initial);
+        for _ in 0..self.attempts {
+            if delay >= max {
+                break
+            }
+            delay = delay.saturating_mul(2);
+        }
+        let mut delay = min(delay, max);
+        self.attempts = self.attempts.saturating_add(1);
+        if self.jitter && delay > 1 {
+            let half = delay / 2;
+            delay = half + self.random() % (delay - half + 1);
+        }
+        Duration::new(delay / NANOS, (delay % NANOS) as u32)
+    }
+
+    /// Starts over with the initial delay.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// Returns the next random number.
+    ///
+    /// This is xorshift64* which is good enough for jitter.
+    fn random(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+/// Returns a new seed for the random numbers of a backoff.
+///
+/// The seed is taken from the system time mixed with a counter so that
+/// backoffs created at the same time still differ. It is never zero.
+fn backoff_seed() -> u64 {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+                               .unwrap_or(Duration::new(0, 0));
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed) as u64;
+    (to_nanos(now) ^ count.wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1
+}
+
+/// Converts a duration into nanoseconds, saturating at the maximum.
+fn to_nanos(duration: Duration) -> u64 {
+    duration.as_secs().saturating_mul(NANOS)
+            .saturating_add(duration.subsec_nanos() as u64)
+}
+
+
+//------------ parse_line ----------------------------------------------------
+
+/// Splits the first CRLF terminated line off the beginning of `data`.
+///
+/// If `data` contains a complete line, returns the line without the
+/// terminating CRLF and the remaining data following it. If there is no
+/// complete line yet, returns `Ok(None)` so that the caller can wait for
+/// more data.
+///
+/// The function fails if the line is longer than `max_len` bytes or, if
+/// there is no line end yet, `data` already is longer than that. This
+/// protects against peers that never end their lines. It also fails if
+/// the line is terminated by a lone LF.
+///
+/// The line is returned as is. In particular, it may contain any octet
+/// other than LF, including NUL and CR, so callers need to check for
+/// those if their protocol doesn’t allow them.
+pub fn parse_line(data: &[u8], max_len: usize)
+                  -> Result<Option<(&[u8], &[u8])>, LineError> {
+    let pos = match data.iter().position(|ch| *ch == b'\n') {
+        Some(pos) => pos,
+        None => {
+            // A trailing CR may still become part of the line end.
+            let len = if data.last() == Some(&b'\r') { data.len() - 1 }
+                      else { data.len() };
+            if len > max_len { return Err(LineError::TooLong) }
+            else { return Ok(None) }
+        }
+    };
+    if pos == 0 || data[pos - 1] != b'\r' {
+        return Err(LineError::BareLf)
+    }
+    let line = &data[..pos - 1];
+    if line.len() > max_len {
+        return Err(LineError::TooLong)
+    }
+    Ok(Some((line, &data[pos + 1..])))
+}
+
+
+//------------ LineError -----------------------------------------------------
+
+/// An error happened while parsing a line.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LineError {
+    /// The line is longer than allowed.
+    TooLong,
+
+    /// The line is terminated by a LF without a preceding CR.
+    BareLf,
+}
+
+impl error::Error for LineError {
+    fn description(&self) -> &str {
+        match *self {
+            LineError::TooLong => "line too long",
+            LineError::BareLf => "line ended without CR",
+        }
+    }
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(error::Error::description(self))
+    }
+}
+
+
+//------------ LineHandler ---------------------------------------------------
+
+/// A handler for line based protocols.
+///
+/// This is what a [LineReader] wraps. Instead of reading from the socket
+/// itself, the handler is given one complete line after the other via
+/// [line()](#tymethod.line).
+///
+/// [LineReader]: struct.LineReader.html
+pub trait LineHandler<T: Stream>: Sized {
+    /// The type of the seed for creating a new handler.
+    type Seed;
+
+    /// Creates a new handler from a seed.
+    ///
+    /// This is the same as `TransportHandler::create()`.
+    fn create(seed: Self::Seed, sock: &mut T, notifier: Notifier)
+              -> Next<Self>;
+
+    /// Called for each complete line.
+    ///
+    /// The line is given without the delimiter.
+    fn line(self, line: &[u8], sock: &mut T) -> Next<Self>;
+
+    /// Called when the socket may have become writable.
+    fn writable(self, sock: &mut T) -> Next<Self>;
+
+    /// Called upon wakeup via a notifier.
+    fn wakeup(self, sock: &mut T) -> Next<Self>;
+
+    /// Called once when the machine has become live.
+    ///
+    /// This is the same as `TransportHandler::on_ready()`, including the
+    /// default implementation changing nothing.
+    fn on_ready(self, sock: &mut T) -> Next<Self> {
+        let _ = sock;
+        Next::wait(self)
+    }
+
+    /// Called when a start-TLS handshake has completed successfully.
+    ///
+    /// This is the same as `TransportHandler::secured()`, including the
+    /// default implementation forwarding to
+    /// [wakeup()](#tymethod.wakeup).
+    fn secured(self, sock: &mut T) -> Next<Self> {
+        self.wakeup(sock)
+    }
+
+    /// Called when a recurring tick is due.
+    ///
+    /// This is the same as `TransportHandler::tick()`, including the
+    /// default implementation changing nothing.
+    fn tick(self, sock: &mut T) -> Next<Self> {
+        let _ = sock;
+        Next::wait(self)
+    }
+
+    /// Returns whether the handler still has output to write.
+    ///
+    /// This is the same as `TransportHandler::has_pending_output()`,
+    /// including the default implementation returning `false`.
+    fn has_pending_output(&self) -> bool {
+        false
+    }
+
+    /// Returns the handler’s write queue if the machine should write it.
+    ///
+    /// This is the same as `TransportHandler::write_queue()`, including
+    /// the default implementation returning `None`.
+    fn write_queue(&mut self) -> Option<QueueWriter<T>> {
+        None
+    }
+
+    /// Called when the machine goes away while the handler is still alive.
+    ///
+    /// This is the same as `TransportHandler::teardown()`, including the
+    /// default implementation doing nothing.
+    fn teardown(self, sock: &mut T) {
+        let _ = sock;
+    }
+
+    /// Returns the delimiter ending a line.
+    ///
+    /// The delimiter must not be empty. The default implementation
+    /// returns CRLF.
+    fn delimiter(&self) -> &'static [u8] {
+        b"\r\n"
+    }
+
+    /// Returns the maximum length of a line without the delimiter.
+    ///
+    /// The default implementation returns 1024.
+    fn max_line_len(&self) -> usize {
+        1024
+    }
+
+    /// Called when the peer has sent something that isn’t a proper line.
+    ///
+    /// Currently, this only happens when a line is longer than
+    /// [max_line_len()](#method.max_line_len). The handler can still write
+    /// a response, for instance telling the peer to stop typing, and
+    /// return `Next::write_then_close()`. The buffered data is discarded.
+    ///
+    /// The default implementation removes the socket.
+    fn line_error(self, err: LineError, sock: &mut T) -> Next<Self> {
+        let _ = (err, sock);
+        Next::remove()
+    }
+
+    /// Called after reading if there wasn’t a complete line yet.
+    ///
+    /// The default implementation waits for more to read.
+    fn incomplete(self, sock: &mut T) -> Next<Self> {
+        let _ = sock;
+        Next::read(self)
+    }
+
+    /// Called when the peer has closed its side of the connection.
+    ///
+    /// This happens after all complete lines have been given to
+    /// [line()](#tymethod.line). An incomplete last line is dropped. The
+    /// default implementation removes the socket.
+    fn eof(self, sock: &mut T) -> Next<Self> {
+        let _ = sock;
+        Next::remove()
+    }
+
+    /// Called when an error has occured on the socket.
+    ///
+    /// This is the same as `TransportHandler::error()`, including the
+    /// default implementation removing the socket.
+    fn error(self, err: Error) -> Next<Self> {
+        let _ = err;
+        Next::remove()
+    }
+}
+
+
+//------------ LineReader ----------------------------------------------------
+
+/// A transport handler that splits its input into lines.
+///
+/// The reader buffers everything read from the socket and calls the
+/// wrapped [LineHandler]’s `line()` for every complete line. The
+/// delimiter and the maximum length of a line are determined by the
+/// handler. If a line grows beyond the maximum, the handler’s
+/// `line_error()` is called with `LineError::TooLong`, protecting against
+/// peers that never end their lines.
+///
+/// If more than one complete line has arrived, they are all dispatched
+/// in one go and only the value returned for the last line determines
+/// what happens next. If the handler asks for removal, though, the
+/// remaining lines are dropped. A handler that can’t take another line
+/// right away, for instance because it waits for an answer, needs to
+/// keep the line itself.
+///
+/// # Panics
+///
+/// Reading panics if the handler’s `delimiter()` is empty.
+///
+/// [LineHandler]: trait.LineHandler.html
+pub struct LineReader<H> {
+    handler: H,
+    buf: Vec<u8>,
+}
+
+impl<H> LineReader<H> {
+    /// Returns a reference to the wrapped handler.
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    /// Wraps a handler’s next value, keeping the buffer.
+    fn wrap(next: Next<H>, buf: Vec<u8>) -> Next<Self> {
+        next.map(move |handler| LineReader { handler: handler, buf: buf })
+    }
+
+    /// Dispatches all complete lines in the buffer.
+    ///
+    /// If `eof` is `true`, the peer has closed its side and the handler’s
+    /// `eof()` is called after the last line.
+    fn dispatch<T>(mut handler: H, mut buf: Vec<u8>, eof: bool,
+                   sock: &mut T) -> Next<Self>
+                where T: Stream, H: LineHandler<T> {
+        let delimiter = handler.delimiter();
+        let max_len = handler.max_line_len();
+        assert!(!delimiter.is_empty(), "empty line delimiter");
+        loop {
+            let (end, start) = match split_line(&buf, delimiter, max_len) {
+                Ok(Some(res)) => res,
+                Ok(None) => {
+                    let next = if eof {
+                        buf.clear();
+                        handler.eof(sock)
+                    }
+                    else {
+                        handler.incomplete(sock)
+                    };
+                    return LineReader::wrap(next, buf)
+                }
+                Err(err) => {
+                    buf.clear();
+                    return LineReader::wrap(handler.line_error(err, sock),
+                                            buf)
+                }
+            };
+            let next = handler.line(&buf[..end], sock);
+            buf.drain(..start);
+            if !eof {
+                if let Ok(None) = split_line(&buf, delimiter, max_len) {
+                    return LineReader::wrap(next, buf)
+                }
+            }
+            handler = match next.into_handler() {
+                Some(handler) => handler,
+                None => return Next::remove()
+            };
+        }
+    }
+}
+
+impl<T: Stream, H: LineHandler<T>> TransportHandler<T> for LineReader<H> {
+    type Seed = H::Seed;
+
+    fn create(seed: Self::Seed, sock: &mut T, notifier: Notifier)
+              -> Next<Self> {
+        LineReader::wrap(H::create(seed, sock, notifier), Vec::new())
+    }
+
+    fn readable(self, sock: &mut T) -> Next<Self> {
+        let LineReader { handler, mut buf } = self;
+        let limit = handler.max_line_len() + handler.delimiter().len();
+        let mut chunk = [0u8; 1024];
+        let mut eof = false;
+        // Stop reading once there is enough for detecting an overlong
+        // line. Anything else stays in the socket for next time.
+        while buf.len() <= limit {
+            match sock.try_read(&mut chunk) {
+                Ok(Some(0)) => {
+                    eof = true;
+                    break
+                }
+                Ok(Some(len)) => buf.extend_from_slice(&chunk[..len]),
+                Ok(None) => break,
+                Err(err) => {
+                    return LineReader::wrap(handler.error(err.into()), buf)
+                }
+            }
+        }
+        LineReader::dispatch(handler, buf, eof, sock)
+    }
+
+    fn writable(self, sock: &mut T) -> Next<Self> {
+        LineReader::wrap(self.handler.writable(sock), self.buf)
+    }
+
+    fn wakeup(self, sock: &mut T) -> Next<Self> {
+        LineReader::wrap(self.handler.wakeup(sock), self.buf)
+    }
+
+    fn on_ready(self, sock: &mut T) -> Next<Self> {
+        LineReader::wrap(self.handler.on_ready(sock), self.buf)
+    }
+
+    fn secured(self, sock: &mut T) -> Next<Self> {
+        LineReader::wrap(self.handler.secured(sock), self.buf)
+    }
+
+    fn tick(self, sock: &mut T) -> Next<Self> {
+        LineReader::wrap(self.handler.tick(sock), self.buf)
+    }
+
+    fn has_pending_output(&self) -> bool {
+        self.handler.has_pending_output()
+    }
+
+    fn write_queue(&mut self) -> Option<QueueWriter<T>> {
+        self.handler.write_queue()
+    }
+
+    fn error(self, err: Error) -> Next<Self> {
+        LineReader::wrap(self.handler.error(err), self.buf)
+    }
+
+    fn teardown(self, sock: &mut T) {
+        self.handler.teardown(sock)
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// Finds the first line in `data` ending in `delimiter`.
+///
+/// Returns the end of the line and the start of whatever follows the
+/// delimiter or `Ok(None)` if there is no complete line yet. The
+/// delimiter must not be empty.
+fn split_line(data: &[u8], delimiter: &[u8], max_len: usize)
+              -> Result<Option<(usize, usize)>, LineError> {
+    match data.windows(delimiter.len()).position(|item| item == delimiter) {
+        Some(pos) => {
+            if pos > max_len { Err(LineError::TooLong) }
+            else { Ok(Some((pos, pos + delimiter.len()))) }
+        }
+        None => {
+            // The end of the data may still be the start of a delimiter.
+            let len = data.len().saturating_sub(delimiter.len() - 1);
+            if len > max_len { Err(LineError::TooLong) }
+            else { Ok(None) }
+        }
+    }
+}
+
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+    use rotor::Notifier;
+    use ::error::Error;
+    use ::handlers::TransportHandler;
+    use ::next::Next;
+    use std::time::Duration;
+    use rotor::Time;
+    use ::sockets::mock::MockStream;
+    use super::*;
+
+    /// A writer that only accepts `room` bytes before it blocks.
+    struct Limited {
+        data: Vec<u8>,
+        room: usize,
+    }
+
+    impl Limited {
+        fn new(room: usize) -> Self {
+            Limited { data: Vec::new(), room: room }
+        }
+    }
+
+    impl Write for Limited {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                          "blocked"))
+            }
+            let len = ::std::cmp::min(buf.len(), self.room);
+            self.data.extend_from_slice(&buf[..len]);
+            self.room -= len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    //--- WriteBuffer
+
+    fn interest(buf: &WriteBuffer) -> String {
+        format!("{:?}", buf.next(()))
+    }
+
+    #[test]
+    fn buffer_water_marks() {
+        let mut buf = WriteBuffer::new(8, 2);
+        assert_eq!(interest(&buf), "Next::Read");
+        buf.push(b"1234567").unwrap();
+        assert!(!buf.is_paused());
+        assert_eq!(interest(&buf), "Next::ReadWrite");
+        buf.push(b"8").unwrap();
+        assert!(buf.is_paused());
+        assert_eq!(interest(&buf), "Next::Write");
+
+        // Writing down to above the low water mark keeps the pause.
+        let mut sock = Limited::new(5);
+        assert_eq!(buf.write_to(&mut sock).unwrap(), false);
+        assert_eq!(buf.len(), 3);
+        assert!(buf.is_paused());
+
+        // Reaching the low water mark ends it.
+        sock.room = 1;
+        assert_eq!(buf.write_to(&mut sock).unwrap(), false);
+        assert_eq!(buf.len(), 2);
+        assert!(!buf.is_paused());
+        assert_eq!(interest(&buf), "Next::ReadWrite");
+
+        sock.room = 10;
+        assert_eq!(buf.write_to(&mut sock).unwrap(), true);
+        assert_eq!(sock.data, b"12345678");
+        assert_eq!(interest(&buf), "Next::Read");
+    }
+
+    #[test]
+    fn buffer_budget() {
+        let budget = WriteBudget::new(10);
+        let mut first = WriteBuffer::with_budget(100, 0, budget.clone());
+        let mut second = WriteBuffer::with_budget(100, 0, budget.clone());
+        first.push(b"123456").unwrap();
+        assert_eq!(second.push(b"12345"), Err(BudgetExceeded));
+        assert!(second.is_paused());
+        assert!(second.is_empty());
+        second.push(b"1234").unwrap();
+        assert_eq!(budget.total(), 10);
+
+        first.write_to(&mut Limited::new(100)).unwrap();
+        assert_eq!(budget.total(), 4);
+        drop(second);
+        assert_eq!(budget.total(), 0);
+    }
+
+    //--- PriorityWriteQueue
+
+    #[test]
+    fn queue_priority_order() {
+        let mut queue = PriorityWriteQueue::new(&[16, 16]);
+        queue.push(1, b"bulk".to_vec());
+        queue.push(0, b"ctrl".to_vec());
+        assert_eq!(queue.len(), 8);
+        let mut sock = Limited::new(100);
+        assert_eq!(queue.write_to(&mut sock).unwrap(), true);
+        assert_eq!(sock.data, b"ctrlbulk");
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn queue_partial_chunk_finishes_first() {
+        let mut queue = PriorityWriteQueue::new(&[4, 4]);
+        queue.push(1, b"aaaaaaaa".to_vec());
+        let mut sock = Limited::new(3);
+        assert_eq!(queue.write_to(&mut sock).unwrap(), false);
+        assert_eq!(queue.band_len(1), 5);
+        queue.push(0, b"bb".to_vec());
+        sock.room = 100;
+        assert_eq!(queue.write_to(&mut sock).unwrap(), true);
+        assert_eq!(sock.data, b"aaaaaaaabb");
+    }
+
+    #[test]
+    fn queue_weighted_rounds() {
+        let mut queue = PriorityWriteQueue::new(&[4, 2]);
+        for _ in 0..3 {
+            queue.push(0, b"0000".to_vec());
+        }
+        for _ in 0..2 {
+            queue.push(1, b"11".to_vec());
+        }
+        let mut sock = Limited::new(100);
+        assert_eq!(queue.write_to(&mut sock).unwrap(), true);
+        assert_eq!(sock.data, b"0000110000110000");
+    }
+
+    #[test]
+    fn queue_reuses_buffers() {
+        let mut queue = PriorityWriteQueue::new(&[1]);
+        let mut buf = queue.buffer();
+        buf.extend_from_slice(b"data");
+        queue.push(0, buf);
+        queue.write_to(&mut Limited::new(100)).unwrap();
+        let buf = queue.buffer();
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= 4);
+    }
+
+    #[test]
+    fn queue_writer() {
+        let mut queue = PriorityWriteQueue::new(&[1]);
+        queue.push(0, b"data".to_vec());
+        let mut sock = Limited::new(2);
+        {
+            let writer = QueueWriter::<Limited>::new(&mut queue);
+            assert!(!writer.is_empty());
+            assert_eq!(writer.write_to(&mut sock).unwrap(), false);
+        }
+        sock.room = 2;
+        assert_eq!(QueueWriter::new(&mut queue).write_to(&mut sock).unwrap(),
+                   true);
+        assert_eq!(sock.data, b"data");
+    }
+
+    //--- parse_line
+
+    #[test]
+    fn parse_line_complete() {
+        assert_eq!(parse_line(b"foo\r\nbar", 8),
+                   Ok(Some((&b"foo"[..], &b"bar"[..]))));
+        assert_eq!(parse_line(b"\r\n", 8), Ok(Some((&b""[..], &b""[..]))));
+        assert_eq!(parse_line(b"a\rb\0\r\n", 8),
+                   Ok(Some((&b"a\rb\0"[..], &b""[..]))));
+    }
+
+    #[test]
+    fn parse_line_incomplete() {
+        assert_eq!(parse_line(b"", 8), Ok(None));
+        assert_eq!(parse_line(b"foo", 8), Ok(None));
+        assert_eq!(parse_line(b"foo\r", 8), Ok(None));
+        // A trailing CR may still become the line end.
+        assert_eq!(parse_line(b"12345678\r", 8), Ok(None));
+    }
+
+    #[test]
+    fn parse_line_too_long() {
+        assert_eq!(parse_line(b"12345678\r\n", 8),
+                   Ok(Some((&b"12345678"[..], &b""[..]))));
+        assert_eq!(parse_line(b"123456789\r\n", 8), Err(LineError::TooLong));
+        assert_eq!(parse_line(b"123456789", 8), Err(LineError::TooLong));
+        assert_eq!(parse_line(b"", 0), Ok(None));
+    }
+
+    #[test]
+    fn parse_line_bare_lf() {
+        assert_eq!(parse_line(b"\n", 8), Err(LineError::BareLf));
+        assert_eq!(parse_line(b"foo\nbar\r\n", 8), Err(LineError::BareLf));
+    }
+
+    //--- LineReader
+
+    /// A line handler collecting everything it gets.
+    #[derive(Default)]
+    struct Collect {
+        lines: Vec<Vec<u8>>,
+        errors: Vec<LineError>,
+        eof: bool,
+    }
+
+    impl LineHandler<MockStream> for Collect {
+        type Seed = ();
+
+        fn create(_seed: (), _sock: &mut MockStream, _notifier: Notifier)
+                  -> Next<Self> {
+            Next::read(Collect::default())
+        }
+
+        fn line(mut self, line: &[u8], _sock: &mut MockStream)
+                -> Next<Self> {
+            self.lines.push(line.to_vec());
+            Next::read(self)
+        }
+
+        fn writable(self, _sock: &mut MockStream) -> Next<Self> {
+            Next::read(self)
+        }
+
+        fn wakeup(self, _sock: &mut MockStream) -> Next<Self> {
+            Next::read(self)
+        }
+
+        fn max_line_len(&self) -> usize {
+            8
+        }
+
+        fn line_error(mut self, err: LineError, _sock: &mut MockStream)
+                      -> Next<Self> {
+            self.errors.push(err);
+            Next::read(self)
+        }
+
+        fn eof(mut self, _sock: &mut MockStream) -> Next<Self> {
+            self.eof = true;
+            Next::read(self)
+        }
+
+        fn error(self, _err: Error) -> Next<Self> {
+            Next::remove()
+        }
+    }
+
+    fn read_lines(data: &[u8], close: bool) -> LineReader<Collect> {
+        let mut sock = MockStream::new();
+        sock.push_inbound(data);
+        if close {
+            sock.close_inbound();
+        }
+        let reader = LineReader { handler: Collect::default(),
+                                  buf: Vec::new() };
+        reader.readable(&mut sock).into_handler().unwrap()
+    }
+
+    #[test]
+    fn split_line_cases() {
+        assert_eq!(split_line(b"ab\r\ncd", b"\r\n", 8), Ok(Some((2, 4))));
+        assert_eq!(split_line(b"\r\n", b"\r\n", 8), Ok(Some((0, 2))));
+        assert_eq!(split_line(b"ab\r", b"\r\n", 8), Ok(None));
+        assert_eq!(split_line(b"", b"\r\n", 8), Ok(None));
+        assert_eq!(split_line(b"a;b", b";", 8), Ok(Some((1, 2))));
+        assert_eq!(split_line(b"12345678\r\n", b"\r\n", 8),
+                   Ok(Some((8, 10))));
+        assert_eq!(split_line(b"123456789\r\n", b"\r\n", 8),
+                   Err(LineError::TooLong));
+        // A trailing partial delimiter doesn’t count towards the length.
+        assert_eq!(split_line(b"12345678\r", b"\r\n", 8), Ok(None));
+        assert_eq!(split_line(b"123456789", b"\r\n", 8), Ok(None));
+        assert_eq!(split_line(b"1234567890", b"\r\n", 8),
+                   Err(LineError::TooLong));
+    }
+
+    #[test]
+    fn several_lines_in_one_read() {
+        let reader = read_lines(b"one\r\ntwo\r\nthree\r\nfo", false);
+        assert_eq!(reader.handler.lines,
+                   vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]);
+        assert_eq!(reader.buf, b"fo");
+        assert!(!reader.handler.eof);
+    }
+
+    #[test]
+    fn overlong_line() {
+        let reader = read_lines(b"0123456789abcdef\r\n", false);
+        assert!(reader.handler.lines.is_empty());
+        assert_eq!(reader.handler.errors, vec![LineError::TooLong]);
+        assert!(reader.buf.is_empty());
+    }
+
+    #[test]
+    fn eof_with_partial_line() {
+        let reader = read_lines(b"one\r\ntw", true);
+        assert_eq!(reader.handler.lines, vec![b"one".to_vec()]);
+        assert!(reader.handler.eof);
+        assert!(reader.buf.is_empty());
+    }
+
+    //--- TokenBucket
+
+    fn at(millis: u64) -> Time {
+        Time::zero() + Duration::from_millis(1_000_000 + millis)
+    }
+
+    #[test]
+    fn bucket_refill() {
+        let mut bucket = TokenBucket::new(10, 5);
+        assert_eq!(bucket.available(at(0)), 10);
+        assert!(bucket.try_take(10, at(0)));
+        assert_eq!(bucket.available(at(0)), 0);
+        assert_eq!(bucket.available(at(199)), 0);
+        assert_eq!(bucket.available(at(200)), 1);
+        assert_eq!(bucket.available(at(1000)), 5);
+        assert_eq!(bucket.available(at(5000)), 10);
+        assert!(!bucket.try_take(11, at(5000)));
+        assert!(bucket.try_take(10, at(5000)));
+        // The half token left over after 300ms carries over.
+        assert!(bucket.try_take(1, at(5300)));
+        assert_eq!(bucket.available(at(5399)), 0);
+        assert_eq!(bucket.available(at(5400)), 1);
+    }
+
+    #[test]
+    fn bucket_time_until() {
+        let mut bucket = TokenBucket::new(10, 5);
+        assert_eq!(bucket.time_until(10, at(0)), Duration::new(0, 0));
+        assert!(bucket.try_take(10, at(0)));
+        assert_eq!(bucket.time_until(1, at(0)), Duration::from_millis(200));
+        assert_eq!(bucket.time_until(5, at(0)), Duration::from_secs(1));
+        assert_eq!(bucket.time_until(100, at(0)), Duration::from_secs(2));
+        assert_eq!(bucket.time_until(1, at(100)),
+                   Duration::from_millis(100));
+    }
+
+    #[test]
+    fn bucket_clock_jumps() {
+        let mut bucket = TokenBucket::new(10, 1);
+        assert!(bucket.try_take(10, at(10_000)));
+
+        // Backwards: nothing is added and the last refill stays put.
+        assert_eq!(bucket.available(at(0)), 0);
+        assert!(!bucket.try_take(1, at(5_000)));
+        assert_eq!(bucket.available(at(11_000)), 1);
+
+        // Far forward: the bucket is full instead of overflowing.
+        let far = Time::zero() + Duration::from_secs(1 << 40);
+        assert_eq!(bucket.available(far), 10);
+        assert!(bucket.try_take(10, far));
+        assert_eq!(bucket.available(far), 0);
+    }
+
+    #[test]
+    fn bucket_huge_capacity() {
+        let mut bucket = TokenBucket::new(u64::max_value(), 1);
+        assert!(bucket.try_take(2, at(0)));
+        assert_eq!(bucket.available(at(1000)), u64::max_value() - 1);
+        assert_eq!(bucket.time_until(u64::max_value(), at(1000)),
+                   Duration::from_secs(1));
+        assert!(bucket.time_until(u64::max_value(), at(0))
+                > Duration::from_secs(1));
+        let mut bucket = TokenBucket::new(u64::max_value(), 1);
+        assert!(bucket.try_take(u64::max_value(), at(0)));
+        assert_eq!(bucket.available(at(1000)), 1);
+        bucket.time_until(u64::max_value(), at(0));
+    }
+}